@@ -26,7 +26,7 @@
 // 01   01   10   00   10   00   00
 // x(0) x(1) L(2) u(3) L(4) u(5) u(6)
 
-use crate::{MemAlloc, SIZE_64K};
+use crate::{InitError, MemAlloc, SIZE_64K};
 
 const TAG_UNUSED: u64 = 0;
 const TAG_INNER: u64 = 1;
@@ -36,6 +36,31 @@ pub struct BuddyAlloc<const DEPTH: usize, const NUM_NODES32: usize> {
     min_size: usize,
     start: usize,               // start address
     bitmap: [u64; NUM_NODES32], // succinct structure of the tree
+    bias: Bias,                 // which subtree is searched first
+    used: usize,                // bytes of used leaves
+    peak: usize,                // high-water mark of used
+}
+
+/// Which side of the address space allocations are packed into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bias {
+    /// Search the left subtree first. Allocations cluster at low addresses.
+    Low,
+    /// Search the right subtree first. Allocations cluster at high addresses,
+    /// which keeps low memory (e.g. a DMA zone) free.
+    High,
+}
+
+/// A broken invariant of the tree found by `BuddyAlloc::verify`.
+/// `idx` is the index of the offending node in the bitmap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The 2-bit tag is none of unused, inner and used leaf.
+    InvalidTag { idx: usize },
+    /// An inner node whose children are all unused, which must have been coalesced.
+    EmptyInner { idx: usize },
+    /// An unused node or a used leaf which has a marked child.
+    MarkedChild { idx: usize },
 }
 
 // let num_nodes = (1 << (DEPTH_OF_TREE + 1)) - 1; // the number of nodes.
@@ -45,6 +70,7 @@ pub struct BuddyAlloc<const DEPTH: usize, const NUM_NODES32: usize> {
 // `u64` can encode 32 nodes.
 // So, at most (#nodes / 32 + 1) elements of `u64` are required to represent
 // a tree of a buddy allocator.
+const NODES_PAGE64K_MEM16M: usize = (((1 << (DEPTH_PAGE64K_MEM16M + 1)) - 1) >> 5) + 1;
 const NODES_PAGE64K_MEM32M: usize = (((1 << (DEPTH_PAGE64K_MEM32M + 1)) - 1) >> 5) + 1;
 const NODES_PAGE64K_MEM64M: usize = (((1 << (DEPTH_PAGE64K_MEM64M + 1)) - 1) >> 5) + 1;
 const NODES_PAGE64K_MEM128M: usize = (((1 << (DEPTH_PAGE64K_MEM128M + 1)) - 1) >> 5) + 1;
@@ -65,6 +91,10 @@ const NODES_PAGE64K_MEM2T: usize = (((1 << (DEPTH_PAGE64K_MEM2T + 1)) - 1) >> 5)
 const NODES_PAGE64K_MEM4T: usize = (((1 << (DEPTH_PAGE64K_MEM4T + 1)) - 1) >> 5) + 1;
 const NODES_PAGE64K_MEM8T: usize = (((1 << (DEPTH_PAGE64K_MEM8T + 1)) - 1) >> 5) + 1;
 
+// The tree of Buddy16M has 511 nodes, which are encoded in 16 words.
+const _: () = assert!(NODES_PAGE64K_MEM16M == 16);
+
+const DEPTH_PAGE64K_MEM16M: usize = 8;
 const DEPTH_PAGE64K_MEM32M: usize = 9;
 const DEPTH_PAGE64K_MEM64M: usize = 10;
 const DEPTH_PAGE64K_MEM128M: usize = 11;
@@ -85,6 +115,7 @@ const DEPTH_PAGE64K_MEM2T: usize = 25;
 const DEPTH_PAGE64K_MEM4T: usize = 26;
 const DEPTH_PAGE64K_MEM8T: usize = 27;
 
+pub type Buddy16M = BuddyAlloc<DEPTH_PAGE64K_MEM16M, NODES_PAGE64K_MEM16M>;
 pub type Buddy32M = BuddyAlloc<DEPTH_PAGE64K_MEM32M, NODES_PAGE64K_MEM32M>;
 pub type Buddy64M = BuddyAlloc<DEPTH_PAGE64K_MEM64M, NODES_PAGE64K_MEM64M>;
 pub type Buddy128M = BuddyAlloc<DEPTH_PAGE64K_MEM128M, NODES_PAGE64K_MEM128M>;
@@ -105,6 +136,26 @@ pub type Buddy2T = BuddyAlloc<DEPTH_PAGE64K_MEM2T, NODES_PAGE64K_MEM2T>;
 pub type Buddy4T = BuddyAlloc<DEPTH_PAGE64K_MEM4T, NODES_PAGE64K_MEM4T>;
 pub type Buddy8T = BuddyAlloc<DEPTH_PAGE64K_MEM8T, NODES_PAGE64K_MEM8T>;
 
+/// Return the number of bytes a buddy allocator of `depth` with 64KiB pages manages,
+/// which is the heap size `MemAlloc::new` requires.
+///
+/// For example, `buddy_capacity_bytes(9)` is 32MiB, the heap size of `Buddy32M`.
+pub const fn buddy_capacity_bytes(depth: usize) -> usize {
+    SIZE_64K << depth
+}
+
+/// Return the smallest depth of a buddy allocator with 64KiB pages managing at least `bytes`.
+///
+/// For example, `buddy_depth_for(20 * 1024 * 1024)` is 9, the depth of `Buddy32M`.
+pub const fn buddy_depth_for(bytes: usize) -> usize {
+    let pages = bytes.div_ceil(SIZE_64K);
+    if pages <= 1 {
+        0
+    } else {
+        (usize::BITS - (pages - 1).leading_zeros()) as usize
+    }
+}
+
 enum Tag {
     Unused = TAG_UNUSED as isize,
     Inner = TAG_INNER as isize,
@@ -112,7 +163,38 @@ enum Tag {
 }
 
 impl<const DEPTH: usize, const NUM_NODES32: usize> BuddyAlloc<DEPTH, NUM_NODES32> {
+    /// Create a buddy allocator whose smallest block is `min_size` bytes.
+    ///
+    /// `min_size` must be a power of two, and `size` must be `(1 << DEPTH) * min_size`.
+    /// Blocks are aligned to their size relative to `start_addr`.
+    pub fn with_min_size(start_addr: usize, size: usize, min_size: usize) -> Self {
+        assert!(min_size.is_power_of_two());
+        assert_eq!(size, (1 << DEPTH) * min_size);
+
+        Self {
+            min_size,
+            start: start_addr,
+            bitmap: [0; NUM_NODES32],
+            bias: Bias::Low,
+            used: 0,
+            peak: 0,
+        }
+    }
+
+    /// Set which subtree is searched first when allocating.
+    pub fn set_allocation_bias(&mut self, bias: Bias) {
+        self.bias = bias;
+    }
+
     pub(crate) fn buddy_alloc(&mut self, size: usize) -> Option<*mut u8> {
+        self.alloc_order(size).map(|(ptr, _)| ptr)
+    }
+
+    /// Allocate a block and return its address and size.
+    ///
+    /// The size of the block is `size` rounded up to a power of two multiple of the minimum block size,
+    /// which is the exact span to be unmapped when the block is freed.
+    pub fn alloc_order(&mut self, size: usize) -> Option<(*mut u8, usize)> {
         self.find_mem(size, (1 << DEPTH) * self.min_size, 0, 0)
     }
 
@@ -120,10 +202,333 @@ impl<const DEPTH: usize, const NUM_NODES32: usize> BuddyAlloc<DEPTH, NUM_NODES32
         self.release_mem(addr as usize, (1 << DEPTH) * self.min_size, 0, 0)
     }
 
-    fn get_tag(&self, idx: usize) -> Tag {
+    /// Return the maximum number of bytes of used blocks at once
+    /// since the creation or the last `reset_peak`.
+    pub fn peak_used_bytes(&self) -> usize {
+        self.peak
+    }
+
+    /// Reset the high-water mark to the number of bytes currently used.
+    pub fn reset_peak(&mut self) {
+        self.peak = self.used;
+    }
+
+    fn add_used(&mut self, bytes: usize) {
+        self.used += bytes;
+        self.peak = self.peak.max(self.used);
+    }
+
+    /// Return the size of the largest unused block.
+    pub fn largest_free_block(&self) -> usize {
+        self.largest_free(0, 0, (1 << DEPTH) * self.min_size)
+    }
+
+    /// Call `f(addr, bytes)` for each unused block in ascending order of address.
+    ///
+    /// Unused buddies are coalesced, so each call reports a maximal free extent of the tree
+    /// rather than every unused block of the minimum size.
+    pub fn for_each_free_block(&self, mut f: impl FnMut(usize, usize)) {
+        self.for_each_free(0, 0, (1 << DEPTH) * self.min_size, &mut f);
+    }
+
+    /// Allocate a block of exactly `min_size << order` bytes.
+    ///
+    /// A free block of the order is used if exists,
+    /// and a larger block is split only if there is no such block.
+    /// `None` is returned if `order` exceeds the depth of the tree or no block is available.
+    /// The block is aligned to its size relative to the start address.
+    pub fn alloc_exact_order(&mut self, order: usize) -> Option<*mut u8> {
+        if order > DEPTH {
+            return None;
+        }
+
+        let bytes = self.min_size << order;
+        match self.find_free_at(0, 0, DEPTH - order) {
+            Some(offset) => {
+                self.set_tag(Self::get_idx(DEPTH - order, offset), Tag::UsedLeaf);
+                self.add_used(bytes);
+                Some((self.start + bytes * offset) as *mut u8)
+            }
+            None => self.buddy_alloc(bytes),
+        }
+    }
+
+    /// Allocate the block at `addr`, e.g. a DMA window at a known physical address.
+    ///
+    /// The size of the block is `size` rounded up to a power of two multiple of the minimum block size,
+    /// and `addr` must be aligned to it relative to the start address.
+    /// Unused blocks covering `addr` are split as in `alloc`.
+    /// `None` is returned if `addr` is misaligned, out of range, or any part of the block is used.
+    pub fn alloc_at(&mut self, addr: usize, size: usize) -> Option<*mut u8> {
+        let total = (1 << DEPTH) * self.min_size;
+        let bytes = size.max(self.min_size).checked_next_power_of_two()?;
+        let offset = addr.checked_sub(self.start)?;
+        if bytes > total || offset >= total || offset % bytes != 0 {
+            return None;
+        }
+
+        // An unused node has only unused descendants,
+        // so nothing has to be rolled back after splitting one.
+        let (mut depth, mut node, mut node_bytes) = (0, 0, total);
+        while node_bytes > bytes {
+            let idx = Self::get_idx(depth, node);
+            match self.get_tag(idx) {
+                Tag::UsedLeaf => return None,
+                Tag::Unused => self.set_tag(idx, Tag::Inner),
+                Tag::Inner => (),
+            }
+
+            depth += 1;
+            node_bytes >>= 1;
+            node = offset / node_bytes;
+        }
+
+        let idx = Self::get_idx(depth, node);
+        if !matches!(self.get_tag(idx), Tag::Unused) {
+            return None;
+        }
+
+        self.set_tag(idx, Tag::UsedLeaf);
+        self.add_used(bytes);
+        Some(addr as *mut u8)
+    }
+
+    /// Mark the blocks covering `[addr, addr + size)` as used permanently,
+    /// e.g. for firmware tables or the kernel image inside the heap.
+    ///
+    /// The range is extended to multiples of the minimum block size,
+    /// and unused nodes partially covering it are split so that the rest stays allocatable.
+    /// Blocks already used in the range are left as they are.
+    /// Reserved blocks must not be freed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is not in the heap.
+    pub fn reserve(&mut self, addr: usize, size: usize) {
+        let total = (1 << DEPTH) * self.min_size;
+        let Some(lo) = addr.checked_sub(self.start) else {
+            panic!("out of range");
+        };
+        let hi = match lo.checked_add(size) {
+            Some(hi) if hi <= total => hi,
+            _ => panic!("out of range"),
+        };
+
+        let lo = lo / self.min_size * self.min_size;
+        let hi = hi.div_ceil(self.min_size) * self.min_size;
+        self.reserve_range(0, 0, total, lo, hi);
+    }
+
+    /// Mark the part of the node in `[lo, hi)`, which is relative to the start address, as used.
+    fn reserve_range(&mut self, depth: usize, offset: usize, bytes: usize, lo: usize, hi: usize) {
+        let node_start = bytes * offset;
+        if hi <= node_start || node_start + bytes <= lo {
+            return;
+        }
+
+        let idx = Self::get_idx(depth, offset);
+        let covered = lo <= node_start && node_start + bytes <= hi;
+        match self.get_tag(idx) {
+            Tag::UsedLeaf => return,
+            Tag::Unused if covered => {
+                self.set_tag(idx, Tag::UsedLeaf);
+                self.add_used(bytes);
+                return;
+            }
+            Tag::Unused => self.set_tag(idx, Tag::Inner),
+            Tag::Inner => (),
+        }
+
+        self.reserve_range(depth + 1, offset * 2, bytes >> 1, lo, hi);
+        self.reserve_range(depth + 1, offset * 2 + 1, bytes >> 1, lo, hi);
+    }
+
+    /// Find an unused node at `target` depth without splitting larger unused nodes.
+    fn find_free_at(&self, depth: usize, offset: usize, target: usize) -> Option<usize> {
+        match self.get_tag(Self::get_idx(depth, offset)) {
+            Tag::Unused if depth == target => Some(offset),
+            Tag::Inner if depth < target => {
+                let first = match self.bias {
+                    Bias::Low => 0,
+                    Bias::High => 1,
+                };
+                self.find_free_at(depth + 1, offset * 2 + first, target)
+                    .or_else(|| self.find_free_at(depth + 1, offset * 2 + (1 - first), target))
+            }
+            _ => None,
+        }
+    }
+
+    /// Grow the block at `addr` to at least `new_size` bytes without moving it.
+    ///
+    /// The block can grow only if it is the left child and its buddy is unused,
+    /// for each level until the block is large enough.
+    /// `false` is returned without any change if a copy is required.
+    pub fn grow_in_place(&mut self, addr: *mut u8, new_size: usize) -> bool {
+        let Some((leaf_depth, leaf_offset, leaf_bytes)) = self.find_leaf(addr as usize) else {
+            return false;
+        };
+
+        // find the ancestor which becomes the new block
+        let (mut depth, mut offset, mut bytes) = (leaf_depth, leaf_offset, leaf_bytes);
+        while bytes < new_size {
+            if depth == 0 || offset & 1 == 1 {
+                return false;
+            }
+
+            if !matches!(self.get_tag(Self::get_idx(depth, offset + 1)), Tag::Unused) {
+                return false;
+            }
+
+            depth -= 1;
+            offset >>= 1;
+            bytes <<= 1;
+        }
+
+        // Nodes under a used leaf must be unused.
+        let (mut d, mut o) = (leaf_depth, leaf_offset);
+        while d > depth {
+            self.set_tag(Self::get_idx(d, o), Tag::Unused);
+            d -= 1;
+            o >>= 1;
+        }
+        self.set_tag(Self::get_idx(depth, offset), Tag::UsedLeaf);
+        self.add_used(bytes - leaf_bytes);
+
+        true
+    }
+
+    /// Return `(depth, offset, bytes)` of the used leaf starting at `addr`.
+    fn find_leaf(&self, addr: usize) -> Option<(usize, usize, usize)> {
+        let mut bytes = (1 << DEPTH) * self.min_size;
+        let (mut depth, mut offset) = (0, 0);
+
+        if addr < self.start || addr >= self.start + bytes {
+            return None;
+        }
+
+        loop {
+            match self.get_tag(Self::get_idx(depth, offset)) {
+                Tag::Unused => return None,
+                Tag::UsedLeaf => {
+                    return if self.start + bytes * offset == addr {
+                        Some((depth, offset, bytes))
+                    } else {
+                        None
+                    };
+                }
+                Tag::Inner => {
+                    let pivot = self.start + bytes * offset + (bytes >> 1);
+                    offset = offset * 2 + (addr >= pivot) as usize;
+                    depth += 1;
+                    bytes >>= 1;
+                }
+            }
+        }
+    }
+
+    /// Check the invariants of the tree,
+    /// e.g. to validate the metadata when booting after a crash.
+    ///
+    /// All tags are checked first, and then each inner node must have a child which is not unused,
+    /// and each unused node or used leaf must have only unused children.
+    /// The node which breaks an invariant first in index order is reported.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        let num_nodes = (1 << (DEPTH + 1)) - 1;
+        let num_parents = (1 << DEPTH) - 1;
+
+        for idx in 0..num_nodes {
+            if self.raw_tag(idx) > TAG_USED_LEAF {
+                return Err(VerifyError::InvalidTag { idx });
+            }
+        }
+
+        for idx in 0..num_nodes {
+            let children = if idx < num_parents {
+                [self.raw_tag(idx * 2 + 1), self.raw_tag(idx * 2 + 2)]
+            } else {
+                [TAG_UNUSED, TAG_UNUSED]
+            };
+            let has_marked = children.iter().any(|tag| *tag != TAG_UNUSED);
+
+            match self.get_tag(idx) {
+                Tag::Inner if !has_marked => return Err(VerifyError::EmptyInner { idx }),
+                Tag::Unused | Tag::UsedLeaf if has_marked => {
+                    return Err(VerifyError::MarkedChild { idx })
+                }
+                _ => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return `(used_bytes, total_bytes)`, where `used_bytes` is the sum of allocated block sizes.
+    /// The utilization is `used_bytes / total_bytes`.
+    pub fn usage(&self) -> (usize, usize) {
+        let total = (1 << DEPTH) * self.min_size;
+        let mut used = 0;
+        self.for_each_used_leaf(0, 0, total, &mut |_, bytes| used += bytes);
+        (used, total)
+    }
+
+    fn largest_free(&self, depth: usize, offset: usize, bytes: usize) -> usize {
+        match self.get_tag(Self::get_idx(depth, offset)) {
+            Tag::Unused => bytes,
+            Tag::UsedLeaf => 0,
+            Tag::Inner => {
+                let left = self.largest_free(depth + 1, offset * 2, bytes >> 1);
+                if left == bytes >> 1 {
+                    left
+                } else {
+                    left.max(self.largest_free(depth + 1, offset * 2 + 1, bytes >> 1))
+                }
+            }
+        }
+    }
+
+    fn for_each_free(
+        &self,
+        depth: usize,
+        offset: usize,
+        bytes: usize,
+        f: &mut dyn FnMut(usize, usize),
+    ) {
+        match self.get_tag(Self::get_idx(depth, offset)) {
+            Tag::Unused => f(self.start + bytes * offset, bytes),
+            Tag::UsedLeaf => (),
+            Tag::Inner => {
+                self.for_each_free(depth + 1, offset * 2, bytes >> 1, f);
+                self.for_each_free(depth + 1, offset * 2 + 1, bytes >> 1, f);
+            }
+        }
+    }
+
+    fn for_each_used_leaf(
+        &self,
+        depth: usize,
+        offset: usize,
+        bytes: usize,
+        f: &mut dyn FnMut(usize, usize),
+    ) {
+        match self.get_tag(Self::get_idx(depth, offset)) {
+            Tag::Unused => (),
+            Tag::UsedLeaf => f(self.start + bytes * offset, bytes),
+            Tag::Inner => {
+                self.for_each_used_leaf(depth + 1, offset * 2, bytes >> 1, f);
+                self.for_each_used_leaf(depth + 1, offset * 2 + 1, bytes >> 1, f);
+            }
+        }
+    }
+
+    fn raw_tag(&self, idx: usize) -> u64 {
         let i = idx >> 5; // div by 32
         let j = idx & 0b11111;
-        match (self.bitmap[i] >> (j * 2)) & 0b11 {
+        (self.bitmap[i] >> (j * 2)) & 0b11
+    }
+
+    fn get_tag(&self, idx: usize) -> Tag {
+        match self.raw_tag(idx) {
             TAG_UNUSED => Tag::Unused,
             TAG_INNER => Tag::Inner,
             TAG_USED_LEAF => Tag::UsedLeaf,
@@ -153,13 +558,19 @@ impl<const DEPTH: usize, const NUM_NODES32: usize> BuddyAlloc<DEPTH, NUM_NODES32
         bytes: usize, // total bytes of this block
         depth: usize,
         offset: usize, // offset of current node in the depth
-    ) -> Option<*mut u8> {
+    ) -> Option<(*mut u8, usize)> {
         if req > bytes || depth > DEPTH {
             return None;
         }
 
         let idx = Self::get_idx(depth, offset);
 
+        // offset of the child searched first
+        let first = match self.bias {
+            Bias::Low => 0,
+            Bias::High => 1,
+        };
+
         match self.get_tag(idx) {
             Tag::UsedLeaf => None,
             Tag::Unused => {
@@ -167,16 +578,17 @@ impl<const DEPTH: usize, const NUM_NODES32: usize> BuddyAlloc<DEPTH, NUM_NODES32
                 if next_bytes >= req && depth < DEPTH {
                     // divide
                     self.set_tag(idx, Tag::Inner);
-                    self.find_mem(req, next_bytes, depth + 1, offset * 2)
+                    self.find_mem(req, next_bytes, depth + 1, offset * 2 + first)
                 } else {
                     self.set_tag(idx, Tag::UsedLeaf);
+                    self.add_used(bytes);
                     let addr = self.start + bytes * offset;
                     let ptr = addr as *mut u8;
-                    Some(ptr)
+                    Some((ptr, bytes))
                 }
             }
-            Tag::Inner => match self.find_mem(req, bytes >> 1, depth + 1, offset * 2) {
-                None => self.find_mem(req, bytes >> 1, depth + 1, offset * 2 + 1),
+            Tag::Inner => match self.find_mem(req, bytes >> 1, depth + 1, offset * 2 + first) {
+                None => self.find_mem(req, bytes >> 1, depth + 1, offset * 2 + (1 - first)),
                 ret => ret,
             },
         }
@@ -192,6 +604,7 @@ impl<const DEPTH: usize, const NUM_NODES32: usize> BuddyAlloc<DEPTH, NUM_NODES32
                 let target = self.start + bytes * offset;
                 if target == addr {
                     self.set_tag(idx, Tag::Unused);
+                    self.used -= bytes;
                 } else {
                     panic!("freed invalid address");
                 }
@@ -239,13 +652,541 @@ impl<const DEPTH: usize, const NUM_NODES32: usize> MemAlloc for BuddyAlloc<DEPTH
         self.buddy_free(addr)
     }
 
+    /// A block is aligned to its size relative to `start`,
+    /// so a block of at least `align` bytes is aligned if `start` is aligned.
+    fn alloc_aligned(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        if !self.start.is_multiple_of(align) {
+            return None;
+        }
+
+        self.buddy_alloc(size.max(align))
+    }
+
+    fn range(&self) -> (usize, usize) {
+        (self.start, self.start + (1 << DEPTH) * self.min_size)
+    }
+
+    /// `alloc_aligned` satisfies any alignment which `start` is aligned to.
+    fn max_alignment(&self) -> usize {
+        1 << self.start.trailing_zeros().min(usize::BITS - 1)
+    }
+
+    fn largest_free_block(&self) -> usize {
+        BuddyAlloc::largest_free_block(self)
+    }
+
+    fn grow_in_place(&mut self, addr: *mut u8, new_size: usize) -> bool {
+        BuddyAlloc::grow_in_place(self, addr, new_size)
+    }
+
+    fn peak_used_bytes(&self) -> usize {
+        BuddyAlloc::peak_used_bytes(self)
+    }
+
+    fn reset_peak(&mut self) {
+        BuddyAlloc::reset_peak(self)
+    }
+
+    fn for_each_used(&self, f: &mut dyn FnMut(usize, usize)) {
+        self.for_each_used_leaf(0, 0, (1 << DEPTH) * self.min_size, f)
+    }
+
+    fn free_bytes(&self) -> usize {
+        (1 << DEPTH) * self.min_size - self.used
+    }
+
+    /// The tree is reset to a single unused root by zeroing the bitmap.
+    fn free_all(&mut self) {
+        self.bitmap = [0; NUM_NODES32];
+        self.used = 0;
+    }
+
+    fn check_size(size: usize) -> Result<(), InitError> {
+        let expected = (1 << DEPTH) * SIZE_64K;
+        if size != expected {
+            return Err(InitError::SizeMismatch {
+                expected,
+                got: size,
+            });
+        }
+
+        Ok(())
+    }
+
     fn new(start_addr: usize, size: usize) -> Self {
-        assert_eq!(size, (1 << DEPTH) * SIZE_64K);
+        Self::with_min_size(start_addr, size, SIZE_64K)
+    }
+}
 
-        Self {
-            min_size: SIZE_64K,
-            start: start_addr,
-            bitmap: [0; NUM_NODES32],
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{
+        buddy_capacity_bytes, buddy_depth_for, Bias, Buddy32M, VerifyError, DEPTH_PAGE64K_MEM16M,
+        DEPTH_PAGE64K_MEM32M, DEPTH_PAGE64K_MEM8T,
+    };
+    use crate::{MemAlloc, SIZE_64K};
+
+    const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+    fn init() -> (Buddy32M, *mut u8) {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        (Buddy32M::new(ptr as usize, HEAP_SIZE), ptr)
+    }
+
+    fn free(ptr: *mut u8) {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        unsafe { std::alloc::dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn test_bias() {
+        let (mut buddy, ptr) = init();
+        let start = ptr as usize;
+        let end = start + HEAP_SIZE;
+
+        buddy.set_allocation_bias(Bias::High);
+
+        let mut v = std::vec::Vec::new();
+        for i in 0..8 {
+            let mem = buddy.alloc(SIZE_64K << (i % 3)).unwrap();
+            // all blocks are placed in the highest 2MiB
+            assert!(mem as usize >= end - 2 * 1024 * 1024);
+            v.push(mem);
+        }
+
+        assert_eq!(v[0] as usize, end - SIZE_64K);
+
+        for mem in v {
+            buddy.free(mem);
+        }
+
+        buddy.set_allocation_bias(Bias::Low);
+        let mem = buddy.alloc(SIZE_64K).unwrap();
+        assert_eq!(mem as usize, start);
+        buddy.free(mem);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_alloc_order() {
+        let (mut buddy, ptr) = init();
+
+        let (mem, bytes) = buddy.alloc_order(70 * 1024).unwrap();
+        assert_eq!(bytes, 131072);
+        assert_eq!(mem as usize % bytes, ptr as usize % bytes);
+
+        let (mem2, bytes2) = buddy.alloc_order(1).unwrap();
+        assert_eq!(bytes2, SIZE_64K);
+
+        assert_eq!(buddy.alloc_order(HEAP_SIZE + 1), None);
+
+        buddy.free(mem);
+        buddy.free(mem2);
+
+        let (mem, bytes) = buddy.alloc_order(HEAP_SIZE).unwrap();
+        assert_eq!((mem, bytes), (ptr, HEAP_SIZE));
+        buddy.free(mem);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_min_size() {
+        const MIN_SIZE: usize = 4096;
+        const SIZE: usize = 512 * MIN_SIZE;
+
+        let (_, ptr) = init();
+        let start = ptr as usize;
+        let mut buddy = Buddy32M::with_min_size(start, SIZE, MIN_SIZE);
+        assert_eq!(buddy.range(), (start, start + SIZE));
+
+        let mut v = std::vec::Vec::new();
+        for (req, expected) in [(1, 4096), (4096, 4096), (4097, 8192), (20000, 32768)] {
+            let (mem, bytes) = buddy.alloc_order(req).unwrap();
+            assert_eq!(bytes, expected);
+            assert_eq!((mem as usize - start) % bytes, 0);
+            v.push(mem);
+        }
+
+        // the first two 4KiB blocks are buddies
+        assert_eq!(v[0] as usize, start);
+        assert_eq!(v[1] as usize, start + MIN_SIZE);
+
+        for mem in v {
+            buddy.free(mem);
+        }
+
+        assert_eq!(buddy.largest_free_block(), SIZE);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_usage() {
+        let (mut buddy, ptr) = init();
+        assert_eq!(buddy.usage(), (0, HEAP_SIZE));
+
+        let mut used = 0;
+        let mut v = std::vec::Vec::new();
+        for (req, bytes) in [
+            (1, SIZE_64K),
+            (SIZE_64K + 1, SIZE_64K * 2),
+            (SIZE_64K, SIZE_64K),
+        ] {
+            v.push(buddy.alloc(req).unwrap());
+            used += bytes;
+            assert_eq!(buddy.usage(), (used, HEAP_SIZE));
+        }
+
+        // The first and third blocks are buddies, and coalesced after both are freed.
+        buddy.free(v[0]);
+        assert_eq!(buddy.usage().0, SIZE_64K * 3);
+        buddy.free(v[2]);
+        assert_eq!(buddy.usage().0, SIZE_64K * 2);
+        assert_eq!(buddy.alloc(SIZE_64K * 2), Some(ptr));
+
+        buddy.free(ptr);
+        buddy.free(v[1]);
+        assert_eq!(buddy.usage(), (0, HEAP_SIZE));
+        assert_eq!(buddy.largest_free_block(), HEAP_SIZE);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_for_each_free_block() {
+        let (mut buddy, ptr) = init();
+        let start = ptr as usize;
+
+        let mut free_blocks = std::vec::Vec::new();
+        buddy.for_each_free_block(|addr, bytes| free_blocks.push((addr, bytes)));
+        assert_eq!(free_blocks, [(start, HEAP_SIZE)]);
+
+        let mut v = std::vec::Vec::new();
+        for i in 0..16 {
+            v.push(buddy.alloc(SIZE_64K << (i % 4)).unwrap());
+        }
+        for mem in v.iter().step_by(3) {
+            buddy.free(*mem);
+        }
+
+        let mut used = std::vec::Vec::new();
+        buddy.for_each_used(&mut |addr, bytes| used.push((addr, bytes)));
+
+        free_blocks.clear();
+        buddy.for_each_free_block(|addr, bytes| free_blocks.push((addr, bytes)));
+
+        // free and used blocks tile the heap without overlaps
+        let mut blocks = [free_blocks.clone(), used].concat();
+        blocks.sort();
+        let mut addr = start;
+        for (a, bytes) in blocks {
+            assert_eq!(a, addr);
+            addr += bytes;
+        }
+        assert_eq!(addr, start + HEAP_SIZE);
+
+        // blocks are maximal, so no two free blocks are buddies
+        for (addr, bytes) in free_blocks.iter() {
+            assert!(bytes.is_power_of_two());
+            assert_eq!((addr - start) % bytes, 0);
+            let buddy_addr = start + ((addr - start) ^ bytes);
+            assert!(!free_blocks.contains(&(buddy_addr, *bytes)));
+        }
+
+        let free_bytes: usize = free_blocks.iter().map(|(_, bytes)| bytes).sum();
+        assert_eq!(free_bytes, buddy.free_bytes());
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_alloc_at() {
+        let (mut buddy, ptr) = init();
+        let start = ptr as usize;
+
+        // reserve 128KiB at 1MiB
+        let addr = start + 1024 * 1024;
+        assert_eq!(buddy.alloc_at(addr, SIZE_64K + 1), Some(addr as *mut u8));
+        assert_eq!(buddy.usage().0, SIZE_64K * 2);
+
+        // overlapping or misaligned requests fail
+        assert_eq!(buddy.alloc_at(addr, SIZE_64K), None);
+        assert_eq!(buddy.alloc_at(addr + SIZE_64K, SIZE_64K), None);
+        assert_eq!(buddy.alloc_at(addr, SIZE_64K * 4), None);
+        assert_eq!(buddy.alloc_at(start + SIZE_64K, SIZE_64K * 2), None);
+        assert_eq!(buddy.alloc_at(start - SIZE_64K, SIZE_64K), None);
+        assert_eq!(buddy.alloc_at(start + HEAP_SIZE, SIZE_64K), None);
+        assert_eq!(buddy.alloc_at(start, HEAP_SIZE * 2), None);
+        assert_eq!(buddy.usage().0, SIZE_64K * 2);
+
+        // general allocations avoid the reserved block
+        let mut v = std::vec::Vec::new();
+        while let Some(mem) = buddy.alloc(SIZE_64K) {
+            let mem = mem as usize;
+            assert!(mem + SIZE_64K <= addr || mem >= addr + SIZE_64K * 2);
+            v.push(mem);
         }
+        assert_eq!(v.len(), HEAP_SIZE / SIZE_64K - 2);
+
+        for mem in v {
+            buddy.free(mem as *mut u8);
+        }
+        buddy.free(addr as *mut u8);
+        assert_eq!(buddy.largest_free_block(), HEAP_SIZE);
+
+        // the whole heap
+        assert_eq!(buddy.alloc_at(start, HEAP_SIZE), Some(ptr));
+        buddy.free(ptr);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_verify() {
+        let (mut buddy, ptr) = init();
+        assert_eq!(buddy.verify(), Ok(()));
+
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+        let mut v = std::vec::Vec::new();
+        for _ in 0..1000 {
+            if v.is_empty() || rand::Rng::gen_bool(&mut rng, 0.6) {
+                let size = SIZE_64K << rand::Rng::gen_range(&mut rng, 0..4);
+                if let Some(mem) = buddy.alloc(size) {
+                    v.push(mem);
+                }
+            } else {
+                let mem = v.swap_remove(rand::Rng::gen_range(&mut rng, 0..v.len()));
+                buddy.free(mem);
+            }
+            assert_eq!(buddy.verify(), Ok(()));
+        }
+
+        // x(0) x(1) u(2) x(3) u(4) x(7) u(8) .. L(511)
+        for mem in v.drain(..) {
+            buddy.free(mem);
+        }
+        let mem = buddy.alloc(SIZE_64K).unwrap();
+        let saved = buddy.bitmap;
+
+        // the tag of u(4) is 0b11
+        buddy.bitmap[0] |= 0b11 << 8;
+        assert_eq!(buddy.verify(), Err(VerifyError::InvalidTag { idx: 4 }));
+        buddy.bitmap = saved;
+
+        // u(2) is an inner node without marked children
+        buddy.bitmap[0] |= 0b01 << 4;
+        assert_eq!(buddy.verify(), Err(VerifyError::EmptyInner { idx: 2 }));
+        buddy.bitmap = saved;
+
+        // u(9), a child of u(4), is a used leaf
+        buddy.bitmap[0] |= 0b10 << 18;
+        assert_eq!(buddy.verify(), Err(VerifyError::MarkedChild { idx: 4 }));
+        buddy.bitmap = saved;
+
+        // L(511), the last node of the 16th word, is an inner node at the deepest level
+        buddy.bitmap[15] ^= 0b11 << 62;
+        assert_eq!(buddy.verify(), Err(VerifyError::EmptyInner { idx: 511 }));
+        buddy.bitmap = saved;
+
+        assert_eq!(buddy.verify(), Ok(()));
+        buddy.free(mem);
+        assert_eq!(buddy.verify(), Ok(()));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_reserve() {
+        let (mut buddy, ptr) = init();
+        let start = ptr as usize;
+
+        // pages 3 to 8 are reserved
+        let reserved = start + 3 * SIZE_64K..start + 9 * SIZE_64K;
+        buddy.reserve(reserved.start + 100, 6 * SIZE_64K - 200);
+        assert_eq!(buddy.usage().0, 6 * SIZE_64K);
+        assert_eq!(buddy.verify(), Ok(()));
+
+        // the other half is untouched
+        assert_eq!(buddy.largest_free_block(), HEAP_SIZE / 2);
+
+        // reserving again changes nothing
+        buddy.reserve(reserved.start, SIZE_64K);
+        assert_eq!(buddy.usage().0, 6 * SIZE_64K);
+
+        let mut v = std::vec::Vec::new();
+        while let Some(mem) = buddy.alloc(SIZE_64K) {
+            assert!(!reserved.contains(&(mem as usize)));
+            v.push(mem);
+        }
+        assert_eq!(v.len(), HEAP_SIZE / SIZE_64K - 6);
+
+        for mem in v {
+            buddy.free(mem);
+        }
+        assert_eq!(buddy.usage().0, 6 * SIZE_64K);
+        assert_eq!(buddy.verify(), Ok(()));
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn test_reserve_out_of_range() {
+        let (mut buddy, ptr) = init();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            buddy.reserve(ptr as usize + HEAP_SIZE - SIZE_64K, 2 * SIZE_64K)
+        }));
+        free(ptr);
+        if let Err(err) = result {
+            std::panic::resume_unwind(err);
+        }
+    }
+
+    #[test]
+    fn test_free_all() {
+        let (mut buddy, ptr) = init();
+        let start = ptr as usize;
+
+        for i in 0..20 {
+            buddy.alloc(SIZE_64K << (i % 4)).unwrap();
+        }
+        buddy.reserve(start + HEAP_SIZE - SIZE_64K, SIZE_64K);
+
+        buddy.free_all();
+        assert_eq!(buddy.usage().0, 0);
+        assert_eq!(buddy.free_bytes(), HEAP_SIZE);
+        assert_eq!(buddy.verify(), Ok(()));
+
+        // the whole heap is a single block again
+        assert_eq!(buddy.largest_free_block(), HEAP_SIZE);
+        assert_eq!(buddy.alloc(HEAP_SIZE), Some(ptr));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_peak_used() {
+        let (mut buddy, ptr) = init();
+
+        let a = buddy.alloc(SIZE_64K).unwrap();
+        let b = buddy.alloc(SIZE_64K * 3).unwrap();
+        assert_eq!(buddy.peak_used_bytes(), SIZE_64K * 5);
+
+        // the peak is kept after frees
+        buddy.free(b);
+        assert_eq!(buddy.peak_used_bytes(), SIZE_64K * 5);
+
+        buddy.reset_peak();
+        assert_eq!(buddy.peak_used_bytes(), SIZE_64K);
+
+        // growing in place is counted
+        assert!(buddy.grow_in_place(a, SIZE_64K * 2));
+        assert_eq!(buddy.peak_used_bytes(), SIZE_64K * 2);
+
+        buddy.free(a);
+        buddy.reset_peak();
+        assert_eq!(buddy.peak_used_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_alloc_exact_order() {
+        let (mut buddy, ptr) = init();
+        let start = ptr as usize;
+
+        // 2MiB
+        let huge = buddy.alloc_exact_order(5).unwrap();
+        assert_eq!((huge as usize - start) % (SIZE_64K << 5), 0);
+
+        // The free buddy of order 5 is used without splitting another block.
+        let mem = buddy.alloc_exact_order(5).unwrap();
+        assert_eq!(mem as usize, start + (SIZE_64K << 5));
+        buddy.free(mem);
+
+        let small = buddy.alloc_exact_order(0).unwrap();
+        assert_eq!((small as usize - start) % SIZE_64K, 0);
+
+        // The whole region is not available anymore.
+        assert_eq!(buddy.alloc_exact_order(9), None);
+        assert_eq!(buddy.alloc_exact_order(10), None);
+
+        buddy.free(huge);
+        buddy.free(small);
+
+        assert_eq!(buddy.alloc_exact_order(9), Some(ptr));
+        buddy.free(ptr);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_grow_in_place() {
+        let (mut buddy, ptr) = init();
+        let start = ptr as usize;
+
+        let a = buddy.alloc(SIZE_64K).unwrap();
+        assert_eq!(a, ptr);
+
+        // The buddy of `a` is unused, so `a` grows to 128KiB, and then 256KiB.
+        assert!(buddy.grow_in_place(a, SIZE_64K * 2));
+        assert_eq!(buddy.usage().0, SIZE_64K * 2);
+        assert!(buddy.grow_in_place(a, SIZE_64K * 3));
+        assert_eq!(buddy.usage().0, SIZE_64K * 4);
+        assert!(buddy.grow_in_place(a, SIZE_64K * 4));
+
+        // Another block is placed right after `a`.
+        let b = buddy.alloc(SIZE_64K).unwrap();
+        assert_eq!(b as usize, start + SIZE_64K * 4);
+        assert!(!buddy.grow_in_place(a, SIZE_64K * 5));
+
+        // `b` is the left child, but its buddy is in use.
+        let c = buddy.alloc(SIZE_64K).unwrap();
+        assert!(!buddy.grow_in_place(b, SIZE_64K * 2));
+
+        // `c` is the right child.
+        assert!(!buddy.grow_in_place(c, SIZE_64K * 2));
+
+        // not allocated
+        assert!(!buddy.grow_in_place((start + SIZE_64K * 8) as *mut u8, SIZE_64K * 2));
+
+        buddy.free(c);
+        buddy.free(b);
+        buddy.free(a);
+        assert_eq!(buddy.usage().0, 0);
+        assert_eq!(buddy.largest_free_block(), HEAP_SIZE);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_capacity() {
+        assert_eq!(buddy_capacity_bytes(DEPTH_PAGE64K_MEM32M), 32 * 1024 * 1024);
+        assert_eq!(buddy_capacity_bytes(DEPTH_PAGE64K_MEM16M), 16 * 1024 * 1024);
+        assert_eq!(buddy_capacity_bytes(DEPTH_PAGE64K_MEM8T), 8 << 40);
+
+        assert_eq!(buddy_depth_for(0), 0);
+        assert_eq!(buddy_depth_for(SIZE_64K), 0);
+        assert_eq!(buddy_depth_for(SIZE_64K + 1), 1);
+        assert_eq!(buddy_depth_for(20 * 1024 * 1024), DEPTH_PAGE64K_MEM32M);
+        assert_eq!(buddy_depth_for(32 * 1024 * 1024), DEPTH_PAGE64K_MEM32M);
+        assert_eq!(
+            buddy_depth_for(32 * 1024 * 1024 + 1),
+            DEPTH_PAGE64K_MEM32M + 1
+        );
+
+        for depth in 0..=DEPTH_PAGE64K_MEM8T {
+            assert_eq!(buddy_depth_for(buddy_capacity_bytes(depth)), depth);
+        }
+
+        // usable in const context
+        const SIZE: usize = buddy_capacity_bytes(buddy_depth_for(3 * SIZE_64K));
+        let pages = [0u64; SIZE / SIZE_64K];
+        assert_eq!(pages.len(), 4);
     }
 }
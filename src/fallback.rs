@@ -0,0 +1,244 @@
+use crate::{InitError, MemAlloc, SIZE_64K};
+
+/// A page allocator which tries `A` first, and `B` if `A` cannot allocate.
+///
+/// For example, `Fallback<Buddy32M, PageManager>` uses the buddy allocator
+/// as a primary region and the page manager as an overflow region.
+pub struct Fallback<A: MemAlloc, B: MemAlloc> {
+    a: A,
+    b: B,
+}
+
+impl<A: MemAlloc, B: MemAlloc> Fallback<A, B> {
+    /// Combine two allocators managing disjoint regions.
+    pub fn from_parts(a: A, b: B) -> Self {
+        let (a_start, a_end) = a.range();
+        let (b_start, b_end) = b.range();
+        assert!(a_end <= b_start || b_end <= a_start, "overlapped regions");
+
+        Fallback { a, b }
+    }
+
+    pub fn primary(&self) -> &A {
+        &self.a
+    }
+
+    pub fn secondary(&self) -> &B {
+        &self.b
+    }
+}
+
+impl<A: MemAlloc, B: MemAlloc> MemAlloc for Fallback<A, B> {
+    fn alloc(&mut self, size: usize) -> Option<*mut u8> {
+        self.a.alloc(size).or_else(|| self.b.alloc(size))
+    }
+
+    fn alloc_aligned(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        self.a
+            .alloc_aligned(size, align)
+            .or_else(|| self.b.alloc_aligned(size, align))
+    }
+
+    fn max_alignment(&self) -> usize {
+        self.a.max_alignment().min(self.b.max_alignment())
+    }
+
+    fn free(&mut self, addr: *mut u8) {
+        let (start, end) = self.a.range();
+        let addr_usize = addr as usize;
+        if start <= addr_usize && addr_usize < end {
+            self.a.free(addr)
+        } else {
+            self.b.free(addr)
+        }
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.a.set_seed(seed);
+        self.b.set_seed(seed);
+    }
+
+    fn free_all(&mut self) {
+        self.a.free_all();
+        self.b.free_all();
+    }
+
+    fn grow_in_place(&mut self, addr: *mut u8, new_size: usize) -> bool {
+        let (start, end) = self.a.range();
+        let addr_usize = addr as usize;
+        if start <= addr_usize && addr_usize < end {
+            self.a.grow_in_place(addr, new_size)
+        } else {
+            self.b.grow_in_place(addr, new_size)
+        }
+    }
+
+    /// Each half of the region must satisfy the requirement of its allocator.
+    fn check_size(size: usize) -> Result<(), InitError> {
+        let half = size >> 1;
+        if !half.is_multiple_of(SIZE_64K) {
+            return Err(InitError::NotMultiple {
+                align: SIZE_64K * 2,
+                got: size,
+            });
+        }
+
+        A::check_size(half)?;
+        B::check_size(size - half)
+    }
+
+    /// The first half of the region is managed by `A`,
+    /// and the second half is managed by `B`.
+    fn new(start_addr: usize, size: usize) -> Self {
+        let half = size >> 1;
+        assert_eq!(half % SIZE_64K, 0);
+
+        Fallback {
+            a: A::new(start_addr, half),
+            b: B::new(start_addr + half, size - half),
+        }
+    }
+
+    fn range(&self) -> (usize, usize) {
+        let (a_start, a_end) = self.a.range();
+        let (b_start, b_end) = self.b.range();
+        (a_start.min(b_start), a_end.max(b_end))
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.a.largest_free_block().max(self.b.largest_free_block())
+    }
+
+    fn for_each_used(&self, f: &mut dyn FnMut(usize, usize)) {
+        self.a.for_each_used(f);
+        self.b.for_each_used(f);
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.a.free_bytes() + self.b.free_bytes()
+    }
+
+    /// The region is added to `A` if it accepts the region, or to `B` otherwise.
+    ///
+    /// `free` tells the allocators apart by the range of `A`,
+    /// so `A` takes the region only if its range does not grow over `B`,
+    /// and `B` takes the region only if it is outside the range of `A`.
+    fn add_region(&mut self, start_addr: usize, size: usize) -> Result<(), InitError> {
+        let end = start_addr.saturating_add(size);
+        let (a_start, a_end) = self.a.range();
+        let (b_start, b_end) = self.b.range();
+        let disjoint = |start: usize, end: usize, (s, e): (usize, usize)| end <= s || e <= start;
+
+        if disjoint(a_start.min(start_addr), a_end.max(end), (b_start, b_end))
+            && self.a.add_region(start_addr, size).is_ok()
+        {
+            return Ok(());
+        }
+
+        if !disjoint(start_addr, end, (a_start, a_end)) {
+            return Err(InitError::Overlapped { start: start_addr });
+        }
+
+        self.b.add_region(start_addr, size)
+    }
+
+    fn page_alignment(&self) -> usize {
+        self.a.page_alignment().min(self.b.page_alignment())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::Fallback;
+    use crate::{
+        buddy::Buddy32M, pager::PageManager, regions::Regions, InitError, MemAlloc, SIZE_64K,
+    };
+
+    const HEAP_SIZE: usize = 64 * 1024 * 1024;
+
+    #[test]
+    fn test_fallback() {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+
+        let mut alloc = Fallback::<Buddy32M, PageManager>::new(ptr as usize, HEAP_SIZE);
+        let (a_start, a_end) = alloc.primary().range();
+        let (b_start, b_end) = alloc.secondary().range();
+        assert_eq!(alloc.range(), (ptr as usize, ptr as usize + HEAP_SIZE));
+
+        // exhaust the primary allocator
+        let mem1 = alloc.alloc(HEAP_SIZE / 2).unwrap() as usize;
+        assert!(a_start <= mem1 && mem1 < a_end);
+
+        // overflow to the secondary allocator
+        let mem2 = alloc.alloc(SIZE_64K).unwrap() as usize;
+        assert!(b_start <= mem2 && mem2 < b_end);
+
+        // free and reuse the primary allocator
+        alloc.free(mem1 as *mut u8);
+        let mem3 = alloc.alloc(SIZE_64K).unwrap() as usize;
+        assert!(a_start <= mem3 && mem3 < a_end);
+
+        alloc.free(mem2 as *mut u8);
+        alloc.free(mem3 as *mut u8);
+        assert_eq!(
+            alloc.secondary().free_page_count(),
+            HEAP_SIZE / 2 / SIZE_64K
+        );
+        assert_eq!(alloc.largest_free_block(), HEAP_SIZE / 2);
+
+        // both allocators are emptied
+        alloc.alloc(HEAP_SIZE / 2).unwrap();
+        alloc.alloc(SIZE_64K).unwrap();
+        alloc.free_all();
+        assert_eq!(alloc.free_bytes(), HEAP_SIZE);
+        assert_eq!(alloc.alloc(HEAP_SIZE / 2), Some(a_start as *mut u8));
+        assert_eq!(alloc.alloc(SIZE_64K), Some(b_start as *mut u8));
+
+        unsafe { std::alloc::dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn test_fallback_regions() {
+        type Alloc = Fallback<PageManager, Regions<PageManager, 2>>;
+
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        let region = std::alloc::Layout::from_size_align(SIZE_64K * 16, crate::ALIGNMENT).unwrap();
+        let ptr2 = unsafe { std::alloc::alloc(region) };
+
+        let mut alloc = Alloc::new(ptr as usize, HEAP_SIZE);
+        assert_eq!(alloc.page_alignment(), SIZE_64K);
+
+        // the primary allocator has a single region, so the secondary one takes the region
+        assert_eq!(
+            alloc.add_region(ptr as usize, SIZE_64K),
+            Err(InitError::Overlapped {
+                start: ptr as usize
+            })
+        );
+        alloc.add_region(ptr2 as usize, region.size()).unwrap();
+        assert_eq!(alloc.free_bytes(), HEAP_SIZE + region.size());
+
+        // blocks of both allocators are reported
+        let mem1 = alloc.alloc(SIZE_64K).unwrap();
+        let mem2 = alloc.alloc(HEAP_SIZE / 2).unwrap();
+        let mut used = std::vec::Vec::new();
+        alloc.for_each_used(&mut |addr, size| used.push((addr, size)));
+        used.sort();
+        assert_eq!(
+            used,
+            [(mem1 as usize, SIZE_64K), (mem2 as usize, HEAP_SIZE / 2)]
+        );
+
+        alloc.free(mem1);
+        alloc.free(mem2);
+
+        unsafe {
+            std::alloc::dealloc(ptr, layout);
+            std::alloc::dealloc(ptr2, region);
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use crate::{InitError, MemAlloc, SIZE_64K};
+
+/// A page allocator threading a singly-linked list through free 64KiB pages.
+///
+/// The first 8 bytes of each free page hold the address of the next free page,
+/// so there is no size limit beyond the heap and no bitmap.
+/// Pages which have never been allocated are not linked but handed out in address order,
+/// so that initialization does not touch the whole heap.
+pub struct FreeListPager {
+    start: usize,
+    end: usize,
+    head: usize,       // address of the first free page, 0 means the list is empty
+    unused: usize,     // address of the first page which has never been allocated
+    free_pages: usize, // number of pages in the list
+}
+
+impl FreeListPager {
+    pub fn page_alloc(&mut self) -> Option<*mut u8> {
+        if self.head != 0 {
+            let addr = self.head;
+            self.head = unsafe { *(addr as *const usize) };
+            self.free_pages -= 1;
+            Some(addr as _)
+        } else if self.unused < self.end {
+            let addr = self.unused;
+            self.unused += SIZE_64K;
+            Some(addr as _)
+        } else {
+            None
+        }
+    }
+
+    pub fn page_free(&mut self, addr: *mut u8) {
+        let addr = addr as usize;
+        if addr & 0xFFFF != 0 || addr >= self.unused || addr < self.start {
+            panic!("invalid address");
+        }
+
+        unsafe { *(addr as *mut usize) = self.head };
+        self.head = addr;
+        self.free_pages += 1;
+    }
+}
+
+impl MemAlloc for FreeListPager {
+    fn alloc(&mut self, size: usize) -> Option<*mut u8> {
+        if size > SIZE_64K {
+            None
+        } else {
+            self.page_alloc()
+        }
+    }
+
+    fn free(&mut self, addr: *mut u8) {
+        self.page_free(addr)
+    }
+
+    fn range(&self) -> (usize, usize) {
+        (self.start, self.end)
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.free_pages * SIZE_64K + (self.end - self.unused)
+    }
+
+    /// All the pages become never allocated, so the list is not walked.
+    fn free_all(&mut self) {
+        self.head = 0;
+        self.unused = self.start;
+        self.free_pages = 0;
+    }
+
+    fn largest_free_block(&self) -> usize {
+        if self.head != 0 || self.unused < self.end {
+            SIZE_64K
+        } else {
+            0
+        }
+    }
+
+    fn check_size(size: usize) -> Result<(), InitError> {
+        if !size.is_multiple_of(SIZE_64K) {
+            return Err(InitError::NotMultiple {
+                align: SIZE_64K,
+                got: size,
+            });
+        }
+
+        Ok(())
+    }
+
+    fn new(start_addr: usize, size: usize) -> Self {
+        assert_eq!(size % SIZE_64K, 0);
+
+        let Some(end) = start_addr.checked_add(size) else {
+            panic!("address overflow");
+        };
+
+        FreeListPager {
+            start: start_addr,
+            end,
+            head: 0,
+            unused: start_addr,
+            free_pages: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::FreeListPager;
+    use crate::{MemAlloc, SIZE_64K};
+
+    const HEAP_SIZE: usize = 4 * 1024 * 1024;
+
+    fn init() -> (FreeListPager, *mut u8) {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        (FreeListPager::new(ptr as usize, HEAP_SIZE), ptr)
+    }
+
+    fn free(ptr: *mut u8) {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        unsafe { std::alloc::dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn test_free_list() {
+        let (mut pager, ptr) = init();
+        let num_pages = HEAP_SIZE / SIZE_64K;
+
+        let mut v = std::vec::Vec::new();
+        while let Some(mem) = pager.alloc(SIZE_64K) {
+            v.push(mem);
+        }
+
+        assert_eq!(v.len(), num_pages);
+        assert_eq!(pager.free_bytes(), 0);
+        for (i, mem) in v.iter().enumerate() {
+            assert_eq!(*mem as usize, ptr as usize + SIZE_64K * i);
+        }
+        assert_eq!(pager.largest_free_block(), 0);
+        assert_eq!(pager.alloc(1), None);
+        assert_eq!(pager.alloc(SIZE_64K + 1), None);
+
+        // free in reverse, so the first page is on the head
+        for mem in v.iter().rev() {
+            pager.free(*mem);
+        }
+        assert_eq!(pager.largest_free_block(), SIZE_64K);
+        assert_eq!(pager.free_bytes(), HEAP_SIZE);
+
+        for mem in v.iter() {
+            assert_eq!(pager.alloc(SIZE_64K), Some(*mem));
+        }
+        assert_eq!(pager.alloc(SIZE_64K), None);
+
+        // LIFO reuse
+        pager.free(v[3]);
+        pager.free(v[7]);
+        assert_eq!(pager.alloc(SIZE_64K), Some(v[7]));
+        assert_eq!(pager.alloc(SIZE_64K), Some(v[3]));
+
+        // pages are handed out in address order again
+        pager.free(v[5]);
+        pager.free_all();
+        assert_eq!(pager.free_bytes(), HEAP_SIZE);
+        for mem in v.iter() {
+            assert_eq!(pager.alloc(SIZE_64K), Some(*mem));
+        }
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid address")]
+    fn test_free_unallocated() {
+        let (mut pager, ptr) = init();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| pager.free(ptr)));
+        free(ptr);
+        if let Err(err) = result {
+            std::panic::resume_unwind(err);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+//! An allocator for `#[global_allocator]`, initialized after the construction of the static.
+//!
+//! `Allocator::init` takes `&mut self`, which a `static` cannot give,
+//! so `GlobalAllocator` wraps an allocator with a flag guarding its initialization.
+//! Allocations return null until `global_init` is called, e.g. once the memory map is known.
+//!
+//! ```
+//! use memac::{buddy::Buddy32M, global::GlobalAllocator};
+//! use core::alloc::GlobalAlloc;
+//!
+//! // #[global_allocator]
+//! static GLOBAL: GlobalAllocator<Buddy32M> = GlobalAllocator::new();
+//!
+//! let layout = std::alloc::Layout::from_size_align(128, 8).unwrap();
+//! assert!(unsafe { GLOBAL.alloc(layout) }.is_null()); // Not initialized yet.
+//!
+//! let heap_size = 32 * 1024 * 1024;
+//! let heap = std::alloc::Layout::from_size_align(heap_size, memac::ALIGNMENT).unwrap();
+//! let ptr = unsafe { std::alloc::alloc(heap) };
+//! GLOBAL.global_init(ptr as usize, heap_size).unwrap(); // Initialize the allocator.
+//!
+//! let mem = unsafe { GLOBAL.alloc(layout) }; // Allocation.
+//! unsafe { GLOBAL.dealloc(mem, layout) };    // Deallocation.
+//! ```
+
+use crate::{Allocator, InitError, MemAlloc};
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    cell::UnsafeCell,
+    ptr::null_mut,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const UNINIT: u8 = 0;
+const INITIALIZING: u8 = 1;
+const READY: u8 = 2;
+
+/// An `Allocator` which can be a `static` and initialized later by `global_init`.
+pub struct GlobalAllocator<PAGEALLOC: MemAlloc> {
+    state: AtomicU8,
+    alloc: UnsafeCell<Allocator<PAGEALLOC>>,
+}
+
+// SAFETY: the allocator is mutated only by the thread which moved `state` to `INITIALIZING`,
+// and shared only after `state` becomes `READY`, from when it is used through `&Allocator`,
+// which is `Sync` for a `Send` page allocator.
+// Initialization on one thread and use on others also moves the page allocator between threads.
+unsafe impl<PAGEALLOC: MemAlloc + Send> Sync for GlobalAllocator<PAGEALLOC> {}
+unsafe impl<PAGEALLOC: MemAlloc + Send> Send for GlobalAllocator<PAGEALLOC> {}
+
+impl<PAGEALLOC: MemAlloc> GlobalAllocator<PAGEALLOC> {
+    pub const fn new() -> Self {
+        GlobalAllocator {
+            state: AtomicU8::new(UNINIT),
+            alloc: UnsafeCell::new(Allocator::new()),
+        }
+    }
+
+    /// Initialize the allocator with the heap like `Allocator::try_init`.
+    ///
+    /// It succeeds only once; later calls, including concurrent ones,
+    /// return `InitError::AlreadyInitialized`.
+    /// If it fails for the heap, the allocator stays uninitialized and may be initialized again.
+    pub fn global_init(&self, heap_start: usize, size: usize) -> Result<(), InitError> {
+        if self
+            .state
+            .compare_exchange(UNINIT, INITIALIZING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(InitError::AlreadyInitialized);
+        }
+
+        let result = unsafe { (*self.alloc.get()).try_init(heap_start, size) };
+        let state = if result.is_ok() { READY } else { UNINIT };
+        self.state.store(state, Ordering::Release);
+
+        result
+    }
+
+    /// Return the allocator if it is initialized, e.g. to read its statistics.
+    pub fn get(&self) -> Option<&Allocator<PAGEALLOC>> {
+        if self.state.load(Ordering::Acquire) == READY {
+            Some(unsafe { &*self.alloc.get() })
+        } else {
+            None
+        }
+    }
+}
+
+impl<PAGEALLOC: MemAlloc> Default for GlobalAllocator<PAGEALLOC> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+unsafe impl<PAGEALLOC: MemAlloc> GlobalAlloc for GlobalAllocator<PAGEALLOC> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.get() {
+            Some(alloc) => alloc.alloc(layout),
+            None => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // No memory is allocated before initialization.
+        if let Some(alloc) = self.get() {
+            alloc.dealloc(ptr, layout)
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        match self.get() {
+            Some(alloc) => GlobalAlloc::realloc(alloc, ptr, layout, new_size),
+            None => null_mut(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::GlobalAllocator;
+    use crate::{buddy::Buddy32M, InitError};
+    use core::alloc::GlobalAlloc;
+
+    const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+    static GLOBAL: GlobalAllocator<Buddy32M> = GlobalAllocator::new();
+
+    #[test]
+    fn test_global_init() {
+        let layout = std::alloc::Layout::from_size_align(100, 8).unwrap();
+
+        // allocations before initialization fail cleanly
+        assert!(GLOBAL.get().is_none());
+        assert!(unsafe { GLOBAL.alloc(layout) }.is_null());
+
+        // a failed initialization can be retried
+        let heap = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(heap) };
+        assert_eq!(
+            GLOBAL.global_init(ptr as usize + 8, HEAP_SIZE),
+            Err(InitError::Unaligned {
+                heap_start: ptr as usize + 8
+            })
+        );
+        assert!(GLOBAL.get().is_none());
+
+        // threads race to initialize, and exactly one wins
+        let start = ptr as usize;
+        let results: std::vec::Vec<_> = std::thread::scope(|s| {
+            let handles: std::vec::Vec<_> = (0..4)
+                .map(|_| s.spawn(|| GLOBAL.global_init(start, HEAP_SIZE)))
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+        assert_eq!(results.iter().filter(|r| r.is_ok()).count(), 1);
+        assert!(results
+            .iter()
+            .all(|r| r.is_ok() || *r == Err(InitError::AlreadyInitialized)));
+
+        let mem = unsafe { GLOBAL.alloc(layout) };
+        assert!(!mem.is_null());
+        unsafe { core::ptr::write_bytes(mem, 0xAA, layout.size()) };
+
+        let mem = unsafe { GLOBAL.realloc(mem, layout, 1000) };
+        assert!(!mem.is_null());
+        assert_eq!(unsafe { *mem.add(99) }, 0xAA);
+
+        let layout = std::alloc::Layout::from_size_align(1000, 8).unwrap();
+        unsafe { GLOBAL.dealloc(mem, layout) };
+        assert_eq!(GLOBAL.get().unwrap().live_bytes(), 0);
+
+        // The static keeps using the heap, which is leaked.
+    }
+}
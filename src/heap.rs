@@ -0,0 +1,131 @@
+//! An allocator owning its heap, for `std` users and tests.
+//!
+//! ```
+//! use memac::{Allocator, buddy::Buddy32M};
+//! use core::alloc::{GlobalAlloc, Layout};
+//!
+//! let heap = Allocator::<Buddy32M>::with_heap(32 * 1024 * 1024).unwrap();
+//!
+//! let layout = Layout::from_size_align(128, 32).unwrap();
+//! let mem = unsafe { heap.alloc(layout) }; // Allocation.
+//! unsafe { heap.dealloc(mem, layout) };    // Deallocation.
+//!
+//! drop(heap); // The heap is freed.
+//! ```
+
+extern crate std;
+
+use crate::{Allocator, InitError, MemAlloc, ALIGNMENT};
+use core::{
+    alloc::Layout,
+    ops::{Deref, DerefMut},
+};
+
+/// An allocator and its heap allocated by the global allocator of `std`.
+///
+/// The heap is freed when the guard is dropped.
+/// Dropping the guard while allocations are alive panics,
+/// because they would point to the freed heap.
+pub struct HeapGuard<PAGEALLOC: MemAlloc> {
+    alloc: Allocator<PAGEALLOC>,
+    heap: *mut u8,
+    layout: Layout,
+}
+
+impl<PAGEALLOC: MemAlloc> Allocator<PAGEALLOC> {
+    /// Allocate a heap of `size` bytes aligned to `ALIGNMENT`,
+    /// and return an allocator initialized with it.
+    ///
+    /// The allocator is owned by the returned guard, which frees the heap on drop.
+    pub fn with_heap(size: usize) -> Result<HeapGuard<PAGEALLOC>, InitError> {
+        PAGEALLOC::check_size(size)?;
+
+        let layout = Layout::from_size_align(size, ALIGNMENT).or(Err(InitError::TooLarge {
+            max: isize::MAX as usize,
+            got: size,
+        }))?;
+        let heap = unsafe { std::alloc::alloc(layout) };
+        if heap.is_null() {
+            std::alloc::handle_alloc_error(layout);
+        }
+
+        let mut alloc = Allocator::new();
+        if let Err(e) = alloc.try_init(heap as usize, size) {
+            unsafe { std::alloc::dealloc(heap, layout) };
+            return Err(e);
+        }
+
+        Ok(HeapGuard {
+            alloc,
+            heap,
+            layout,
+        })
+    }
+}
+
+impl<PAGEALLOC: MemAlloc> Deref for HeapGuard<PAGEALLOC> {
+    type Target = Allocator<PAGEALLOC>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.alloc
+    }
+}
+
+impl<PAGEALLOC: MemAlloc> DerefMut for HeapGuard<PAGEALLOC> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.alloc
+    }
+}
+
+impl<PAGEALLOC: MemAlloc> Drop for HeapGuard<PAGEALLOC> {
+    fn drop(&mut self) {
+        let live_bytes = self.alloc.live_bytes();
+
+        // The heap may be taken by another allocator right after it is freed.
+        #[cfg(feature = "debug")]
+        self.alloc.unregister_heap();
+
+        unsafe { std::alloc::dealloc(self.heap, self.layout) };
+
+        if live_bytes != 0 && !std::thread::panicking() {
+            panic!("heap dropped with {live_bytes} live bytes");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{buddy::Buddy32M, pager::PageManager, Allocator, InitError, SIZE_64K};
+    use core::alloc::{GlobalAlloc, Layout};
+
+    const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+    #[test]
+    fn test_with_heap() {
+        let heap = Allocator::<Buddy32M>::with_heap(HEAP_SIZE).unwrap();
+
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        let mem = unsafe { heap.alloc(layout) };
+        assert_eq!(mem as usize % 64, 0);
+        assert!(heap.live_bytes() > 0);
+        unsafe { heap.dealloc(mem, layout) };
+
+        drop(heap);
+
+        assert_eq!(
+            Allocator::<Buddy32M>::with_heap(HEAP_SIZE / 2).err(),
+            Some(InitError::SizeMismatch {
+                expected: HEAP_SIZE,
+                got: HEAP_SIZE / 2
+            })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "heap dropped with 65536 live bytes")]
+    fn test_with_heap_leak() {
+        let heap = Allocator::<PageManager>::with_heap(HEAP_SIZE).unwrap();
+        let layout = Layout::from_size_align(SIZE_64K, 8).unwrap();
+        let _leaked = heap.mem_alloc_align(layout);
+    }
+}
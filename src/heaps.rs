@@ -0,0 +1,66 @@
+//! A global registry of the heaps of initialized allocators,
+//! to detect allocators initialized over overlapping heaps.
+//!
+//! Each heap is registered with an id, which the allocator keeps to deregister it
+//! on `reset` or drop, even if another allocator has registered the same range since.
+
+use crate::{lock::SpinLock, InitError};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Maximum number of heaps registered at once.
+/// More heaps are not checked.
+const MAX_HEAPS: usize = 64;
+
+/// `(start, end, id)` of each heap, id 0 means an empty entry.
+static HEAPS: SpinLock<[(usize, usize, usize); MAX_HEAPS]> = SpinLock::new([(0, 0, 0); MAX_HEAPS]);
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Register the heap `[start, end)` in place of the heap of `old`,
+/// and return its id, or 0 if the registry is full.
+pub(crate) fn register(start: usize, end: usize, old: usize) -> Result<usize, InitError> {
+    HEAPS.with(|heaps| {
+        let overlapped = heaps
+            .iter()
+            .any(|(s, e, id)| *id != 0 && *id != old && start < *e && *s < end);
+        if overlapped {
+            return Err(InitError::Overlapped { start });
+        }
+
+        if let Some(heap) = heaps.iter_mut().find(|heap| old != 0 && heap.2 == old) {
+            *heap = (0, 0, 0);
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        match heaps.iter_mut().find(|heap| heap.2 == 0) {
+            Some(heap) => {
+                *heap = (start, end, id);
+                Ok(id)
+            }
+            None => Ok(0),
+        }
+    })
+}
+
+/// Deregister the heap of `id`.
+pub(crate) fn unregister(id: usize) {
+    if id == 0 {
+        return;
+    }
+
+    HEAPS.with(|heaps| {
+        if let Some(heap) = heaps.iter_mut().find(|heap| heap.2 == id) {
+            *heap = (0, 0, 0);
+        }
+    });
+}
+
+/// Deregister the heap starting at `start`, which is being freed while its allocator is alive.
+#[cfg(test)]
+pub(crate) fn unregister_at(start: usize) {
+    HEAPS.with(|heaps| {
+        if let Some(heap) = heaps.iter_mut().find(|heap| heap.2 != 0 && heap.0 == start) {
+            *heap = (0, 0, 0);
+        }
+    });
+}
@@ -0,0 +1,137 @@
+//! A pool of fixed-size blocks for interrupt service routines.
+//!
+//! `IsrPool` never takes a lock nor calls the page allocator after initialization,
+//! so it can be used in contexts where the allocator's MCS lock may be held by
+//! the interrupted thread.
+//!
+//! ```
+//! use memac::{Allocator, buddy::Buddy32M, isr::IsrPool};
+//!
+//! let mut alloc = Allocator::<Buddy32M>::new();
+//!
+//! let heap_size = 32 * 1024 * 1024;
+//! let layout = std::alloc::Layout::from_size_align(heap_size, memac::ALIGNMENT).unwrap();
+//! let ptr = unsafe { std::alloc::alloc(layout) };
+//! alloc.init(ptr as usize, heap_size);
+//!
+//! let pool = IsrPool::<128, 64>::new(&alloc).unwrap(); // 64 blocks of 128 bytes.
+//!
+//! let mem = pool.alloc().unwrap(); // Allocation without a lock.
+//! unsafe { pool.free(mem) };       // Deallocation without a lock.
+//!
+//! unsafe { pool.release(&alloc) }; // Return the blocks to the allocator.
+//! ```
+
+use crate::{Allocator, MemAlloc};
+use core::{
+    alloc::Layout,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+
+const NIL: u32 = u32::MAX;
+
+/// `N` pre-allocated blocks of `SIZE` bytes managed by a lock-free stack.
+///
+/// The head of the stack contains a tag which is incremented by every update
+/// to avoid the ABA problem.
+pub struct IsrPool<const SIZE: usize, const N: usize> {
+    base: usize,
+    head: AtomicU64, // tag (upper 32 bits) and index of the top block (lower 32 bits)
+    next: [AtomicU32; N],
+}
+
+impl<const SIZE: usize, const N: usize> IsrPool<SIZE, N> {
+    /// Size of a block including padding for 8-byte alignment.
+    const STRIDE: usize = (SIZE + 7) & !7;
+
+    fn layout() -> Layout {
+        Layout::from_size_align(Self::STRIDE * N, 8).unwrap()
+    }
+
+    /// Allocate `N` blocks from `parent`.
+    pub fn new<PAGEALLOC: MemAlloc>(parent: &Allocator<PAGEALLOC>) -> Option<Self> {
+        assert!(SIZE > 0 && N > 0 && N < NIL as usize);
+
+        let base = parent.mem_alloc_align(Self::layout())? as usize;
+
+        let next = core::array::from_fn(|i| {
+            if i + 1 < N {
+                AtomicU32::new(i as u32 + 1)
+            } else {
+                AtomicU32::new(NIL)
+            }
+        });
+
+        Some(IsrPool {
+            base,
+            head: AtomicU64::new(0),
+            next,
+        })
+    }
+
+    /// Return the blocks to `parent`.
+    ///
+    /// # Safety
+    ///
+    /// `parent` must be the allocator passed to `new`,
+    /// and no block can be used after this call.
+    pub unsafe fn release<PAGEALLOC: MemAlloc>(self, parent: &Allocator<PAGEALLOC>) {
+        parent.mem_free_align(self.base as *mut u8, Self::layout());
+    }
+
+    /// Allocate a block of `SIZE` bytes.
+    /// `None` is returned if all blocks are in use.
+    pub fn alloc(&self) -> Option<*mut u8> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let idx = head as u32;
+            if idx == NIL {
+                return None;
+            }
+
+            let next = self.next[idx as usize].load(Ordering::Relaxed);
+            let new_head = ((head >> 32).wrapping_add(1) << 32) | next as u64;
+
+            match self.head.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some((self.base + idx as usize * Self::STRIDE) as *mut u8),
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    /// Deallocate a block.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `alloc` of this pool.
+    pub unsafe fn free(&self, ptr: *mut u8) {
+        let offset = (ptr as usize).wrapping_sub(self.base);
+        assert!(
+            offset.is_multiple_of(Self::STRIDE) && offset / Self::STRIDE < N,
+            "invalid address"
+        );
+
+        let idx = (offset / Self::STRIDE) as u32;
+
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            self.next[idx as usize].store(head as u32, Ordering::Relaxed);
+            let new_head = ((head >> 32).wrapping_add(1) << 32) | idx as u64;
+
+            match self.head.compare_exchange_weak(
+                head,
+                new_head,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+}
@@ -39,29 +39,339 @@
 //! ```
 
 #![no_std]
+#![cfg_attr(feature = "nightly", feature(allocator_api))]
 
 use core::{
     alloc::{GlobalAlloc, Layout},
-    ptr::null_mut,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
 };
-use synctools::mcs::{MCSLock, MCSNode};
 
 extern crate alloc;
 
 pub mod buddy;
+pub mod fallback;
+pub mod freelist;
+pub mod global;
+#[cfg(feature = "std")]
+pub mod heap;
+#[cfg(feature = "debug")]
+mod heaps;
+pub mod isr;
+mod lock;
+pub mod magazine;
 pub mod pager;
+pub mod regions;
+#[cfg(feature = "debug_registry")]
+mod registry;
 mod slab;
+#[cfg(feature = "std")]
+pub mod stdpager;
+
+#[cfg(feature = "track")]
+mod track;
 
 pub trait MemAlloc {
     fn alloc(&mut self, size: usize) -> Option<*mut u8>;
     fn free(&mut self, addr: *mut u8);
+
+    /// Allocate a block of `size` bytes aligned to `align`, which is a power of two.
+    ///
+    /// The default implementation calls `alloc`, and fails if the block is not aligned.
+    /// Backends returning naturally aligned blocks should override this to satisfy
+    /// the alignment without padding.
+    fn alloc_aligned(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let ptr = self.alloc(size)?;
+        if (ptr as usize).is_multiple_of(align) {
+            Some(ptr)
+        } else {
+            self.free(ptr);
+            None
+        }
+    }
+
     fn new(start_addr: usize, size: usize) -> Self;
+
+    /// Return the address range `[start, end)` managed by this allocator.
+    fn range(&self) -> (usize, usize);
+
+    /// Return the size of the largest block which can be allocated at once.
+    /// 0 means unknown or no free block.
+    fn largest_free_block(&self) -> usize {
+        0
+    }
+
+    /// Call `f(addr, size)` for each allocated block.
+    fn for_each_used(&self, _f: &mut dyn FnMut(usize, usize)) {}
+
+    /// Return the number of bytes which are not allocated.
+    ///
+    /// The default implementation sums up blocks reported by `for_each_used`.
+    /// Backends should override this with an incrementally maintained counter.
+    fn free_bytes(&self) -> usize {
+        let (start, end) = self.range();
+        let mut used = 0;
+        self.for_each_used(&mut |_, size| used += size);
+        (end - start).saturating_sub(used)
+    }
+
+    /// Return the maximum number of bytes allocated at once
+    /// since `new` or the last `reset_peak`.
+    /// 0 means unknown.
+    fn peak_used_bytes(&self) -> usize {
+        0
+    }
+
+    /// Reset the high-water mark of `peak_used_bytes` to the bytes currently allocated.
+    fn reset_peak(&mut self) {}
+
+    /// Check whether a heap of `size` bytes can be managed by this allocator.
+    /// `new` may panic if this returns an error.
+    fn check_size(_size: usize) -> Result<(), InitError> {
+        Ok(())
+    }
+
+    /// Grow the block at `addr` returned by `alloc` to at least `new_size` bytes without moving it.
+    ///
+    /// The default implementation never grows a block.
+    /// `false` is returned without any change if the block cannot grow in place.
+    fn grow_in_place(&mut self, _addr: *mut u8, _new_size: usize) -> bool {
+        false
+    }
+
+    /// Derive the internal scan order from `seed`, so that the same sequence of requests
+    /// on a fresh heap returns the same addresses relative to the heap.
+    ///
+    /// The default implementation does nothing, which suits allocators without such state.
+    fn set_seed(&mut self, _seed: u64) {}
+
+    /// Free all the blocks at once, e.g. when the heap is reset,
+    /// which invalidates all the addresses returned so far.
+    ///
+    /// The default implementation does nothing, so the blocks of a backend
+    /// without a bulk free are left allocated.
+    fn free_all(&mut self) {}
+
+    /// Add a region of `size` bytes at `start_addr`, which is disjoint from managed ones.
+    ///
+    /// The default implementation manages a single region given by `new`,
+    /// so it always fails.
+    fn add_region(&mut self, _start_addr: usize, _size: usize) -> Result<(), InitError> {
+        Err(InitError::TooManyRegions { max: 1 })
+    }
+
+    /// Return the alignment of blocks returned by `alloc`.
+    ///
+    /// Slabs are placed on pages from `alloc` and found by masking addresses of their objects,
+    /// so this must be at least 64KiB.
+    fn page_alignment(&self) -> usize {
+        SIZE_64K
+    }
+
+    /// Return the largest alignment which `alloc_aligned` always satisfies given enough memory.
+    ///
+    /// The value must not change after `new`, because a larger alignment is served
+    /// by padding and told apart on deallocation by this value.
+    /// The default implementation returns `page_alignment`.
+    fn max_alignment(&self) -> usize {
+        self.page_alignment()
+    }
+}
+
+/// Errors of allocation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The alignment is not a power of two or not a multiple of the pointer size.
+    BadAlignment,
+    /// There is no memory region to satisfy the request.
+    OutOfMemory,
+}
+
+/// Return a layout of `size` bytes aligned to `align`.
+///
+/// Alignments are applied by masking with `align - 1`,
+/// so an alignment which is not a power of two is rejected here
+/// instead of silently producing a misaligned memory region.
+///
+/// # Errors
+///
+/// - `BadAlignment` if `align` is not a power of two.
+/// - `OutOfMemory` if `size` rounded up to `align` overflows `isize`.
+pub fn checked_layout(size: usize, align: usize) -> Result<Layout, AllocError> {
+    if !align.is_power_of_two() {
+        return Err(AllocError::BadAlignment);
+    }
+
+    Layout::from_size_align(size, align).or(Err(AllocError::OutOfMemory))
+}
+
+/// Return the number of objects a slab of `class_size` can hold.
+///
+/// `class_size` is one of 16, 32, 64, 128, 256, 512, 1024, 2040, 4088, 8184, 16376, 32752,
+/// and 65512, and 0 is returned for other sizes.
+/// For example, a slab of 16 bytes holds 4062 objects and a slab of 2040 bytes holds 32 objects.
+pub const fn slab_object_count(class_size: usize) -> usize {
+    slab::object_count(class_size)
+}
+
+/// A page allocator recommended by `recommended_backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// `pager::PageManager`, which allocates 64KiB pages without fragmentation.
+    PageManager,
+    /// `freelist::FreeListPager` for a heap larger than 16GiB, which `PageManager` cannot manage.
+    FreeListPager,
+    /// `buddy::BuddyAlloc` of `depth`, e.g. `Buddy32M` for 9,
+    /// whose heap must be `buddy::buddy_capacity_bytes(depth)` bytes.
+    Buddy { depth: usize },
+}
+
+/// Return a page allocator suitable for a heap of `heap_bytes` bytes
+/// where a single allocation is at most `max_single_alloc` bytes.
+///
+/// Allocations up to 64KiB are served by slabs or a single page,
+/// so a page allocator of 64KiB pages suffices.
+/// Larger allocations need contiguous pages, which the buddy allocator serves without limit,
+/// while `PageManager` can hold only `pager::MAX_SPANS` runs and its first-fit search fragments the heap.
+pub const fn recommended_backend(max_single_alloc: usize, heap_bytes: usize) -> Backend {
+    if max_single_alloc <= SIZE_64K {
+        if heap_bytes <= 64 * 64 * 64 * SIZE_64K {
+            Backend::PageManager
+        } else {
+            Backend::FreeListPager
+        }
+    } else {
+        let depth = buddy::buddy_depth_for(heap_bytes);
+        let min_depth = buddy::buddy_depth_for(max_single_alloc);
+        Backend::Buddy {
+            depth: if depth < min_depth { min_depth } else { depth },
+        }
+    }
+}
+
+/// How a request is served, returned by `Allocator::allocation_kind`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocKind {
+    /// An object of the slab class of the size, which shares a 64KiB page with other objects.
+    Slab(usize),
+    /// A dedicated block of the page allocator.
+    Large,
+}
+
+/// Statistics returned by `Allocator::stats` and `Allocator::stats_relaxed`.
+///
+/// The counters are maintained only with the `stats` feature, which is enabled by default.
+/// Disabling it removes the atomic updates from the allocation and deallocation paths.
+#[cfg(feature = "stats")]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Bytes currently allocated.
+    pub live_bytes: usize,
+    /// Number of live allocations.
+    pub live_count: usize,
+    /// Total bytes allocated since `init`.
+    pub allocated_bytes: usize,
+    /// Total bytes freed since `init`.
+    pub freed_bytes: usize,
+}
+
+/// Errors of initialization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitError {
+    /// The start address of the heap is not aligned to `ALIGNMENT`.
+    Unaligned { heap_start: usize },
+    /// The backend requires a heap of exactly `expected` bytes.
+    SizeMismatch { expected: usize, got: usize },
+    /// The size of the heap is not a multiple of `align` bytes.
+    NotMultiple { align: usize, got: usize },
+    /// The backend can manage at most `max` bytes.
+    TooLarge { max: usize, got: usize },
+    /// The backend can manage at most `max` regions.
+    TooManyRegions { max: usize },
+    /// The region starting at `start` overlaps a managed region,
+    /// or the heap of another allocator with the `debug` feature.
+    Overlapped { start: usize },
+    /// `GlobalAllocator::global_init` has already been called.
+    AlreadyInitialized,
+}
+
+/// An event of the allocator passed to the hook set by `Allocator::set_trace_hook`.
+#[cfg(feature = "trace")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEvent {
+    /// `size` bytes are allocated at `ptr`.
+    Alloc { size: usize, ptr: *mut u8 },
+    /// The memory region at `ptr` is deallocated.
+    Free { ptr: *mut u8 },
+    /// The page at `addr` is taken from the page allocator for a new slab.
+    MapPage { addr: usize },
+    /// The unmap callback is called for the page at `addr`.
+    UnmapPage { addr: usize },
 }
 
 /// A custom memory allocator.
+///
+/// It is `Send` and `Sync`, and can be shared by threads.
+/// Use `global::GlobalAllocator` to initialize a `#[global_allocator]` static.
 pub struct Allocator<PAGEALLOC: MemAlloc> {
-    slab: Option<MCSLock<slab::SlabAllocator<PAGEALLOC>>>,
+    slab: Option<lock::Lock<slab::SlabAllocator<PAGEALLOC>>>,
     unmapf: fn(usize, usize),
+    mapf: fn(usize, usize),
+    #[cfg(feature = "trace")]
+    trace: fn(TraceEvent),
+    heap_start: usize,
+    heap_end: usize,
+    shadow_base: usize,
+    #[cfg(feature = "stats")]
+    allocated_bytes: AtomicUsize, // total bytes ever allocated
+    #[cfg(feature = "stats")]
+    freed_bytes: AtomicUsize, // total bytes ever freed
+    #[cfg(feature = "stats")]
+    allocs: AtomicUsize, // total number of allocations
+    #[cfg(feature = "stats")]
+    frees: AtomicUsize, // total number of deallocations
+    #[cfg(test)]
+    lock_count: AtomicUsize, // number of times `with_slab` takes the lock
+    lock_users: AtomicUsize, // number of threads holding or waiting for the lock
+    generation: u64,         // bumped on each init and reset
+    large_threshold: usize,  // larger requests are allocated from the page allocator
+    max_align: usize,        // larger alignments are served by padding
+    min_class: usize,        // index of the smallest slab class used
+    retry_on_oom: bool,      // `GlobalAlloc::alloc` retries once before returning null
+    #[cfg(test)]
+    before_retry: Option<fn(&Self)>, // called between the attempts of `GlobalAlloc::alloc`
+    #[cfg(feature = "debug")]
+    heap_id: usize, // id of the heap registered to detect overlapping heaps, 0 means none
+}
+
+// SAFETY: `SlabAllocator` holds raw pointers to slabs, which are not `Send`,
+// but they point to pages of the heap, not to the stack or thread-local data of any thread,
+// and are reached only through `with_slab` or `&mut self`.
+// `with_slab` takes the lock, so at most one thread touches the slabs and the page allocator at a time,
+// and the lock orders the accesses, so a thread sees the writes of the previous holder.
+// The page allocator is used from whichever thread holds the lock, so it must be `Send`.
+// The other fields are atomics, or plain values written only through `&mut self`.
+unsafe impl<PAGEALLOC: MemAlloc + Send> Send for Allocator<PAGEALLOC> {}
+unsafe impl<PAGEALLOC: MemAlloc + Send> Sync for Allocator<PAGEALLOC> {}
+
+/// A header placed right before a memory region allocated with padding for alignment.
+/// `magic` is adjacent to the region so that an underflow write corrupts it first.
+#[repr(C)]
+struct AlignedHeader {
+    orig: u64,       // pointer returned by the slab allocator
+    generation: u64, // generation of the allocator when allocated
+    magic: u64,      // must be ALIGNED_MAGIC
+}
+
+const ALIGNED_MAGIC: u64 = 0x6d65_6d61_635f_616c; // "memac_al"
+const ALIGNED_HEADER_SIZE: usize = core::mem::size_of::<AlignedHeader>();
+
+/// Read the header right before the memory region at `addr`.
+///
+/// `addr` is given by the caller of a free function and may be misaligned if it is wrong,
+/// so the header is read without assuming its alignment.
+unsafe fn read_header(addr: usize) -> AlignedHeader {
+    ((addr - ALIGNED_HEADER_SIZE) as *const AlignedHeader).read_unaligned()
 }
 
 const SIZE_64K: usize = 64 * 1024;
@@ -70,6 +380,49 @@ const MASK_64K: usize = SIZE_64K - 1;
 pub const ALIGNMENT: usize = SIZE_64K;
 pub const MASK: usize = !(MASK_64K);
 
+/// Byte written over newly allocated memory regions by the `poison` feature,
+/// so that reading memory before writing it yields an obviously bogus value.
+#[cfg(feature = "poison")]
+pub const POISON: u8 = 0xCC;
+
+impl<PAGEALLOC: MemAlloc> Default for Allocator<PAGEALLOC> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Summarize the state of the allocator, e.g. for `dbg!` during bring-up.
+///
+/// The state is snapshotted while holding the lock, and no address is printed.
+impl<PAGEALLOC: MemAlloc> core::fmt::Debug for Allocator<PAGEALLOC> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let Some((live_bytes, used, stats)) = self.with_slab(|slab| {
+            let mut used = 0;
+            slab.page_alloc.for_each_used(&mut |_, size| used += size);
+            (slab.live_bytes, used, slab.class_stats())
+        }) else {
+            return f.debug_struct("Allocator").finish_non_exhaustive();
+        };
+
+        // class size => (partial, full)
+        struct Slabs([slab::ClassStats; slab::NUM_CLASSES]);
+
+        impl core::fmt::Debug for Slabs {
+            fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+                f.debug_map()
+                    .entries(self.0.iter().map(|s| (s.class_size, (s.partial, s.full))))
+                    .finish()
+            }
+        }
+
+        f.debug_struct("Allocator")
+            .field("live_bytes", &live_bytes)
+            .field("mapped_pages", &used.div_ceil(SIZE_64K))
+            .field("slabs", &Slabs(stats))
+            .finish()
+    }
+}
+
 impl<PAGEALLOC: MemAlloc> Allocator<PAGEALLOC> {
     pub const fn new() -> Self {
         fn dummy(_: usize, _: usize) {}
@@ -77,6 +430,32 @@ impl<PAGEALLOC: MemAlloc> Allocator<PAGEALLOC> {
         Allocator {
             slab: None,
             unmapf: dummy,
+            mapf: dummy,
+            #[cfg(feature = "trace")]
+            trace: |_| {},
+            heap_start: 0,
+            heap_end: 0,
+            shadow_base: 0,
+            #[cfg(feature = "stats")]
+            allocated_bytes: AtomicUsize::new(0),
+            #[cfg(feature = "stats")]
+            freed_bytes: AtomicUsize::new(0),
+            #[cfg(feature = "stats")]
+            allocs: AtomicUsize::new(0),
+            #[cfg(feature = "stats")]
+            frees: AtomicUsize::new(0),
+            #[cfg(test)]
+            lock_count: AtomicUsize::new(0),
+            lock_users: AtomicUsize::new(0),
+            generation: 0,
+            large_threshold: slab::MAX_SLAB_SIZE,
+            max_align: SIZE_64K,
+            min_class: 0,
+            retry_on_oom: false,
+            #[cfg(test)]
+            before_retry: None,
+            #[cfg(feature = "debug")]
+            heap_id: 0,
         }
     }
 
@@ -84,250 +463,4662 @@ impl<PAGEALLOC: MemAlloc> Allocator<PAGEALLOC> {
     ///
     /// - `heap_size = 2^`buddy::MAX_DEPTH` * `min_size`
     /// - `heap_end` = `heap_start` + `heap_size`
+    ///
+    /// # Panics
+    ///
+    /// Panics if `try_init` fails.
     pub fn init(&mut self, heap_start: usize, size: usize) {
-        assert_eq!(heap_start & MASK_64K, 0);
-
-        let s = slab::SlabAllocator::new(heap_start, size);
-        self.slab = Some(MCSLock::new(s));
+        self.try_init(heap_start, size).unwrap()
     }
 
-    /// Set a callback function to unmap a memory region.
-    pub fn set_unmap_callback(&mut self, unmapf: fn(usize, usize)) {
-        self.unmapf = unmapf;
+    /// Initialize allocator after checking that `heap_start` is aligned to `ALIGNMENT`
+    /// and `size` satisfies the requirement of the backend.
+    ///
+    /// With the `debug` feature, the heap is also checked not to overlap the heap of
+    /// any other initialized allocator, which would hand out the same memory twice.
+    pub fn try_init(&mut self, heap_start: usize, size: usize) -> Result<(), InitError> {
+        self.try_init_heap(heap_start, size, true)
     }
 
-    /// Allocate a memory region.
-    pub fn mem_alloc_align(&self, layout: Layout) -> Option<*mut u8> {
-        let size = layout.size();
-        let alignment = layout.align();
+    /// Initialize allocator like `try_init`, registering the heap if `register` is `true`.
+    fn try_init_heap(
+        &mut self,
+        heap_start: usize,
+        size: usize,
+        #[cfg_attr(not(feature = "debug"), allow(unused_variables))] register: bool,
+    ) -> Result<(), InitError> {
+        if heap_start & MASK_64K != 0 {
+            return Err(InitError::Unaligned { heap_start });
+        }
 
-        if alignment <= 8 {
-            self.mem_alloc(size)
-        } else {
-            let align_1 = alignment - 1;
-            let size = size + align_1 + 8;
-            if let Some(ptr) = self.mem_alloc(size) {
-                let addr = ((ptr as usize) + align_1 + 8) & !align_1;
-                let result = addr as *mut u8;
-                let ptr_to_orig = (addr - 8) as *mut u64;
+        PAGEALLOC::check_size(size)?;
 
-                unsafe { *ptr_to_orig = ptr as u64 };
+        #[cfg(feature = "debug")]
+        if register {
+            self.heap_id = heaps::register(heap_start, heap_start + size, self.heap_id)?;
+        } else {
+            self.unregister_heap();
+        }
 
-                Some(result)
-            } else {
-                None
-            }
+        let mut s = slab::SlabAllocator::<PAGEALLOC>::new(heap_start, size);
+        s.mapf = self.mapf;
+        #[cfg(feature = "trace")]
+        {
+            s.trace = self.trace;
         }
+        self.max_align = s.page_alloc.max_alignment();
+        self.slab = Some(lock::Lock::new(s));
+        self.heap_start = heap_start;
+        self.heap_end = heap_start + size;
+        self.generation += 1;
+
+        Ok(())
     }
 
-    /// Deallocate a memory region.
+    /// Add a disjoint region of `size` bytes at `start` to the backend,
+    /// e.g. `regions::Regions`, so that the slabs take pages from it as well.
+    /// The allocator is initialized with the region if it is not yet.
     ///
-    /// # Safety
+    /// The heap covers all the regions including gaps between them,
+    /// e.g. for `shadow_addr`.
     ///
-    /// `ptr` must be a pointer returned by `mem_alloc`.
-    pub unsafe fn mem_free_align(&mut self, ptr: *mut u8, layout: Layout) {
-        let size = layout.size();
-        let alignment = layout.align();
+    /// # Panics
+    ///
+    /// Panics if `try_add_region` fails.
+    pub fn add_region(&mut self, start: usize, size: usize) {
+        self.try_add_region(start, size).unwrap()
+    }
 
-        if alignment <= 8 {
-            self.mem_free(ptr, size)
-        } else {
-            let addr = ptr as usize;
-            let ptr_to_orig = (addr - 8) as *mut u64;
-            let ptr = (*ptr_to_orig) as *mut u8;
-            let size = size + alignment - 1 + 8;
-            self.mem_free(ptr, size);
+    /// Add a region after checking that `start` is aligned to `ALIGNMENT`
+    /// and the backend accepts the region.
+    pub fn try_add_region(&mut self, start: usize, size: usize) -> Result<(), InitError> {
+        let Some(slab) = self.slab.as_ref() else {
+            return self.try_init(start, size);
+        };
+
+        if start & MASK_64K != 0 {
+            return Err(InitError::Unaligned { heap_start: start });
         }
+
+        slab.with(|slab| slab.page_alloc.add_region(start, size))?;
+        self.heap_start = self.heap_start.min(start);
+        self.heap_end = self.heap_end.max(start + size);
+
+        Ok(())
     }
 
-    fn mem_alloc(&self, size: usize) -> Option<*mut u8> {
-        if size <= slab::MAX_SLAB_SIZE {
-            unsafe {
-                if let Some(slab) = &self.slab {
-                    let mut node = MCSNode::new();
-                    let mut guard = slab.lock(&mut node);
-                    guard.slab_alloc(size)
-                } else {
-                    None
-                }
-            }
-        } else {
-            if let Some(slab) = &self.slab {
-                let mut node = MCSNode::new();
-                let mut guard = slab.lock(&mut node);
-                guard.page_alloc.alloc(size)
-            } else {
-                None
-            }
+    /// Forget all the allocations and make the allocator uninitialized
+    /// so that it can be initialized again by `init`.
+    ///
+    /// The generation of the allocator is bumped on each `init` and `reset`.
+    /// Memory regions allocated with an alignment larger than 8 bytes record the generation,
+    /// and freeing one of an old generation is ignored instead of corrupting the current heap.
+    /// Other memory regions of an old generation must not be freed.
+    ///
+    /// With the `debug` feature, allocating a slab on a page which still holds a live slab panics,
+    /// so a heap must be reset before it is initialized again.
+    ///
+    /// Pages queued by `set_defer_unmap` are unmapped,
+    /// and all the blocks are returned to the backend at once by `MemAlloc::free_all`.
+    pub fn reset(&mut self) {
+        self.flush_unmaps();
+
+        // Pages of the slabs must not be taken for live slabs when the heap is reused.
+        #[cfg(feature = "debug")]
+        self.with_slab(|slab| slab.release_all());
+
+        self.with_slab(|slab| slab.page_alloc.free_all());
+
+        #[cfg(feature = "debug")]
+        self.unregister_heap();
+
+        self.slab = None;
+        self.heap_start = 0;
+        self.heap_end = 0;
+        #[cfg(feature = "stats")]
+        {
+            self.allocated_bytes.store(0, Ordering::Relaxed);
+            self.freed_bytes.store(0, Ordering::Relaxed);
+            self.allocs.store(0, Ordering::Relaxed);
+            self.frees.store(0, Ordering::Relaxed);
         }
+        self.generation += 1;
     }
 
-    unsafe fn mem_free(&self, ptr: *mut u8, size: usize) {
-        if slab::MAX_SLAB_SIZE >= size {
-            let result;
-            {
-                result = if let Some(slab) = &self.slab {
-                    let mut node = MCSNode::new();
-                    let mut guard = slab.lock(&mut node);
-                    guard.slab_dealloc(ptr)
-                } else {
-                    return;
-                }
-            }
-            if let Some(addr) = result {
-                (self.unmapf)(addr, addr);
-            }
-        } else {
-            {
-                if let Some(slab) = &self.slab {
-                    let mut node = MCSNode::new();
-                    slab.lock(&mut node).page_alloc.free(ptr);
-                }
-            }
+    /// Deregister the heap from the registry of overlapping heaps.
+    #[cfg(feature = "debug")]
+    pub(crate) fn unregister_heap(&mut self) {
+        heaps::unregister(self.heap_id);
+        self.heap_id = 0;
+    }
 
-            let start = ptr as usize;
-            let end = start >> (16 + if start & MASK_64K == 0 { 0 } else { 1 });
-            (self.unmapf)(start, end);
-        }
+    /// Return the generation of the allocator, which is bumped on each `init` and `reset`.
+    pub fn generation(&self) -> u64 {
+        self.generation
     }
-}
 
-//#[global_allocator]
-//static GLOBAL: Allocator = Allocator {};
+    /// Set a callback function to unmap a memory region.
+    pub fn set_unmap_callback(&mut self, unmapf: fn(usize, usize)) {
+        self.unmapf = unmapf;
+    }
 
-unsafe impl<PAGEALLOC: MemAlloc> GlobalAlloc for Allocator<PAGEALLOC> {
-    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let size = layout.size();
-        let alignment = layout.align();
+    /// Set a callback function called with the start address and the length of each page
+    /// taken from the page allocator for a new slab, before the slab is written to it,
+    /// e.g. to map the page or to account for it.
+    /// It is called while holding the lock, so it must not allocate from this allocator.
+    pub fn set_map_callback(&mut self, mapf: fn(usize, usize)) {
+        self.mapf = mapf;
+        self.with_slab(|slab| slab.mapf = mapf);
+    }
 
-        if alignment <= 8 {
-            if let Some(ptr) = self.mem_alloc(size) {
-                ptr
-            } else {
-                null_mut()
-            }
-        } else {
-            let align_1 = alignment - 1;
-            let size = size + align_1 + 8;
-            if let Some(ptr) = self.mem_alloc(size) {
-                let addr = ((ptr as usize) + align_1 + 8) & !align_1;
-                let result = addr as *mut u8;
-                let ptr_to_orig = (addr - 8) as *mut u64;
+    /// Set a hook called with each event of the allocator, e.g. to feed a tracer of the kernel.
+    /// The default does nothing.
+    ///
+    /// Events of allocations and slab pages are reported while holding the lock,
+    /// so the hook must not allocate from this allocator.
+    #[cfg(feature = "trace")]
+    pub fn set_trace_hook(&mut self, hook: fn(TraceEvent)) {
+        self.trace = hook;
+        self.with_slab(|slab| slab.trace = hook);
+    }
 
-                *ptr_to_orig = ptr as u64;
+    /// Call the unmap callback for `[start, end)`.
+    fn unmap(&self, start: usize, end: usize) {
+        #[cfg(feature = "trace")]
+        (self.trace)(TraceEvent::UnmapPage { addr: start });
 
-                result
-            } else {
-                null_mut()
-            }
+        (self.unmapf)(start, end);
+    }
+
+    /// Queue pages of emptied slabs instead of calling the unmap callback immediately,
+    /// so that `flush_unmaps` can unmap them in a batch, e.g. with a single TLB shootdown.
+    /// Pages of blocks larger than the large threshold are still unmapped immediately.
+    ///
+    /// Up to 32 pages are queued, and the callback is called immediately while the queue is full.
+    /// A queued page taken again for a new slab is removed from the queue instead of being unmapped.
+    /// Disabling the mode flushes the queue.
+    /// This must be called after `init`.
+    pub fn set_defer_unmap(&mut self, enabled: bool) {
+        if !enabled {
+            self.flush_unmaps();
         }
+
+        self.with_slab(|slab| slab.defer_unmap = enabled);
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
-        let size = layout.size();
-        let alignment = layout.align();
+    /// Call the unmap callback for each page queued by `set_defer_unmap`.
+    pub fn flush_unmaps(&mut self) {
+        self.with_slab(|slab| slab.flush_unmaps(&mut |addr| self.unmap(addr, addr)));
+    }
 
-        if alignment <= 8 {
-            self.mem_free(ptr, size)
-        } else {
-            let addr = ptr as usize;
-            let ptr_to_orig = (addr - 8) as *mut u64;
-            let ptr = (*ptr_to_orig) as *mut u8;
-            let size = size + alignment - 1 + 8;
-            self.mem_free(ptr, size);
+    /// Allocate requests larger than `bytes` directly from the page allocator instead of slabs.
+    /// The default is 65504, the largest size the slab classes can serve.
+    ///
+    /// A lower threshold avoids fragmentation of slabs of large classes,
+    /// e.g. for workloads dominated by ~32KiB allocations.
+    /// For an alignment larger than 8 bytes, the size includes padding and the 24-byte header.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` exceeds 65504 or memory regions are allocated,
+    /// because they would be freed to the wrong allocator.
+    pub fn set_large_threshold(&mut self, bytes: usize) {
+        if bytes > slab::MAX_SLAB_SIZE {
+            panic!("too large threshold");
         }
+
+        if self.live_bytes() != 0 {
+            panic!("memory regions are allocated");
+        }
+
+        self.large_threshold = bytes;
     }
-}
 
-#[cfg(test)]
-mod tests {
-    extern crate std;
+    /// Round requests smaller than `class_size` up to the slab class of `class_size`
+    /// instead of using the smaller classes.
+    /// The default is 16, the smallest class.
+    ///
+    /// This trades internal fragmentation for fewer distinct slab pages and better locality,
+    /// and each object has the same overhead.
+    /// `alloc_external` is not affected.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `class_size` is not a size of the slab classes or memory regions are allocated,
+    /// because the usable sizes of live objects would be computed from the wrong class.
+    pub fn set_min_class(&mut self, class_size: usize) {
+        let Some(idx) = slab::CLASS_SIZES.iter().position(|c| *c == class_size) else {
+            panic!("invalid class size");
+        };
 
-    use core::alloc::GlobalAlloc;
-    use std::println;
+        if self.live_bytes() != 0 {
+            panic!("memory regions are allocated");
+        }
 
-    use crate::{buddy::Buddy32M, pager::PageManager, Allocator, MemAlloc, SIZE_64K};
+        self.min_class = idx;
+    }
 
-    fn init<T: MemAlloc>() -> (Allocator<T>, *mut u8) {
-        let mut alloc = Allocator::new();
+    /// Make `GlobalAlloc::alloc` and `GlobalAlloc::realloc` retry the allocation once before returning null,
+    /// which makes the caller call `handle_alloc_error` and abort.
+    /// The default is `false`.
+    ///
+    /// The retry takes the lock again, so memory freed by other threads in the meantime
+    /// is available, and empty slabs are reclaimed again.
+    /// It is done only once so that an allocation never livelocks under memory pressure.
+    pub fn set_retry_on_oom(&mut self, enabled: bool) {
+        self.retry_on_oom = enabled;
+    }
 
-        let heap_size = 32 * 1024 * 1024;
-        let layout = std::alloc::Layout::from_size_align(heap_size, crate::ALIGNMENT).unwrap();
-        let ptr = unsafe { std::alloc::alloc(layout) };
+    /// Retry a failed allocation of `GlobalAlloc::alloc` once.
+    #[cold]
+    fn retry_alloc(&self, layout: Layout) -> *mut u8 {
+        #[cfg(test)]
+        if let Some(f) = self.before_retry {
+            f(self);
+        }
 
-        alloc.init(ptr as usize, heap_size);
+        self.mem_alloc_align(layout).unwrap_or(null_mut())
+    }
 
-        (alloc, ptr)
+    /// Enable or disable cache coloring of slabs.
+    ///
+    /// When enabled, the first object of each new slab is offset by a rotating
+    /// multiple of the cache line size, using the unused tail of the slab page,
+    /// so that objects at the same index of different slabs do not map to the same cache set.
+    /// Classes without a spare cache line in their slab page are not colored.
+    /// This must be called after `init`.
+    pub fn set_cache_coloring(&mut self, enabled: bool) {
+        self.with_slab(|slab| slab.cache_coloring = enabled);
     }
 
-    fn free(ptr: *mut u8) {
-        let heap_size = 32 * 1024 * 1024;
-        let layout = std::alloc::Layout::from_size_align(heap_size, crate::ALIGNMENT).unwrap();
-        unsafe { std::alloc::dealloc(ptr, layout) };
+    /// Make the placement of allocations deterministic from `seed`.
+    ///
+    /// The scan order of the page allocator, e.g. the first book of the round-robin mode of `PageManager`,
+    /// and the next cache color of each class are derived from `seed`.
+    /// The same sequence of requests on a fresh heap then returns the same addresses relative to the heap,
+    /// so that a failing case found by fuzzing can be replayed.
+    /// Without this, placement depends only on the sequence of requests,
+    /// and the seed lets a fuzzer vary it reproducibly.
+    /// This must be called after `init` and before any allocation.
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.with_slab(|slab| slab.set_seed(seed));
     }
 
-    #[test]
-    fn test_page_alloc() {
-        for _ in 0..64 {
-            for align in 0..=7 {
-                let (alloc, ptr) = init::<PageManager>();
-                let mut v = std::vec::Vec::new();
+    /// Pull `slabs` pages from the page allocator and link them as empty slabs of `class_size`
+    /// so that subsequent allocations of the class do not need a new page.
+    /// This is useful before entering a latency-sensitive path.
+    ///
+    /// Return the number of slabs prewarmed, which is less than `slabs` if pages run out.
+    /// Prewarmed slabs are returned to the page allocator when they become empty after use.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `class_size` is not a size of the slab classes,
+    /// e.g. 16, 32, ..., 1024, 2040, 4088, 8184, 16376, 32752, and 65512.
+    pub fn prewarm(&mut self, class_size: usize, slabs: usize) -> usize {
+        self.with_slab(|slab| slab.prewarm(class_size, slabs))
+            .unwrap_or(0)
+    }
 
-                for i in 0..16 {
-                    for j in 0..16 {
-                        let size = (rand::random::<usize>() % SIZE_64K) + 1;
-                        let layout = std::alloc::Layout::from_size_align(size, 4).unwrap();
+    /// Keep up to `keep` empty slabs of `class_size` instead of returning their pages
+    /// to the page allocator immediately,
+    /// so that allocating and freeing around a slab boundary does not map and unmap pages repeatedly.
+    /// The default is 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `class_size` is not a size of the slab classes.
+    pub fn set_empty_slab_cache(&mut self, class_size: usize, keep: usize) {
+        let Some(idx) = slab::CLASS_SIZES.iter().position(|c| *c == class_size) else {
+            panic!("invalid class size");
+        };
 
-                        println!("allocate: {i}, {j}, layout = {:?}", layout);
+        self.with_slab(|slab| slab.keep_empty[idx] = keep);
+    }
 
-                        let mem = unsafe { alloc.alloc(layout) };
-                        v.push((mem, layout));
+    /// Return empty slabs of all the classes to the page allocator,
+    /// including those kept by `set_empty_slab_cache` or `prewarm`,
+    /// and return the number of returned 64KiB pages.
+    ///
+    /// This is done automatically once when an allocation runs out of memory.
+    pub fn reclaim(&self) -> usize {
+        self.with_slab(|slab| slab.reclaim(&mut |addr| self.unmap(addr, addr)))
+            .unwrap_or(0)
+    }
 
-                        // must be aligned
-                        assert_eq!(mem as usize % 1 << align, 0);
-                    }
-                }
+    /// Set the base address of the shadow address space.
+    ///
+    /// Addresses returned by the allocator depend on where the heap is placed,
+    /// but offsets from the heap start only depend on the sequence of allocations.
+    /// The shadow address space maps the heap onto a fixed base address
+    /// so that the same sequence of allocations always yields the same shadow addresses,
+    /// which is useful to reproduce fuzzing results.
+    pub fn set_shadow_base(&mut self, base: usize) {
+        self.shadow_base = base;
+    }
 
-                for (mem, layout) in v {
-                    println!("deallocate: layout = {:?}", layout);
-                    unsafe { alloc.dealloc(mem, layout) };
-                }
+    /// Translate `ptr` in the heap into the shadow address space.
+    pub fn shadow_addr(&self, ptr: *const u8) -> Option<usize> {
+        let addr = ptr as usize;
+        if self.heap_start <= addr && addr < self.heap_end {
+            Some(self.shadow_base + (addr - self.heap_start))
+        } else {
+            None
+        }
+    }
 
-                free(ptr);
-            }
+    /// Translate a shadow address into the real address in the heap.
+    pub fn real_addr(&self, shadow: usize) -> Option<*mut u8> {
+        let offset = shadow.checked_sub(self.shadow_base)?;
+        if offset < self.heap_end - self.heap_start {
+            Some((self.heap_start + offset) as *mut u8)
+        } else {
+            None
         }
     }
 
-    #[test]
-    fn test_alloc() {
-        for _ in 0..64 {
-            for align in 0..=7 {
-                let (alloc, ptr) = init::<Buddy32M>();
-                let mut v = std::vec::Vec::new();
+    /// Return whether `a` and `b` are slab objects sharing the same 64KiB slab page,
+    /// which means that they likely share TLB entries and cache locality.
+    ///
+    /// `false` is returned if either is outside the heap or is not a slab object.
+    /// Blocks allocated directly from the page allocator are 64KiB aligned,
+    /// and so is only the first object of a slab of `alloc_external`,
+    /// because the other slab objects are preceded by their header or the previous object.
+    pub fn same_page(&self, a: *mut u8, b: *mut u8) -> bool {
+        let is_slab_obj = |ptr: *mut u8| {
+            let addr = ptr as usize;
+            if addr < self.heap_start || addr >= self.heap_end {
+                return false;
+            }
 
-                for i in 0..16 {
-                    let size = 4 << i;
-                    for j in 0..16 {
-                        let size = size + (rand::random::<usize>() % size);
-                        let layout = std::alloc::Layout::from_size_align(size, 1 << align).unwrap();
+            addr & MASK_64K != 0
+                || self.with_slab(|slab| slab.external_slab(addr).is_some()) == Some(true)
+        };
 
-                        println!("allocate: {i}, {j}, layout = {:?}", layout);
+        is_slab_obj(a) && is_slab_obj(b) && (a as usize & MASK) == (b as usize & MASK)
+    }
 
-                        let mem = unsafe { alloc.alloc(layout) };
-                        v.push((mem, layout));
+    /// Return whether `[ptr, ptr + size)` crosses a `boundary`-aligned address,
+    /// which some DMA engines cannot handle in a single transfer.
+    ///
+    /// `boundary` must be a power of two.
+    pub fn crosses_boundary(&self, ptr: *const u8, size: usize, boundary: usize) -> bool {
+        assert!(boundary.is_power_of_two());
 
-                        // must be aligned
-                        assert_eq!(mem as usize % 1 << align, 0);
-                    }
-                }
+        if size == 0 {
+            return false;
+        }
 
-                for (mem, layout) in v {
+        let start = ptr as usize;
+        let last = start + (size - 1);
+        start & !(boundary - 1) != last & !(boundary - 1)
+    }
+
+    /// Allocate a memory region.
+    ///
+    /// A slab object is padded for an alignment larger than 8 bytes,
+    /// but an alignment of 64KiB or more is served by a block of the page allocator aligned to it.
+    /// A zero-size layout consumes no memory.
+    /// A dangling but well-aligned pointer, `layout.align()`, is returned for it,
+    /// and `mem_free_align` ignores it.
+    pub fn mem_alloc_align(&self, layout: Layout) -> Option<*mut u8> {
+        let size = layout.size();
+        let alignment = layout.align();
+
+        if size == 0 {
+            return Some(alignment as *mut u8);
+        }
+
+        self.with_slab(|slab| self.alloc_layout(slab, layout))?
+    }
+
+    /// Allocate a memory region like `mem_alloc_align`,
+    /// or return the size of the largest memory region which can be allocated now on failure,
+    /// so that the caller can degrade gracefully, e.g. by shrinking a cache.
+    ///
+    /// The size is the larger of the largest free block of the page allocator
+    /// and the usable size of the largest slab class with a free object,
+    /// which is taken under the same lock as the failed allocation.
+    /// It does not account for padding of an alignment larger than 8 bytes.
+    /// 0 is returned if nothing can be allocated or the allocator is not initialized.
+    pub fn alloc_or_capacity(&self, layout: Layout) -> Result<*mut u8, usize> {
+        if layout.size() == 0 {
+            return Ok(layout.align() as *mut u8);
+        }
+
+        self.with_slab(|slab| {
+            self.alloc_layout(slab, layout).ok_or_else(|| {
+                let slot = slab.largest_free_slot().min(self.large_threshold);
+                slab.page_alloc.largest_free_block().max(slot)
+            })
+        })
+        .unwrap_or(Err(0))
+    }
+
+    /// Allocate a memory region like `mem_alloc_align`,
+    /// but give up and return `None` if the lock cannot be acquired after `max_spins` attempts,
+    /// so that a real-time caller is not stalled by contention.
+    ///
+    /// Attempts fail while another thread holds or waits for the lock.
+    pub fn try_alloc(&self, layout: Layout, max_spins: usize) -> Option<*mut u8> {
+        if layout.size() == 0 {
+            return Some(layout.align() as *mut u8);
+        }
+
+        self.try_with_slab(max_spins, |slab| self.alloc_layout(slab, layout))?
+    }
+
+    /// Allocate a memory region like `posix_memalign`.
+    ///
+    /// `alignment` must be a power of two and a multiple of `size_of::<*mut u8>()`.
+    /// The memory region must be deallocated by `mem_free_align` with
+    /// `Layout::from_size_align(size, alignment)`.
+    pub fn memalign(&self, alignment: usize, size: usize) -> Result<*mut u8, AllocError> {
+        if !alignment.is_power_of_two()
+            || !alignment.is_multiple_of(core::mem::size_of::<*mut u8>())
+        {
+            return Err(AllocError::BadAlignment);
+        }
+
+        let layout = checked_layout(size, alignment)?;
+        self.mem_alloc_align(layout).ok_or(AllocError::OutOfMemory)
+    }
+
+    /// Allocate `count` slots of `size` bytes placed `stride` bytes apart
+    /// in one contiguous memory region aligned to `align`.
+    ///
+    /// Slot `i` is at `base + i * stride`.
+    /// A stride larger than `size` pads slots, which is useful to avoid false sharing.
+    /// The memory region must be deallocated by `mem_free_align` with
+    /// `Layout::from_size_align(count * stride, align)`.
+    pub fn alloc_strided(
+        &self,
+        count: usize,
+        size: usize,
+        stride: usize,
+        align: usize,
+    ) -> Option<*mut u8> {
+        if stride < size {
+            return None;
+        }
+
+        let total = count.checked_mul(stride)?;
+        let layout = checked_layout(total, align).ok()?;
+        self.mem_alloc_align(layout)
+    }
+
+    /// Deallocate a memory region.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `mem_alloc`.
+    pub unsafe fn mem_free_align(&self, ptr: *mut u8, layout: Layout) {
+        if layout.size() == 0 {
+            return;
+        }
+
+        if let Some(Some((start, end))) =
+            self.with_slab(|slab| self.free_layout_locked(slab, ptr, layout))
+        {
+            self.unmap(start, end);
+        }
+    }
+
+    /// Deallocate memory regions of `items`, each of which is allocated with its layout,
+    /// while taking the lock only once, e.g. when dropping a large data structure.
+    ///
+    /// The unmap callback is called after releasing the lock for pages returned to the page allocator,
+    /// except that it is called while holding the lock if more than 64 pages are returned.
+    ///
+    /// # Safety
+    ///
+    /// Each pointer must be a pointer returned by `mem_alloc_align` with its layout,
+    /// and must appear only once.
+    pub unsafe fn free_batch(&self, items: &[(*mut u8, Layout)]) {
+        const MAX_UNMAPS: usize = 64;
+
+        let mut unmaps = [(0, 0); MAX_UNMAPS];
+        let mut num_unmaps = 0;
+
+        self.with_slab(|slab| {
+            for (ptr, layout) in items.iter() {
+                let Some(unmap) = self.free_layout_locked(slab, *ptr, *layout) else {
+                    continue;
+                };
+
+                if num_unmaps == MAX_UNMAPS {
+                    for (start, end) in unmaps.iter() {
+                        self.unmap(*start, *end);
+                    }
+                    num_unmaps = 0;
+                }
+
+                unmaps[num_unmaps] = unmap;
+                num_unmaps += 1;
+            }
+        });
+
+        for (start, end) in unmaps[..num_unmaps].iter() {
+            self.unmap(*start, *end);
+        }
+    }
+
+    /// Deallocate a slab object without its layout.
+    ///
+    /// The size is recovered from the header of the slab object,
+    /// where the allocation records it, so statistics such as `live_bytes`
+    /// decrease by the same size as deallocation with the layout.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `mem_alloc_align` or `alloc`
+    /// with a layout which is served by a slab class,
+    /// i.e. `size + align - 1 + 24` is at most the large threshold for `align > 8`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ptr` is not a slab object, e.g. a block allocated directly from the page allocator.
+    pub unsafe fn free_unsized(&self, ptr: *mut u8) {
+        let in_heap = |addr: usize| self.heap_start <= addr && addr < self.heap_end;
+
+        // Objects with a header are never 64KiB aligned.
+        // Only the first object of a slab of `alloc_external` can be, which `free_external` frees.
+        let addr = ptr as usize;
+        if !in_heap(addr) || addr & MASK_64K == 0 {
+            panic!("not a slab object");
+        }
+
+        // `magic` of `AlignedHeader` is right before the memory region,
+        // and it never equals the header of a slab object, which is an address in the heap.
+        let orig = if ((addr - 8) as *const u64).read_unaligned() == ALIGNED_MAGIC {
+            if self.is_stale(addr) {
+                return;
+            }
+
+            let header = read_header(addr);
+            header.orig as usize
+        } else {
+            addr
+        };
+
+        #[cfg(feature = "track")]
+        self.with_slab(|slab| slab.tracker.remove(addr));
+
+        #[cfg(feature = "trace")]
+        (self.trace)(TraceEvent::Free { ptr });
+
+        #[cfg(feature = "debug_registry")]
+        self.unregister(addr);
+
+        let (addr_slab, size) = slab::read_object_header(orig);
+        if size == 0 || !in_heap(addr_slab) {
+            panic!("not a slab object");
+        }
+
+        // The object is freed to its slab even if the large threshold would send its size to the page allocator.
+        let class_size = slab::class_size_of_slab(addr_slab);
+        let Some(idx) = slab::CLASS_SIZES.iter().position(|c| *c == class_size) else {
+            panic!("not a slab object");
+        };
+
+        if let Some(Some((start, end))) =
+            self.with_slab(|slab| self.free_placed_locked(slab, orig as *mut u8, size, Some(idx)))
+        {
+            self.unmap(start, end);
+        }
+    }
+
+    /// Return the live allocation which was allocated first,
+    /// and the number of allocations made after it as its age.
+    /// This points leak triage at the most suspicious allocation.
+    ///
+    /// At most `1024` allocations are tracked at once.
+    #[cfg(feature = "track")]
+    pub fn oldest_live(&self) -> Option<(*mut u8, u64)> {
+        let (addr, age) = self.with_slab(|slab| slab.tracker.oldest())??;
+        Some((addr as *mut u8, age))
+    }
+
+    /// Return the number of bytes which can be used from `ptr`
+    /// allocated by `mem_alloc_align` with `layout`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `mem_alloc_align` with `layout`.
+    unsafe fn usable_size(&self, ptr: *mut u8, layout: Layout) -> usize {
+        let size = layout.size();
+        let alignment = layout.align();
+
+        if size == 0 {
+            0
+        } else if alignment <= 8 {
+            self.class_index(size)
+                .map_or(size, |idx| slab::USABLE_SIZES[idx])
+        } else if !self.is_padded(layout) {
+            size
+        } else {
+            let size = size + alignment - 1 + ALIGNED_HEADER_SIZE;
+            if let Some(idx) = self.class_index(size) {
+                let header = read_header(ptr as usize);
+                let orig = header.orig as usize;
+                orig + slab::USABLE_SIZES[idx] - ptr as usize
+            } else {
+                layout.size()
+            }
+        }
+    }
+
+    /// Allocate a memory region like `mem_alloc_align`,
+    /// and return it as a slice pointer whose length is the usable size,
+    /// which may be larger than `layout.size()`.
+    ///
+    /// The memory region must be deallocated by `deallocate` or `mem_free_align` with `layout`.
+    pub fn allocate(&self, layout: Layout) -> Option<NonNull<[u8]>> {
+        let ptr = NonNull::new(self.mem_alloc_align(layout)?)?;
+        let len = unsafe { self.usable_size(ptr.as_ptr(), layout) };
+        Some(NonNull::slice_from_raw_parts(ptr, len))
+    }
+
+    /// Allocate a memory region of at least `min` and up to `max` bytes aligned to `align`,
+    /// which is as large as can be given cheaply,
+    /// and return it with its size, e.g. for a growable buffer.
+    ///
+    /// A size served by a slab is extended to the usable size of its class,
+    /// e.g. 120 bytes for `min` of 100 bytes, which is capped by `max`.
+    /// For larger sizes, a block of `max` bytes is tried first,
+    /// and the size is halved toward `min` while the page allocator cannot give it.
+    /// `None` is returned if `min` is greater than `max`, `align` is invalid, or memory runs out.
+    /// A `max` of 0 gives the dangling pointer of a zero-size layout.
+    ///
+    /// The memory region must be deallocated by `mem_free_align` with
+    /// `Layout::from_size_align(size, align)`, where `size` is the returned size.
+    pub fn alloc_range(&self, min: usize, max: usize, align: usize) -> Option<(*mut u8, usize)> {
+        if min > max {
+            return None;
+        }
+
+        if max == 0 {
+            let layout = checked_layout(0, align).ok()?;
+            return Some((self.mem_alloc_align(layout)?, 0));
+        }
+
+        // Halving must not reach a zero-size block, which would never be freed.
+        let min = min.max(1);
+
+        let layout = checked_layout(min, align).ok()?;
+        if let (padded, Some(idx)) = self.placement(layout) {
+            // Sizes up to the usable size of the class are served by the same class.
+            let mut size = slab::USABLE_SIZES[idx].min(self.large_threshold);
+            if padded {
+                size -= align - 1 + ALIGNED_HEADER_SIZE;
+            }
+            let size = size.min(max);
+
+            let layout = Layout::from_size_align(size, align).ok()?;
+            return Some((self.mem_alloc_align(layout)?, size));
+        }
+
+        self.with_slab(|slab| {
+            let mut size = max;
+            loop {
+                let layout = Layout::from_size_align(size, align).ok()?;
+                if let Some(ptr) = self.alloc_layout(slab, layout) {
+                    return Some((ptr, size));
+                }
+
+                if size == min {
+                    return None;
+                }
+                size = (size / 2).max(min);
+            }
+        })?
+    }
+
+    /// Deallocate a memory region allocated by `allocate`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `allocate` with `layout`.
+    pub unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.mem_free_align(ptr.as_ptr(), layout)
+    }
+
+    /// Shrink a memory region allocated by `mem_alloc_align` with `old_layout` to `new_size` bytes,
+    /// and return the shrunk memory region, or null if it cannot be allocated.
+    /// The old memory region is left untouched on failure.
+    ///
+    /// The memory region stays in place without copying if `new_size` is served by the same slab class,
+    /// or both sizes are allocated directly from the page allocator.
+    /// Otherwise, a memory region of the smaller class is allocated,
+    /// `new_size` bytes are copied, and the old memory region is freed.
+    ///
+    /// The returned memory region must be deallocated with
+    /// `Layout::from_size_align(new_size, old_layout.align())`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `mem_alloc_align` with `old_layout`,
+    /// and `new_size` must not be greater than `old_layout.size()`.
+    pub unsafe fn shrink(&self, ptr: *mut u8, old_layout: Layout, new_size: usize) -> *mut u8 {
+        debug_assert!(new_size <= old_layout.size());
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, old_layout.align());
+        if new_size != 0 && self.placement(old_layout) == self.placement(new_layout) {
+            let old_size = self.backing_size(old_layout);
+            let new_size = self.backing_size(new_layout);
+            let (padded, idx) = self.placement(old_layout);
+            self.with_slab(|slab| {
+                slab.account_free(old_size, idx.is_none());
+                slab.account_alloc(new_size, idx.is_none());
+                #[cfg(feature = "stats")]
+                {
+                    self.freed_bytes
+                        .fetch_add(old_size - new_size, Ordering::Relaxed);
+                    if idx.is_some() {
+                        slab.requested_bytes -= old_size - new_size;
+                    }
+                }
+            });
+
+            if idx.is_some() {
+                let orig = if padded {
+                    read_header(ptr as usize).orig as usize
+                } else {
+                    ptr as usize
+                };
+                slab::set_object_size(orig, new_size);
+            }
+
+            // A tracer sees a resize in place as a free and an allocation at the same address.
+            #[cfg(feature = "trace")]
+            {
+                (self.trace)(TraceEvent::Free { ptr });
+                (self.trace)(TraceEvent::Alloc {
+                    size: new_layout.size(),
+                    ptr,
+                });
+            }
+
+            return ptr;
+        }
+
+        let Some(new_ptr) = self.mem_alloc_align(new_layout) else {
+            return null_mut();
+        };
+
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, new_size);
+        self.mem_free_align(ptr, old_layout);
+
+        new_ptr
+    }
+
+    /// Resize a memory region allocated by `mem_alloc_align` with `old_layout` to `new_size` bytes,
+    /// and return the resized memory region, or null if it cannot be allocated.
+    /// The old memory region is left untouched on failure.
+    ///
+    /// A smaller size is handled by `shrink`.
+    /// A block allocated directly from the page allocator grows in place if the backend can extend it,
+    /// e.g. the buddy of a block of `buddy::BuddyAlloc` or pages following a block of
+    /// `pager::PageManager` are free, which avoids copying the whole block.
+    /// Otherwise, a new memory region is allocated, the old contents are copied,
+    /// and the old memory region is freed.
+    /// Objects of slabs are always copied, including those of the class of 65512 bytes
+    /// taking a whole page, because the metadata of the slab is placed at the end of the page.
+    ///
+    /// The returned memory region must be deallocated with
+    /// `Layout::from_size_align(new_size, old_layout.align())`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `mem_alloc_align` with `old_layout`.
+    pub unsafe fn realloc(&self, ptr: *mut u8, old_layout: Layout, new_size: usize) -> *mut u8 {
+        let old_size = old_layout.size();
+        if new_size <= old_size {
+            return self.shrink(ptr, old_layout, new_size);
+        }
+
+        // Blocks of the page allocator are never padded, and larger sizes are also such blocks.
+        if old_size > self.large_threshold && self.grow_locked(ptr, old_size, new_size) {
+            return ptr;
+        }
+
+        let new_layout = Layout::from_size_align_unchecked(new_size, old_layout.align());
+        let Some(new_ptr) = self.mem_alloc_align(new_layout) else {
+            return null_mut();
+        };
+
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, old_size);
+        self.mem_free_align(ptr, old_layout);
+
+        new_ptr
+    }
+
+    /// Resize a memory region like `realloc`, but copy the whole usable size of the old memory region
+    /// when it moves, as far as it fits in the usable size of the new one.
+    ///
+    /// The usable size may be larger than `old_layout.size()` because the size is rounded up to a slab class,
+    /// e.g. 120 bytes for 100 bytes.
+    /// `realloc` copies only the requested bytes, which are all that `GlobalAlloc` guarantees to keep,
+    /// while this also keeps bytes written to the slack, e.g. by a structure using `usable_size` of `allocate`.
+    /// The memory region stays in place in the same cases as `realloc`.
+    ///
+    /// The returned memory region must be deallocated with
+    /// `Layout::from_size_align(new_size, old_layout.align())`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `mem_alloc_align` with `old_layout`.
+    pub unsafe fn realloc_copy_usable(
+        &self,
+        ptr: *mut u8,
+        old_layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        let old_size = old_layout.size();
+        let new_layout = Layout::from_size_align_unchecked(new_size, old_layout.align());
+        if new_size <= old_size && self.placement(old_layout) == self.placement(new_layout) {
+            return self.shrink(ptr, old_layout, new_size);
+        }
+
+        if new_size > old_size
+            && old_size > self.large_threshold
+            && self.grow_locked(ptr, old_size, new_size)
+        {
+            return ptr;
+        }
+
+        let Some(new_ptr) = self.mem_alloc_align(new_layout) else {
+            return null_mut();
+        };
+
+        let len = self
+            .usable_size(ptr, old_layout)
+            .min(self.usable_size(new_ptr, new_layout));
+        core::ptr::copy_nonoverlapping(ptr, new_ptr, len);
+        self.mem_free_align(ptr, old_layout);
+
+        new_ptr
+    }
+
+    /// Grow the block at `ptr` of the page allocator from `old_size` to `new_size` bytes in place.
+    fn grow_locked(&self, ptr: *mut u8, old_size: usize, new_size: usize) -> bool {
+        let grown = self.with_slab(|slab| {
+            if !slab.page_alloc.grow_in_place(ptr, new_size) {
+                return false;
+            }
+
+            slab.account_free(old_size, true);
+            slab.account_alloc(new_size, true);
+            #[cfg(feature = "stats")]
+            self.allocated_bytes
+                .fetch_add(new_size - old_size, Ordering::Relaxed);
+
+            // Pages queued to be unmapped may be taken by the block.
+            let addr = ptr as usize;
+            slab.cancel_unmap(addr, addr + new_size);
+
+            #[cfg(feature = "trace")]
+            {
+                (self.trace)(TraceEvent::Free { ptr });
+                (self.trace)(TraceEvent::Alloc {
+                    size: new_size,
+                    ptr,
+                });
+            }
+
+            true
+        });
+
+        #[cfg(feature = "poison")]
+        if grown == Some(true) {
+            unsafe { core::ptr::write_bytes(ptr.add(old_size), POISON, new_size - old_size) };
+        }
+
+        grown == Some(true)
+    }
+
+    /// Return whether a memory region for `layout` is a slab object padded for alignment.
+    /// An alignment of 64KiB or more is given by the page allocator without padding,
+    /// which costs a page instead of twice the alignment,
+    /// unless the backend cannot guarantee it, e.g. `Buddy32M` on a heap aligned to 64KiB only.
+    fn is_padded(&self, layout: Layout) -> bool {
+        let align = layout.align();
+        if align < SIZE_64K {
+            align > 8 && layout.size() <= self.large_threshold
+        } else {
+            align > self.max_align
+        }
+    }
+
+    /// Return the number of bytes allocated for `layout` from slabs or the page allocator,
+    /// which includes padding and the header for an alignment larger than 8 bytes.
+    fn backing_size(&self, layout: Layout) -> usize {
+        if self.is_padded(layout) {
+            layout.size() + layout.align() - 1 + ALIGNED_HEADER_SIZE
+        } else {
+            layout.size()
+        }
+    }
+
+    /// Return whether a memory region for `layout` is padded for alignment,
+    /// and the index of the slab class serving it, or `None` for the page allocator.
+    /// Memory regions of the same placement are interchangeable.
+    fn placement(&self, layout: Layout) -> (bool, Option<usize>) {
+        let idx = if layout.align() < SIZE_64K {
+            self.class_index(self.backing_size(layout))
+        } else {
+            None
+        };
+        (self.is_padded(layout), idx)
+    }
+
+    /// Allocate at least `size` bytes from a slab whose objects have no inline header,
+    /// so that the whole memory region is usable by the caller,
+    /// e.g. for a DMA buffer which must not contain metadata of the allocator.
+    ///
+    /// The memory region is a whole object of the smallest slab class of at least `size` bytes,
+    /// e.g. 128 bytes for 100 bytes, and is aligned to 8 bytes.
+    /// Slabs serving such objects are found by a side table keyed by page address,
+    /// so at most `MAX_EXTERNAL_SLABS` of them can exist at once.
+    /// Metadata of a slab is still placed at the end of its page, outside of any object.
+    ///
+    /// The memory region must be deallocated by `free_external`.
+    pub fn alloc_external(&self, size: usize) -> Option<*mut u8> {
+        self.with_slab(|slab| {
+            let (ptr, size) = unsafe { slab.slab_alloc_external(size) }?;
+            slab.account_alloc(size, false);
+            #[cfg(feature = "stats")]
+            {
+                self.allocated_bytes.fetch_add(size, Ordering::Relaxed);
+                self.allocs.fetch_add(1, Ordering::Relaxed);
+            }
+
+            // A page queued to be unmapped may be taken again.
+            let page = ptr as usize & !MASK_64K;
+            slab.cancel_unmap(page, page + SIZE_64K);
+
+            #[cfg(feature = "trace")]
+            (self.trace)(TraceEvent::Alloc { size, ptr });
+
+            Some(ptr)
+        })?
+    }
+
+    /// Deallocate a memory region allocated by `alloc_external`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `alloc_external`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the page of `ptr` is not a slab serving `alloc_external`.
+    pub unsafe fn free_external(&self, ptr: *mut u8) {
+        let Some(page) = self.with_slab(|slab| {
+            let (size, page) = slab.slab_dealloc_external(ptr);
+            slab.account_free(size, false);
+            #[cfg(feature = "trace")]
+            (self.trace)(TraceEvent::Free { ptr });
+            #[cfg(feature = "stats")]
+            {
+                self.freed_bytes.fetch_add(size, Ordering::Relaxed);
+                self.frees.fetch_add(1, Ordering::Relaxed);
+            }
+            page.filter(|addr| !slab.queue_unmap(*addr))
+        }) else {
+            return;
+        };
+
+        if let Some(addr) = page {
+            self.unmap(addr, addr);
+        }
+    }
+
+    /// Return whether a request of `size` bytes with an alignment up to 8 bytes
+    /// is served by a slab or the page allocator, following `set_large_threshold`.
+    ///
+    /// An object of a class of `class_size` bytes holds `class_size - 16` bytes
+    /// for classes from 2040 to 32752 bytes, and `class_size - 8` bytes for the others,
+    /// so sizes just up to them pack best.
+    pub fn allocation_kind(&self, size: usize) -> AllocKind {
+        match self.class_index(size) {
+            Some(idx) => AllocKind::Slab(slab::CLASS_SIZES[idx]),
+            None => AllocKind::Large,
+        }
+    }
+
+    /// Return the number of bytes currently allocated.
+    pub fn live_bytes(&self) -> usize {
+        self.with_slab(|slab| slab.live_bytes).unwrap_or(0)
+    }
+
+    /// Return true if there is no live allocation, e.g. for assertions at teardown.
+    ///
+    /// It reads the counter of live allocations instead of walking the slabs,
+    /// so empty slabs kept by `set_empty_slab_cache` or `set_defer_unmap` may still hold pages.
+    pub fn is_empty(&self) -> bool {
+        self.with_slab(|slab| {
+            let empty = slab.live_count == 0;
+            debug_assert!(
+                !empty
+                    || slab
+                        .class_stats()
+                        .iter()
+                        .all(|s| s.live == 0 && s.full == 0),
+                "live objects are not counted"
+            );
+            empty
+        })
+        .unwrap_or(true)
+    }
+
+    /// Return the maximum number of bytes that have been allocated at once
+    /// since `init` or the last `reset_peak`.
+    pub fn peak_bytes(&self) -> usize {
+        self.with_slab(|slab| slab.peak_bytes).unwrap_or(0)
+    }
+
+    /// Return the maximum number of bytes taken from the backend at once
+    /// since `init` or the last `reset_peak`,
+    /// which counts whole slab pages and blocks rounded up by the backend.
+    /// 0 means the backend does not track it.
+    pub fn backend_peak_bytes(&self) -> usize {
+        self.with_slab(|slab| slab.page_alloc.peak_used_bytes())
+            .unwrap_or(0)
+    }
+
+    /// Return the number of bytes which can still be allocated in O(1),
+    /// which are the free slots of existing slabs plus the free bytes of the page allocator.
+    ///
+    /// This is cheap enough for a frequently-polled "is memory low?" check.
+    /// A free slot is counted by the usable size of its class,
+    /// and the bytes cannot necessarily be allocated at once.
+    pub fn available_bytes(&self) -> usize {
+        self.with_slab(|slab| slab.slab_free_bytes + slab.page_alloc.free_bytes())
+            .unwrap_or(0)
+    }
+
+    /// Return the number of bytes currently allocated without taking the lock.
+    ///
+    /// The value is eventually consistent.
+    /// It may lag behind allocations and deallocations in progress on other CPUs,
+    /// so use `live_bytes` if an exact value is required.
+    #[cfg(feature = "stats")]
+    pub fn live_bytes_approx(&self) -> usize {
+        let freed = self.freed_bytes.load(Ordering::Relaxed);
+        let allocated = self.allocated_bytes.load(Ordering::Relaxed);
+        allocated.saturating_sub(freed)
+    }
+
+    /// Return statistics of the allocator while holding the lock.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> AllocStats {
+        self.with_slab(|slab| AllocStats {
+            live_bytes: slab.live_bytes,
+            live_count: slab.live_count,
+            allocated_bytes: self.allocated_bytes.load(Ordering::Relaxed),
+            freed_bytes: self.freed_bytes.load(Ordering::Relaxed),
+        })
+        .unwrap_or_default()
+    }
+
+    /// Return statistics of the allocator only from atomic counters,
+    /// without taking the lock or walking slabs,
+    /// so that this is safe to call from an interrupt handler
+    /// which may have interrupted a thread holding the lock.
+    ///
+    /// Each counter is read individually and is eventually consistent,
+    /// so the counters may lag behind allocations and deallocations in progress,
+    /// including one interrupted by the handler,
+    /// and may not be consistent with each other.
+    /// Use `stats` if an exact snapshot is required.
+    #[cfg(feature = "stats")]
+    pub fn stats_relaxed(&self) -> AllocStats {
+        let allocated_bytes = self.allocated_bytes.load(Ordering::Relaxed);
+        let freed_bytes = self.freed_bytes.load(Ordering::Relaxed);
+        let allocs = self.allocs.load(Ordering::Relaxed);
+        let frees = self.frees.load(Ordering::Relaxed);
+
+        AllocStats {
+            live_bytes: allocated_bytes.saturating_sub(freed_bytes),
+            live_count: allocs.saturating_sub(frees),
+            allocated_bytes,
+            freed_bytes,
+        }
+    }
+
+    /// Reset the high-water mark to the number of bytes currently allocated.
+    pub fn reset_peak(&mut self) {
+        self.with_slab(|slab| {
+            slab.peak_bytes = slab.live_bytes;
+            slab.page_alloc.reset_peak();
+        });
+    }
+
+    /// Enumerate metrics as named pairs for exporting them to a metrics system.
+    ///
+    /// - `live_bytes`: bytes currently allocated
+    /// - `peak_bytes`: high-water mark of `live_bytes`
+    /// - `live_count`: number of live allocations
+    /// - `committed_bytes`: bytes of slab pages plus bytes of large allocations
+    /// - `slab_class_{size}_pages`: number of pages used by the slab class
+    /// - `buddy_largest_free`: bytes of the largest block the backend can allocate
+    /// - `buddy_peak_used`: high-water mark of bytes taken from the backend
+    ///
+    /// `f` is called after releasing the lock.
+    pub fn metrics(&self, f: &mut dyn FnMut(&'static str, u64)) {
+        const CLASS_KEYS: [&str; slab::NUM_CLASSES] = [
+            "slab_class_16_pages",
+            "slab_class_32_pages",
+            "slab_class_64_pages",
+            "slab_class_128_pages",
+            "slab_class_256_pages",
+            "slab_class_512_pages",
+            "slab_class_1024_pages",
+            "slab_class_2040_pages",
+            "slab_class_4088_pages",
+            "slab_class_8184_pages",
+            "slab_class_16376_pages",
+            "slab_class_32752_pages",
+            "slab_class_65512_pages",
+        ];
+
+        let Some((live_bytes, peak_bytes, live_count, large_bytes, pages, largest, backend_peak)) =
+            self.with_slab(|slab| {
+                (
+                    slab.live_bytes,
+                    slab.peak_bytes,
+                    slab.live_count,
+                    slab.large_bytes,
+                    slab.class_pages(),
+                    slab.page_alloc.largest_free_block(),
+                    slab.page_alloc.peak_used_bytes(),
+                )
+            })
+        else {
+            return;
+        };
+
+        let committed = pages.iter().sum::<usize>() * SIZE_64K + large_bytes;
+
+        f("live_bytes", live_bytes as u64);
+        f("peak_bytes", peak_bytes as u64);
+        f("live_count", live_count as u64);
+        f("committed_bytes", committed as u64);
+        for (key, n) in CLASS_KEYS.iter().zip(pages.iter()) {
+            f(key, *n as u64);
+        }
+        f("buddy_largest_free", largest as u64);
+        f("buddy_peak_used", backend_peak as u64);
+    }
+
+    /// Return `(class_size, allocs, frees)` of each slab class,
+    /// which are the numbers of allocations and deallocations since `init`.
+    #[cfg(feature = "stats")]
+    pub fn class_counters(&self) -> [(usize, u64, u64); slab::NUM_CLASSES] {
+        let mut result = [(0, 0, 0); slab::NUM_CLASSES];
+        self.with_slab(|slab| {
+            for (i, r) in result.iter_mut().enumerate() {
+                *r = (
+                    slab::CLASS_SIZES[i],
+                    slab.alloc_count[i],
+                    slab.free_count[i],
+                );
+            }
+        });
+        result
+    }
+
+    /// Return `(class_size, allocs)` of each slab class,
+    /// where `allocs` is the number of allocations since `init` or the last `reset_histogram`,
+    /// e.g. to compare the distribution of requested sizes between time windows.
+    #[cfg(feature = "stats")]
+    pub fn size_histogram(&self) -> [(usize, u64); slab::NUM_CLASSES] {
+        let mut result = [(0, 0); slab::NUM_CLASSES];
+        self.with_slab(|slab| {
+            for (i, r) in result.iter_mut().enumerate() {
+                *r = (
+                    slab::CLASS_SIZES[i],
+                    slab.alloc_count[i] - slab.histogram_base[i],
+                );
+            }
+        });
+        result
+    }
+
+    /// Return `(wasted_bytes, rounded_bytes)` of the live slab objects,
+    /// where `rounded_bytes` is the sum of the usable sizes of their classes,
+    /// and `wasted_bytes` is the part of it exceeding the requested sizes.
+    ///
+    /// The figure is exact, because shrinking in place and deallocation account the size
+    /// given at allocation, which `free_unsized` reads from the header of the object.
+    /// A request aligned by padding counts its padded size as requested,
+    /// and blocks of the page allocator are not counted.
+    #[cfg(feature = "stats")]
+    pub fn internal_fragmentation(&self) -> (usize, usize) {
+        self.with_slab(|slab| {
+            (
+                slab.rounded_bytes.saturating_sub(slab.requested_bytes),
+                slab.rounded_bytes,
+            )
+        })
+        .unwrap_or((0, 0))
+    }
+
+    /// Start a new time window of `size_histogram`.
+    /// The counters of `class_counters` are kept.
+    #[cfg(feature = "stats")]
+    pub fn reset_histogram(&self) {
+        self.with_slab(|slab| slab.histogram_base = slab.alloc_count);
+    }
+
+    /// Return `(live_objects, total_capacity)` across all the slabs of `class_size`,
+    /// where `total_capacity` is the number of objects the slabs can hold.
+    ///
+    /// A low ratio means that live objects are scattered over sparsely-filled slabs,
+    /// which is a hint that migrating them to fewer slabs is worthwhile.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `class_size` is not a size of the slab classes.
+    pub fn class_fill_ratio(&self, class_size: usize) -> (usize, usize) {
+        let Some(idx) = slab::CLASS_SIZES.iter().position(|c| *c == class_size) else {
+            panic!("invalid class size");
+        };
+
+        self.with_slab(|slab| {
+            let stats = slab.class_stats()[idx];
+            (stats.live, stats.capacity)
+        })
+        .unwrap_or((0, 0))
+    }
+
+    /// Write a human-readable dump of the heap to `w` for debugging.
+    ///
+    /// For each slab class, the numbers of partial and full slabs and live objects are written,
+    /// followed by the utilization of the backend.
+    /// This does not allocate, and `w` is written after releasing the lock.
+    pub fn dump(&self, w: &mut impl core::fmt::Write) -> core::fmt::Result {
+        let Some((stats, (start, end), used)) = self.with_slab(|slab| {
+            let mut used = 0;
+            slab.page_alloc.for_each_used(&mut |_, size| used += size);
+            (slab.class_stats(), slab.page_alloc.range(), used)
+        }) else {
+            return writeln!(w, "not initialized");
+        };
+
+        for s in stats.iter() {
+            writeln!(
+                w,
+                "slab{}: partial = {}, full = {}, live = {}",
+                s.class_size, s.partial, s.full, s.live
+            )?;
+        }
+
+        let total = end - start;
+        writeln!(
+            w,
+            "backend: used = {} / {} bytes ({}%)",
+            used,
+            total,
+            used * 100 / total
+        )
+    }
+
+    /// Return an iterator over live allocations as `(address, size)`.
+    ///
+    /// Slab objects are reported by the address right after their header,
+    /// and blocks allocated from the page allocator are reported by their block size.
+    /// Allocations are snapshotted while holding the lock,
+    /// and at most `LIVE_CAPACITY` allocations are reported.
+    #[cfg(any(test, feature = "debug"))]
+    pub fn live_allocations(&self) -> LiveIter {
+        let mut iter = LiveIter {
+            buf: [(0, 0); LIVE_CAPACITY],
+            len: 0,
+            pos: 0,
+        };
+
+        self.with_slab(|slab| {
+            slab.for_each_live(&mut |addr, size| {
+                if iter.len < LIVE_CAPACITY {
+                    iter.buf[iter.len] = (addr, size);
+                    iter.len += 1;
+                }
+            })
+        });
+
+        iter
+    }
+
+    /// Remove `addr` from the registry of live pointers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `addr` is not live, i.e. it has been freed already or was never allocated.
+    #[cfg(feature = "debug_registry")]
+    fn unregister(&self, addr: usize) {
+        if self.with_slab(|slab| slab.registry.remove(addr)) == Some(false) {
+            panic!("double free or free of an unknown pointer: {addr:#x}");
+        }
+    }
+
+    /// Return whether the memory region at `addr` allocated with padding for alignment
+    /// belongs to an old generation.
+    ///
+    /// # Safety
+    ///
+    /// `addr` must be preceded by `AlignedHeader`.
+    unsafe fn is_stale(&self, addr: usize) -> bool {
+        let header = read_header(addr);
+        header.generation != self.generation
+    }
+
+    /// Return the index of the slab class which serves a request of `size` bytes,
+    /// or `None` if it is allocated from the page allocator.
+    fn class_index(&self, size: usize) -> Option<usize> {
+        if size <= self.large_threshold {
+            slab::class_index(size).map(|idx| idx.max(self.min_class))
+        } else {
+            None
+        }
+    }
+
+    /// Run `f` while holding the lock of the slab allocator.
+    fn with_slab<R>(&self, f: impl FnOnce(&mut slab::SlabAllocator<PAGEALLOC>) -> R) -> Option<R> {
+        let slab = self.slab.as_ref()?;
+
+        self.lock_users.fetch_add(1, Ordering::Acquire);
+        let _user = LockUser(&self.lock_users);
+        #[cfg(test)]
+        self.lock_count.fetch_add(1, Ordering::Relaxed);
+
+        Some(slab.with(f))
+    }
+
+    /// Run `f` while holding the lock of the slab allocator
+    /// if nobody holds or waits for the lock within `max_spins` attempts.
+    ///
+    /// The locks have no `try_lock`, so `lock_users` is used instead.
+    fn try_with_slab<R>(
+        &self,
+        max_spins: usize,
+        f: impl FnOnce(&mut slab::SlabAllocator<PAGEALLOC>) -> R,
+    ) -> Option<R> {
+        let slab = self.slab.as_ref()?;
+
+        for _ in 0..max_spins {
+            if self
+                .lock_users
+                .compare_exchange_weak(0, 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_ok()
+            {
+                let _user = LockUser(&self.lock_users);
+                return Some(slab.with(f));
+            }
+
+            core::hint::spin_loop();
+        }
+
+        None
+    }
+
+    /// Allocate a memory region for `layout` while holding the lock.
+    fn alloc_layout(
+        &self,
+        slab: &mut slab::SlabAllocator<PAGEALLOC>,
+        layout: Layout,
+    ) -> Option<*mut u8> {
+        let size = layout.size();
+        let alignment = layout.align();
+
+        // The registry grows before allocating, so a failure leaves nothing to undo.
+        #[cfg(feature = "debug_registry")]
+        if !slab.registry.reserve(&mut slab.page_alloc) {
+            return None;
+        }
+
+        let result = if alignment <= 8 {
+            self.alloc_locked(slab, size, 1)?
+        } else if !self.is_padded(layout) {
+            // The backend aligns large blocks and blocks of 64KiB alignment or more without padding.
+            self.alloc_locked(slab, size, alignment)?
+        } else {
+            // `Layout` guarantees a power of two, which the mask below relies on.
+            debug_assert!(alignment.is_power_of_two());
+            let align_1 = alignment - 1;
+            let size = size + align_1 + ALIGNED_HEADER_SIZE;
+            let ptr = self.alloc_locked(slab, size, 1)?;
+            let addr = ((ptr as usize) + align_1 + ALIGNED_HEADER_SIZE) & !align_1;
+            let header = (addr - ALIGNED_HEADER_SIZE) as *mut AlignedHeader;
+
+            // The magic detects corruption of the header before trusting `orig`.
+            unsafe {
+                *header = AlignedHeader {
+                    orig: ptr as u64,
+                    generation: self.generation,
+                    magic: ALIGNED_MAGIC,
+                }
+            };
+
+            addr as *mut u8
+        };
+
+        #[cfg(feature = "track")]
+        slab.tracker.insert(result as usize);
+
+        #[cfg(feature = "trace")]
+        (self.trace)(TraceEvent::Alloc {
+            size: layout.size(),
+            ptr: result,
+        });
+
+        #[cfg(feature = "debug_registry")]
+        slab.registry.insert(result as usize);
+
+        Some(result)
+    }
+
+    #[cfg(test)]
+    fn mem_alloc(&self, size: usize) -> Option<*mut u8> {
+        self.with_slab(|slab| self.alloc_locked(slab, size, 1))?
+    }
+
+    /// Allocate `size` bytes while holding the lock.
+    /// Blocks larger than `large_threshold` or of `align` of 64KiB or more
+    /// are aligned to `align` by the backend.
+    fn alloc_locked(
+        &self,
+        slab: &mut slab::SlabAllocator<PAGEALLOC>,
+        size: usize,
+        align: usize,
+    ) -> Option<*mut u8> {
+        let idx = self.locked_class_index(size, align);
+
+        // Return the address and the usable bytes of the memory region.
+        let alloc = |slab: &mut slab::SlabAllocator<PAGEALLOC>| match idx {
+            Some(idx) => {
+                let ptr = unsafe { slab.slab_alloc_index(idx) }?;
+                Some((ptr, slab::USABLE_SIZES[idx]))
+            }
+            None => {
+                // The backend may round `size` up, e.g. to a power of two,
+                // which is measured only to poison the whole block.
+                #[cfg(feature = "poison")]
+                let free = slab.page_alloc.free_bytes();
+                let ptr = slab.page_alloc.alloc_aligned(size, align)?;
+                #[cfg(feature = "poison")]
+                let size = (free - slab.page_alloc.free_bytes()).max(size);
+                Some((ptr, size))
+            }
+        };
+
+        let mut block = alloc(slab);
+
+        // Empty slabs cached by other classes may free enough pages; retry once.
+        if block.is_none() && slab.reclaim(&mut |addr| self.unmap(addr, addr)) > 0 {
+            block = alloc(slab);
+        }
+
+        // Fill the usable bytes of a slab object but not its header,
+        // or the whole block of the backend.
+        #[cfg(feature = "poison")]
+        if let Some((ptr, len)) = block {
+            unsafe { core::ptr::write_bytes(ptr, POISON, len) };
+        }
+
+        let result = block.map(|(ptr, _)| ptr);
+
+        if let Some(ptr) = result {
+            slab.account_alloc(size, idx.is_none());
+            #[cfg(feature = "stats")]
+            {
+                self.allocated_bytes.fetch_add(size, Ordering::Relaxed);
+                self.allocs.fetch_add(1, Ordering::Relaxed);
+            }
+
+            #[cfg(feature = "stats")]
+            if let Some(idx) = idx {
+                slab.requested_bytes += size;
+                slab.rounded_bytes += slab::USABLE_SIZES[idx];
+            }
+
+            // `free_unsized` accounts the same size without the layout.
+            if idx.is_some() {
+                unsafe { slab::set_object_size(ptr as usize, size) };
+            }
+
+            // A page queued to be unmapped may be taken again.
+            let addr = ptr as usize;
+            slab.cancel_unmap(addr & !MASK_64K, addr + size);
+        }
+
+        result
+    }
+
+    /// Return the index of the slab class serving `size` bytes aligned to `align` by `alloc_locked`,
+    /// or `None` if they are allocated from the page allocator.
+    fn locked_class_index(&self, size: usize, align: usize) -> Option<usize> {
+        if align < SIZE_64K {
+            self.class_index(size)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(test)]
+    unsafe fn mem_free(&self, ptr: *mut u8, size: usize) {
+        if let Some(Some((start, end))) =
+            self.with_slab(|slab| self.free_locked(slab, ptr, size, 1))
+        {
+            self.unmap(start, end);
+        }
+    }
+
+    /// Deallocate `size` bytes at `ptr` allocated by `alloc_locked` with `align` while holding the lock,
+    /// and return the arguments of the unmap callback if pages are returned to the page allocator.
+    unsafe fn free_locked(
+        &self,
+        slab: &mut slab::SlabAllocator<PAGEALLOC>,
+        ptr: *mut u8,
+        size: usize,
+        align: usize,
+    ) -> Option<(usize, usize)> {
+        let idx = self.locked_class_index(size, align);
+        self.free_placed_locked(slab, ptr, size, idx)
+    }
+
+    /// Deallocate `size` bytes at `ptr` of the slab class of `idx`,
+    /// or of the page allocator if `idx` is `None`, while holding the lock.
+    unsafe fn free_placed_locked(
+        &self,
+        slab: &mut slab::SlabAllocator<PAGEALLOC>,
+        ptr: *mut u8,
+        size: usize,
+        idx: Option<usize>,
+    ) -> Option<(usize, usize)> {
+        slab.account_free(size, idx.is_none());
+        #[cfg(feature = "stats")]
+        {
+            self.freed_bytes.fetch_add(size, Ordering::Relaxed);
+            self.frees.fetch_add(1, Ordering::Relaxed);
+        }
+
+        #[cfg(feature = "stats")]
+        if let Some(idx) = idx {
+            slab.requested_bytes = slab.requested_bytes.saturating_sub(size);
+            slab.rounded_bytes = slab.rounded_bytes.saturating_sub(slab::USABLE_SIZES[idx]);
+        }
+
+        if idx.is_some() {
+            slab.slab_dealloc(ptr)
+                .filter(|addr| !slab.queue_unmap(*addr))
+                .map(|addr| (addr, addr))
+        } else {
+            #[cfg(feature = "zero_on_free")]
+            core::ptr::write_bytes(ptr, 0, size);
+
+            slab.page_alloc.free(ptr);
+
+            let start = ptr as usize;
+            let end = start >> (16 + if start & MASK_64K == 0 { 0 } else { 1 });
+            Some((start, end))
+        }
+    }
+
+    /// Deallocate a memory region allocated with `layout` while holding the lock,
+    /// and return the arguments of the unmap callback if pages are returned to the page allocator.
+    unsafe fn free_layout_locked(
+        &self,
+        slab: &mut slab::SlabAllocator<PAGEALLOC>,
+        ptr: *mut u8,
+        layout: Layout,
+    ) -> Option<(usize, usize)> {
+        let size = layout.size();
+        let alignment = layout.align();
+
+        if size == 0 {
+            return None;
+        }
+
+        let padded = self.is_padded(layout);
+        if padded && self.is_stale(ptr as usize) {
+            return None;
+        }
+
+        #[cfg(feature = "track")]
+        slab.tracker.remove(ptr as usize);
+
+        #[cfg(feature = "trace")]
+        (self.trace)(TraceEvent::Free { ptr });
+
+        #[cfg(feature = "debug_registry")]
+        if !slab.registry.remove(ptr as usize) {
+            panic!(
+                "double free or free of an unknown pointer: {:#x}",
+                ptr as usize
+            );
+        }
+
+        if !padded {
+            self.free_locked(slab, ptr, size, alignment)
+        } else {
+            let header = read_header(ptr as usize);
+            if header.magic != ALIGNED_MAGIC {
+                panic!("corrupted header of aligned memory region");
+            }
+
+            let ptr = header.orig as *mut u8;
+            let size = size + alignment - 1 + ALIGNED_HEADER_SIZE;
+            self.free_locked(slab, ptr, size, 1)
+        }
+    }
+}
+
+#[cfg(feature = "debug")]
+impl<PAGEALLOC: MemAlloc> Drop for Allocator<PAGEALLOC> {
+    fn drop(&mut self) {
+        self.unregister_heap();
+    }
+}
+
+/// A holder or waiter counted in `Allocator::lock_users`,
+/// which is uncounted on drop even if the closure run under the lock panics.
+struct LockUser<'a>(&'a AtomicUsize);
+
+impl Drop for LockUser<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// Maximum number of slabs serving `Allocator::alloc_external` at once.
+pub const MAX_EXTERNAL_SLABS: usize = 64;
+
+/// Maximum number of allocations reported by `Allocator::live_allocations`.
+#[cfg(any(test, feature = "debug"))]
+pub const LIVE_CAPACITY: usize = 256;
+
+/// An iterator over live allocations returned by `Allocator::live_allocations`.
+#[cfg(any(test, feature = "debug"))]
+pub struct LiveIter {
+    buf: [(usize, usize); LIVE_CAPACITY],
+    len: usize,
+    pos: usize,
+}
+
+#[cfg(any(test, feature = "debug"))]
+impl Iterator for LiveIter {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.pos < self.len {
+            self.pos += 1;
+            Some(self.buf[self.pos - 1])
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl<PAGEALLOC: MemAlloc> GlobalAlloc for Allocator<PAGEALLOC> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match self.mem_alloc_align(layout) {
+            Some(ptr) => ptr,
+            None if self.retry_on_oom => self.retry_alloc(layout),
+            None => null_mut(),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.mem_free_align(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = Allocator::realloc(self, ptr, layout, new_size);
+        if new_ptr.is_null() && self.retry_on_oom {
+            return Allocator::realloc(self, ptr, layout, new_size);
+        }
+
+        new_ptr
+    }
+}
+
+#[cfg(feature = "nightly")]
+unsafe impl<PAGEALLOC: MemAlloc> core::alloc::Allocator for &Allocator<PAGEALLOC> {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        Allocator::allocate(self, layout).ok_or(core::alloc::AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        Allocator::deallocate(self, ptr, layout)
+    }
+
+    /// Grow in place like `Allocator::realloc` if the alignment is kept.
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        if new_layout.align() != old_layout.align() {
+            return self.move_to(ptr, old_layout, new_layout);
+        }
+
+        let new_ptr = Allocator::realloc(self, ptr.as_ptr(), old_layout, new_layout.size());
+        let new_ptr = NonNull::new(new_ptr).ok_or(core::alloc::AllocError)?;
+        let len = self.usable_size(new_ptr.as_ptr(), new_layout);
+        Ok(NonNull::slice_from_raw_parts(new_ptr, len))
+    }
+
+    unsafe fn grow_zeroed(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        let new = core::alloc::Allocator::grow(self, ptr, old_layout, new_layout)?;
+
+        // Only the old bytes are copied, and the rest may hold bytes of a previous object.
+        let old_size = old_layout.size();
+        core::ptr::write_bytes(
+            new.cast::<u8>().as_ptr().add(old_size),
+            0,
+            new.len() - old_size,
+        );
+        Ok(new)
+    }
+
+    /// Shrink in place like `Allocator::shrink` if the alignment is kept.
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        if new_layout.align() != old_layout.align() {
+            return self.move_to(ptr, old_layout, new_layout);
+        }
+
+        let new_ptr = Allocator::shrink(self, ptr.as_ptr(), old_layout, new_layout.size());
+        let new_ptr = NonNull::new(new_ptr).ok_or(core::alloc::AllocError)?;
+        let len = self.usable_size(new_ptr.as_ptr(), new_layout);
+        Ok(NonNull::slice_from_raw_parts(new_ptr, len))
+    }
+}
+
+#[cfg(feature = "nightly")]
+impl<PAGEALLOC: MemAlloc> Allocator<PAGEALLOC> {
+    /// Move a memory region to a new one of `new_layout`, copying as many bytes as both hold,
+    /// for a resize changing the alignment, which `realloc` and `shrink` keep.
+    unsafe fn move_to(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<[u8]>, core::alloc::AllocError> {
+        let new = Allocator::allocate(self, new_layout).ok_or(core::alloc::AllocError)?;
+        let len = old_layout.size().min(new_layout.size());
+        core::ptr::copy_nonoverlapping(ptr.as_ptr(), new.cast::<u8>().as_ptr(), len);
+        Allocator::deallocate(self, ptr, old_layout);
+        Ok(new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::alloc::GlobalAlloc;
+    use std::println;
+
+    use crate::{
+        buddy::Buddy32M, pager::PageManager, AllocKind, Allocator, InitError, MemAlloc, ALIGNMENT,
+        MASK_64K, SIZE_64K,
+    };
+
+    #[cfg(feature = "stats")]
+    use crate::AllocStats;
+
+    fn init<T: MemAlloc>() -> (Allocator<T>, *mut u8) {
+        let mut alloc = Allocator::new();
+
+        let heap_size = 32 * 1024 * 1024;
+        let layout = std::alloc::Layout::from_size_align(heap_size, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+
+        alloc.init(ptr as usize, heap_size);
+
+        (alloc, ptr)
+    }
+
+    fn free(ptr: *mut u8) {
+        let heap_size = 32 * 1024 * 1024;
+        let layout = std::alloc::Layout::from_size_align(heap_size, crate::ALIGNMENT).unwrap();
+        dealloc(ptr, layout);
+    }
+
+    /// Run `f`, which is expected to panic, on a fresh allocator,
+    /// and free the heap before the panic goes on.
+    fn init_panicking<T: MemAlloc>(f: impl FnOnce(&mut Allocator<T>)) {
+        let (mut alloc, ptr) = init::<T>();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || f(&mut alloc)));
+        free(ptr);
+        if let Err(err) = result {
+            std::panic::resume_unwind(err);
+        }
+    }
+
+    /// Free a heap whose allocator may still be alive.
+    fn dealloc(ptr: *mut u8, layout: std::alloc::Layout) {
+        // The heap may be taken by another test right after it is freed.
+        #[cfg(feature = "debug")]
+        crate::heaps::unregister_at(ptr as usize);
+
+        unsafe { std::alloc::dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn test_page_alloc() {
+        for seed in 0..64 {
+            let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(seed);
+            for _ in 0..=7 {
+                let (alloc, ptr) = init::<PageManager>();
+                let mut v = std::vec::Vec::new();
+
+                println!("seed = {seed}");
+                for i in 0..16 {
+                    for j in 0..16 {
+                        let size = rand::Rng::gen_range(&mut rng, 1..=SIZE_64K);
+                        let layout = std::alloc::Layout::from_size_align(size, 4).unwrap();
+
+                        println!("allocate: {i}, {j}, layout = {:?}", layout);
+
+                        let mem = unsafe { alloc.alloc(layout) };
+                        v.push((mem, layout));
+
+                        // must be aligned
+                        assert_eq!(mem as usize % layout.align(), 0);
+                    }
+                }
+
+                for (mem, layout) in v {
+                    println!("deallocate: layout = {:?}", layout);
+                    unsafe { alloc.dealloc(mem, layout) };
+                }
+
+                free(ptr);
+            }
+        }
+    }
+
+    #[test]
+    fn test_alloc() {
+        for seed in 0..64 {
+            let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(seed);
+            for align in 0..=7 {
+                let (alloc, ptr) = init::<Buddy32M>();
+                let mut v = std::vec::Vec::new();
+
+                println!("seed = {seed}");
+                for i in 0..16 {
+                    let size = 4 << i;
+                    for j in 0..16 {
+                        let size = size + rand::Rng::gen_range(&mut rng, 0..size);
+                        let layout = std::alloc::Layout::from_size_align(size, 1 << align).unwrap();
+
+                        println!("allocate: {i}, {j}, layout = {:?}", layout);
+
+                        let mem = unsafe { alloc.alloc(layout) };
+                        v.push((mem, layout));
+
+                        // must be aligned
+                        assert_eq!(mem as usize % (1 << align), 0);
+                    }
+                }
+
+                for (mem, layout) in v {
                     println!("deallocate: layout = {:?}", layout);
                     unsafe { alloc.dealloc(mem, layout) };
                 }
 
-                free(ptr);
+                free(ptr);
+            }
+        }
+    }
+
+    #[test]
+    fn test_peak_bytes() {
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        let mut v = std::vec::Vec::new();
+        let mut total = 0;
+
+        for i in 0..64 {
+            let size = 32 << (i % 12);
+            let layout = std::alloc::Layout::from_size_align(size, 8).unwrap();
+            let mem = unsafe { alloc.alloc(layout) };
+            assert!(!mem.is_null());
+            v.push((mem, layout));
+            total += size;
+        }
+
+        assert_eq!(alloc.live_bytes(), total);
+        assert_eq!(alloc.peak_bytes(), total);
+
+        for (mem, layout) in v {
+            unsafe { alloc.dealloc(mem, layout) };
+        }
+
+        assert_eq!(alloc.live_bytes(), 0);
+        assert_eq!(alloc.peak_bytes(), total);
+        assert!(alloc.backend_peak_bytes() >= total);
+
+        alloc.reset_peak();
+        assert_eq!(alloc.peak_bytes(), 0);
+
+        // slab pages are still held by the allocator
+        let used = alloc
+            .with_slab(|slab| 32 * 1024 * 1024 - slab.page_alloc.free_bytes())
+            .unwrap();
+        assert_eq!(alloc.backend_peak_bytes(), used);
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn test_allocator_api() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        {
+            let mut v = std::vec::Vec::new_in(&alloc);
+            for i in 0..(128 * 1024) {
+                v.push(i as u32);
+            }
+
+            for (i, n) in v.iter().enumerate() {
+                assert_eq!(i as u32, *n);
+            }
+
+            let mut v = std::vec::Vec::with_capacity_in(3, &alloc);
+            v.push(1u64);
+            assert!(v.capacity() >= 3);
+        }
+
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn test_allocator_api_resize() {
+        use core::alloc::Allocator as Api;
+        use core::ptr::NonNull;
+
+        let (alloc, ptr) = init::<Buddy32M>();
+        let alloc = &&alloc;
+        let layout = |size, align| std::alloc::Layout::from_size_align(size, align).unwrap();
+        let check = |mem: NonNull<[u8]>, len: usize| {
+            (0..len).all(|i| unsafe { *mem.cast::<u8>().as_ptr().add(i) } == i as u8)
+        };
+
+        unsafe {
+            let a = Api::allocate(alloc, layout(100, 8)).unwrap();
+            for i in 0..100 {
+                *a.cast::<u8>().as_ptr().add(i) = i as u8;
+            }
+
+            // shrinking within the class stays in place, and growing moves
+            let b = Api::shrink(alloc, a.cast(), layout(100, 8), layout(90, 8)).unwrap();
+            assert_eq!(b.cast::<u8>().as_ptr(), a.cast::<u8>().as_ptr());
+            assert!(check(b, 90));
+            let c = Api::grow(alloc, b.cast(), layout(90, 8), layout(3000, 8)).unwrap();
+            assert!(c.len() >= 3000);
+            assert!(check(c, 90));
+
+            // the new part is zeroed even if the memory held bytes
+            let d = Api::allocate(alloc, layout(10, 8)).unwrap();
+            core::ptr::write_bytes(d.cast::<u8>().as_ptr(), 0xff, d.len());
+            let e = Api::grow_zeroed(alloc, d.cast(), layout(10, 8), layout(12, 8)).unwrap();
+            assert!((10..e.len()).all(|i| *e.cast::<u8>().as_ptr().add(i) == 0));
+            let f =
+                Api::grow_zeroed(alloc, e.cast(), layout(12, 8), layout(SIZE_64K * 2, 8)).unwrap();
+            assert!((12..f.len()).all(|i| *f.cast::<u8>().as_ptr().add(i) == 0));
+
+            // a change of the alignment moves the memory region
+            let g = Api::grow(alloc, c.cast(), layout(3000, 8), layout(4000, 256)).unwrap();
+            assert_eq!(g.cast::<u8>().as_ptr() as usize % 256, 0);
+            assert!(check(g, 90));
+            let h = Api::shrink(alloc, g.cast(), layout(4000, 256), layout(50, 16)).unwrap();
+            assert_eq!(h.cast::<u8>().as_ptr() as usize % 16, 0);
+            assert!(check(h, 50));
+
+            Api::deallocate(alloc, h.cast(), layout(50, 16));
+            Api::deallocate(alloc, f.cast(), layout(SIZE_64K * 2, 8));
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    // the debug registry takes pages from the backend
+    #[cfg(not(feature = "debug_registry"))]
+    #[test]
+    fn test_metrics() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        let small = std::alloc::Layout::from_size_align(8, 8).unwrap();
+        let large = std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap();
+
+        let mem1 = unsafe { alloc.alloc(small) };
+        let mem2 = unsafe { alloc.alloc(small) };
+        let mem3 = unsafe { alloc.alloc(large) };
+
+        let mut map = std::collections::BTreeMap::new();
+        alloc.metrics(&mut |key, val| {
+            map.insert(key, val);
+        });
+
+        assert_eq!(map["live_bytes"], 16 + SIZE_64K as u64 * 2);
+        assert_eq!(map["live_count"], 3);
+        assert_eq!(map["committed_bytes"], SIZE_64K as u64 * 3);
+        assert_eq!(map["slab_class_16_pages"], 1);
+        assert_eq!(map["slab_class_32_pages"], 0);
+        assert_eq!(map["slab_class_65512_pages"], 0);
+        assert_eq!(map["buddy_largest_free"], 16 * 1024 * 1024);
+        let backend_peak = map["buddy_peak_used"];
+        assert!(backend_peak >= map["committed_bytes"]);
+
+        unsafe {
+            alloc.dealloc(mem1, small);
+            alloc.dealloc(mem2, small);
+            alloc.dealloc(mem3, large);
+        }
+
+        map.clear();
+        alloc.metrics(&mut |key, val| {
+            map.insert(key, val);
+        });
+
+        assert_eq!(map["live_count"], 0);
+        assert_eq!(map["committed_bytes"], 0);
+        assert_eq!(map["slab_class_16_pages"], 0);
+        assert_eq!(map["buddy_largest_free"], 32 * 1024 * 1024);
+        assert_eq!(map["buddy_peak_used"], backend_peak);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_shadow_addr() {
+        fn run(seed: u64) -> std::vec::Vec<usize> {
+            let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(seed);
+
+            // placing another heap changes the address of the allocator's heap
+            let (_alloc, ptr1) = init::<Buddy32M>();
+            let (mut alloc, ptr2) = init::<Buddy32M>();
+            alloc.set_shadow_base(0x1000_0000_0000);
+
+            let mut result = std::vec::Vec::new();
+            let mut v = std::vec::Vec::new();
+
+            for _ in 0..64 {
+                let size = rand::Rng::gen_range(&mut rng, 1..=SIZE_64K * 2);
+                let align = 1 << rand::Rng::gen_range(&mut rng, 0..=7);
+                let layout = std::alloc::Layout::from_size_align(size, align).unwrap();
+
+                let mem = unsafe { alloc.alloc(layout) };
+                let shadow = alloc.shadow_addr(mem).unwrap();
+                assert_eq!(alloc.real_addr(shadow), Some(mem));
+
+                result.push(shadow);
+                v.push((mem, layout));
+            }
+
+            for (mem, layout) in v {
+                unsafe { alloc.dealloc(mem, layout) };
+            }
+
+            free(ptr1);
+            free(ptr2);
+
+            result
+        }
+
+        assert_eq!(run(0), run(0));
+    }
+
+    #[cfg(feature = "track")]
+    #[test]
+    fn test_oldest_live() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        let layout1 = std::alloc::Layout::from_size_align(100, 8).unwrap();
+        let layout2 = std::alloc::Layout::from_size_align(3000, 64).unwrap();
+        let layout3 = std::alloc::Layout::from_size_align(SIZE_64K * 3, 8).unwrap();
+
+        assert_eq!(alloc.oldest_live(), None);
+
+        let mem1 = unsafe { alloc.alloc(layout1) };
+        let mem2 = unsafe { alloc.alloc(layout2) };
+        let mem3 = unsafe { alloc.alloc(layout3) };
+
+        assert_eq!(alloc.oldest_live(), Some((mem1, 2)));
+
+        unsafe {
+            alloc.dealloc(mem2, layout2);
+            alloc.dealloc(mem3, layout3);
+        }
+
+        assert_eq!(alloc.oldest_live(), Some((mem1, 2)));
+
+        unsafe { alloc.dealloc(mem1, layout1) };
+        assert_eq!(alloc.oldest_live(), None);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_isr_pool() {
+        use crate::isr::IsrPool;
+
+        const SIZE: usize = 100;
+        const N: usize = 64;
+        const NUM_THREADS: usize = 8;
+
+        let (alloc, ptr) = init::<Buddy32M>();
+        let pool = IsrPool::<SIZE, N>::new(&alloc).unwrap();
+
+        std::thread::scope(|s| {
+            for i in 0..NUM_THREADS {
+                let pool = &pool;
+                s.spawn(move || {
+                    for _ in 0..10000 {
+                        let mut v = std::vec::Vec::new();
+                        while v.len() < 4 {
+                            if let Some(mem) = pool.alloc() {
+                                // nobody else uses this block
+                                unsafe { core::ptr::write_bytes(mem, i as u8, SIZE) };
+                                v.push(mem);
+                            }
+                        }
+
+                        for mem in v {
+                            let buf = unsafe { core::slice::from_raw_parts(mem, SIZE) };
+                            assert!(buf.iter().all(|b| *b == i as u8));
+                            unsafe { pool.free(mem) };
+                        }
+                    }
+                });
+            }
+        });
+
+        // exhaust the pool
+        let mut v = std::vec::Vec::new();
+        while let Some(mem) = pool.alloc() {
+            assert_eq!(mem as usize % 8, 0);
+            v.push(mem as usize);
+        }
+
+        assert_eq!(v.len(), N);
+        v.sort();
+        v.dedup();
+        assert_eq!(v.len(), N);
+
+        for mem in v {
+            unsafe { pool.free(mem as *mut u8) };
+        }
+
+        unsafe { pool.release(&alloc) };
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    // the debug registry takes pages from the backend
+    #[cfg(not(feature = "debug_registry"))]
+    #[test]
+    fn test_live_allocations() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        assert_eq!(alloc.live_allocations().count(), 0);
+
+        let mut v = std::vec::Vec::new();
+        for i in 0..16 {
+            let layout = std::alloc::Layout::from_size_align(8 << i, 8).unwrap();
+            v.push((unsafe { alloc.alloc(layout) }, layout));
+        }
+
+        // leak one 64-byte block
+        let layout = std::alloc::Layout::from_size_align(64, 8).unwrap();
+        let leaked = unsafe { alloc.alloc(layout) };
+
+        assert_eq!(alloc.live_allocations().count(), 17);
+
+        for (mem, layout) in v {
+            unsafe { alloc.dealloc(mem, layout) };
+        }
+
+        let live: std::vec::Vec<_> = alloc.live_allocations().collect();
+        assert_eq!(live, [(leaked as usize, 120)]);
+
+        unsafe { alloc.dealloc(leaked, layout) };
+        assert_eq!(alloc.live_allocations().count(), 0);
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_live_bytes_approx() {
+        const NUM_THREADS: usize = 2;
+        const NUM_LIVE: usize = 16;
+        const MAX_SIZE: usize = 4096;
+
+        let (alloc, ptr) = init::<Buddy32M>();
+        let done = std::sync::atomic::AtomicBool::new(false);
+
+        std::thread::scope(|s| {
+            let alloc = &alloc;
+            let done = &done;
+
+            let mut workers = std::vec::Vec::new();
+            for seed in 0..NUM_THREADS as u64 {
+                workers.push(s.spawn(move || {
+                    let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(seed);
+                    for _ in 0..100 {
+                        let mut v = std::vec::Vec::new();
+                        for _ in 0..NUM_LIVE {
+                            let size = rand::Rng::gen_range(&mut rng, 1..=MAX_SIZE);
+                            let layout = std::alloc::Layout::from_size_align(size, 8).unwrap();
+                            v.push((unsafe { alloc.alloc(layout) }, layout));
+                        }
+
+                        for (mem, layout) in v {
+                            unsafe { alloc.dealloc(mem, layout) };
+                        }
+                    }
+                }));
+            }
+
+            s.spawn(move || {
+                while !done.load(std::sync::atomic::Ordering::Relaxed) {
+                    assert!(alloc.live_bytes_approx() <= NUM_THREADS * NUM_LIVE * MAX_SIZE);
+                    std::thread::yield_now();
+                }
+            });
+
+            for w in workers {
+                w.join().unwrap();
+            }
+            done.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+
+        assert_eq!(alloc.live_bytes_approx(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_same_page() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        let layout16 = std::alloc::Layout::from_size_align(8, 8).unwrap();
+        let layout32 = std::alloc::Layout::from_size_align(24, 8).unwrap();
+        let large = std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap();
+
+        let mem1 = unsafe { alloc.alloc(layout16) };
+        let mem2 = unsafe { alloc.alloc(layout16) };
+        let mem3 = unsafe { alloc.alloc(layout32) };
+        let mem4 = unsafe { alloc.alloc(large) };
+
+        // same partial slab
+        assert!(alloc.same_page(mem1, mem2));
+
+        // different slab classes never share a page
+        assert!(!alloc.same_page(mem1, mem3));
+
+        // not a slab object
+        assert!(!alloc.same_page(mem4, mem4));
+        assert!(!alloc.same_page(mem1, core::ptr::null_mut()));
+
+        // the first object of a slab without inline headers is 64KiB aligned
+        let ext: std::vec::Vec<_> = (0..16).map(|_| alloc.alloc_external(8).unwrap()).collect();
+        let first = *ext
+            .iter()
+            .find(|mem| **mem as usize & MASK_64K == 0)
+            .unwrap();
+        assert!(ext.iter().all(|mem| alloc.same_page(first, *mem)));
+        assert!(!alloc.same_page(first, mem1));
+        for mem in ext {
+            unsafe { alloc.free_external(mem) };
+        }
+
+        unsafe {
+            alloc.dealloc(mem1, layout16);
+            alloc.dealloc(mem2, layout16);
+            alloc.dealloc(mem3, layout32);
+            alloc.dealloc(mem4, large);
+        }
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_memalign() {
+        use crate::AllocError;
+
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        assert_eq!(alloc.memalign(24, 100), Err(AllocError::BadAlignment));
+        assert_eq!(alloc.memalign(4, 100), Err(AllocError::BadAlignment));
+        assert_eq!(alloc.memalign(0, 100), Err(AllocError::BadAlignment));
+
+        assert_eq!(alloc.memalign(8, 1 << 40), Err(AllocError::OutOfMemory));
+        assert_eq!(alloc.memalign(8, usize::MAX), Err(AllocError::OutOfMemory));
+
+        let mem = alloc.memalign(4096, 100).unwrap();
+        assert_eq!(mem as usize % 4096, 0);
+
+        let layout = std::alloc::Layout::from_size_align(100, 4096).unwrap();
+        unsafe { alloc.mem_free_align(mem, layout) };
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_allocate() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        // usable sizes of Slab128, Slab2040, an aligned object in Slab1024, and a buddy block
+        for (size, align, len) in [
+            (100, 8, 120),
+            (2000, 8, 2024),
+            (100, 512, 1016),
+            (SIZE_64K * 2, 8, SIZE_64K * 2),
+        ] {
+            let layout = std::alloc::Layout::from_size_align(size, align).unwrap();
+            let mem = alloc.allocate(layout).unwrap();
+            let mem_ptr = mem.as_ptr() as *mut u8;
+            assert_eq!(mem_ptr as usize % align, 0);
+            assert!(mem.len() >= size, "size = {size}");
+            if align <= 8 {
+                assert_eq!(mem.len(), len, "size = {size}");
+            } else {
+                assert!(mem.len() <= len, "size = {size}");
+            }
+
+            // the whole slice is usable
+            unsafe { core::ptr::write_bytes(mem_ptr, 0xAB, mem.len()) };
+            unsafe { alloc.deallocate(core::ptr::NonNull::new(mem_ptr).unwrap(), layout) };
+            assert_eq!(alloc.live_bytes(), 0);
+        }
+
+        let layout = std::alloc::Layout::from_size_align(0, 16).unwrap();
+        assert_eq!(alloc.allocate(layout).unwrap().len(), 0);
+
+        let layout = std::alloc::Layout::from_size_align(SIZE_64K * 1024, 8).unwrap();
+        assert!(alloc.allocate(layout).is_none());
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_large_threshold() {
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        let used = |alloc: &Allocator<Buddy32M>| {
+            let mut used = 0;
+            alloc.with_slab(|slab| slab.page_alloc.for_each_used(&mut |_, size| used += size));
+            used
+        };
+        let before = used(&alloc);
+
+        alloc.set_large_threshold(32 * 1024);
+
+        // 40KiB is allocated from the buddy allocator instead of Slab65512
+        for align in [8, 4096] {
+            let layout = std::alloc::Layout::from_size_align(40 * 1024, align).unwrap();
+            let mem = alloc.mem_alloc_align(layout).unwrap();
+            assert_eq!(mem as usize % SIZE_64K, 0);
+            assert_eq!(alloc.class_fill_ratio(65512), (0, 0));
+            assert_eq!(used(&alloc), before + SIZE_64K);
+
+            unsafe { alloc.mem_free_align(mem, layout) };
+            assert_eq!(used(&alloc), before);
+        }
+
+        // padding for alignment may exceed the threshold
+        let layout = std::alloc::Layout::from_size_align(32 * 1024, 64).unwrap();
+        let mem = alloc.mem_alloc_align(layout).unwrap();
+        assert_eq!(used(&alloc), before + SIZE_64K);
+        unsafe { alloc.mem_free_align(mem, layout) };
+
+        // 8KiB is still served by a slab
+        let layout = std::alloc::Layout::from_size_align(8 * 1024, 8).unwrap();
+        let mem = alloc.mem_alloc_align(layout).unwrap();
+        assert_eq!(alloc.class_fill_ratio(16376).0, 1);
+        unsafe { alloc.mem_free_align(mem, layout) };
+
+        assert_eq!(used(&alloc), before);
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "memory regions are allocated")]
+    fn test_large_threshold_live() {
+        init_panicking::<Buddy32M>(|alloc| {
+            let _mem = alloc.mem_alloc(100);
+            alloc.set_large_threshold(1024);
+        });
+    }
+
+    #[test]
+    fn test_large_threshold_free() {
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        let large_bytes = |alloc: &Allocator<Buddy32M>| alloc.with_slab(|s| s.large_bytes).unwrap();
+
+        // 30000 and 32000 bytes are served by the 32752-byte class,
+        // whose usable size of 32736 bytes exceeds the threshold
+        alloc.set_large_threshold(32000);
+
+        for round in 0..5 {
+            let mut v = std::vec::Vec::new();
+            for size in [30000, 32000] {
+                let layout = std::alloc::Layout::from_size_align(size, 8).unwrap();
+                let mem = alloc.mem_alloc_align(layout).unwrap();
+                assert_ne!(mem as usize & MASK_64K, 0);
+                v.push((mem, layout));
+            }
+            assert_eq!(alloc.class_fill_ratio(32752).0, 2);
+            assert_eq!(large_bytes(&alloc), 0);
+
+            unsafe {
+                match round {
+                    0 => v
+                        .iter()
+                        .for_each(|(mem, layout)| alloc.mem_free_align(*mem, *layout)),
+                    1 => v
+                        .iter()
+                        .for_each(|(mem, layout)| alloc.dealloc(*mem, *layout)),
+                    2 => alloc.free_batch(&v),
+                    3 => v.iter().for_each(|(mem, _)| alloc.free_unsized(*mem)),
+                    _ => v.iter().for_each(|(mem, layout)| {
+                        alloc.deallocate(core::ptr::NonNull::new(*mem).unwrap(), *layout)
+                    }),
+                }
+            }
+            assert_eq!(alloc.class_fill_ratio(32752).0, 0, "round = {round}");
+            assert_eq!(alloc.with_slab(|s| s.live_count), Some(0));
+        }
+
+        // blocks above a lowered threshold are large even if a slab class could serve them
+        alloc.set_large_threshold(20000);
+        let mem = alloc.mem_alloc(30000).unwrap();
+        assert_eq!(large_bytes(&alloc), 30000);
+        unsafe { alloc.mem_free(mem, 30000) };
+        assert_eq!(large_bytes(&alloc), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_min_class() {
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        alloc.set_min_class(256);
+        assert_eq!(alloc.allocation_kind(16), AllocKind::Slab(256));
+        assert_eq!(alloc.allocation_kind(1000), AllocKind::Slab(1024));
+
+        let mut v = std::vec::Vec::new();
+        for size in [1, 16, 100, 248] {
+            let layout = std::alloc::Layout::from_size_align(size, 8).unwrap();
+            let mem = alloc.mem_alloc_align(layout).unwrap();
+            unsafe { core::ptr::write_bytes(mem, 0xaa, 248) };
+            v.push((mem, layout));
+        }
+
+        // all of them share a slab of the minimum class
+        assert_eq!(alloc.class_fill_ratio(256).0, 4);
+        assert_eq!(alloc.class_fill_ratio(16), (0, 0));
+        assert_eq!(alloc.class_fill_ratio(128), (0, 0));
+        assert!(v.iter().all(|(mem, _)| alloc.same_page(*mem, v[0].0)));
+        let slice = alloc.allocate(v[0].1).unwrap();
+        assert_eq!(slice.len(), 248);
+
+        unsafe { alloc.deallocate(slice.cast(), v[0].1) };
+        for (mem, layout) in v {
+            unsafe { alloc.mem_free_align(mem, layout) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+        assert_eq!(alloc.class_fill_ratio(256), (0, 0));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_retry_on_oom() {
+        use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static PAGE: AtomicUsize = AtomicUsize::new(0);
+        static RETRIES: AtomicUsize = AtomicUsize::new(0);
+
+        // free a page on another thread between the attempts
+        fn free_page(alloc: &Allocator<Buddy32M>) {
+            RETRIES.fetch_add(1, Ordering::Relaxed);
+            let page = PAGE.swap(0, Ordering::Relaxed);
+            if page != 0 {
+                let layout = std::alloc::Layout::from_size_align(SIZE_64K, 8).unwrap();
+                std::thread::scope(|s| {
+                    s.spawn(|| unsafe { alloc.dealloc(page as *mut u8, layout) });
+                });
+            }
+        }
+
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        alloc.before_retry = Some(free_page);
+
+        // exhaust the heap
+        let layout = std::alloc::Layout::from_size_align(SIZE_64K, 8).unwrap();
+        let mut v = std::vec::Vec::new();
+        loop {
+            let mem = unsafe { alloc.alloc(layout) };
+            if mem.is_null() {
+                break;
+            }
+            v.push(mem);
+        }
+        assert_eq!(RETRIES.load(Ordering::Relaxed), 0);
+
+        alloc.set_retry_on_oom(true);
+        PAGE.store(v.pop().unwrap() as usize, Ordering::Relaxed);
+        let mem = unsafe { alloc.alloc(layout) };
+        assert!(!mem.is_null());
+        assert_eq!(RETRIES.load(Ordering::Relaxed), 1);
+
+        // the retry is done only once
+        assert!(unsafe { alloc.alloc(layout) }.is_null());
+        assert_eq!(RETRIES.load(Ordering::Relaxed), 2);
+
+        v.push(mem);
+        for mem in v {
+            unsafe { alloc.dealloc(mem, layout) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid class size")]
+    fn test_min_class_invalid() {
+        let mut alloc = Allocator::<Buddy32M>::new();
+        alloc.set_min_class(200);
+    }
+
+    #[test]
+    fn test_page_manager_large() {
+        let (alloc, ptr) = init::<PageManager>();
+
+        let layout = std::alloc::Layout::from_size_align(200 * 1024, 8).unwrap();
+        let mem = alloc.mem_alloc_align(layout).unwrap();
+        assert_eq!(mem as usize % SIZE_64K, 0);
+        unsafe { core::ptr::write_bytes(mem, 0xFF, layout.size()) };
+        assert_eq!(alloc.live_bytes(), 200 * 1024);
+
+        // 4 pages are taken
+        let free_pages = alloc
+            .with_slab(|slab| slab.page_alloc.free_page_count())
+            .unwrap();
+        unsafe { alloc.mem_free_align(mem, layout) };
+        assert_eq!(
+            alloc
+                .with_slab(|slab| slab.page_alloc.free_page_count())
+                .unwrap(),
+            free_pages + 4
+        );
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_recommended_backend() {
+        use crate::{recommended_backend, Backend};
+
+        const MIB: usize = 1024 * 1024;
+
+        assert_eq!(recommended_backend(100, 32 * MIB), Backend::PageManager);
+        assert_eq!(
+            recommended_backend(SIZE_64K, 32 * MIB),
+            Backend::PageManager
+        );
+        assert_eq!(
+            recommended_backend(SIZE_64K + 1, 32 * MIB),
+            Backend::Buddy { depth: 9 }
+        );
+
+        // the heap is rounded up to a power of two
+        assert_eq!(
+            recommended_backend(SIZE_64K + 1, 20 * MIB),
+            Backend::Buddy { depth: 9 }
+        );
+
+        // the heap must hold the largest allocation
+        assert_eq!(
+            recommended_backend(64 * MIB, 32 * MIB),
+            Backend::Buddy { depth: 10 }
+        );
+
+        // PageManager manages up to 16GiB
+        assert_eq!(
+            recommended_backend(SIZE_64K, 16 * 1024 * MIB),
+            Backend::PageManager
+        );
+        assert_eq!(
+            recommended_backend(SIZE_64K, 16 * 1024 * MIB + SIZE_64K),
+            Backend::FreeListPager
+        );
+    }
+
+    #[test]
+    fn test_shrink() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        for align in [8, 64] {
+            // 800 and 700 bytes are in the same class
+            let layout = std::alloc::Layout::from_size_align(800, align).unwrap();
+            let mem = alloc.mem_alloc_align(layout).unwrap();
+            unsafe { core::ptr::write_bytes(mem, 0xAB, 800) };
+
+            let shrunk = unsafe { alloc.shrink(mem, layout, 700) };
+            assert_eq!(shrunk, mem);
+            let layout = std::alloc::Layout::from_size_align(700, align).unwrap();
+            assert_eq!(alloc.live_bytes(), alloc.backing_size(layout));
+
+            // 100 bytes are in a smaller class
+            let shrunk = unsafe { alloc.shrink(mem, layout, 100) };
+            assert_ne!(shrunk, mem);
+            assert_eq!(shrunk as usize % align, 0);
+            let data = unsafe { core::slice::from_raw_parts(shrunk, 100) };
+            assert!(data.iter().all(|b| *b == 0xAB));
+
+            let layout = std::alloc::Layout::from_size_align(100, align).unwrap();
+            assert_eq!(alloc.live_bytes(), alloc.backing_size(layout));
+            unsafe { alloc.mem_free_align(shrunk, layout) };
+            assert_eq!(alloc.live_bytes(), 0);
+        }
+
+        // blocks of the page allocator stay in place
+        let layout = std::alloc::Layout::from_size_align(SIZE_64K * 4, 8).unwrap();
+        let mem = alloc.mem_alloc_align(layout).unwrap();
+        let shrunk = unsafe { alloc.shrink(mem, layout, SIZE_64K * 2) };
+        assert_eq!(shrunk, mem);
+        assert_eq!(alloc.live_bytes(), SIZE_64K * 2);
+
+        // a block of the page allocator to a slab object
+        let layout = std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap();
+        let shrunk = unsafe { alloc.shrink(mem, layout, 0) };
+        assert_eq!(shrunk as usize, 8);
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    // also run under `cargo miri test` to check that headers are read without misaligned accesses
+    #[test]
+    fn test_realloc() {
+        let check = |mem: *mut u8, len: usize| (0..len).all(|i| unsafe { *mem.add(i) } == i as u8);
+        let fill = |mem: *mut u8, len: usize| {
+            for i in 0..len {
+                unsafe { *mem.add(i) = i as u8 };
+            }
+        };
+
+        let (alloc, ptr) = init::<PageManager>();
+        let layout = std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap();
+        let a = alloc.mem_alloc_align(layout).unwrap();
+        let b = alloc.mem_alloc_align(layout).unwrap();
+        assert_eq!(b as usize, a as usize + SIZE_64K * 2);
+        fill(a, layout.size());
+
+        // `b` follows `a`, so `a` is copied
+        let c = unsafe { alloc.realloc(a, layout, SIZE_64K * 3) };
+        assert_ne!(c, a);
+        assert!(check(c, layout.size()));
+
+        // pages following `c` are free
+        let layout = std::alloc::Layout::from_size_align(SIZE_64K * 3, 8).unwrap();
+        let d = unsafe { alloc.realloc(c, layout, SIZE_64K * 5) };
+        assert_eq!(d, c);
+        assert!(check(d, SIZE_64K * 2));
+        assert_eq!(alloc.live_bytes(), SIZE_64K * 7);
+
+        // an object of Slab65512 is copied
+        let small = std::alloc::Layout::from_size_align(65504, 8).unwrap();
+        let e = alloc.mem_alloc_align(small).unwrap();
+        fill(e, small.size());
+        let f = unsafe { alloc.realloc(e, small, SIZE_64K * 2) };
+        assert_ne!(f, e);
+        assert!(check(f, small.size()));
+
+        unsafe {
+            alloc.mem_free_align(
+                b,
+                std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap(),
+            );
+            alloc.mem_free_align(
+                d,
+                std::alloc::Layout::from_size_align(SIZE_64K * 5, 8).unwrap(),
+            );
+            alloc.mem_free_align(
+                f,
+                std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap(),
+            );
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+        free(ptr);
+
+        // the buddy of a block of the buddy allocator is free
+        let (alloc, ptr) = init::<Buddy32M>();
+        let layout = std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap();
+        let a = unsafe { alloc.alloc(layout) };
+        fill(a, layout.size());
+        let b = unsafe { GlobalAlloc::realloc(&alloc, a, layout, SIZE_64K * 4) };
+        // the debug registry takes pages from the backend, which may take the buddy
+        #[cfg(not(feature = "debug_registry"))]
+        assert_eq!(b, a);
+        assert!(check(b, layout.size()));
+
+        unsafe {
+            alloc.dealloc(
+                b,
+                std::alloc::Layout::from_size_align(SIZE_64K * 4, 8).unwrap(),
+            )
+        };
+        assert_eq!(alloc.live_bytes(), 0);
+        #[cfg(not(feature = "debug_registry"))]
+        assert_eq!(alloc.with_slab(|slab| slab.page_alloc.usage().0), Some(0));
+        free(ptr);
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_heap_overlap() {
+        let (alloc1, ptr) = init::<Buddy32M>();
+        let start = ptr as usize;
+
+        // the latter half of the heap of `alloc1`
+        let mut alloc2 = Allocator::<crate::buddy::Buddy16M>::new();
+        let half = start + 16 * 1024 * 1024;
+        assert_eq!(
+            alloc2.try_init(half, 16 * 1024 * 1024),
+            Err(InitError::Overlapped { start: half })
+        );
+
+        // an allocator can be initialized again over its own heap
+        let mut alloc1 = alloc1;
+        alloc1.init(start, 32 * 1024 * 1024);
+
+        // the heap is available after `reset`
+        alloc1.reset();
+        assert_eq!(alloc2.try_init(half, 16 * 1024 * 1024), Ok(()));
+        assert_eq!(
+            alloc1.try_init(start, 32 * 1024 * 1024),
+            Err(InitError::Overlapped { start })
+        );
+
+        // and after drop
+        drop(alloc2);
+        alloc1.init(start, 32 * 1024 * 1024);
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_size_histogram() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        let run = |n: usize| {
+            // 16, 32 and 128-byte classes in the ratio of 4:2:1
+            let mut v = std::vec::Vec::new();
+            for i in 0..n * 7 {
+                let size = match i % 7 {
+                    0..=3 => 8,
+                    4..=5 => 24,
+                    _ => 100,
+                };
+                v.push((alloc.mem_alloc(size).unwrap(), size));
+            }
+            for (mem, size) in v {
+                unsafe { alloc.mem_free(mem, size) };
+            }
+        };
+        let count = |histogram: &[(usize, u64)], class_size| {
+            histogram.iter().find(|(c, _)| *c == class_size).unwrap().1
+        };
+
+        run(10);
+        let histogram = alloc.size_histogram();
+        assert_eq!(count(&histogram, 16), 40);
+        assert_eq!(count(&histogram, 32), 20);
+        assert_eq!(count(&histogram, 128), 10);
+        assert_eq!(histogram.iter().map(|(_, n)| n).sum::<u64>(), 70);
+
+        alloc.reset_histogram();
+        assert!(alloc.size_histogram().iter().all(|(_, n)| *n == 0));
+
+        run(3);
+        let histogram = alloc.size_histogram();
+        assert_eq!(count(&histogram, 16), 12);
+        assert_eq!(count(&histogram, 32), 6);
+        assert_eq!(count(&histogram, 128), 3);
+
+        // the counters since `init` are kept
+        assert_eq!(alloc.class_counters()[0], (16, 52, 52));
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_internal_fragmentation() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        assert_eq!(alloc.internal_fragmentation(), (0, 0));
+
+        // 100 bytes are rounded up to the 128-byte class holding 120 bytes
+        let mem1 = alloc.mem_alloc(100).unwrap();
+        assert_eq!(alloc.internal_fragmentation(), (20, 120));
+
+        // exact fits waste nothing
+        let mem2 = alloc.mem_alloc(120).unwrap();
+        assert_eq!(alloc.internal_fragmentation(), (20, 240));
+
+        // 1025 bytes are rounded up to the 2040-byte class holding 2024 bytes
+        let mem3 = alloc.mem_alloc(1025).unwrap();
+        assert_eq!(alloc.internal_fragmentation(), (20 + 999, 240 + 2024));
+
+        // blocks of the page allocator are not counted
+        let mem4 = alloc.mem_alloc(SIZE_64K * 2).unwrap();
+        assert_eq!(alloc.internal_fragmentation(), (20 + 999, 240 + 2024));
+
+        unsafe {
+            alloc.mem_free(mem3, 1025);
+            assert_eq!(alloc.internal_fragmentation(), (20, 240));
+            alloc.mem_free(mem1, 100);
+            assert_eq!(alloc.internal_fragmentation(), (0, 120));
+            alloc.mem_free(mem2, 120);
+            alloc.mem_free(mem4, SIZE_64K * 2);
+        }
+        assert_eq!(alloc.internal_fragmentation(), (0, 0));
+
+        // shrinking in place keeps the class, so the waste grows
+        let layout = std::alloc::Layout::from_size_align(800, 8).unwrap();
+        let mem = alloc.mem_alloc_align(layout).unwrap();
+        assert_eq!(alloc.internal_fragmentation(), (1016 - 800, 1016));
+        let shrunk = unsafe { alloc.shrink(mem, layout, 700) };
+        assert_eq!(shrunk, mem);
+        assert_eq!(alloc.internal_fragmentation(), (1016 - 700, 1016));
+        unsafe {
+            alloc.mem_free_align(shrunk, std::alloc::Layout::from_size_align(700, 8).unwrap())
+        };
+        assert_eq!(alloc.internal_fragmentation(), (0, 0));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_is_empty() {
+        assert!(Allocator::<Buddy32M>::new().is_empty());
+
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        assert!(alloc.is_empty());
+
+        for size in [8, 4000, SIZE_64K * 2] {
+            let mem = alloc.mem_alloc(size).unwrap();
+            assert!(!alloc.is_empty());
+            unsafe { alloc.mem_free(mem, size) };
+            assert!(alloc.is_empty());
+        }
+
+        // empty slabs kept for reuse are not live
+        alloc.set_empty_slab_cache(16, 1);
+        let mem = alloc.mem_alloc(8).unwrap();
+        unsafe { alloc.mem_free(mem, 8) };
+        assert!(alloc.is_empty());
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_map_callback() {
+        static MAPPED: std::sync::Mutex<std::vec::Vec<(usize, usize)>> =
+            std::sync::Mutex::new(std::vec::Vec::new());
+
+        fn mapf(start: usize, len: usize) {
+            MAPPED.lock().unwrap().push((start, len));
+        }
+
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        alloc.set_map_callback(mapf);
+
+        // 10 slabs of the 4088-byte class
+        let per_slab = crate::slab::object_count(4088);
+        let mut v = std::vec::Vec::new();
+        for _ in 0..per_slab * 10 {
+            v.push(alloc.mem_alloc(4000).unwrap());
+        }
+
+        let mut pages: std::vec::Vec<_> = v.iter().map(|mem| *mem as usize & !MASK_64K).collect();
+        pages.dedup();
+        assert_eq!(pages.len(), 10);
+
+        let mapped = core::mem::take(&mut *MAPPED.lock().unwrap());
+        assert_eq!(mapped.len(), 10);
+        for (page, (start, len)) in pages.iter().zip(mapped.iter()) {
+            assert_eq!((*page, *len), (*start, SIZE_64K));
+        }
+
+        // blocks of the page allocator are not slabs
+        let mem = alloc.mem_alloc(SIZE_64K * 2).unwrap();
+        assert!(MAPPED.lock().unwrap().is_empty());
+
+        // prewarmed slabs are new slabs as well
+        assert_ne!(alloc.prewarm(16, 2), 0);
+        assert_eq!(MAPPED.lock().unwrap().len(), 2);
+
+        unsafe {
+            alloc.mem_free(mem, SIZE_64K * 2);
+            for mem in v {
+                alloc.mem_free(mem, 4000);
+            }
+        }
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn test_trace_hook() {
+        use crate::TraceEvent;
+
+        struct Events(std::vec::Vec<TraceEvent>);
+
+        // Events hold raw pointers, which are only compared.
+        unsafe impl Send for Events {}
+
+        static EVENTS: std::sync::Mutex<Events> =
+            std::sync::Mutex::new(Events(std::vec::Vec::new()));
+
+        fn hook(event: TraceEvent) {
+            EVENTS.lock().unwrap().0.push(event);
+        }
+
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        alloc.set_trace_hook(hook);
+
+        let layout1 = std::alloc::Layout::from_size_align(100, 8).unwrap();
+        let layout2 = std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap();
+        let mem1 = alloc.mem_alloc_align(layout1).unwrap();
+        let mem2 = alloc.mem_alloc_align(layout1).unwrap();
+        let mem3 = alloc.mem_alloc_align(layout2).unwrap();
+        unsafe {
+            alloc.mem_free_align(mem1, layout1);
+            alloc.mem_free_align(mem2, layout1);
+            alloc.mem_free_align(mem3, layout2);
+        }
+
+        let page = mem1 as usize & !MASK_64K;
+        let events = core::mem::take(&mut EVENTS.lock().unwrap().0);
+        assert_eq!(
+            events,
+            [
+                TraceEvent::MapPage { addr: page },
+                TraceEvent::Alloc {
+                    size: 100,
+                    ptr: mem1
+                },
+                TraceEvent::Alloc {
+                    size: 100,
+                    ptr: mem2
+                },
+                // blocks of the page allocator are not slab pages
+                TraceEvent::Alloc {
+                    size: SIZE_64K * 2,
+                    ptr: mem3
+                },
+                TraceEvent::Free { ptr: mem1 },
+                // the emptied slab is returned
+                TraceEvent::Free { ptr: mem2 },
+                TraceEvent::UnmapPage { addr: page },
+                TraceEvent::Free { ptr: mem3 },
+                TraceEvent::UnmapPage {
+                    addr: mem3 as usize
+                },
+            ]
+        );
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_page_alignment() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let used = || alloc.with_slab(|slab| slab.page_alloc.usage().0).unwrap();
+        let before = used();
+
+        // a small object aligned to a page costs a page of the backend instead of a padded object
+        let layout = std::alloc::Layout::from_size_align(100, SIZE_64K).unwrap();
+        let a = unsafe { alloc.alloc(layout) };
+        assert_eq!(a as usize % SIZE_64K, 0);
+        assert_eq!(used() - before, SIZE_64K);
+        assert_eq!(alloc.live_bytes(), 100);
+        assert_eq!(unsafe { alloc.usable_size(a, layout) }, 100);
+
+        // shrinking stays in place, and growing moves to another page block
+        let c = unsafe { alloc.realloc(a, layout, 50) };
+        assert_eq!(c, a);
+        let layout = std::alloc::Layout::from_size_align(50, SIZE_64K).unwrap();
+        let d = unsafe { GlobalAlloc::realloc(&alloc, c, layout, 200) };
+        assert_eq!(d as usize % SIZE_64K, 0);
+
+        unsafe {
+            alloc.dealloc(
+                d,
+                std::alloc::Layout::from_size_align(200, SIZE_64K).unwrap(),
+            );
+        }
+        assert_eq!(used(), before);
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_large_alignment() {
+        fn check<T: MemAlloc>(alloc: &Allocator<T>) {
+            let free_bytes = || {
+                alloc
+                    .with_slab(|slab| slab.page_alloc.free_bytes())
+                    .unwrap()
+            };
+            let before = free_bytes();
+
+            for align in [SIZE_64K * 2, SIZE_64K * 16] {
+                for size in [100, SIZE_64K * 3] {
+                    let layout = std::alloc::Layout::from_size_align(size, align).unwrap();
+                    let a = unsafe { alloc.alloc(layout) };
+                    assert!(!a.is_null());
+                    assert_eq!(a as usize % align, 0);
+                    assert!(unsafe { alloc.usable_size(a, layout) } >= size);
+                    unsafe { core::ptr::write_bytes(a, 0xAA, size) };
+
+                    let b = unsafe { GlobalAlloc::realloc(alloc, a, layout, size * 2) };
+                    assert_eq!(b as usize % align, 0);
+                    assert_eq!(unsafe { *b.add(size - 1) }, 0xAA);
+
+                    let layout = std::alloc::Layout::from_size_align(size * 2, align).unwrap();
+                    unsafe { alloc.dealloc(b, layout) };
+                    assert_eq!(free_bytes(), before);
+                    assert_eq!(alloc.live_bytes(), 0);
+                }
+            }
+        }
+
+        let (alloc, ptr) = init::<PageManager>();
+        check(&alloc);
+        free(ptr);
+
+        // Buddy32M aligns blocks only as much as the heap start is aligned
+        let heap_size = 32 * 1024 * 1024;
+        let layout = std::alloc::Layout::from_size_align(heap_size * 2, heap_size).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        for start in [ptr as usize, ptr as usize + SIZE_64K] {
+            let mut alloc = Allocator::<Buddy32M>::new();
+            alloc.init(start, heap_size);
+            check(&alloc);
+            alloc.reset();
+        }
+        dealloc(ptr, layout);
+    }
+
+    #[test]
+    fn test_realloc_copy_usable() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let fill = |mem: *mut u8, len: usize| {
+            for i in 0..len {
+                unsafe { *mem.add(i) = i as u8 };
+            }
+        };
+        let check = |mem: *mut u8, len: usize| (0..len).all(|i| unsafe { *mem.add(i) } == i as u8);
+
+        for align in [8, 64] {
+            // 100 bytes are served by the 128-byte class
+            let layout = std::alloc::Layout::from_size_align(100, align).unwrap();
+            let a = alloc.allocate(layout).unwrap();
+            let usable = a.len();
+            assert!(usable > 100);
+            let a = a.as_ptr() as *mut u8;
+            fill(a, usable);
+
+            // the slack is copied to a larger class
+            let b = unsafe { alloc.realloc_copy_usable(a, layout, 200) };
+            assert_ne!(b, a);
+            assert!(check(b, usable));
+
+            // and to a smaller class as far as it fits
+            let layout = std::alloc::Layout::from_size_align(200, align).unwrap();
+            fill(b, unsafe { alloc.usable_size(b, layout) });
+            let c = unsafe { alloc.realloc_copy_usable(b, layout, 20) };
+            let layout = std::alloc::Layout::from_size_align(20, align).unwrap();
+            let usable = unsafe { alloc.usable_size(c, layout) };
+            assert!(usable > 20);
+            assert!(check(c, usable));
+
+            unsafe { alloc.mem_free_align(c, layout) };
+        }
+
+        assert_eq!(alloc.live_bytes(), 0);
+        free(ptr);
+    }
+
+    #[test]
+    fn test_alloc_or_capacity() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let layout = |size| std::alloc::Layout::from_size_align(size, 8).unwrap();
+
+        // a slab of the 128-byte class with free objects
+        let obj = alloc.alloc_or_capacity(layout(100)).unwrap();
+        let mut v = std::vec::Vec::new();
+
+        // The reported capacity can be allocated, but a byte more cannot.
+        let mut size = 1024 * 1024;
+        loop {
+            match alloc.alloc_or_capacity(layout(size)) {
+                Ok(mem) => v.push((mem, size)),
+                Err(capacity) => {
+                    assert!(capacity < size);
+                    if capacity == 0 {
+                        break;
+                    }
+                    assert!(alloc.alloc_or_capacity(layout(capacity + 1)).is_err());
+                    size = capacity;
+                }
+            }
+        }
+
+        // pages are exhausted, and then objects of the slab are given
+        assert!(v.iter().any(|(_, size)| *size == 120));
+        #[cfg(not(feature = "debug_registry"))]
+        assert!(v.iter().any(|(_, size)| *size == SIZE_64K));
+
+        unsafe { alloc.mem_free_align(obj, layout(100)) };
+        assert_eq!(alloc.alloc_or_capacity(layout(121)), Err(120));
+
+        for (mem, size) in v {
+            unsafe { alloc.mem_free_align(mem, layout(size)) };
+        }
+        #[cfg(not(feature = "debug_registry"))]
+        assert_eq!(
+            alloc.alloc_or_capacity(layout(64 * 1024 * 1024)),
+            Err(32 * 1024 * 1024)
+        );
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_alloc_range() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        let mut v = std::vec::Vec::new();
+        for (min, max, align, expected) in [
+            (100, 1000, 8, 120),
+            (100, 110, 8, 110),
+            (1000, 5000, 8, 1016),
+            (1017, 5000, 8, 2024),
+            // the class of 256 bytes holds 248 bytes, including padding and the header
+            (100, 1000, 64, 248 - 63 - 24),
+            (65504, 65504, 8, 65504),
+        ] {
+            let (mem, size) = alloc.alloc_range(min, max, align).unwrap();
+            assert_eq!(size, expected);
+            assert!(min <= size && size <= max);
+            assert_eq!(mem as usize % align, 0);
+            unsafe { core::ptr::write_bytes(mem, 0xaa, size) };
+            v.push((
+                mem,
+                std::alloc::Layout::from_size_align(size, align).unwrap(),
+            ));
+        }
+
+        // the heap has slabs, so 32MiB is not available
+        let (mem, size) = alloc.alloc_range(100 * 1024, 64 * 1024 * 1024, 8).unwrap();
+        assert_eq!(size, 16 * 1024 * 1024);
+        v.push((mem, std::alloc::Layout::from_size_align(size, 8).unwrap()));
+
+        // the largest free block is 8MiB, so 14MiB is halved to 7MiB, and then raised to `min`
+        let (min, max) = (7 * 1024 * 1024 + 1, 14 * 1024 * 1024);
+        let (mem, size) = alloc.alloc_range(min, max, 8).unwrap();
+        assert_eq!(size, min);
+        v.push((mem, std::alloc::Layout::from_size_align(size, 8).unwrap()));
+        assert_eq!(alloc.alloc_range(min, max, 8), None);
+
+        assert_eq!(alloc.alloc_range(200, 100, 8), None);
+        assert_eq!(alloc.alloc_range(100, 200, 3), None);
+
+        for (mem, layout) in v {
+            unsafe { alloc.mem_free_align(mem, layout) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_free_large_alignment() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        let mut v = std::vec::Vec::new();
+        for align in [16, 32, 64, 256, 4096, 32768] {
+            for size in [1, 13, 100, 1000] {
+                let layout = std::alloc::Layout::from_size_align(size, align).unwrap();
+                let mem = alloc.mem_alloc_align(layout).unwrap();
+                assert_eq!(mem as usize % align, 0);
+                v.push((mem, layout));
+            }
+        }
+
+        for (i, (mem, layout)) in v.into_iter().enumerate() {
+            if i % 2 == 0 {
+                unsafe { alloc.mem_free_align(mem, layout) };
+            } else {
+                unsafe { alloc.free_unsized(mem) };
+            }
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid slab pointer")]
+    fn test_free_misaligned() {
+        init_panicking::<Buddy32M>(|alloc| {
+            let mem = alloc.mem_alloc(100).unwrap();
+            unsafe { alloc.mem_free(mem.add(1), 100) };
+        });
+    }
+
+    #[test]
+    fn test_debug() {
+        let alloc = Allocator::<PageManager>::new();
+        assert_eq!(std::format!("{alloc:?}"), "Allocator { .. }");
+
+        let (alloc, ptr) = init::<PageManager>();
+        let before = std::format!("{alloc:?}");
+        assert!(before.starts_with("Allocator { live_bytes: 0, "));
+        assert!(before.contains("128: (0, 0)"));
+
+        let mem = alloc.mem_alloc(100).unwrap();
+        let after = std::format!("{alloc:?}");
+        assert_ne!(before, after);
+        assert!(after.starts_with("Allocator { live_bytes: 100, "));
+        assert!(after.contains("128: (1, 0)"));
+        assert!(!after.contains(&std::format!("{:x}", mem as usize)));
+
+        unsafe { alloc.mem_free(mem, 100) };
+        free(ptr);
+    }
+
+    #[test]
+    fn test_alloc_external() {
+        let (alloc, ptr) = init::<PageManager>();
+        let free_pages = |alloc: &Allocator<PageManager>| {
+            alloc
+                .with_slab(|slab| slab.page_alloc.free_page_count())
+                .unwrap()
+        };
+        let pages = free_pages(&alloc);
+
+        for class_size in crate::slab::CLASS_SIZES {
+            // adjacent objects have no header between them
+            let a = alloc.alloc_external(class_size).unwrap();
+            let b = alloc.alloc_external(class_size - 1).unwrap();
+            assert_eq!(a as usize % 8, 0);
+            if class_size != 65512 {
+                assert_eq!(b as usize, a as usize + class_size);
+            }
+
+            // the whole objects are usable
+            unsafe {
+                core::ptr::write_bytes(a, 0xAA, class_size);
+                core::ptr::write_bytes(b, 0xBB, class_size);
+            }
+            let data = unsafe { core::slice::from_raw_parts(a, class_size) };
+            assert!(data.iter().all(|v| *v == 0xAA));
+            assert_eq!(alloc.live_bytes(), class_size * 2);
+
+            unsafe {
+                alloc.free_external(b);
+                alloc.free_external(a);
+            }
+            assert_eq!(alloc.live_bytes(), 0);
+            assert_eq!(free_pages(&alloc), pages);
+        }
+
+        assert!(alloc.alloc_external(65513).is_none());
+
+        // objects with inline headers are served by other slabs
+        let a = alloc.mem_alloc(100).unwrap();
+        let b = alloc.alloc_external(100).unwrap();
+        assert_ne!(a as usize & crate::MASK, b as usize & crate::MASK);
+        unsafe {
+            alloc.mem_free(a, 100);
+            alloc.free_external(b);
+        }
+        assert_eq!(free_pages(&alloc), pages);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_alloc_external_max_slabs() {
+        let (alloc, ptr) = init::<PageManager>();
+
+        let mut v = std::vec::Vec::new();
+        for _ in 0..crate::MAX_EXTERNAL_SLABS {
+            v.push(alloc.alloc_external(60000).unwrap());
+        }
+
+        // the side table is full
+        assert!(alloc.alloc_external(60000).is_none());
+        assert!(alloc.alloc_external(16).is_none());
+
+        unsafe { alloc.free_external(v.pop().unwrap()) };
+        v.push(alloc.alloc_external(16).unwrap());
+
+        for mem in v {
+            unsafe { alloc.free_external(mem) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "not an external slab object")]
+    fn test_free_external_inline() {
+        init_panicking::<PageManager>(|alloc| {
+            let mem = alloc.mem_alloc(100).unwrap();
+            unsafe { alloc.free_external(mem) };
+        });
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_stats_relaxed() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        assert_eq!(alloc.stats_relaxed(), AllocStats::default());
+
+        let mut v = std::vec::Vec::new();
+        for (i, size) in [8, 100, 3000, 60000, 200000].into_iter().enumerate() {
+            let layout = std::alloc::Layout::from_size_align(size, 8 << (i % 3)).unwrap();
+            v.push((alloc.mem_alloc_align(layout).unwrap(), layout));
+        }
+        let ext = alloc.alloc_external(500).unwrap();
+
+        let (mem, layout) = v.pop().unwrap();
+        unsafe { alloc.mem_free_align(mem, layout) };
+        let (mem, _) = v.pop().unwrap();
+        unsafe { alloc.free_unsized(mem) };
+
+        // quiescent
+        let stats = alloc.stats();
+        assert_eq!(stats.live_count, 4);
+        assert_eq!(alloc.stats_relaxed(), stats);
+
+        for (mem, layout) in v {
+            unsafe { alloc.mem_free_align(mem, layout) };
+        }
+        unsafe { alloc.free_external(ext) };
+
+        let stats = alloc.stats();
+        assert_eq!(stats.live_bytes, 0);
+        assert_eq!(stats.live_count, 0);
+        assert_eq!(alloc.stats_relaxed(), stats);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_free_batch() {
+        let (alloc1, ptr1) = init::<Buddy32M>();
+        let (alloc2, ptr2) = init::<Buddy32M>();
+
+        let layouts: std::vec::Vec<_> = (0..10000)
+            .map(|i| {
+                let size = if i % 1000 == 0 { 100000 } else { 1 + i % 3000 };
+                std::alloc::Layout::from_size_align(size, 8 << (i % 4)).unwrap()
+            })
+            .collect();
+
+        let mut items1 = std::vec::Vec::new();
+        let mut items2 = std::vec::Vec::new();
+        for layout in layouts.iter() {
+            items1.push((alloc1.mem_alloc_align(*layout).unwrap(), *layout));
+            items2.push((alloc2.mem_alloc_align(*layout).unwrap(), *layout));
+        }
+
+        // a lock per deallocation, and a single lock for the whole batch
+        let lock_count = |alloc: &Allocator<Buddy32M>| {
+            alloc.lock_count.load(core::sync::atomic::Ordering::Relaxed)
+        };
+
+        let locks = lock_count(&alloc1);
+        for (mem, layout) in items1.iter() {
+            unsafe { alloc1.mem_free_align(*mem, *layout) };
+        }
+        assert_eq!(lock_count(&alloc1), locks + items1.len());
+
+        let locks = lock_count(&alloc2);
+        unsafe { alloc2.free_batch(&items2) };
+        assert_eq!(lock_count(&alloc2), locks + 1);
+
+        // the same final state
+        #[cfg(feature = "stats")]
+        assert_eq!(alloc1.stats(), alloc2.stats());
+        assert_eq!(alloc2.live_bytes(), 0);
+        assert_eq!(std::format!("{alloc1:?}"), std::format!("{alloc2:?}"));
+        assert_eq!(
+            alloc1.with_slab(|slab| slab.page_alloc.free_bytes()),
+            alloc2.with_slab(|slab| slab.page_alloc.free_bytes())
+        );
+
+        free(ptr1);
+        free(ptr2);
+    }
+
+    #[test]
+    fn test_allocation_kind() {
+        let (mut alloc, ptr) = init::<Buddy32M>();
+
+        for (i, class_size) in crate::slab::CLASS_SIZES.into_iter().enumerate() {
+            let usable = crate::slab::USABLE_SIZES[i];
+            assert_eq!(alloc.allocation_kind(usable), AllocKind::Slab(class_size));
+
+            let next = match crate::slab::CLASS_SIZES.get(i + 1) {
+                Some(next) => AllocKind::Slab(*next),
+                None => AllocKind::Large,
+            };
+            assert_eq!(alloc.allocation_kind(usable + 1), next);
+
+            // the same class is used
+            let mem = alloc.mem_alloc(usable).unwrap();
+            let (addr_slab, _) = unsafe { crate::slab::read_object_header(mem as usize) };
+            assert_eq!(
+                unsafe { crate::slab::class_size_of_slab(addr_slab) },
+                class_size
+            );
+            unsafe { alloc.mem_free(mem, usable) };
+        }
+
+        assert_eq!(alloc.allocation_kind(1), AllocKind::Slab(16));
+        assert_eq!(
+            alloc.allocation_kind(crate::slab::MAX_SLAB_SIZE),
+            AllocKind::Slab(65512)
+        );
+        assert_eq!(
+            alloc.allocation_kind(crate::slab::MAX_SLAB_SIZE + 1),
+            AllocKind::Large
+        );
+
+        alloc.set_large_threshold(1000);
+        assert_eq!(alloc.allocation_kind(1000), AllocKind::Slab(1024));
+        assert_eq!(alloc.allocation_kind(1001), AllocKind::Large);
+
+        free(ptr);
+    }
+
+    // The hot paths behave the same with and without the `stats` feature.
+    #[test]
+    fn test_stats_feature() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+
+        let mut addrs = std::vec::Vec::new();
+        let mut v = std::vec::Vec::new();
+        for i in 0..2000 {
+            if v.is_empty() || rand::Rng::gen_bool(&mut rng, 0.6) {
+                let size = rand::Rng::gen_range(&mut rng, 1..100000);
+                let layout = std::alloc::Layout::from_size_align(size, 8 << (i % 4)).unwrap();
+                let mem = alloc.mem_alloc_align(layout).unwrap();
+                addrs.push(mem as usize - ptr as usize);
+                v.push((mem, layout));
+            } else {
+                let (mem, layout) = v.swap_remove(rand::Rng::gen_range(&mut rng, 0..v.len()));
+                unsafe { alloc.mem_free_align(mem, layout) };
+            }
+        }
+
+        // a fingerprint of the addresses, which is independent of the `stats` feature
+        // (`debug_registry` pads regions, which moves them)
+        let hash = addrs
+            .iter()
+            .fold(0u64, |h, a| (h ^ *a as u64).wrapping_mul(0x100_0000_01b3));
+        #[cfg(not(feature = "debug_registry"))]
+        assert_eq!(hash, 0x9d6c533e3af52168);
+        #[cfg(feature = "debug_registry")]
+        let _ = hash;
+
+        for (mem, layout) in v {
+            unsafe { alloc.mem_free_align(mem, layout) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        #[cfg(feature = "stats")]
+        {
+            let stats = alloc.stats();
+            assert_eq!(stats.allocated_bytes, stats.freed_bytes);
+            assert_eq!(alloc.stats_relaxed(), stats);
+        }
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_add_region() {
+        const REGION_SIZE: usize = 4 * 1024 * 1024;
+        let layout = std::alloc::Layout::from_size_align(REGION_SIZE, ALIGNMENT).unwrap();
+        let ptr1 = unsafe { std::alloc::alloc(layout) };
+        let ptr2 = unsafe { std::alloc::alloc(layout) };
+        let in_region = |mem: *mut u8, region: *mut u8| {
+            (region as usize..region as usize + REGION_SIZE).contains(&(mem as usize))
+        };
+
+        let mut alloc = Allocator::<crate::regions::Regions<PageManager, 2>>::new();
+        alloc.add_region(ptr1 as usize, REGION_SIZE);
+        assert_eq!(
+            alloc.try_add_region(ptr2 as usize + 4096, REGION_SIZE),
+            Err(InitError::Unaligned {
+                heap_start: ptr2 as usize + 4096
+            })
+        );
+        alloc.add_region(ptr2 as usize, REGION_SIZE);
+
+        // pages of the first region are exhausted, and then pages of the second are used
+        let page = std::alloc::Layout::from_size_align(SIZE_64K, 8).unwrap();
+        let mut pages = std::vec::Vec::new();
+        loop {
+            let mem = alloc.mem_alloc_align(page).unwrap();
+            pages.push(mem);
+            if !in_region(mem, ptr1) {
+                assert!(in_region(mem, ptr2));
+                break;
+            }
+        }
+        assert!(pages.len() > 1);
+
+        // a slab is placed in the second region
+        let small = std::alloc::Layout::from_size_align(100, 8).unwrap();
+        let obj = alloc.mem_alloc_align(small).unwrap();
+        assert!(in_region(obj, ptr2));
+
+        unsafe { alloc.mem_free_align(obj, small) };
+        for mem in pages {
+            unsafe { alloc.mem_free_align(mem, page) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        // freed pages are returned to the first region
+        let mem = alloc.mem_alloc_align(page).unwrap();
+        assert!(in_region(mem, ptr1));
+        unsafe { alloc.mem_free_align(mem, page) };
+
+        dealloc(ptr1, layout);
+        dealloc(ptr2, layout);
+    }
+
+    // `Allocator` can be shared as a static across threads.
+    #[test]
+    fn test_static_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<Allocator<Buddy32M>>();
+        assert_send_sync::<Allocator<PageManager>>();
+
+        // The heap is never freed because the allocator lives until the process exits.
+        static ALLOC: std::sync::OnceLock<Allocator<Buddy32M>> = std::sync::OnceLock::new();
+        let alloc = ALLOC.get_or_init(|| init::<Buddy32M>().0);
+
+        std::thread::scope(|s| {
+            for i in 0..2 {
+                s.spawn(move || {
+                    let layout = std::alloc::Layout::from_size_align(16 << i, 8).unwrap();
+                    for _ in 0..100 {
+                        let mem = unsafe { alloc.alloc(layout) };
+                        assert!(!mem.is_null());
+                        unsafe { alloc.dealloc(mem, layout) };
+                    }
+                });
+            }
+        });
+
+        assert_eq!(alloc.live_bytes(), 0);
+    }
+
+    #[test]
+    fn test_checked_layout() {
+        use crate::{checked_layout, AllocError};
+
+        assert_eq!(checked_layout(100, 24), Err(AllocError::BadAlignment));
+        assert_eq!(checked_layout(100, 0), Err(AllocError::BadAlignment));
+        assert_eq!(checked_layout(usize::MAX, 8), Err(AllocError::OutOfMemory));
+
+        let layout = checked_layout(100, 32).unwrap();
+        assert_eq!((layout.size(), layout.align()), (100, 32));
+    }
+
+    #[test]
+    fn test_slab_object_count() {
+        use crate::slab_object_count;
+
+        assert_eq!(slab_object_count(16), (65536 - 32 - 8 * 64) / 16);
+        assert_eq!(slab_object_count(2040), 32);
+        assert_eq!(slab_object_count(65512), 1);
+        assert_eq!(slab_object_count(0), 0);
+        assert_eq!(slab_object_count(24), 0);
+
+        // objects and the metadata at the end of a slab, which is at least 24 bytes, fit in a page
+        for class_size in crate::slab::CLASS_SIZES {
+            let count = slab_object_count(class_size);
+            assert!(count > 0);
+            assert!(
+                count * class_size <= SIZE_64K - 24,
+                "class_size = {class_size}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_alloc_strided() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        assert_eq!(alloc.alloc_strided(16, 64, 8, 8), None);
+        assert_eq!(alloc.alloc_strided(usize::MAX, 8, 64, 8), None);
+        assert_eq!(alloc.alloc_strided(16, 8, 24, 24), None);
+
+        // 16 slots of 8 bytes placed 64 bytes apart
+        let base = alloc.alloc_strided(16, 8, 64, 8).unwrap();
+        assert_eq!(alloc.live_bytes(), 16 * 64);
+        for i in 0..16 {
+            unsafe { *(base.add(i * 64) as *mut u64) = i as u64 };
+        }
+        for i in 0..16 {
+            assert_eq!(unsafe { *(base.add(i * 64) as *const u64) }, i as u64);
+        }
+
+        let layout = std::alloc::Layout::from_size_align(16 * 64, 8).unwrap();
+        unsafe { alloc.mem_free_align(base, layout) };
+
+        // slots aligned to cache lines
+        let base = alloc.alloc_strided(16, 8, 64, 64).unwrap();
+        assert_eq!(base as usize % 64, 0);
+
+        let layout = std::alloc::Layout::from_size_align(16 * 64, 64).unwrap();
+        unsafe { alloc.mem_free_align(base, layout) };
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_cache_coloring() {
+        let (mut alloc, ptr) = init::<PageManager>();
+        alloc.set_cache_coloring(true);
+
+        // Slab2040 holds 32 objects, so the 33rd object is the first object of a new slab.
+        let mems: std::vec::Vec<_> = (0..33).map(|_| alloc.mem_alloc(2000).unwrap()).collect();
+        let first = mems[0] as usize;
+        let second = mems[32] as usize;
+
+        assert!(alloc.same_page(mems[0], mems[31]));
+        assert!(!alloc.same_page(mems[0], mems[32]));
+        assert_ne!(first & MASK_64K, second & MASK_64K);
+        assert_eq!(
+            (first & MASK_64K).abs_diff(second & MASK_64K) % crate::slab::CACHE_LINE,
+            0
+        );
+
+        for mem in mems {
+            unsafe { alloc.mem_free(mem, 2000) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_deterministic() {
+        fn run(seed: u64) -> std::vec::Vec<usize> {
+            let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(seed);
+
+            let (mut alloc, ptr) = init::<PageManager>();
+            alloc.set_cache_coloring(true);
+            alloc.with_slab(|slab| slab.page_alloc.set_round_robin(true));
+            alloc.set_deterministic(seed);
+
+            let mut offsets = std::vec::Vec::new();
+            let mut v = std::vec::Vec::new();
+            for _ in 0..1000 {
+                if v.is_empty() || rand::Rng::gen_bool(&mut rng, 0.55) {
+                    let size = rand::Rng::gen_range(&mut rng, 1..=SIZE_64K);
+                    let mem = alloc.mem_alloc(size).unwrap();
+                    offsets.push(mem as usize - ptr as usize);
+                    v.push((mem, size));
+                } else {
+                    let (mem, size) = v.swap_remove(rand::Rng::gen_range(&mut rng, 0..v.len()));
+                    unsafe { alloc.mem_free(mem, size) };
+                }
+            }
+
+            for (mem, size) in v {
+                unsafe { alloc.mem_free(mem, size) };
+            }
+
+            free(ptr);
+            offsets
+        }
+
+        for seed in 0..4 {
+            assert_eq!(run(seed), run(seed));
+        }
+
+        // the seed moves the first slab of a class within its page
+        let (mut alloc, ptr) = init::<PageManager>();
+        alloc.set_cache_coloring(true);
+        let firsts: std::vec::Vec<_> = (0..8)
+            .map(|seed| {
+                alloc.set_deterministic(seed);
+                let mem = alloc.mem_alloc(2000).unwrap();
+                unsafe { alloc.mem_free(mem, 2000) };
+                mem as usize & MASK_64K
+            })
+            .collect();
+        assert!(firsts.iter().any(|first| *first != firsts[0]));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_crosses_boundary() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        let layout = std::alloc::Layout::from_size_align(4096, 4096).unwrap();
+        let mem = alloc.mem_alloc_align(layout).unwrap();
+
+        assert!(!alloc.crosses_boundary(mem, 4096, 4096));
+        assert!(!alloc.crosses_boundary(mem, 0, 4096));
+        assert!(alloc.crosses_boundary(mem, 4097, 4096));
+        assert!(!alloc.crosses_boundary(unsafe { mem.add(4095) }, 1, 4096));
+        assert!(alloc.crosses_boundary(unsafe { mem.add(4095) }, 2, 4096));
+        assert!(alloc.crosses_boundary(unsafe { mem.add(2048) }, 4096, 4096));
+
+        unsafe { alloc.mem_free_align(mem, layout) };
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_try_init() {
+        let heap_size = 32 * 1024 * 1024;
+        let layout = std::alloc::Layout::from_size_align(heap_size * 2, ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+
+        let mut alloc = Allocator::<Buddy32M>::new();
+        assert_eq!(
+            alloc.try_init(ptr as usize, heap_size / 2),
+            Err(InitError::SizeMismatch {
+                expected: heap_size,
+                got: heap_size / 2
+            })
+        );
+        assert_eq!(
+            alloc.try_init(ptr as usize, heap_size * 2),
+            Err(InitError::SizeMismatch {
+                expected: heap_size,
+                got: heap_size * 2
+            })
+        );
+        assert_eq!(
+            alloc.try_init(ptr as usize + 4096, heap_size),
+            Err(InitError::Unaligned {
+                heap_start: ptr as usize + 4096
+            })
+        );
+        assert_eq!(alloc.mem_alloc(8), None);
+
+        assert_eq!(alloc.try_init(ptr as usize, heap_size), Ok(()));
+        let mem = alloc.mem_alloc(8).unwrap();
+        unsafe { alloc.mem_free(mem, 8) };
+
+        let mut alloc = Allocator::<PageManager>::new();
+        assert_eq!(
+            alloc.try_init(ptr as usize, heap_size + 4096),
+            Err(InitError::NotMultiple {
+                align: SIZE_64K,
+                got: heap_size + 4096
+            })
+        );
+
+        dealloc(ptr, layout);
+    }
+
+    // the debug registry takes pages from the backend
+    #[cfg(not(feature = "debug_registry"))]
+    #[test]
+    fn test_dump() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        let mut s = std::string::String::new();
+        alloc.dump(&mut s).unwrap();
+        assert!(s.contains("slab16: partial = 0, full = 0, live = 0\n"));
+        assert!(s.contains("backend: used = 0 / 33554432 bytes (0%)\n"));
+
+        let small: std::vec::Vec<_> = (0..3).map(|_| alloc.mem_alloc(8).unwrap()).collect();
+        let full = alloc.mem_alloc(60000).unwrap();
+        let large = alloc.mem_alloc(SIZE_64K * 4).unwrap();
+
+        let mut s = std::string::String::new();
+        alloc.dump(&mut s).unwrap();
+        assert!(s.contains("slab16: partial = 1, full = 0, live = 3\n"));
+        assert!(s.contains("slab65512: partial = 0, full = 1, live = 1\n"));
+        assert!(s.contains("slab2040: partial = 0, full = 0, live = 0\n"));
+        assert!(s.contains("backend: used = 393216 / 33554432 bytes (1%)\n"));
+
+        for mem in small {
+            unsafe { alloc.mem_free(mem, 8) };
+        }
+        unsafe {
+            alloc.mem_free(full, 60000);
+            alloc.mem_free(large, SIZE_64K * 4);
+        }
+
+        let mut s = std::string::String::new();
+        alloc.dump(&mut s).unwrap();
+        assert!(s.contains("slab16: partial = 0, full = 0, live = 0\n"));
+        assert!(s.contains("backend: used = 0 / 33554432 bytes (0%)\n"));
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "stats")]
+    #[test]
+    fn test_class_counters() {
+        let (alloc, ptr) = init::<PageManager>();
+
+        // (size, number of allocations)
+        let dist = [(8, 10), (24, 5), (1000, 3), (2000, 2), (60000, 1)];
+
+        let mut v = std::vec::Vec::new();
+        for (size, n) in dist {
+            for _ in 0..n {
+                v.push((alloc.mem_alloc(size).unwrap(), size));
+            }
+        }
+
+        // free the half of 16 bytes objects
+        for _ in 0..5 {
+            let (mem, size) = v.remove(0);
+            unsafe { alloc.mem_free(mem, size) };
+        }
+
+        let counters = alloc.class_counters();
+        for (class_size, allocs, frees) in counters {
+            let expected = match class_size {
+                16 => (10, 5),
+                32 => (5, 0),
+                1024 => (3, 0),
+                2040 => (2, 0),
+                65512 => (1, 0),
+                _ => (0, 0),
+            };
+            assert_eq!((allocs, frees), expected, "class = {class_size}");
+        }
+        assert_eq!(counters[0].0, 16);
+        assert_eq!(counters[12].0, 65512);
+
+        // large allocations are not counted
+        let mem = alloc.mem_alloc(SIZE_64K).unwrap();
+        unsafe { alloc.mem_free(mem, SIZE_64K) };
+        assert_eq!(alloc.class_counters(), counters);
+
+        for (mem, size) in v {
+            unsafe { alloc.mem_free(mem, size) };
+        }
+        assert!(alloc.class_counters().iter().all(|(_, a, f)| a == f));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_available_bytes() {
+        // free slots of slabs and free bytes of the backend, by walking all the slabs and blocks
+        fn walk<T: MemAlloc>(alloc: &Allocator<T>) -> usize {
+            alloc
+                .with_slab(|slab| {
+                    let free_slots: usize = slab
+                        .class_stats()
+                        .iter()
+                        .map(|s| (s.capacity - s.live) * crate::slab::usable_size(s.class_size))
+                        .sum();
+
+                    let (start, end) = slab.page_alloc.range();
+                    let mut used = 0;
+                    slab.page_alloc.for_each_used(&mut |_, size| used += size);
+
+                    free_slots + (end - start - used)
+                })
+                .unwrap()
+        }
+
+        fn run<T: MemAlloc>() {
+            let (mut alloc, ptr) = init::<T>();
+            let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+            let mut v = std::vec::Vec::new();
+
+            // the debug registry takes pages from the backend
+            #[cfg(not(feature = "debug_registry"))]
+            assert_eq!(alloc.available_bytes(), 32 * 1024 * 1024);
+            assert_eq!(alloc.available_bytes(), walk(&alloc));
+
+            for _ in 0..2000 {
+                if v.is_empty() || rand::Rng::gen_bool(&mut rng, 0.6) {
+                    let size = match rand::Rng::gen_range(&mut rng, 0..10) {
+                        0..=6 => rand::Rng::gen_range(&mut rng, 1..=1024),
+                        7..=8 => rand::Rng::gen_range(&mut rng, 1025..=65504),
+                        _ => rand::Rng::gen_range(&mut rng, 65505..=SIZE_64K * 3),
+                    };
+                    if let Some(mem) = alloc.mem_alloc(size) {
+                        v.push((mem, size));
+                    }
+                } else {
+                    let i = rand::Rng::gen_range(&mut rng, 0..v.len());
+                    let (mem, size) = v.swap_remove(i);
+                    unsafe { alloc.mem_free(mem, size) };
+                }
+
+                assert_eq!(alloc.available_bytes(), walk(&alloc));
+            }
+
+            alloc.prewarm(64, 2);
+            assert_eq!(alloc.available_bytes(), walk(&alloc));
+
+            for (mem, size) in v {
+                unsafe { alloc.mem_free(mem, size) };
+            }
+            assert_eq!(alloc.available_bytes(), walk(&alloc));
+
+            free(ptr);
+        }
+
+        run::<Buddy32M>();
+        run::<PageManager>();
+    }
+
+    #[test]
+    fn test_class_fill_ratio() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let capacity = (65536 - 32 - 8 * 64) / 16;
+
+        assert_eq!(alloc.class_fill_ratio(16), (0, 0));
+
+        // a full slab and a partial slab
+        let mut v: std::vec::Vec<_> = (0..capacity + 100)
+            .map(|_| alloc.mem_alloc(8).unwrap())
+            .collect();
+        assert_eq!(alloc.class_fill_ratio(16), (capacity + 100, capacity * 2));
+        assert_eq!(alloc.class_fill_ratio(32), (0, 0));
+
+        // both slabs are partial
+        for mem in v.drain(..2000) {
+            unsafe { alloc.mem_free(mem, 8) };
+        }
+        assert_eq!(alloc.class_fill_ratio(16), (capacity - 1900, capacity * 2));
+
+        for mem in v {
+            unsafe { alloc.mem_free(mem, 8) };
+        }
+        assert_eq!(alloc.class_fill_ratio(16), (0, 0));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_prewarm() {
+        let (mut alloc, ptr) = init::<PageManager>();
+        let free_pages = |alloc: &Allocator<PageManager>| {
+            alloc
+                .with_slab(|slab| slab.page_alloc.free_page_count())
+                .unwrap()
+        };
+
+        let before = free_pages(&alloc);
+        assert_eq!(alloc.prewarm(64, 2), 2);
+        assert_eq!(free_pages(&alloc), before - 2);
+
+        // Slab64 holds 1021 objects, so 2 slabs serve 2042 allocations without a new page.
+        let mut v = std::vec::Vec::new();
+        for _ in 0..2042 {
+            v.push(alloc.mem_alloc(56).unwrap());
+            assert_eq!(free_pages(&alloc), before - 2);
+        }
+
+        // both prewarmed slabs are linked into the full list
+        let stats = alloc.with_slab(|slab| slab.class_stats()[2]).unwrap();
+        assert_eq!((stats.partial, stats.full, stats.live), (0, 2, 2042));
+
+        v.push(alloc.mem_alloc(56).unwrap());
+        assert_eq!(free_pages(&alloc), before - 3);
+
+        for mem in v {
+            unsafe { alloc.mem_free(mem, 56) };
+        }
+        assert_eq!(free_pages(&alloc), before);
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid class size")]
+    fn test_prewarm_invalid_class() {
+        init_panicking::<PageManager>(|alloc| {
+            alloc.prewarm(100, 1);
+        });
+    }
+
+    #[test]
+    fn test_zero_size() {
+        let (alloc, ptr) = init::<PageManager>();
+        let free_pages = || {
+            alloc
+                .with_slab(|slab| slab.page_alloc.free_page_count())
+                .unwrap()
+        };
+        let before = free_pages();
+
+        for align in [1, 8, 16, 4096] {
+            let layout = std::alloc::Layout::from_size_align(0, align).unwrap();
+            let mem = unsafe { alloc.alloc(layout) };
+            assert!(!mem.is_null());
+            assert_eq!(mem as usize % align, 0);
+            unsafe { alloc.dealloc(mem, layout) };
+        }
+
+        assert_eq!(free_pages(), before);
+        assert_eq!(alloc.live_bytes(), 0);
+        #[cfg(feature = "stats")]
+        assert_eq!(alloc.class_counters()[0].1, 0);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_zero_size_aligned() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let free_bytes = || {
+            alloc
+                .with_slab(|slab| slab.page_alloc.free_bytes())
+                .unwrap()
+        };
+        let before = free_bytes();
+
+        for align in [16, 4096, SIZE_64K] {
+            let layout = std::alloc::Layout::from_size_align(0, align).unwrap();
+
+            // the alignment itself is the pointer, and no header is written
+            assert_eq!(alloc.mem_alloc_align(layout), Some(align as *mut u8));
+            assert_eq!(alloc.alloc_range(0, 0, align), Some((align as *mut u8, 0)));
+
+            let mem = unsafe { alloc.alloc(layout) };
+            assert_eq!(mem, align as *mut u8);
+
+            // growing from and shrinking to zero bytes
+            let mem = unsafe { alloc.realloc(mem, layout, 100) };
+            assert_eq!(mem as usize % align, 0);
+            unsafe { core::ptr::write_bytes(mem, 0xAB, 100) };
+            let grown = std::alloc::Layout::from_size_align(100, align).unwrap();
+            let mem = unsafe { Allocator::realloc(&alloc, mem, grown, 0) };
+            assert_eq!(mem, align as *mut u8);
+
+            unsafe {
+                alloc.dealloc(mem, layout);
+                alloc.free_batch(&[(mem, layout), (mem, layout)]);
+            }
+            assert_eq!(free_bytes(), before, "align = {align}");
+        }
+
+        // the range down to zero bytes never takes a zero-size block
+        let (mem, size) = alloc.alloc_range(0, SIZE_64K * 2, SIZE_64K).unwrap();
+        assert_ne!(size, 0);
+        let layout = std::alloc::Layout::from_size_align(size, SIZE_64K).unwrap();
+        unsafe { alloc.mem_free_align(mem, layout) };
+
+        assert_eq!(free_bytes(), before);
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    // the debug registry takes pages from the backend
+    #[cfg(not(feature = "debug_registry"))]
+    #[test]
+    fn test_native_alignment() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let used_bytes = || {
+            alloc
+                .with_slab(|slab| {
+                    let mut used = 0;
+                    slab.page_alloc.for_each_used(&mut |_, size| used += size);
+                    used
+                })
+                .unwrap()
+        };
+
+        let size = 128 * 1024;
+        let align = SIZE_64K;
+
+        // The padded path needs `size + align - 1 + 8` bytes,
+        // which is rounded up to a 256KiB block.
+        let padded = alloc
+            .with_slab(|slab| {
+                let (mem, bytes) = slab.page_alloc.alloc_order(size + align - 1 + 8).unwrap();
+                slab.page_alloc.free(mem);
+                bytes
+            })
+            .unwrap();
+        assert_eq!(padded, 256 * 1024);
+
+        // The native path uses a 128KiB block without padding.
+        let layout = std::alloc::Layout::from_size_align(size, align).unwrap();
+        let mem = alloc.mem_alloc_align(layout).unwrap();
+        assert_eq!(mem as usize % align, 0);
+        assert_eq!(used_bytes(), size);
+        assert_eq!(alloc.live_bytes(), size);
+
+        unsafe { core::ptr::write_bytes(mem, 0xFF, size) };
+        unsafe { alloc.mem_free_align(mem, layout) };
+        assert_eq!(used_bytes(), 0);
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    // poisoning on allocation overwrites zeros
+    #[cfg(all(feature = "zero_on_free", not(feature = "poison")))]
+    #[test]
+    fn test_zero_on_free() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        for size in [8, 100, 2000, 60000, SIZE_64K * 2] {
+            let mem = alloc.mem_alloc(size).unwrap();
+            unsafe { core::ptr::write_bytes(mem, 0xFF, size) };
+            unsafe { alloc.mem_free(mem, size) };
+
+            // the same slot is reused
+            let mem2 = alloc.mem_alloc(size).unwrap();
+            assert_eq!(mem, mem2);
+            let data = unsafe { core::slice::from_raw_parts(mem2, size) };
+            assert!(data.iter().all(|b| *b == 0), "size = {size}");
+            unsafe { alloc.mem_free(mem2, size) };
+        }
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "poison")]
+    #[test]
+    fn test_poison() {
+        use crate::POISON;
+
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        // usable sizes of Slab16, Slab2040 and Slab65512, and blocks of the buddy allocator,
+        // which are rounded up to a power of two
+        for (size, usable) in [
+            (8, 8),
+            (2000, 2024),
+            (60000, 65504),
+            (SIZE_64K * 2, SIZE_64K * 2),
+            (SIZE_64K * 3, SIZE_64K * 4),
+        ] {
+            let mem = alloc.mem_alloc(size).unwrap();
+            unsafe {
+                assert_eq!(*mem, POISON, "size = {size}");
+                assert_eq!(*mem.add(usable - 1), POISON, "size = {size}");
+                core::ptr::write_bytes(mem, 0, usable);
+                alloc.mem_free(mem, size);
+            }
+
+            // stale data is overwritten on reuse
+            let mem2 = alloc.mem_alloc(size).unwrap();
+            assert_eq!(mem, mem2);
+            let data = unsafe { core::slice::from_raw_parts(mem2, usable) };
+            assert!(data.iter().all(|b| *b == POISON), "size = {size}");
+            unsafe { alloc.mem_free(mem2, size) };
+        }
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_try_alloc() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let layout = std::alloc::Layout::from_size_align(64, 8).unwrap();
+
+        // another thread holds the lock
+        alloc.with_slab(|_| {
+            std::thread::scope(|s| {
+                s.spawn(|| assert_eq!(alloc.try_alloc(layout, 100), None));
+            });
+        });
+
+        let mem = alloc.try_alloc(layout, 100).unwrap();
+        assert_eq!(alloc.live_bytes(), 64);
+        unsafe { alloc.mem_free_align(mem, layout) };
+
+        // a panic under the lock does not leave the lock counted as taken
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            alloc.with_slab(|_| panic!("under the lock"));
+        }));
+        assert!(result.is_err());
+        let mem = alloc.try_alloc(layout, 1).unwrap();
+        unsafe { alloc.mem_free_align(mem, layout) };
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "corrupted header of aligned memory region")]
+    fn test_aligned_header_corruption() {
+        init_panicking::<Buddy32M>(|alloc| {
+            let layout = std::alloc::Layout::from_size_align(100, 64).unwrap();
+
+            let mem = alloc.mem_alloc_align(layout).unwrap();
+            assert_eq!(mem as usize % 64, 0);
+
+            // a one-byte underflow write
+            unsafe { *mem.sub(1) = 0 };
+            unsafe { alloc.mem_free_align(mem, layout) };
+        });
+    }
+
+    #[test]
+    fn test_stale_generation() {
+        let (mut alloc, ptr) = init::<Buddy32M>();
+        let layout = std::alloc::Layout::from_size_align(100, 64).unwrap();
+        let generation = alloc.generation();
+
+        let stale1 = alloc.mem_alloc_align(layout).unwrap();
+        let stale2 = alloc.mem_alloc_align(layout).unwrap();
+
+        alloc.reset();
+        assert_eq!(alloc.live_bytes(), 0);
+        alloc.init(ptr as usize, 32 * 1024 * 1024);
+        assert_eq!(alloc.generation(), generation + 2);
+
+        let small = std::alloc::Layout::from_size_align(8, 8).unwrap();
+        let mem = alloc.mem_alloc_align(small).unwrap();
+        unsafe { *mem = 0xAB };
+        let live = alloc.live_bytes();
+        #[cfg(feature = "stats")]
+        let counters = alloc.class_counters();
+
+        // freeing pointers of the old generation is ignored
+        unsafe {
+            alloc.mem_free_align(stale1, layout);
+            alloc.free_unsized(stale2);
+        }
+        assert_eq!(alloc.live_bytes(), live);
+        #[cfg(feature = "stats")]
+        assert_eq!(alloc.class_counters(), counters);
+        assert_eq!(unsafe { *mem }, 0xAB);
+
+        unsafe { alloc.mem_free_align(mem, small) };
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    // the debug registry takes pages from the backend
+    #[cfg(not(feature = "debug_registry"))]
+    #[test]
+    fn test_buddy16m() {
+        let heap_size = 16 * 1024 * 1024;
+        let layout = std::alloc::Layout::from_size_align(heap_size, ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+
+        let mut alloc = Allocator::<crate::buddy::Buddy16M>::new();
+        alloc.init(ptr as usize, heap_size);
+
+        // all 256 pages can be allocated
+        let page = std::alloc::Layout::from_size_align(SIZE_64K, 8).unwrap();
+        let mut v = std::vec::Vec::new();
+        while let Some(mem) = alloc.mem_alloc_align(page) {
+            v.push(mem);
+        }
+        assert_eq!(v.len(), 256);
+
+        for mem in v {
+            unsafe { alloc.mem_free_align(mem, page) };
+        }
+
+        // the whole region at once
+        let whole = std::alloc::Layout::from_size_align(heap_size, 8).unwrap();
+        let mem = alloc.mem_alloc_align(whole).unwrap();
+        assert_eq!(mem, ptr);
+        assert_eq!(alloc.mem_alloc_align(page), None);
+        unsafe { alloc.mem_free_align(mem, whole) };
+
+        dealloc(ptr, layout);
+    }
+
+    #[test]
+    fn test_empty_slab_cache() {
+        let (mut alloc, ptr) = init::<PageManager>();
+        let free_pages = |alloc: &Allocator<PageManager>| {
+            alloc
+                .with_slab(|slab| slab.page_alloc.free_page_count())
+                .unwrap()
+        };
+        let before = free_pages(&alloc);
+
+        // Without the cache, the page is returned every time.
+        for _ in 0..4 {
+            let mem = alloc.mem_alloc(100).unwrap();
+            assert_eq!(free_pages(&alloc), before - 1);
+            unsafe { alloc.mem_free(mem, 100) };
+            assert_eq!(free_pages(&alloc), before);
+        }
+
+        alloc.set_empty_slab_cache(128, 1);
+
+        for _ in 0..4 {
+            let mem = alloc.mem_alloc(100).unwrap();
+            unsafe { alloc.mem_free(mem, 100) };
+            assert_eq!(free_pages(&alloc), before - 1);
+        }
+
+        // Only one empty slab is cached.
+        let mut v = std::vec::Vec::new();
+        for _ in 0..(511 * 2) {
+            v.push(alloc.mem_alloc(100).unwrap());
+        }
+        assert_eq!(free_pages(&alloc), before - 2);
+        for mem in v {
+            unsafe { alloc.mem_free(mem, 100) };
+        }
+        assert_eq!(free_pages(&alloc), before - 1);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_reclaim() {
+        let (mut alloc, ptr) = init::<PageManager>();
+        let free_pages = |alloc: &Allocator<PageManager>| {
+            alloc
+                .with_slab(|slab| slab.page_alloc.free_page_count())
+                .unwrap()
+        };
+
+        // fill the heap with Slab65512, and cache all the slabs when freed
+        alloc.set_empty_slab_cache(65512, usize::MAX);
+        let mut v = std::vec::Vec::new();
+        while let Some(mem) = alloc.mem_alloc(60000) {
+            v.push(mem);
+        }
+        assert_eq!(free_pages(&alloc), 0);
+
+        let num = v.len();
+        for mem in v {
+            unsafe { alloc.mem_free(mem, 60000) };
+        }
+        assert_eq!(free_pages(&alloc), 0);
+
+        // another class takes pages of the cached slabs
+        let mem = alloc.mem_alloc(100).unwrap();
+        assert_eq!(free_pages(&alloc), num - 1);
+        unsafe { alloc.mem_free(mem, 100) };
+        assert_eq!(free_pages(&alloc), num);
+
+        // nothing to reclaim
+        assert_eq!(alloc.reclaim(), 0);
+
+        assert_eq!(alloc.prewarm(2040, 3), 3);
+        assert_eq!(alloc.reclaim(), 3);
+        assert_eq!(free_pages(&alloc), num);
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    std::thread_local! {
+        static UNMAPPED: core::cell::RefCell<std::vec::Vec<usize>> =
+            const { core::cell::RefCell::new(std::vec::Vec::new()) };
+    }
+
+    fn record_unmap(addr: usize, _: usize) {
+        UNMAPPED.with(|v| v.borrow_mut().push(addr));
+    }
+
+    #[test]
+    fn test_defer_unmap() {
+        let (mut alloc, ptr) = init::<PageManager>();
+        alloc.set_unmap_callback(record_unmap);
+        alloc.set_defer_unmap(true);
+
+        // each object takes a slab page
+        let mut v = std::vec::Vec::new();
+        for _ in 0..crate::slab::UNMAP_QUEUE_LEN {
+            v.push(alloc.mem_alloc(60000).unwrap());
+        }
+
+        for mem in v.iter() {
+            unsafe { alloc.mem_free(*mem, 60000) };
+        }
+        UNMAPPED.with(|u| assert!(u.borrow().is_empty()));
+
+        alloc.flush_unmaps();
+        let mut pages: std::vec::Vec<usize> =
+            v.iter().map(|mem| *mem as usize & crate::MASK).collect();
+        pages.sort();
+        UNMAPPED.with(|u| {
+            let mut u = u.borrow_mut();
+            u.sort();
+            assert_eq!(*u, pages);
+            u.clear();
+        });
+
+        // nothing is queued after flushing
+        alloc.flush_unmaps();
+        UNMAPPED.with(|u| assert!(u.borrow().is_empty()));
+
+        // a queued page taken again is not unmapped
+        let mem = alloc.mem_alloc(60000).unwrap();
+        unsafe { alloc.mem_free(mem, 60000) };
+        let mem = alloc.mem_alloc(60000).unwrap();
+        alloc.flush_unmaps();
+        UNMAPPED.with(|u| assert!(u.borrow().is_empty()));
+        unsafe { alloc.mem_free(mem, 60000) };
+
+        // pages are unmapped immediately while the queue is full
+        let mut v = std::vec::Vec::new();
+        for _ in 0..=crate::slab::UNMAP_QUEUE_LEN {
+            v.push(alloc.mem_alloc(60000).unwrap());
+        }
+        for mem in v.iter() {
+            unsafe { alloc.mem_free(*mem, 60000) };
+        }
+        UNMAPPED.with(|u| assert_eq!(u.borrow().len(), 1));
+
+        alloc.set_defer_unmap(false);
+        UNMAPPED.with(|u| assert_eq!(u.borrow().len(), crate::slab::UNMAP_QUEUE_LEN + 1));
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid class size")]
+    fn test_empty_slab_cache_invalid_class() {
+        init_panicking::<PageManager>(|alloc| alloc.set_empty_slab_cache(100, 1));
+    }
+
+    // the debug registry takes pages from the backend
+    #[cfg(not(feature = "debug_registry"))]
+    #[test]
+    fn test_free_unsized() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let used_bytes = || {
+            alloc
+                .with_slab(|slab| {
+                    let mut used = 0;
+                    slab.page_alloc.for_each_used(&mut |_, size| used += size);
+                    used
+                })
+                .unwrap()
+        };
+
+        let layouts: std::vec::Vec<_> = [(8, 8), (100, 8), (100, 64), (3000, 8), (20000, 256)]
+            .iter()
+            .map(|(size, align)| std::alloc::Layout::from_size_align(*size, *align).unwrap())
+            .collect();
+
+        // free by the layout-based path
+        for layout in layouts.iter() {
+            let mem = alloc.mem_alloc_align(*layout).unwrap();
+            unsafe { alloc.mem_free_align(mem, *layout) };
+        }
+        assert_eq!(used_bytes(), 0);
+
+        // free without layouts
+        let mems: std::vec::Vec<_> = layouts
+            .iter()
+            .map(|layout| alloc.mem_alloc_align(*layout).unwrap())
+            .collect();
+        assert!(used_bytes() > 0);
+
+        // the sizes given at allocation are freed, not the usable sizes of the classes
+        for (mem, layout) in mems.into_iter().zip(layouts.iter()) {
+            let live = alloc.live_bytes();
+            unsafe { alloc.free_unsized(mem) };
+            assert_eq!(live - alloc.live_bytes(), alloc.backing_size(*layout));
+        }
+
+        // so is the size after shrinking in place
+        let layout = std::alloc::Layout::from_size_align(800, 8).unwrap();
+        let mem = alloc.mem_alloc_align(layout).unwrap();
+        let shrunk = unsafe { alloc.shrink(mem, layout, 700) };
+        assert_eq!(shrunk, mem);
+        assert_eq!(alloc.live_bytes(), 700);
+        unsafe { alloc.free_unsized(shrunk) };
+
+        assert_eq!(used_bytes(), 0);
+        assert_eq!(alloc.live_bytes(), 0);
+        assert!(alloc.is_empty());
+        #[cfg(feature = "stats")]
+        {
+            let stats = alloc.stats();
+            assert_eq!(stats.allocated_bytes, stats.freed_bytes);
+            assert_eq!(alloc.internal_fragmentation(), (0, 0));
+        }
+
+        let mut live_count = None;
+        alloc.metrics(&mut |key, value| {
+            if key == "live_count" {
+                live_count = Some(value);
+            }
+        });
+        assert_eq!(live_count, Some(0));
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "not a slab object")]
+    fn test_free_unsized_large() {
+        init_panicking::<Buddy32M>(|alloc| {
+            let layout = std::alloc::Layout::from_size_align(SIZE_64K * 2, 8).unwrap();
+            let mem = alloc.mem_alloc_align(layout).unwrap();
+            unsafe { alloc.free_unsized(mem) };
+        });
+    }
+
+    #[cfg(feature = "debug_registry")]
+    #[test]
+    fn test_debug_registry_grow() {
+        let (alloc, ptr) = init::<Buddy32M>();
+
+        // far more small objects than the initial table of 64 entries per heap page
+        let layout = std::alloc::Layout::new::<u64>();
+        let mems: std::vec::Vec<_> = (0..100_000)
+            .map(|_| alloc.mem_alloc_align(layout).unwrap())
+            .collect();
+        for mem in mems {
+            unsafe { alloc.mem_free_align(mem, layout) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "debug_registry")]
+    #[test]
+    fn test_debug_registry() {
+        let (alloc, ptr) = init::<Buddy32M>();
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+        let mut v = std::vec::Vec::new();
+
+        for _ in 0..4000 {
+            if v.is_empty() || rand::Rng::gen_bool(&mut rng, 0.55) {
+                let size = rand::Rng::gen_range(&mut rng, 0..=SIZE_64K * 2);
+                let align = 1 << rand::Rng::gen_range(&mut rng, 0..=10);
+                let layout = std::alloc::Layout::from_size_align(size, align).unwrap();
+                if let Some(mem) = alloc.mem_alloc_align(layout) {
+                    v.push((mem, layout));
+                }
+            } else {
+                let i = rand::Rng::gen_range(&mut rng, 0..v.len());
+                let (mem, layout) = v.swap_remove(i);
+                if layout.size() > 0 && layout.size() + layout.align() + 24 <= 65504 {
+                    unsafe { alloc.free_unsized(mem) };
+                } else {
+                    unsafe { alloc.mem_free_align(mem, layout) };
+                }
             }
         }
+
+        for (mem, layout) in v {
+            unsafe { alloc.mem_free_align(mem, layout) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "debug_registry")]
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn test_debug_registry_double_free() {
+        init_panicking::<Buddy32M>(|alloc| {
+            let layout = std::alloc::Layout::from_size_align(64, 8).unwrap();
+            let mem1 = alloc.mem_alloc_align(layout).unwrap();
+            let _mem2 = alloc.mem_alloc_align(layout).unwrap();
+
+            unsafe { alloc.mem_free_align(mem1, layout) };
+            unsafe { alloc.mem_free_align(mem1, layout) };
+        });
     }
 }
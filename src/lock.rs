@@ -0,0 +1,140 @@
+//! Locks protecting the slab allocator.
+//!
+//! `Lock<T>` is `McsLock<T>` by default, which is fair and scalable under contention.
+//! The `spin_lock` feature selects `SpinLock<T>` instead,
+//! which has no per-thread node and suits single-core targets that never contend.
+
+use core::{
+    cell::UnsafeCell,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use synctools::mcs::{MCSLock, MCSNode};
+
+#[cfg(not(feature = "spin_lock"))]
+pub(crate) type Lock<T> = McsLock<T>;
+
+#[cfg(feature = "spin_lock")]
+pub(crate) type Lock<T> = SpinLock<T>;
+
+/// `synctools::mcs::MCSLock` taking a node on the stack of the caller.
+#[cfg_attr(feature = "spin_lock", allow(dead_code))]
+pub(crate) struct McsLock<T>(MCSLock<T>);
+
+#[cfg_attr(feature = "spin_lock", allow(dead_code))]
+impl<T> McsLock<T> {
+    pub(crate) fn new(v: T) -> Self {
+        McsLock(MCSLock::new(v))
+    }
+
+    /// Run `f` while holding the lock.
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        let mut node = MCSNode::new();
+        let mut guard = self.0.lock(&mut node);
+        f(&mut guard)
+    }
+}
+
+/// A test-and-test-and-set spinlock.
+#[cfg_attr(not(feature = "spin_lock"), allow(dead_code))]
+pub(crate) struct SpinLock<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: `data` is accessed only by the thread holding `locked`, like `MCSLock`,
+// so a value which can be sent to another thread can be shared through the lock.
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+#[cfg_attr(not(feature = "spin_lock"), allow(dead_code))]
+impl<T> SpinLock<T> {
+    pub(crate) const fn new(v: T) -> Self {
+        SpinLock {
+            locked: AtomicBool::new(false),
+            data: UnsafeCell::new(v),
+        }
+    }
+
+    /// Run `f` while holding the lock.
+    pub(crate) fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+
+        // Release the lock even if `f` panics.
+        struct Unlock<'a>(&'a AtomicBool);
+
+        impl Drop for Unlock<'_> {
+            fn drop(&mut self) {
+                self.0.store(false, Ordering::Release);
+            }
+        }
+
+        let _unlock = Unlock(&self.locked);
+        f(unsafe { &mut *self.data.get() })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{McsLock, SpinLock};
+
+    // The same suite runs for both locks.
+    macro_rules! lock_tests {
+        ($name:ident, $lock:ident) => {
+            mod $name {
+                extern crate std;
+
+                use super::$lock;
+
+                #[test]
+                fn test_with() {
+                    let lock = $lock::new(1);
+                    assert_eq!(lock.with(|v| *v + 1), 2);
+                    lock.with(|v| *v = 10);
+                    assert_eq!(lock.with(|v| *v), 10);
+                }
+
+                #[test]
+                fn test_contention() {
+                    let lock = $lock::new(0usize);
+
+                    std::thread::scope(|s| {
+                        for _ in 0..2 {
+                            s.spawn(|| {
+                                for _ in 0..100 {
+                                    lock.with(|v| *v += 1);
+                                }
+                            });
+                        }
+                    });
+
+                    assert_eq!(lock.with(|v| *v), 200);
+                }
+
+                #[test]
+                fn test_panic() {
+                    let lock = $lock::new(0);
+                    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        lock.with(|_| panic!("in the critical section"))
+                    }));
+                    assert!(result.is_err());
+
+                    // the lock is released
+                    assert_eq!(lock.with(|v| *v), 0);
+                }
+            }
+        };
+    }
+
+    lock_tests!(mcs, McsLock);
+    lock_tests!(spin, SpinLock);
+}
@@ -0,0 +1,335 @@
+//! A per-CPU cache of free slab objects in front of the allocator.
+//!
+//! Each CPU keeps a magazine of up to `ROUNDS` free objects for each slab class.
+//! `alloc` pops an object from the magazine of the current CPU without taking the lock,
+//! and refills half of the magazine under a single lock only when it is empty.
+//! `free` pushes an object to the magazine, and returns half of it under a single lock
+//! only when it is full.
+//!
+//! The current CPU is given by a hook of the user, e.g. reading a per-CPU register.
+//!
+//! ```
+//! use memac::{Allocator, buddy::Buddy32M, magazine::MagazineCache};
+//!
+//! let mut alloc = Allocator::<Buddy32M>::new();
+//!
+//! let heap_size = 32 * 1024 * 1024;
+//! let layout = std::alloc::Layout::from_size_align(heap_size, memac::ALIGNMENT).unwrap();
+//! let ptr = unsafe { std::alloc::alloc(layout) };
+//! alloc.init(ptr as usize, heap_size);
+//!
+//! fn cpu_id() -> usize {
+//!     0 // A single CPU.
+//! }
+//!
+//! let cache = MagazineCache::<_, 1, 16>::new(&alloc, cpu_id);
+//!
+//! let layout = std::alloc::Layout::from_size_align(100, 8).unwrap();
+//! let mem = cache.alloc(layout).unwrap(); // Refill the magazine of the 128-byte class.
+//! unsafe { cache.free(mem, layout) };     // Allocation and deallocation without a lock.
+//!
+//! drop(cache); // Return the cached objects to the allocator.
+//! ```
+
+use crate::{slab, Allocator, MemAlloc};
+use core::{
+    alloc::Layout,
+    cell::UnsafeCell,
+    ptr::null_mut,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Free objects of a slab class cached by a CPU.
+struct Magazine<const ROUNDS: usize> {
+    rounds: [*mut u8; ROUNDS],
+    len: usize,
+}
+
+/// Magazines of a CPU.
+///
+/// `busy` is set while the CPU uses its magazines,
+/// so that a reentrant call, e.g. from an interrupt handler, falls back to the allocator.
+struct Cpu<const ROUNDS: usize> {
+    busy: AtomicBool,
+    magazines: UnsafeCell<[Magazine<ROUNDS>; slab::NUM_CLASSES]>,
+}
+
+/// Magazines of `ROUNDS` objects of each slab class for `CPUS` CPUs.
+///
+/// Requests with an alignment larger than 8 bytes or served by the page allocator
+/// are passed to the allocator as they are.
+///
+/// Cached objects are still allocated from the point of view of the allocator,
+/// so they are counted as live, and are not scrubbed nor poisoned until returned.
+/// An object is taken from the allocator with the usable size of its class,
+/// so statistics of the allocator count the size of the class instead of the requested size.
+pub struct MagazineCache<'a, PAGEALLOC: MemAlloc, const CPUS: usize, const ROUNDS: usize> {
+    parent: &'a Allocator<PAGEALLOC>,
+    cpu_id: fn() -> usize,
+    cpus: [Cpu<ROUNDS>; CPUS],
+}
+
+// SAFETY: the magazines of a CPU are accessed only while holding its `busy` flag,
+// objects in them are owned by the cache, and the parent allocator is shared only when it is `Sync`.
+unsafe impl<PAGEALLOC: MemAlloc + Send, const CPUS: usize, const ROUNDS: usize> Sync
+    for MagazineCache<'_, PAGEALLOC, CPUS, ROUNDS>
+{
+}
+unsafe impl<PAGEALLOC: MemAlloc + Send, const CPUS: usize, const ROUNDS: usize> Send
+    for MagazineCache<'_, PAGEALLOC, CPUS, ROUNDS>
+{
+}
+
+impl<'a, PAGEALLOC: MemAlloc, const CPUS: usize, const ROUNDS: usize>
+    MagazineCache<'a, PAGEALLOC, CPUS, ROUNDS>
+{
+    /// Create empty magazines in front of `parent`.
+    ///
+    /// `cpu_id` must return the index of the current CPU, which is less than `CPUS`.
+    /// A thread may migrate to another CPU between calls, which only costs the locality.
+    pub fn new(parent: &'a Allocator<PAGEALLOC>, cpu_id: fn() -> usize) -> Self {
+        assert!(CPUS > 0 && ROUNDS > 0);
+
+        MagazineCache {
+            parent,
+            cpu_id,
+            cpus: core::array::from_fn(|_| Cpu {
+                busy: AtomicBool::new(false),
+                magazines: UnsafeCell::new(core::array::from_fn(|_| Magazine {
+                    rounds: [null_mut(); ROUNDS],
+                    len: 0,
+                })),
+            }),
+        }
+    }
+
+    /// Allocate a memory region like `Allocator::mem_alloc_align`.
+    pub fn alloc(&self, layout: Layout) -> Option<*mut u8> {
+        let Some(idx) = self.class_of(layout) else {
+            return self.parent.mem_alloc_align(layout);
+        };
+
+        let class_layout = self.class_layout(idx);
+        self.with_magazine(idx, |magazine| {
+            if magazine.len == 0 {
+                // take half of the magazine at once
+                let target = ROUNDS.div_ceil(2);
+                self.parent.with_slab(|slab| {
+                    while magazine.len < target {
+                        let Some(ptr) = self.parent.alloc_layout(slab, class_layout) else {
+                            break;
+                        };
+                        magazine.rounds[magazine.len] = ptr;
+                        magazine.len += 1;
+                    }
+                });
+
+                if magazine.len == 0 {
+                    return None;
+                }
+            }
+
+            magazine.len -= 1;
+            Some(magazine.rounds[magazine.len])
+        })
+        .unwrap_or_else(|| self.parent.mem_alloc_align(class_layout))
+    }
+
+    /// Deallocate a memory region allocated by `alloc`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be a pointer returned by `alloc` of this cache with `layout`.
+    pub unsafe fn free(&self, ptr: *mut u8, layout: Layout) {
+        let Some(idx) = self.class_of(layout) else {
+            return self.parent.mem_free_align(ptr, layout);
+        };
+
+        let class_layout = self.class_layout(idx);
+        let cached = self.with_magazine(idx, |magazine| {
+            if magazine.len == ROUNDS {
+                // return the older half of the magazine at once
+                let half = ROUNDS.div_ceil(2);
+                let mut items = [(null_mut(), class_layout); ROUNDS];
+                for (item, ptr) in items.iter_mut().zip(magazine.rounds[..half].iter()) {
+                    item.0 = *ptr;
+                }
+                self.parent.free_batch(&items[..half]);
+
+                magazine.rounds.copy_within(half.., 0);
+                magazine.len -= half;
+            }
+
+            magazine.rounds[magazine.len] = ptr;
+            magazine.len += 1;
+        });
+
+        if cached.is_none() {
+            self.parent.mem_free_align(ptr, class_layout);
+        }
+    }
+
+    /// Return all the cached objects to the allocator.
+    ///
+    /// Magazines of CPUs using them at the moment are skipped.
+    pub fn drain(&self) {
+        for cpu in self.cpus.iter() {
+            if cpu.busy.swap(true, Ordering::Acquire) {
+                continue;
+            }
+
+            let magazines = unsafe { &mut *cpu.magazines.get() };
+            for (idx, magazine) in magazines.iter_mut().enumerate() {
+                if magazine.len == 0 {
+                    continue;
+                }
+
+                let class_layout = self.class_layout(idx);
+                let mut items = [(null_mut(), class_layout); ROUNDS];
+                for (item, ptr) in items.iter_mut().zip(magazine.rounds[..magazine.len].iter()) {
+                    item.0 = *ptr;
+                }
+                unsafe { self.parent.free_batch(&items[..magazine.len]) };
+                magazine.len = 0;
+            }
+
+            cpu.busy.store(false, Ordering::Release);
+        }
+    }
+
+    /// Return the number of objects cached by all the CPUs.
+    pub fn cached(&mut self) -> usize {
+        self.cpus
+            .iter_mut()
+            .flat_map(|cpu| cpu.magazines.get_mut().iter())
+            .map(|magazine| magazine.len)
+            .sum()
+    }
+
+    /// Run `f` with the magazine of `idx` of the current CPU,
+    /// or return `None` if the CPU is already using its magazines.
+    fn with_magazine<R>(
+        &self,
+        idx: usize,
+        f: impl FnOnce(&mut Magazine<ROUNDS>) -> R,
+    ) -> Option<R> {
+        let Some(cpu) = self.cpus.get((self.cpu_id)()) else {
+            panic!("invalid CPU");
+        };
+
+        if cpu.busy.swap(true, Ordering::Acquire) {
+            return None;
+        }
+
+        let result = f(unsafe { &mut (*cpu.magazines.get())[idx] });
+        cpu.busy.store(false, Ordering::Release);
+
+        Some(result)
+    }
+
+    /// Return the slab class of `layout`, or `None` if it is not cached.
+    fn class_of(&self, layout: Layout) -> Option<usize> {
+        if layout.size() == 0 || layout.align() > 8 {
+            None
+        } else {
+            self.parent.class_index(layout.size())
+        }
+    }
+
+    /// Return the layout with which objects of the slab class of `idx` are allocated.
+    fn class_layout(&self, idx: usize) -> Layout {
+        let size = slab::USABLE_SIZES[idx].min(self.parent.large_threshold);
+        Layout::from_size_align(size, 8).unwrap()
+    }
+}
+
+impl<PAGEALLOC: MemAlloc, const CPUS: usize, const ROUNDS: usize> Drop
+    for MagazineCache<'_, PAGEALLOC, CPUS, ROUNDS>
+{
+    fn drop(&mut self) {
+        self.drain();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::MagazineCache;
+    use crate::{buddy::Buddy32M, Allocator};
+    use core::sync::atomic::Ordering;
+
+    const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+    std::thread_local! {
+        static CPU: core::cell::Cell<usize> = const { core::cell::Cell::new(0) };
+    }
+
+    fn cpu_id() -> usize {
+        CPU.with(|cpu| cpu.get())
+    }
+
+    #[test]
+    fn test_magazine() {
+        const CPUS: usize = 4;
+        const ITER: usize = 2000;
+
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        let mut alloc = Allocator::<Buddy32M>::new();
+        alloc.init(ptr as usize, HEAP_SIZE);
+
+        let mut cache = MagazineCache::<_, CPUS, 32>::new(&alloc, cpu_id);
+        let locks = alloc.lock_count.load(Ordering::Relaxed);
+
+        // threads stand in for CPUs
+        std::thread::scope(|s| {
+            for id in 0..CPUS {
+                let cache = &cache;
+                s.spawn(move || {
+                    CPU.with(|cpu| cpu.set(id));
+                    let mut rng =
+                        <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(id as u64);
+
+                    for _ in 0..ITER {
+                        let mut v = std::vec::Vec::new();
+                        for _ in 0..8 {
+                            let size = rand::Rng::gen_range(&mut rng, 1..=256);
+                            let layout = std::alloc::Layout::from_size_align(size, 8).unwrap();
+                            let mem = cache.alloc(layout).unwrap();
+                            unsafe { core::ptr::write_bytes(mem, 0xAA, size) };
+                            v.push((mem, layout));
+                        }
+
+                        for (mem, layout) in v {
+                            unsafe { cache.free(mem, layout) };
+                        }
+                    }
+                });
+            }
+        });
+
+        // 16 operations per iteration
+        let locks = alloc.lock_count.load(Ordering::Relaxed) - locks;
+        assert!(locks * 100 < CPUS * ITER * 16, "{locks} locks");
+
+        // cached objects are still live
+        assert!(cache.cached() > 0);
+        assert_ne!(alloc.live_bytes(), 0);
+
+        // aligned requests bypass the magazines
+        let aligned = std::alloc::Layout::from_size_align(64, 64).unwrap();
+        let mem = cache.alloc(aligned).unwrap();
+        assert_eq!(mem as usize % 64, 0);
+        unsafe { cache.free(mem, aligned) };
+
+        drop(cache);
+        assert_eq!(alloc.live_bytes(), 0);
+
+        // The heap may be taken by another test right after it is freed.
+        #[cfg(feature = "debug")]
+        crate::heaps::unregister_at(ptr as usize);
+
+        unsafe { std::alloc::dealloc(ptr, layout) };
+    }
+}
@@ -1,4 +1,10 @@
-use crate::{MemAlloc, SIZE_64K};
+use crate::{InitError, MemAlloc, SIZE_64K};
+
+/// Maximum number of runs of pages allocated at once by `alloc` for more than 64KiB.
+pub const MAX_SPANS: usize = 64;
+
+/// Maximum number of NUMA nodes given by `set_nodes`.
+pub const MAX_NODES: usize = 8;
 
 /// 64 * 64 * 64 pages = 64 * 64 * 64 * 64KiB = 16GiB
 pub struct PageManager {
@@ -7,6 +13,12 @@ pub struct PageManager {
     vacancy_books: u64,
     vacancy_pages: [u64; 64],
     book: [Book; 64],
+    used_pages: usize, // number of allocated or reserved pages
+    round_robin: bool,
+    last_book: usize,                   // book of the last allocation
+    spans: [(usize, usize); MAX_SPANS], // (address, pages) of runs, address 0 means unused
+    nodes: [(usize, usize); MAX_NODES], // [start, end) of the address range of each NUMA node
+    num_nodes: usize,
 }
 
 #[derive(Copy, Clone)]
@@ -35,30 +47,150 @@ impl PageManager {
     // }
 
     pub fn page_alloc(&mut self) -> Option<*mut u8> {
-        if self.vacancy_books == !0 {
-            return None;
+        // books which are not full
+        let mut books = !self.vacancy_books;
+
+        // the scan starts from the book next to the last allocation in round-robin mode
+        let first = if self.round_robin {
+            // books beyond the heap are never full, so they must be skipped
+            let num_books = (self.end - self.start).div_ceil(64 * 64 * SIZE_64K);
+            if num_books < 64 {
+                books &= !(!0 >> num_books);
+            }
+            (self.last_book + 1) % 64
+        } else {
+            0
+        };
+
+        while books != 0 {
+            let idx1 = (first + books.rotate_left(first as u32).leading_zeros() as usize) % 64;
+            let idx2 = (!self.vacancy_pages[idx1]).leading_zeros() as usize;
+            let idx3 = (!self.book[idx1].pages[idx2]).leading_zeros() as usize;
+
+            let addr = match self.page_addr(idx1, idx2, idx3) {
+                Some(addr) if addr < self.end => addr,
+                _ => {
+                    // Free pages of the book are beyond the heap.
+                    // Books after it are also beyond the heap unless the scan wrapped around,
+                    // which happens only in round-robin mode.
+                    if !self.round_robin {
+                        return None;
+                    }
+                    books &= !(1 << (63 - idx1));
+                    continue;
+                }
+            };
+
+            self.book[idx1].pages[idx2] |= 1 << (63 - idx3);
+            if self.book[idx1].pages[idx2] == !0 {
+                self.vacancy_pages[idx1] |= 1 << (63 - idx2);
+                if self.vacancy_pages[idx1] == !0 {
+                    self.vacancy_books |= 1 << (63 - idx1);
+                }
+            }
+            self.used_pages += 1;
+            self.last_book = idx1;
+
+            return Some(addr as _);
         }
 
-        let idx1 = (!self.vacancy_books).leading_zeros() as usize;
-        let idx2 = (!self.vacancy_pages[idx1]).leading_zeros() as usize;
-        let idx3 = (!self.book[idx1].pages[idx2]).leading_zeros() as usize;
+        None
+    }
 
-        let addr =
-            64 * 1024 * 64 * 64 * idx1 + 64 * 1024 * 64 * idx2 + 64 * 1024 * idx3 + self.start;
+    /// Spread allocations across books of 256MiB (4096 pages) in a round-robin manner
+    /// instead of always taking the lowest free page.
+    /// This avoids clustering allocations at low addresses,
+    /// which is useful for wear leveling or interleaving memory controllers.
+    pub fn set_round_robin(&mut self, enabled: bool) {
+        self.round_robin = enabled;
+    }
 
-        if addr >= self.end {
-            return None;
+    /// Start the round-robin scan from a book derived from `seed` instead of the first book.
+    pub fn set_seed(&mut self, seed: u64) {
+        let num_books = (self.end - self.start).div_ceil(64 * 64 * SIZE_64K).max(1);
+        // the scan starts from the book next to `last_book`
+        self.last_book = ((seed % num_books as u64) as usize + 63) % 64;
+    }
+
+    /// Set the address range `[start, end)` of each NUMA node, indexed by node number.
+    /// Ranges may extend beyond the heap, and pages outside of all the ranges belong to no node.
+    ///
+    /// # Panics
+    ///
+    /// Panics if more than `MAX_NODES` ranges are given.
+    pub fn set_nodes(&mut self, ranges: &[(usize, usize)]) {
+        if ranges.len() > MAX_NODES {
+            panic!("too many nodes");
         }
 
-        self.book[idx1].pages[idx2] |= 1 << (63 - idx3);
-        if self.book[idx1].pages[idx2] == !0 {
-            self.vacancy_pages[idx1] |= 1 << (63 - idx2);
-            if self.vacancy_pages[idx1] == !0 {
-                self.vacancy_books |= 1 << (63 - idx1);
+        self.nodes[..ranges.len()].copy_from_slice(ranges);
+        self.num_nodes = ranges.len();
+    }
+
+    /// Allocate a page preferably from `node` set by `set_nodes`.
+    ///
+    /// Only the books covering the range of `node` are scanned.
+    /// If the node is full, a page is taken from anywhere by `page_alloc`.
+    /// The returned flag is `true` if the page is on `node`, and `false` if it fell back.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `node` is not set.
+    pub fn page_alloc_on_node(&mut self, node: usize) -> Option<(*mut u8, bool)> {
+        if node >= self.num_nodes {
+            panic!("invalid node");
+        }
+
+        let (start, end) = self.nodes[node];
+        match self.page_alloc_in(start, end) {
+            Some(addr) => Some((addr, true)),
+            None => Some((self.page_alloc()?, false)),
+        }
+    }
+
+    /// Allocate the lowest free page in `[start, end)`.
+    fn page_alloc_in(&mut self, start: usize, end: usize) -> Option<*mut u8> {
+        let first = (start.max(self.start) - self.start).div_ceil(SIZE_64K);
+        let last = end.min(self.end).saturating_sub(self.start) / SIZE_64K;
+
+        let mut idx = first;
+        while idx < last {
+            if self.vacancy_books & (1 << (63 - (idx >> 12))) != 0 {
+                // skip a full book
+                idx = (idx | 4095) + 1;
+                continue;
+            }
+
+            let word = self.book[idx >> 12].pages[(idx >> 6) & 63];
+            if word == !0 {
+                idx = (idx | 63) + 1;
+                continue;
+            }
+
+            if word & (1 << (63 - (idx & 63))) == 0 {
+                let addr = self.start + idx * SIZE_64K;
+                self.set_used(addr);
+                return Some(addr as _);
             }
+            idx += 1;
         }
 
-        Some(addr as _)
+        None
+    }
+
+    /// Return the address of a page from its indices, or `None` if it overflows.
+    /// A heap mapped at a high address may overflow.
+    fn page_addr(&self, idx1: usize, idx2: usize, idx3: usize) -> Option<usize> {
+        let offset = (64 * 1024 * 64 * 64usize)
+            .checked_mul(idx1)?
+            .checked_add((64 * 1024 * 64usize).checked_mul(idx2)?)?
+            .checked_add((64 * 1024usize).checked_mul(idx3)?)?;
+        self.start.checked_add(offset)
+    }
+
+    /// Return the number of pages which can be allocated.
+    pub fn free_page_count(&self) -> usize {
+        (self.end - self.start) / SIZE_64K - self.used_pages
     }
 
     pub fn page_free(&mut self, addr: *mut u8) {
@@ -67,38 +199,720 @@ impl PageManager {
             panic!("invalid address");
         }
 
-        let idx1 = ((addr - self.start) >> 28) & 0b111111;
-        let idx2 = (addr >> 22) & 0b111111;
-        let idx3 = (addr >> 16) & 0b111111;
+        let (idx1, idx2, idx3) = self.indices(addr);
+
+        if self.book[idx1].pages[idx2] & (1 << (63 - idx3)) != 0 {
+            self.used_pages -= 1;
+        }
 
         self.book[idx1].pages[idx2] &= !(1 << (63 - idx3));
         self.vacancy_pages[idx1] &= !(1 << (63 - idx2));
         self.vacancy_books &= !(1 << (63 - idx1));
     }
+
+    /// Check that the summary bits agree with `book`.
+    ///
+    /// Bit `idx2` of `vacancy_pages[idx1]` must be set iff `book[idx1].pages[idx2]` is full,
+    /// and bit `idx1` of `vacancy_books` must be set iff `vacancy_pages[idx1]` is full.
+    /// The first disagreement is returned as `idx1 * 64 + idx2` for `vacancy_pages`,
+    /// or as `64 * 64 + idx1` for `vacancy_books`.
+    pub fn verify(&self) -> Result<(), usize> {
+        for idx1 in 0..64 {
+            for idx2 in 0..64 {
+                let full = self.book[idx1].pages[idx2] == !0;
+                let vacancy = self.vacancy_pages[idx1] & (1 << (63 - idx2)) != 0;
+                if full != vacancy {
+                    return Err(idx1 * 64 + idx2);
+                }
+            }
+
+            let full = self.vacancy_pages[idx1] == !0;
+            let vacancy = self.vacancy_books & (1 << (63 - idx1)) != 0;
+            if full != vacancy {
+                return Err(64 * 64 + idx1);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reserve pages in `[addr, addr + len)` so that `page_alloc` never returns them.
+    /// This is used to exclude MMIO or firmware-reserved regions.
+    pub fn reserve(&mut self, addr: usize, len: usize) {
+        self.check_range(addr, len);
+
+        for page in (addr..addr + len).step_by(SIZE_64K) {
+            self.set_used(page);
+        }
+    }
+
+    /// Free all the pages at once, including reserved ones, e.g. for a fast teardown.
+    ///
+    /// The bitmaps of the books covering the heap are reset word by word,
+    /// instead of freeing pages one by one.
+    pub fn clear(&mut self) {
+        self.clear_with(&mut |_| ());
+    }
+
+    /// Free all the pages like `clear`, and call `f(addr)` for each page which was allocated,
+    /// e.g. to unmap it.
+    pub fn clear_with(&mut self, f: &mut dyn FnMut(usize)) {
+        let num_books = (self.end - self.start).div_ceil(64 * 64 * SIZE_64K);
+        for idx1 in 0..num_books.min(64) {
+            for idx2 in 0..64 {
+                let mut word = self.book[idx1].pages[idx2];
+                while word != 0 {
+                    let idx3 = word.leading_zeros() as usize;
+                    word &= !(1 << (63 - idx3));
+                    if let Some(addr) = self.page_addr(idx1, idx2, idx3) {
+                        f(addr);
+                    }
+                }
+                self.book[idx1].pages[idx2] = 0;
+            }
+        }
+
+        self.vacancy_books = 0;
+        self.vacancy_pages = [0; 64];
+        self.used_pages = 0;
+        self.spans = [(0, 0); MAX_SPANS];
+    }
+
+    /// Allocate `pages` contiguous pages, and return the address of the first page.
+    /// The first free run which is long enough is taken.
+    ///
+    /// The pages must be freed by `free`, which frees the whole run,
+    /// and at most `MAX_SPANS` runs can be allocated at once.
+    pub fn page_alloc_contiguous(&mut self, pages: usize) -> Option<*mut u8> {
+        let slot = self.spans.iter().position(|(addr, _)| *addr == 0)?;
+        let (first, _) = self.find_free_run(pages);
+        let addr = self.start + first? * SIZE_64K;
+
+        for page in (addr..addr + pages * SIZE_64K).step_by(SIZE_64K) {
+            self.set_used(page);
+        }
+        self.spans[slot] = (addr, pages);
+
+        Some(addr as _)
+    }
+
+    /// Return the index of the first page of the first free run of at least `pages` pages,
+    /// and the number of pages of the longest free run scanned.
+    fn find_free_run(&self, pages: usize) -> (Option<usize>, usize) {
+        let num_pages = (self.end - self.start) / SIZE_64K;
+        let mut longest = 0;
+        let mut first = 0;
+        let mut len = 0;
+        let mut idx = 0;
+
+        while idx < num_pages {
+            let word = self.book[idx >> 12].pages[(idx >> 6) & 63];
+            if idx & 63 == 0 && word == !0 {
+                // skip 64 allocated pages at once
+                len = 0;
+                idx += 64;
+                continue;
+            }
+
+            if word & (1 << (63 - (idx & 63))) == 0 {
+                if len == 0 {
+                    first = idx;
+                }
+                len += 1;
+                longest = longest.max(len);
+
+                if len == pages {
+                    return (Some(first), longest);
+                }
+            } else {
+                len = 0;
+            }
+            idx += 1;
+        }
+
+        (None, longest)
+    }
+
+    /// Mark the page at `addr` as allocated.
+    fn set_used(&mut self, addr: usize) {
+        let (idx1, idx2, idx3) = self.indices(addr);
+
+        if self.book[idx1].pages[idx2] & (1 << (63 - idx3)) == 0 {
+            self.used_pages += 1;
+        }
+
+        self.book[idx1].pages[idx2] |= 1 << (63 - idx3);
+        if self.book[idx1].pages[idx2] == !0 {
+            self.vacancy_pages[idx1] |= 1 << (63 - idx2);
+            if self.vacancy_pages[idx1] == !0 {
+                self.vacancy_books |= 1 << (63 - idx1);
+            }
+        }
+    }
+
+    /// Release pages reserved by `reserve`.
+    pub fn unreserve(&mut self, addr: usize, len: usize) {
+        self.check_range(addr, len);
+
+        for page in (addr..addr + len).step_by(SIZE_64K) {
+            self.page_free(page as *mut u8);
+        }
+    }
+
+    /// Grow the heap to `[start, new_end)` so that the newly-covered pages can be allocated.
+    /// This is used when more memory is given at runtime, e.g. by a balloon driver.
+    ///
+    /// Pages at or beyond `end` are never marked as allocated,
+    /// so their bits are already clear and need no update.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `new_end` is not 64KiB aligned, is not greater than `end`,
+    /// or exceeds the 16GiB limit from `start`.
+    pub fn extend(&mut self, new_end: usize) {
+        if new_end & 0xFFFF != 0 {
+            panic!("unaligned address");
+        }
+
+        if new_end <= self.end {
+            panic!("not greater than the current end");
+        }
+
+        if new_end - self.start > 64 * 64 * 64 * SIZE_64K {
+            panic!("too large");
+        }
+
+        self.end = new_end;
+    }
+
+    fn check_range(&self, addr: usize, len: usize) {
+        if addr & 0xFFFF != 0 || len & 0xFFFF != 0 {
+            panic!("unaligned range");
+        }
+
+        if addr < self.start || addr + len > self.end {
+            panic!("out of range");
+        }
+    }
+
+    /// Return the indices of `book`, `pages`, and the bit of the page at `addr`.
+    /// Indices are relative to `start`, same as `page_alloc`.
+    fn indices(&self, addr: usize) -> (usize, usize, usize) {
+        let offset = addr - self.start;
+        let idx1 = (offset >> 28) & 0b111111;
+        let idx2 = (offset >> 22) & 0b111111;
+        let idx3 = (offset >> 16) & 0b111111;
+        (idx1, idx2, idx3)
+    }
 }
 
 impl MemAlloc for PageManager {
     fn alloc(&mut self, size: usize) -> Option<*mut u8> {
         if size > SIZE_64K {
-            None
+            self.page_alloc_contiguous(size.div_ceil(SIZE_64K))
         } else {
             self.page_alloc()
         }
     }
 
+    fn set_seed(&mut self, seed: u64) {
+        PageManager::set_seed(self, seed)
+    }
+
+    fn free_all(&mut self) {
+        self.clear()
+    }
+
+    /// The block grows if enough pages following it are free.
+    /// A single page becomes a run, which takes one of `MAX_SPANS` slots.
+    fn grow_in_place(&mut self, addr: *mut u8, new_size: usize) -> bool {
+        let addr = addr as usize;
+        if addr & 0xFFFF != 0 || addr < self.start || addr >= self.end {
+            return false;
+        }
+
+        let span = self.spans.iter().position(|(a, _)| *a == addr);
+        let pages = span.map_or(1, |span| self.spans[span].1);
+        let new_pages = new_size.div_ceil(SIZE_64K);
+        if new_pages <= pages {
+            return true;
+        }
+
+        let Some(slot) = span.or_else(|| self.spans.iter().position(|(a, _)| *a == 0)) else {
+            return false;
+        };
+
+        // pages to be taken must be in the heap and free
+        let end = addr + pages * SIZE_64K;
+        let Some(new_end) = addr.checked_add(new_pages * SIZE_64K) else {
+            return false;
+        };
+        if new_end > self.end {
+            return false;
+        }
+
+        for page in (end..new_end).step_by(SIZE_64K) {
+            let (idx1, idx2, idx3) = self.indices(page);
+            if self.book[idx1].pages[idx2] & (1 << (63 - idx3)) != 0 {
+                return false;
+            }
+        }
+
+        for page in (end..new_end).step_by(SIZE_64K) {
+            self.set_used(page);
+        }
+        self.spans[slot] = (addr, new_pages);
+
+        true
+    }
+
     fn free(&mut self, addr: *mut u8) {
-        self.page_free(addr)
+        let span = self.spans.iter().position(|(a, _)| *a == addr as usize);
+        if let Some(span) = span {
+            let (addr, pages) = self.spans[span];
+            for page in (addr..addr + pages * SIZE_64K).step_by(SIZE_64K) {
+                self.page_free(page as *mut u8);
+            }
+            self.spans[span] = (0, 0);
+        } else {
+            self.page_free(addr)
+        }
+    }
+
+    fn range(&self) -> (usize, usize) {
+        (self.start, self.end)
+    }
+
+    /// A run of pages allocated at once is reported as a block.
+    fn for_each_used(&self, f: &mut dyn FnMut(usize, usize)) {
+        let num_pages = (self.end - self.start) / SIZE_64K;
+        let mut idx = 0;
+        while idx < num_pages {
+            let word = self.book[idx >> 12].pages[(idx >> 6) & 63];
+            if word & (1 << (63 - (idx & 63))) == 0 {
+                idx += 1;
+                continue;
+            }
+
+            let addr = self.start + idx * SIZE_64K;
+            let pages = self
+                .spans
+                .iter()
+                .find(|(a, _)| *a == addr)
+                .map_or(1, |(_, pages)| *pages);
+            f(addr, pages * SIZE_64K);
+            idx += pages;
+        }
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.free_page_count() * SIZE_64K
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.find_free_run(usize::MAX).1 * SIZE_64K
+    }
+
+    fn check_size(size: usize) -> Result<(), InitError> {
+        if !size.is_multiple_of(SIZE_64K) {
+            return Err(InitError::NotMultiple {
+                align: SIZE_64K,
+                got: size,
+            });
+        }
+
+        let max = 64 * 64 * 64 * SIZE_64K;
+        if size > max {
+            return Err(InitError::TooLarge { max, got: size });
+        }
+
+        Ok(())
     }
 
     fn new(start_addr: usize, size: usize) -> Self {
         assert_eq!(size % SIZE_64K, 0);
 
+        let Some(end) = start_addr.checked_add(size) else {
+            panic!("address overflow");
+        };
+
         PageManager {
             start: start_addr,
-            end: start_addr + size,
+            end,
             vacancy_books: 0,
             vacancy_pages: [0; 64],
             book: [Book { pages: [0; 64] }; 64],
+            used_pages: 0,
+            round_robin: false,
+            last_book: 63,
+            spans: [(0, 0); MAX_SPANS],
+            nodes: [(0, 0); MAX_NODES],
+            num_nodes: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::PageManager;
+    use crate::{MemAlloc, SIZE_64K};
+
+    const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+    fn init() -> (PageManager, *mut u8) {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        (PageManager::new(ptr as usize, HEAP_SIZE), ptr)
+    }
+
+    fn free(ptr: *mut u8) {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        unsafe { std::alloc::dealloc(ptr, layout) };
+    }
+
+    #[test]
+    fn test_reserve() {
+        let (mut pager, ptr) = init();
+        let num_pages = HEAP_SIZE / SIZE_64K;
+        let reserved = ptr as usize + SIZE_64K * 100;
+
+        pager.reserve(reserved, SIZE_64K);
+        assert_eq!(pager.free_page_count(), num_pages - 1);
+
+        let mut v = std::vec::Vec::new();
+        while let Some(mem) = pager.page_alloc() {
+            assert_ne!(mem as usize, reserved);
+            v.push(mem as usize);
+        }
+
+        // neighbors are allocated
+        assert_eq!(v.len(), num_pages - 1);
+        assert!(v.contains(&(reserved - SIZE_64K)));
+        assert!(v.contains(&(reserved + SIZE_64K)));
+
+        for mem in v {
+            pager.page_free(mem as *mut u8);
+        }
+
+        pager.unreserve(reserved, SIZE_64K);
+        assert_eq!(pager.free_page_count(), num_pages);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_verify() {
+        let (mut pager, ptr) = init();
+        assert_eq!(pager.verify(), Ok(()));
+
+        // fill `book[0].pages[0]` and `book[0].pages[1]`
+        let mut v = std::vec::Vec::new();
+        for _ in 0..128 {
+            v.push(pager.page_alloc().unwrap());
+            assert_eq!(pager.verify(), Ok(()));
+        }
+
+        // `book[0].pages[1]` is full but its bit is cleared
+        pager.vacancy_pages[0] &= !(1 << 62);
+        assert_eq!(pager.verify(), Err(1));
+        pager.vacancy_pages[0] |= 1 << 62;
+
+        // `book[0].pages[2]` is not full but its bit is set
+        pager.vacancy_pages[0] |= 1 << 61;
+        assert_eq!(pager.verify(), Err(2));
+        pager.vacancy_pages[0] &= !(1 << 61);
+
+        // `vacancy_pages[1]` is not full but the bit of the book is set
+        pager.vacancy_books |= 1 << 62;
+        assert_eq!(pager.verify(), Err(64 * 64 + 1));
+        pager.vacancy_books &= !(1 << 62);
+
+        for mem in v {
+            pager.page_free(mem);
+            assert_eq!(pager.verify(), Ok(()));
+        }
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_grow_in_place() {
+        let (mut pager, ptr) = init();
+        let num_pages = HEAP_SIZE / SIZE_64K;
+
+        let a = pager.page_alloc().unwrap();
+        let b = pager.alloc(SIZE_64K * 2).unwrap();
+        let c = pager.page_alloc().unwrap();
+        pager.free(b);
+
+        // a single page grows into the hole of `b`, but not beyond it
+        assert!(pager.grow_in_place(a, SIZE_64K * 3));
+        assert!(!pager.grow_in_place(a, SIZE_64K * 3 + 1));
+        assert!(pager.grow_in_place(a, SIZE_64K * 2));
+        assert_eq!(pager.free_page_count(), num_pages - 4);
+        assert_eq!(pager.verify(), Ok(()));
+
+        // `c` grows to the end of the heap
+        assert!(pager.grow_in_place(c, HEAP_SIZE - SIZE_64K * 3));
+        assert!(!pager.grow_in_place(c, HEAP_SIZE - SIZE_64K * 2));
+        assert_eq!(pager.free_page_count(), 0);
+
+        // the grown runs are freed at once
+        pager.free(a);
+        pager.free(c);
+        assert_eq!(pager.free_page_count(), num_pages);
+        assert_eq!(pager.verify(), Ok(()));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_high_address() {
+        // the heap ends right below the top of the address space
+        let size = SIZE_64K * 3;
+        let start = 0usize.wrapping_sub(SIZE_64K * 4);
+        let mut pager = PageManager::new(start, size);
+
+        for i in 0..3 {
+            assert_eq!(pager.page_alloc(), Some((start + SIZE_64K * i) as *mut u8));
+        }
+        assert_eq!(pager.page_alloc(), None);
+
+        pager.page_free(start as *mut u8);
+        assert_eq!(pager.page_alloc(), Some(start as *mut u8));
+    }
+
+    #[test]
+    fn test_clear() {
+        // Pages are never touched, so a heap of 2 books need not be mapped.
+        const BOOK: usize = 64 * 64 * SIZE_64K;
+        let start = 1 << 40;
+        let mut pager = PageManager::new(start, BOOK * 2);
+        let num = BOOK * 2 / SIZE_64K;
+
+        let mut v = std::vec::Vec::new();
+        for _ in 0..num - 10 {
+            v.push(pager.page_alloc().unwrap() as usize);
+        }
+        let run = pager.page_alloc_contiguous(4).unwrap() as usize;
+        v.extend((run..run + 4 * SIZE_64K).step_by(SIZE_64K));
+
+        let mut unmapped = std::vec::Vec::new();
+        pager.clear_with(&mut |addr| unmapped.push(addr));
+        assert_eq!(unmapped, v);
+        assert_eq!(pager.free_page_count(), num);
+        assert_eq!(pager.verify(), Ok(()));
+
+        // all the pages can be allocated again, including runs
+        assert_eq!(pager.page_alloc_contiguous(8), Some(start as *mut u8));
+        let mut count = 8;
+        while pager.page_alloc().is_some() {
+            count += 1;
+        }
+        assert_eq!(count, num);
+
+        pager.clear();
+        assert_eq!(pager.free_page_count(), num);
+
+        pager.page_alloc_contiguous(3).unwrap();
+        MemAlloc::free_all(&mut pager);
+        assert_eq!(pager.free_page_count(), num);
+        assert_eq!(pager.page_alloc(), Some(start as *mut u8));
+    }
+
+    #[test]
+    fn test_nodes() {
+        // Pages are never touched, so a heap of 2 books need not be mapped.
+        const BOOK: usize = 64 * 64 * SIZE_64K;
+        let start = 1 << 40;
+        let mut pager = PageManager::new(start, BOOK * 2);
+
+        // the boundary of the nodes is not at a book boundary
+        let boundary = start + BOOK + 16 * SIZE_64K;
+        pager.set_nodes(&[(start, boundary), (boundary, start + BOOK * 2)]);
+
+        let nodes = [start..boundary, boundary..start + BOOK * 2];
+        let mut num = 0;
+        while let Some((addr, local)) = pager.page_alloc_on_node(1) {
+            if !local {
+                assert!(nodes[0].contains(&(addr as usize)));
+                break;
+            }
+            assert!(nodes[1].contains(&(addr as usize)));
+            num += 1;
         }
+        assert_eq!(num, BOOK / SIZE_64K - 16);
+
+        // node 0 is still available
+        let (addr, local) = pager.page_alloc_on_node(0).unwrap();
+        assert!(local);
+        assert!(nodes[0].contains(&(addr as usize)));
+
+        // a page freed to node 1 is taken again
+        pager.page_free(boundary as *mut u8);
+        assert_eq!(
+            pager.page_alloc_on_node(1),
+            Some((boundary as *mut u8, true))
+        );
+    }
+
+    #[test]
+    fn test_round_robin() {
+        // Pages are never touched, so a heap of 3 books and a page need not be mapped.
+        const BOOK: usize = 64 * 64 * SIZE_64K;
+        let start = 1 << 40;
+        let mut pager = PageManager::new(start, BOOK * 3 + SIZE_64K);
+        pager.set_round_robin(true);
+
+        let mut v = std::vec::Vec::new();
+        for _ in 0..8 {
+            v.push(pager.page_alloc().unwrap() as usize);
+        }
+
+        // spread over all the books, and the last book has a single page
+        let mut counts = [0; 4];
+        for addr in v.iter() {
+            counts[(addr - start) / BOOK] += 1;
+        }
+        assert_eq!(counts, [3, 2, 2, 1]);
+        assert_eq!(v[3], start + BOOK * 3);
+        assert_eq!(v[7], start + 2 * SIZE_64K);
+
+        assert_eq!(
+            pager.page_alloc(),
+            Some((start + BOOK + 2 * SIZE_64K) as *mut u8)
+        );
+        assert_eq!(
+            pager.page_alloc(),
+            Some((start + BOOK * 2 + 2 * SIZE_64K) as *mut u8)
+        );
+
+        // a freed page is reused when the cursor reaches its book
+        pager.page_free(v[3] as *mut u8);
+        assert_eq!(pager.page_alloc(), Some(v[3] as *mut u8));
+
+        // all the pages can be allocated
+        let mut num = 10;
+        while pager.page_alloc().is_some() {
+            num += 1;
+        }
+        assert_eq!(num, 64 * 64 * 3 + 1);
+        assert_eq!(pager.free_page_count(), 0);
+
+        // the seed chooses the first book of the scan
+        let mut pager = PageManager::new(start, BOOK * 3 + SIZE_64K);
+        pager.set_round_robin(true);
+        pager.set_seed(6);
+        assert_eq!(pager.page_alloc(), Some((start + BOOK * 2) as *mut u8));
+        assert_eq!(pager.page_alloc(), Some((start + BOOK * 3) as *mut u8));
+        assert_eq!(pager.page_alloc(), Some(start as *mut u8));
+    }
+
+    #[test]
+    fn test_contiguous() {
+        let (mut pager, ptr) = init();
+        let num_pages = HEAP_SIZE / SIZE_64K;
+        let start = ptr as usize;
+
+        // leave holes of 1 and 2 pages
+        let a = pager.page_alloc().unwrap();
+        let b = pager.page_alloc().unwrap();
+        let c = pager.page_alloc().unwrap();
+        let d = pager.page_alloc().unwrap();
+        pager.free(b);
+        pager.free(d);
+        assert_eq!(pager.largest_free_block(), (num_pages - 3) * SIZE_64K);
+
+        // the hole of `d` and pages after it
+        let run = pager.alloc(SIZE_64K * 3 + 1).unwrap();
+        assert_eq!(run, d);
+        assert_eq!(pager.free_page_count(), num_pages - 2 - 4);
+
+        let mut used = std::vec::Vec::new();
+        pager.for_each_used(&mut |addr, size| used.push((addr, size)));
+        assert_eq!(
+            used,
+            [
+                (a as usize, SIZE_64K),
+                (c as usize, SIZE_64K),
+                (run as usize, SIZE_64K * 4)
+            ]
+        );
+
+        // the whole run is freed
+        pager.free(run);
+        assert_eq!(pager.free_page_count(), num_pages - 2);
+        assert_eq!(pager.alloc(HEAP_SIZE - SIZE_64K * 3), Some(d));
+        assert_eq!(pager.alloc(SIZE_64K * 2), None);
+        assert_eq!(pager.page_alloc(), Some(b));
+        assert_eq!(pager.largest_free_block(), 0);
+        assert_eq!(start, a as usize);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_max_spans() {
+        let (mut pager, ptr) = init();
+
+        let mut v = std::vec::Vec::new();
+        for _ in 0..super::MAX_SPANS {
+            v.push(pager.alloc(SIZE_64K * 2).unwrap());
+        }
+        assert_eq!(pager.alloc(SIZE_64K * 2), None);
+
+        // single pages are not limited
+        assert!(pager.alloc(SIZE_64K).is_some());
+
+        pager.free(v[0]);
+        assert_eq!(pager.alloc(SIZE_64K * 2), Some(v[0]));
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_extend() {
+        let (_, ptr) = init();
+        let start = ptr as usize;
+
+        // use the first 4 pages only
+        let mut pager = PageManager::new(start, SIZE_64K * 4);
+        for _ in 0..4 {
+            assert!(pager.page_alloc().is_some());
+        }
+        assert_eq!(pager.page_alloc(), None);
+        assert_eq!(pager.free_page_count(), 0);
+
+        pager.extend(start + SIZE_64K * 6);
+        assert_eq!(pager.free_page_count(), 2);
+        assert_eq!(pager.range(), (start, start + SIZE_64K * 6));
+
+        for i in 4..6 {
+            assert_eq!(pager.page_alloc(), Some((start + SIZE_64K * i) as *mut u8));
+        }
+        assert_eq!(pager.page_alloc(), None);
+
+        // pages freed before extending are reused first
+        pager.page_free(start as *mut u8);
+        pager.extend(start + HEAP_SIZE);
+        assert_eq!(pager.page_alloc(), Some(start as *mut u8));
+        assert_eq!(pager.page_alloc(), Some((start + SIZE_64K * 6) as *mut u8));
+
+        free(ptr);
+    }
+
+    #[test]
+    #[should_panic(expected = "not greater than the current end")]
+    fn test_extend_shrink() {
+        let mut pager = PageManager::new(SIZE_64K * 16, SIZE_64K * 4);
+        pager.extend(SIZE_64K * 18);
+    }
+
+    #[test]
+    #[should_panic(expected = "address overflow")]
+    fn test_overflow() {
+        PageManager::new(0usize.wrapping_sub(SIZE_64K), SIZE_64K * 2);
     }
 }
@@ -0,0 +1,225 @@
+use crate::{InitError, MemAlloc, SIZE_64K};
+
+/// A page allocator chaining up to `N` allocators of disjoint regions,
+/// e.g. usable RAM ranges of the memory map which are not contiguous.
+///
+/// The first region is given by `new`, and the others are added by `add_region`.
+/// `alloc` tries each region in the order of addition,
+/// and `free` routes an address to the region containing it.
+///
+/// ```
+/// use memac::{Allocator, pager::PageManager, regions::Regions};
+///
+/// let size = 4 * 1024 * 1024;
+/// let layout = std::alloc::Layout::from_size_align(size, memac::ALIGNMENT).unwrap();
+/// let ptr1 = unsafe { std::alloc::alloc(layout) };
+/// let ptr2 = unsafe { std::alloc::alloc(layout) };
+///
+/// let mut alloc = Allocator::<Regions<PageManager, 4>>::new();
+/// alloc.init(ptr1 as usize, size);
+/// alloc.add_region(ptr2 as usize, size);
+/// ```
+pub struct Regions<P: MemAlloc, const N: usize> {
+    regions: [Option<P>; N],
+}
+
+impl<P: MemAlloc, const N: usize> Regions<P, N> {
+    /// Return the allocator of the `i`-th region in the order of addition.
+    pub fn region(&self, i: usize) -> Option<&P> {
+        self.regions.get(i)?.as_ref()
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &P> {
+        self.regions.iter().flatten()
+    }
+
+    /// Return the region containing `addr`.
+    fn region_mut(&mut self, addr: *mut u8) -> Option<&mut P> {
+        self.regions.iter_mut().flatten().find(|region| {
+            let (start, end) = region.range();
+            start <= addr as usize && (addr as usize) < end
+        })
+    }
+}
+
+impl<P: MemAlloc, const N: usize> MemAlloc for Regions<P, N> {
+    fn alloc(&mut self, size: usize) -> Option<*mut u8> {
+        self.regions
+            .iter_mut()
+            .flatten()
+            .find_map(|region| region.alloc(size))
+    }
+
+    fn alloc_aligned(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        self.regions
+            .iter_mut()
+            .flatten()
+            .find_map(|region| region.alloc_aligned(size, align))
+    }
+
+    /// A region added later may be aligned less than the current ones,
+    /// so only the alignment of pages is guaranteed.
+    fn max_alignment(&self) -> usize {
+        SIZE_64K
+    }
+
+    fn free(&mut self, addr: *mut u8) {
+        let Some(region) = self.region_mut(addr) else {
+            panic!("invalid address");
+        };
+
+        region.free(addr)
+    }
+
+    fn grow_in_place(&mut self, addr: *mut u8, new_size: usize) -> bool {
+        self.region_mut(addr)
+            .is_some_and(|region| region.grow_in_place(addr, new_size))
+    }
+
+    fn free_all(&mut self) {
+        for region in self.regions.iter_mut().flatten() {
+            region.free_all();
+        }
+    }
+
+    /// Each region is seeded with `seed`.
+    fn set_seed(&mut self, seed: u64) {
+        for region in self.regions.iter_mut().flatten() {
+            region.set_seed(seed);
+        }
+    }
+
+    /// Each region must satisfy the requirement of `P`.
+    fn check_size(size: usize) -> Result<(), InitError> {
+        P::check_size(size)
+    }
+
+    fn new(start_addr: usize, size: usize) -> Self {
+        let mut regions = [const { None }; N];
+        regions[0] = Some(P::new(start_addr, size));
+        Regions { regions }
+    }
+
+    fn add_region(&mut self, start_addr: usize, size: usize) -> Result<(), InitError> {
+        P::check_size(size)?;
+
+        let end = start_addr.checked_add(size).ok_or(InitError::TooLarge {
+            max: usize::MAX - start_addr,
+            got: size,
+        })?;
+        for region in self.iter() {
+            let (s, e) = region.range();
+            if start_addr < e && s < end {
+                return Err(InitError::Overlapped { start: start_addr });
+            }
+        }
+
+        let Some(slot) = self.regions.iter_mut().find(|region| region.is_none()) else {
+            return Err(InitError::TooManyRegions { max: N });
+        };
+
+        *slot = Some(P::new(start_addr, size));
+        Ok(())
+    }
+
+    /// Return the smallest range covering all the regions, including gaps between them.
+    fn range(&self) -> (usize, usize) {
+        self.iter()
+            .map(|region| region.range())
+            .reduce(|(s1, e1), (s2, e2)| (s1.min(s2), e1.max(e2)))
+            .unwrap_or((0, 0))
+    }
+
+    fn largest_free_block(&self) -> usize {
+        self.iter()
+            .map(|region| region.largest_free_block())
+            .max()
+            .unwrap_or(0)
+    }
+
+    fn for_each_used(&self, f: &mut dyn FnMut(usize, usize)) {
+        for region in self.iter() {
+            region.for_each_used(f);
+        }
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.iter().map(|region| region.free_bytes()).sum()
+    }
+
+    fn page_alignment(&self) -> usize {
+        self.iter()
+            .map(|region| region.page_alignment())
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::Regions;
+    use crate::{pager::PageManager, InitError, MemAlloc, SIZE_64K};
+
+    const REGION_SIZE: usize = 4 * 1024 * 1024;
+
+    #[test]
+    fn test_regions() {
+        let layout = std::alloc::Layout::from_size_align(REGION_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr1 = unsafe { std::alloc::alloc(layout) };
+        let ptr2 = unsafe { std::alloc::alloc(layout) };
+
+        let mut alloc = Regions::<PageManager, 2>::new(ptr1 as usize, REGION_SIZE);
+        assert_eq!(
+            alloc.add_region(ptr1 as usize + SIZE_64K, SIZE_64K),
+            Err(InitError::Overlapped {
+                start: ptr1 as usize + SIZE_64K
+            })
+        );
+        alloc.add_region(ptr2 as usize, REGION_SIZE).unwrap();
+        assert_eq!(
+            alloc.add_region(0, SIZE_64K),
+            Err(InitError::TooManyRegions { max: 2 })
+        );
+        assert_eq!(alloc.free_bytes(), REGION_SIZE * 2);
+
+        let in_region = |ptr: *mut u8, region: *mut u8| {
+            (region as usize..region as usize + REGION_SIZE).contains(&(ptr as usize))
+        };
+
+        // the first region is exhausted, and then allocations spill into the second
+        let mut v = std::vec::Vec::new();
+        for i in 0..REGION_SIZE / SIZE_64K * 2 {
+            let mem = alloc.alloc(SIZE_64K).unwrap();
+            let region = if i < REGION_SIZE / SIZE_64K {
+                ptr1
+            } else {
+                ptr2
+            };
+            assert!(in_region(mem, region));
+            v.push(mem);
+        }
+        assert!(alloc.alloc(SIZE_64K).is_none());
+
+        // a page freed to the first region is reused
+        let mem = v.swap_remove(0);
+        alloc.free(mem);
+        assert_eq!(alloc.alloc(SIZE_64K), Some(mem));
+        v.push(mem);
+
+        for mem in v {
+            alloc.free(mem);
+        }
+        assert_eq!(alloc.free_bytes(), REGION_SIZE * 2);
+        assert_eq!(
+            alloc.region(1).unwrap().free_page_count(),
+            REGION_SIZE / SIZE_64K
+        );
+
+        unsafe {
+            std::alloc::dealloc(ptr1, layout);
+            std::alloc::dealloc(ptr2, layout);
+        }
+    }
+}
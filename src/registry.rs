@@ -0,0 +1,183 @@
+//! A registry of live pointers to detect double-free and freeing unknown pointers.
+//!
+//! The registry is an open-addressed hash set with linear probing.
+//! Its table is allocated from the page allocator at initialization,
+//! and is split into 64KiB pages because some backends, e.g. `FreeListPager`,
+//! cannot allocate contiguous pages.
+//! The table doubles before it becomes 3/4 full,
+//! so it is limited only by the pages the backend can give.
+
+use crate::{MemAlloc, SIZE_64K};
+
+/// Number of table entries per 64KiB page of the heap.
+const ENTRIES_PER_HEAP_PAGE: usize = 64;
+
+/// Number of entries in a 64KiB page of the table.
+const ENTRIES_PER_TABLE_PAGE: usize = SIZE_64K / core::mem::size_of::<usize>();
+
+/// Maximum number of pages of the table, whose addresses fill a 64KiB page.
+const MAX_TABLE_PAGES: usize = SIZE_64K / core::mem::size_of::<*mut usize>();
+
+pub(crate) struct Registry {
+    pages: *mut *mut usize, // a page of the addresses of the table pages
+    mask: usize,            // number of entries - 1, where address 0 means an empty entry
+    len: usize,             // number of live pointers
+}
+
+impl Registry {
+    /// Allocate the table for a heap of `size` bytes from `page_alloc`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the table cannot be allocated.
+    pub(crate) fn new(page_alloc: &mut impl MemAlloc, size: usize) -> Self {
+        let capacity = (size / SIZE_64K * ENTRIES_PER_HEAP_PAGE)
+            .next_power_of_two()
+            .clamp(
+                ENTRIES_PER_TABLE_PAGE,
+                ENTRIES_PER_TABLE_PAGE * MAX_TABLE_PAGES,
+            );
+
+        let Some(registry) = Self::with_capacity(page_alloc, capacity) else {
+            panic!("failed to allocate the debug registry");
+        };
+
+        registry
+    }
+
+    /// Allocate an empty table of `capacity` entries, which is a multiple of a table page,
+    /// or return `None` without leaking pages if the backend runs out of them.
+    fn with_capacity(page_alloc: &mut impl MemAlloc, capacity: usize) -> Option<Self> {
+        let pages = page_alloc.alloc(SIZE_64K)? as *mut *mut usize;
+        let registry = Registry {
+            pages,
+            mask: capacity - 1,
+            len: 0,
+        };
+
+        for i in 0..registry.num_pages() {
+            let Some(ptr) = page_alloc.alloc(SIZE_64K) else {
+                registry.free_pages(page_alloc, i);
+                return None;
+            };
+
+            unsafe {
+                core::ptr::write_bytes(ptr, 0, SIZE_64K);
+                *pages.add(i) = ptr as *mut usize;
+            }
+        }
+
+        Some(registry)
+    }
+
+    fn num_pages(&self) -> usize {
+        (self.mask + 1) / ENTRIES_PER_TABLE_PAGE
+    }
+
+    /// Return the first `n` pages of the table and the page of their addresses to `page_alloc`.
+    fn free_pages(&self, page_alloc: &mut impl MemAlloc, n: usize) {
+        for i in 0..n {
+            page_alloc.free(unsafe { *self.pages.add(i) } as *mut u8);
+        }
+        page_alloc.free(self.pages as *mut u8);
+    }
+
+    /// Make room for another live pointer, doubling the table if it is 3/4 full,
+    /// and return `false` if the table cannot grow, in which case no pointer may be inserted.
+    pub(crate) fn reserve(&mut self, page_alloc: &mut impl MemAlloc) -> bool {
+        if self.len < (self.mask + 1) / 4 * 3 {
+            return true;
+        }
+
+        if self.num_pages() * 2 > MAX_TABLE_PAGES {
+            return false;
+        }
+
+        let Some(mut table) = Self::with_capacity(page_alloc, (self.mask + 1) * 2) else {
+            return false;
+        };
+
+        for idx in 0..=self.mask {
+            let addr = *self.entry(idx);
+            if addr != 0 {
+                table.insert(addr);
+            }
+        }
+
+        self.free_pages(page_alloc, self.num_pages());
+        *self = table;
+        true
+    }
+
+    fn hash(&self, addr: usize) -> usize {
+        // Fibonacci hashing; the upper bits are well mixed.
+        let h = (addr as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        (h >> 32) as usize & self.mask
+    }
+
+    fn entry(&mut self, idx: usize) -> &mut usize {
+        let page = unsafe { *self.pages.add(idx / ENTRIES_PER_TABLE_PAGE) };
+        unsafe { &mut *page.add(idx % ENTRIES_PER_TABLE_PAGE) }
+    }
+
+    /// Register `addr` as live.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `addr` is already live or the table is 3/4 full,
+    /// which `reserve` prevents.
+    pub(crate) fn insert(&mut self, addr: usize) {
+        if self.len >= (self.mask + 1) / 4 * 3 {
+            panic!("debug registry is full");
+        }
+
+        let mut idx = self.hash(addr);
+        loop {
+            let entry = self.entry(idx);
+            if *entry == 0 {
+                *entry = addr;
+                self.len += 1;
+                return;
+            } else if *entry == addr {
+                panic!("allocated a live pointer: {addr:#x}");
+            }
+            idx = (idx + 1) & self.mask;
+        }
+    }
+
+    /// Unregister `addr`, and return `false` if it is not live.
+    pub(crate) fn remove(&mut self, addr: usize) -> bool {
+        let mut idx = self.hash(addr);
+        loop {
+            let entry = *self.entry(idx);
+            if entry == 0 {
+                return false;
+            } else if entry == addr {
+                break;
+            }
+            idx = (idx + 1) & self.mask;
+        }
+
+        // Backward shift deletion: move following entries of the cluster
+        // into the hole unless it precedes their home slot.
+        let mut hole = idx;
+        let mut next = (hole + 1) & self.mask;
+        loop {
+            let entry = *self.entry(next);
+            if entry == 0 {
+                break;
+            }
+
+            let home = self.hash(entry);
+            if (next.wrapping_sub(home) & self.mask) >= (next.wrapping_sub(hole) & self.mask) {
+                *self.entry(hole) = entry;
+                hole = next;
+            }
+            next = (next + 1) & self.mask;
+        }
+
+        *self.entry(hole) = 0;
+        self.len -= 1;
+        true
+    }
+}
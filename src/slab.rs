@@ -1,44 +1,167 @@
-use crate::{MemAlloc, SIZE_64K};
+use crate::{MemAlloc, MAX_EXTERNAL_SLABS, SIZE_64K};
 use core::ptr::null_mut;
 
 pub(crate) const MAX_SLAB_SIZE: usize = 65512 - 8;
 
+pub(crate) const NUM_CLASSES: usize = 13;
+
+/// Sizes of the slab classes.
+pub(crate) const CLASS_SIZES: [usize; NUM_CLASSES] = [
+    16, 32, 64, 128, 256, 512, 1024, 2040, 4088, 8184, 16376, 32752, 65512,
+];
+
+pub(crate) const CACHE_LINE: usize = 64;
+
+/// Usable sizes of the slab classes.
+pub(crate) const USABLE_SIZES: [usize; NUM_CLASSES] = {
+    let mut sizes = [0; NUM_CLASSES];
+    let mut i = 0;
+    while i < NUM_CLASSES {
+        sizes[i] = usable_size(CLASS_SIZES[i]);
+        i += 1;
+    }
+    sizes
+};
+
+/// Return the index of the smallest class whose usable size is at least `size`.
+const fn smallest_class(size: usize) -> u8 {
+    let mut i = 0;
+    while USABLE_SIZES[i] < size {
+        i += 1;
+    }
+    i as u8
+}
+
+/// Class indices of sizes up to 1016 bytes in buckets of 8 bytes, i.e. `(size + 7) / 8`.
+/// Boundaries of the small classes are multiples of 8,
+/// so all the sizes in a bucket are served by the same class.
+const SMALL_INDEX: [u8; 128] = {
+    let mut table = [0; 128];
+    let mut i = 0;
+    while i < 128 {
+        table[i] = smallest_class(i * 8);
+        i += 1;
+    }
+    table
+};
+
+/// Class indices of sizes larger than 1016 bytes in buckets of 2KiB, i.e. `(size - 1) / 2048`,
+/// which are the classes of the smallest sizes in the buckets.
+/// A bucket contains at most one boundary of the large classes,
+/// so the next class serves sizes beyond the usable size of the class.
+const LARGE_INDEX: [u8; 32] = {
+    let mut table = [0; 32];
+    let mut i = 0;
+    while i < 32 {
+        let size = i * 2048 + 1;
+        table[i] = smallest_class(if size < 1017 { 1017 } else { size });
+        i += 1;
+    }
+    table
+};
+
+/// Return the index of the slab class which serves a request of `size` bytes.
+///
+/// Classes up to 1024 bytes spend 8 bytes for the header,
+/// so a request of `size` bytes needs a class of at least `size + 8` bytes.
+/// The class is looked up from precomputed tables
+/// instead of a cascade of comparisons, which is branchy on the hot path.
+#[inline]
+pub(crate) fn class_index(size: usize) -> Option<usize> {
+    if size <= 1024 - 8 {
+        Some(SMALL_INDEX[(size + 7) >> 3] as usize)
+    } else if size <= MAX_SLAB_SIZE {
+        let idx = LARGE_INDEX[(size - 1) >> 11] as usize;
+        Some(idx + (size > USABLE_SIZES[idx]) as usize)
+    } else {
+        None
+    }
+}
+
+/// Return the size of the slab class which serves a request of `size` bytes.
+#[cfg(test)]
+pub(crate) fn class_size(size: usize) -> Option<usize> {
+    class_index(size).map(|idx| CLASS_SIZES[idx])
+}
+
+/// Return the number of bytes available to the caller in an object of `class_size`.
+/// Small classes and Slab65512 spend 8 bytes for the header, and large classes spend 16 bytes.
+pub(crate) const fn usable_size(class_size: usize) -> usize {
+    match class_size {
+        16..=1024 | 65512 => class_size - 8,
+        _ => class_size - 16,
+    }
+}
+
+/// Statistics of a slab class.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ClassStats {
+    pub(crate) class_size: usize,
+    pub(crate) partial: usize,  // number of partial slabs
+    pub(crate) full: usize,     // number of full slabs
+    pub(crate) live: usize,     // number of live objects
+    pub(crate) capacity: usize, // number of objects all the slabs can hold
+}
+
+/// Maximum number of pages waiting to be unmapped in the `defer_unmap` mode.
+pub(crate) const UNMAP_QUEUE_LEN: usize = 32;
+
 pub(crate) struct SlabAllocator<PAGEALLOC: MemAlloc> {
     pub(crate) page_alloc: PAGEALLOC,
 
-    slab16_partial: *mut Slab16,
-    slab32_partial: *mut Slab32,
-    slab64_partial: *mut Slab64,
-    slab128_partial: *mut Slab128,
-    slab256_partial: *mut Slab256,
-    slab512_partial: *mut Slab512,
-    slab1024_partial: *mut Slab1024,
-    slab2040_partial: *mut Slab2040,
-    slab4088_partial: *mut Slab4088,
-    slab8184_partial: *mut Slab8184,
-    slab16376_partial: *mut Slab16376,
-    slab32752_partial: *mut Slab32752,
-    slab65512_partial: *mut Slab65512, // always null
-
-    slab16_full: *mut Slab16,
-    slab32_full: *mut Slab32,
-    slab64_full: *mut Slab64,
-    slab128_full: *mut Slab128,
-    slab256_full: *mut Slab256,
-    slab512_full: *mut Slab512,
-    slab1024_full: *mut Slab1024,
-    slab2040_full: *mut Slab2040,
-    slab4088_full: *mut Slab4088,
-    slab8184_full: *mut Slab8184,
-    slab16376_full: *mut Slab16376,
-    slab32752_full: *mut Slab32752,
-    slab65512_full: *mut Slab65512,
+    pub(crate) live_bytes: usize,      // bytes currently allocated
+    pub(crate) peak_bytes: usize,      // high-water mark of live_bytes
+    pub(crate) live_count: usize,      // number of live allocations
+    pub(crate) large_bytes: usize,     // bytes allocated directly from page_alloc
+    pub(crate) slab_free_bytes: usize, // usable bytes of free slots in existing slabs
+
+    #[cfg(feature = "track")]
+    pub(crate) tracker: crate::track::Tracker,
+
+    #[cfg(feature = "debug_registry")]
+    pub(crate) registry: crate::registry::Registry,
+
+    pub(crate) mapf: fn(usize, usize), // called with each page taken for a new slab
+    #[cfg(feature = "trace")]
+    pub(crate) trace: fn(crate::TraceEvent),
+
+    pub(crate) cache_coloring: bool,
+    colors: [usize; NUM_CLASSES], // next color of each class
+
+    #[cfg(feature = "stats")]
+    pub(crate) alloc_count: [u64; NUM_CLASSES], // number of allocations of each class
+    #[cfg(feature = "stats")]
+    pub(crate) free_count: [u64; NUM_CLASSES], // number of deallocations of each class
+    #[cfg(feature = "stats")]
+    pub(crate) histogram_base: [u64; NUM_CLASSES], // `alloc_count` at the last reset of the histogram
+    #[cfg(feature = "stats")]
+    pub(crate) requested_bytes: usize, // requested bytes of live slab objects
+    #[cfg(feature = "stats")]
+    pub(crate) rounded_bytes: usize, // usable bytes of live slab objects
+
+    pub(crate) keep_empty: [usize; NUM_CLASSES], // number of empty slabs cached in each class
+
+    pub(crate) defer_unmap: bool,
+    unmaps: [usize; UNMAP_QUEUE_LEN], // pages freed to page_alloc but not unmapped yet
+    num_unmaps: usize,
+
+    // (partial, full) lists of each class, whose slab type is given by `with_slab_type!`.
+    // The partial list of Slab65512 is empty unless prewarmed.
+    lists: [(*mut u8, *mut u8); NUM_CLASSES],
+
+    // lists of slabs whose objects have no inline header, and (page, class index) of the slabs
+    external_lists: [(*mut u8, *mut u8); NUM_CLASSES],
+    external_slabs: [(usize, usize); MAX_EXTERNAL_SLABS],
+    num_external_slabs: usize,
 }
 
 unsafe fn alloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
     page_alloc: &mut PAGEALLOC,
     slab_partial: &mut *mut SLAB,
     slab_full: &mut *mut SLAB,
+    color: Option<&mut usize>,
+    external: bool,
+    mapf: fn(usize, usize),
 ) -> Option<*mut u8> {
     let slab_partial_top = slab_partial;
     let slab_partial = *slab_partial_top;
@@ -48,7 +171,12 @@ unsafe fn alloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
 
     match slab_partial.as_mut() {
         Some(partial) => {
-            let ret = partial.alloc(); // Allocate a memory region.
+            // Allocate a memory region.
+            let ret = if external {
+                partial.alloc_external()
+            } else {
+                partial.alloc()
+            };
 
             if partial.is_full() {
                 if let Some(next) = partial.next().as_mut() {
@@ -62,18 +190,28 @@ unsafe fn alloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
                 }
 
                 partial.set_next(slab_full);
+                *slab_full_top = slab_partial;
             }
 
             Some(ret)
         }
         None => {
-            if let Some(addr) = page_alloc.alloc(SIZE_64K) {
+            if let Some(addr) = page_alloc.alloc(SLAB::PAGE_SIZE) {
+                mapf(addr as usize, SLAB::PAGE_SIZE);
+
+                #[cfg(feature = "debug")]
+                check_fresh(addr as usize);
+
                 let slab_ptr = addr as *mut SLAB;
 
                 if let Some(slab) = slab_ptr.as_mut() {
-                    slab.init();
+                    slab.init(next_color::<SLAB>(color));
 
-                    let ret = slab.alloc();
+                    let ret = if external {
+                        slab.alloc_external()
+                    } else {
+                        slab.alloc()
+                    };
 
                     if slab.is_full() {
                         // for only Slab65512
@@ -98,16 +236,76 @@ unsafe fn alloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
     }
 }
 
+/// Return the offset of the first object of a new slab.
+///
+/// The offset is rotated by a cache line
+/// so that objects of different slabs do not map to the same cache set.
+fn next_color<SLAB: Slab>(color: Option<&mut usize>) -> usize {
+    match color {
+        Some(color) => {
+            // a seeded color may be out of the range of this class
+            let current = *color % SLAB::COLORS;
+            *color = (current + 1) % SLAB::COLORS;
+            current * CACHE_LINE
+        }
+        None => 0,
+    }
+}
+
+/// Allocate an empty slab and push it to the partial list.
+unsafe fn prewarm_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
+    page_alloc: &mut PAGEALLOC,
+    slab_partial: &mut *mut SLAB,
+    color: Option<&mut usize>,
+    mapf: fn(usize, usize),
+) -> bool {
+    let Some(addr) = page_alloc.alloc(SLAB::PAGE_SIZE) else {
+        return false;
+    };
+
+    mapf(addr as usize, SLAB::PAGE_SIZE);
+
+    #[cfg(feature = "debug")]
+    check_fresh(addr as usize);
+
+    let slab_ptr = addr as *mut SLAB;
+    let slab = &mut *slab_ptr;
+    slab.init(next_color::<SLAB>(color));
+
+    if let Some(partial) = slab_partial.as_mut() {
+        partial.set_prev(slab_ptr);
+    }
+
+    slab.set_next(*slab_partial);
+    *slab_partial = slab_ptr;
+
+    true
+}
+
 unsafe fn dealloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
     ptr: *mut u8,
     addr_slab: usize,
     page_alloc: &mut PAGEALLOC,
     slab_partial: &mut *mut SLAB,
     slab_full: &mut *mut SLAB,
+    keep_empty: usize,
+    external: bool,
 ) -> Option<usize> {
     if let Some(slab) = (addr_slab as *mut SLAB).as_mut() {
         let is_full = slab.is_full();
-        slab.free(ptr);
+        if external {
+            slab.free_external(ptr);
+        } else {
+            slab.free(ptr);
+        }
+
+        // Keep up to `keep_empty` empty slabs in the partial list
+        // so that a workload oscillating around a slab boundary does not map and unmap pages repeatedly.
+        // The slab itself is counted if it is in the partial list.
+        let keep = slab.is_empty()
+            && keep_empty > 0
+            && count_empty_slabs(*slab_partial) + is_full as usize <= keep_empty;
+
         if is_full {
             if let Some(prev) = slab.prev().as_mut() {
                 prev.set_next(slab.next());
@@ -119,7 +317,10 @@ unsafe fn dealloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
                 next.set_prev(slab.prev());
             }
 
-            if slab.is_empty() {
+            if slab.is_empty() && !keep {
+                #[cfg(feature = "debug")]
+                release_slab(addr_slab);
+
                 page_alloc.free(addr_slab as *mut u8);
                 Some(addr_slab) // Should unmap this page.
             } else {
@@ -135,7 +336,7 @@ unsafe fn dealloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
 
                 None
             }
-        } else if slab.is_empty() {
+        } else if slab.is_empty() && !keep {
             if let Some(prev) = slab.prev().as_mut() {
                 prev.set_next(slab.next());
             } else {
@@ -146,6 +347,9 @@ unsafe fn dealloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
                 next.set_prev(slab.prev());
             }
 
+            #[cfg(feature = "debug")]
+            release_slab(addr_slab);
+
             page_alloc.free(addr_slab as *mut u8);
             Some(addr_slab) // Should unmap this page.
         } else {
@@ -156,104 +360,599 @@ unsafe fn dealloc_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
     }
 }
 
+/// Return empty slabs in the partial list to `page_alloc`,
+/// and call `f` with the address of each returned page.
+unsafe fn reclaim_memory<PAGEALLOC: MemAlloc, SLAB: Slab>(
+    page_alloc: &mut PAGEALLOC,
+    slab_partial: &mut *mut SLAB,
+    f: &mut dyn FnMut(usize),
+) -> usize {
+    let mut n = 0;
+    let mut slab_ptr = *slab_partial;
+    while let Some(slab) = slab_ptr.as_mut() {
+        let next = slab.next();
+
+        if slab.is_empty() {
+            if let Some(prev) = slab.prev().as_mut() {
+                prev.set_next(next);
+            } else {
+                *slab_partial = next;
+            }
+
+            if let Some(next) = next.as_mut() {
+                next.set_prev(slab.prev());
+            }
+
+            #[cfg(feature = "debug")]
+            release_slab(slab_ptr as usize);
+
+            page_alloc.free(slab_ptr as *mut u8);
+            f(slab_ptr as usize);
+            n += 1;
+        }
+
+        slab_ptr = next;
+    }
+
+    n
+}
+
+/// Evaluate `$e` with the type alias `$slab` bound to the slab type of class `$idx`.
+macro_rules! with_slab_type {
+    ($idx:expr, $slab:ident => $e:expr) => {
+        match $idx {
+            0 => {
+                type $slab = Slab16;
+                $e
+            }
+            1 => {
+                type $slab = Slab32;
+                $e
+            }
+            2 => {
+                type $slab = Slab64;
+                $e
+            }
+            3 => {
+                type $slab = Slab128;
+                $e
+            }
+            4 => {
+                type $slab = Slab256;
+                $e
+            }
+            5 => {
+                type $slab = Slab512;
+                $e
+            }
+            6 => {
+                type $slab = Slab1024;
+                $e
+            }
+            7 => {
+                type $slab = Slab2040;
+                $e
+            }
+            8 => {
+                type $slab = Slab4088;
+                $e
+            }
+            9 => {
+                type $slab = Slab8184;
+                $e
+            }
+            10 => {
+                type $slab = Slab16376;
+                $e
+            }
+            11 => {
+                type $slab = Slab32752;
+                $e
+            }
+            12 => {
+                type $slab = Slab65512;
+                $e
+            }
+            _ => unreachable!(),
+        }
+    };
+}
+
+/// Value of `checksum` of a live slab without the `checksum` feature.
+#[cfg(all(feature = "debug", not(feature = "checksum")))]
+const LIVE_MAGIC: u16 = 0x6c76; // "lv"
+
+/// Return whether the page at `addr` holds a live slab, which is initialized and not released.
+/// The checksum of a live slab matches with the `checksum` feature,
+/// and `checksum` is `LIVE_MAGIC` otherwise.
+#[cfg(feature = "debug")]
+unsafe fn is_live_slab(addr: usize) -> bool {
+    let size = class_size_of_slab(addr);
+    let Some(_idx) = CLASS_SIZES.iter().position(|c| *c == size) else {
+        return false;
+    };
+
+    #[cfg(feature = "checksum")]
+    {
+        with_slab_type!(_idx, S => (*(addr as *const S)).sealed())
+    }
+
+    #[cfg(not(feature = "checksum"))]
+    {
+        // `checksum` is right before `size` in every slab.
+        *((addr + SIZE_64K - 4) as *const u16) == LIVE_MAGIC
+    }
+}
+
+/// Mark the slab at `addr` as released before returning its page to the page allocator.
+#[cfg(feature = "debug")]
+pub(crate) unsafe fn release_slab(addr: usize) {
+    *((addr + SIZE_64K - 2) as *mut u16) = 0;
+}
+
+/// Panic if a page handed out by the page allocator holds a live slab,
+/// which means that the page allocator handed out a page in use,
+/// and initializing it would lose all the objects of the slab.
+#[cfg(feature = "debug")]
+unsafe fn check_fresh(addr: usize) {
+    if is_live_slab(addr) {
+        panic!("page of a live slab is allocated again: {addr:#x}");
+    }
+}
+
+/// Return the number of objects a slab of `class_size` can hold, or 0 if it is not a class size.
+pub(crate) const fn object_count(class_size: usize) -> usize {
+    let mut idx = 0;
+    while idx < NUM_CLASSES {
+        if CLASS_SIZES[idx] == class_size {
+            return with_slab_type!(idx, S => S::CAPACITY);
+        }
+        idx += 1;
+    }
+    0
+}
+
+/// View a type-erased list of `SlabAllocator::lists` as a list of `SLAB`.
+///
+/// # Safety
+///
+/// `SLAB` must be the slab type of the class of the list.
+unsafe fn typed<SLAB: Slab>(list: &mut *mut u8) -> &mut *mut SLAB {
+    &mut *(list as *mut *mut u8 as *mut *mut SLAB)
+}
+
+/// A visitor of the partial and full lists of every slab class.
+trait Visitor {
+    fn visit<SLAB: Slab>(&mut self, class_size: usize, partial: *mut SLAB, full: *mut SLAB);
+}
+
+/// Call `f` for each slab in a list.
+unsafe fn for_each_slab<SLAB: Slab>(mut slab: *mut SLAB, mut f: impl FnMut(&SLAB)) {
+    while let Some(s) = slab.as_ref() {
+        f(s);
+        slab = s.next();
+    }
+}
+
+/// Return the number of empty slabs in a list.
+unsafe fn count_empty_slabs<SLAB: Slab>(slab: *mut SLAB) -> usize {
+    let mut n = 0;
+    for_each_slab(slab, |s| n += s.is_empty() as usize);
+    n
+}
+
+/// Return the number of slabs in a list.
+unsafe fn count_slabs<SLAB: Slab>(slab: *mut SLAB) -> usize {
+    let mut n = 0;
+    for_each_slab(slab, |_| n += 1);
+    n
+}
+
 impl<PAGEALLOC: MemAlloc> SlabAllocator<PAGEALLOC> {
-    pub(crate) unsafe fn slab_alloc(&mut self, size: usize) -> Option<*mut u8> {
-        let n = (size as u64 + 8 - 1).leading_zeros();
+    /// Account an allocation of `size` bytes,
+    /// where `large` tells whether it is a block of the page allocator instead of a slab object.
+    pub(crate) fn account_alloc(&mut self, size: usize, large: bool) {
+        self.live_count += 1;
+        self.live_bytes += size;
+        if large {
+            self.large_bytes += size;
+        }
+        if self.live_bytes > self.peak_bytes {
+            self.peak_bytes = self.live_bytes;
+        }
+    }
 
-        match n {
-            61 | 60 => alloc_memory(
-                &mut self.page_alloc,
-                &mut self.slab16_partial,
-                &mut self.slab16_full,
-            ),
-            59 => alloc_memory(
-                &mut self.page_alloc,
-                &mut self.slab32_partial,
-                &mut self.slab32_full,
-            ),
-            58 => alloc_memory(
-                &mut self.page_alloc,
-                &mut self.slab64_partial,
-                &mut self.slab64_full,
-            ),
-            57 => alloc_memory(
-                &mut self.page_alloc,
-                &mut self.slab128_partial,
-                &mut self.slab128_full,
-            ),
-            56 => alloc_memory(
-                &mut self.page_alloc,
-                &mut self.slab256_partial,
-                &mut self.slab256_full,
-            ),
-            55 => alloc_memory(
-                &mut self.page_alloc,
-                &mut self.slab512_partial,
-                &mut self.slab512_full,
-            ),
-            54 => alloc_memory(
-                &mut self.page_alloc,
-                &mut self.slab1024_partial,
-                &mut self.slab1024_full,
-            ),
-            _ => {
-                if size <= 4088 - 16 {
-                    if size <= 2040 - 16 {
-                        // Slab2040
-                        alloc_memory(
-                            &mut self.page_alloc,
-                            &mut self.slab2040_partial,
-                            &mut self.slab2040_full,
-                        )
-                    } else {
-                        // Slab4088
-                        alloc_memory(
-                            &mut self.page_alloc,
-                            &mut self.slab4088_partial,
-                            &mut self.slab4088_full,
-                        )
-                    }
-                } else if size <= 16376 - 16 {
-                    if size <= 8184 - 16 {
-                        // Slab8184
-                        alloc_memory(
-                            &mut self.page_alloc,
-                            &mut self.slab8184_partial,
-                            &mut self.slab8184_full,
-                        )
-                    } else {
-                        // Slab16376
-                        alloc_memory(
-                            &mut self.page_alloc,
-                            &mut self.slab16376_partial,
-                            &mut self.slab16376_full,
-                        )
-                    }
-                } else if size <= 32752 - 16 {
-                    // Slab32752
-                    alloc_memory(
-                        &mut self.page_alloc,
-                        &mut self.slab32752_partial,
-                        &mut self.slab32752_full,
-                    )
-                } else if size <= 65512 - 8 {
-                    // Slab65512
-                    alloc_memory(
-                        &mut self.page_alloc,
-                        &mut self.slab65512_partial,
-                        &mut self.slab65512_full,
-                    )
-                } else {
-                    None
+    pub(crate) fn account_free(&mut self, size: usize, large: bool) {
+        self.live_count = self.live_count.saturating_sub(1);
+        self.live_bytes = self.live_bytes.saturating_sub(size);
+        if large {
+            self.large_bytes = self.large_bytes.saturating_sub(size);
+        }
+    }
+
+    fn visit(&self, v: &mut impl Visitor) {
+        for (idx, (partial, full)) in self.lists.iter().enumerate() {
+            with_slab_type!(idx, S => {
+                v.visit(CLASS_SIZES[idx], *partial as *mut S, *full as *mut S)
+            });
+        }
+    }
+
+    /// Release all the slabs so that their pages are not taken for live slabs
+    /// when the heap is reused.
+    #[cfg(feature = "debug")]
+    pub(crate) fn release_all(&self) {
+        struct Release;
+
+        impl Visitor for Release {
+            fn visit<SLAB: Slab>(&mut self, _: usize, partial: *mut SLAB, full: *mut SLAB) {
+                let f = |slab: &SLAB| unsafe { release_slab(slab as *const SLAB as usize) };
+                unsafe {
+                    for_each_slab(partial, f);
+                    for_each_slab(full, f);
+                }
+            }
+        }
+
+        self.visit(&mut Release);
+
+        for (page, _) in self.external_slabs[..self.num_external_slabs].iter() {
+            unsafe { release_slab(*page) };
+        }
+    }
+
+    /// Call `f(addr, size)` for each live object in slabs,
+    /// and for each block allocated from `page_alloc` other than slabs.
+    /// Objects without inline headers are not reported.
+    #[cfg(any(test, feature = "debug"))]
+    pub(crate) fn for_each_live(&self, f: &mut dyn FnMut(usize, usize)) {
+        struct Objects<'a>(&'a mut dyn FnMut(usize, usize));
+
+        impl Visitor for Objects<'_> {
+            fn visit<SLAB: Slab>(&mut self, _: usize, partial: *mut SLAB, full: *mut SLAB) {
+                unsafe {
+                    for_each_slab(partial, |slab| slab.for_each_live(self.0));
+                    for_each_slab(full, |slab| slab.for_each_live(self.0));
+                }
+            }
+        }
+
+        struct IsSlab(usize, bool);
+
+        impl Visitor for IsSlab {
+            fn visit<SLAB: Slab>(&mut self, _: usize, partial: *mut SLAB, full: *mut SLAB) {
+                let mut f = |slab: &SLAB| {
+                    self.1 |= slab as *const SLAB as usize == self.0;
+                };
+                unsafe {
+                    for_each_slab(partial, &mut f);
+                    for_each_slab(full, &mut f);
+                }
+            }
+        }
+
+        self.visit(&mut Objects(f));
+
+        self.page_alloc.for_each_used(&mut |addr, size| {
+            let mut is_slab = IsSlab(addr, false);
+            self.visit(&mut is_slab);
+            if !is_slab.1 && self.external_slab(addr).is_none() {
+                f(addr, size);
+            }
+        });
+    }
+
+    /// Return the number of pages used by each slab class.
+    pub(crate) fn class_pages(&self) -> [usize; NUM_CLASSES] {
+        struct Pages([usize; NUM_CLASSES], usize);
+
+        impl Visitor for Pages {
+            fn visit<SLAB: Slab>(&mut self, _: usize, partial: *mut SLAB, full: *mut SLAB) {
+                self.0[self.1] = unsafe { count_slabs(partial) + count_slabs(full) };
+                self.1 += 1;
+            }
+        }
+
+        let mut pages = Pages([0; NUM_CLASSES], 0);
+        self.visit(&mut pages);
+        pages.0
+    }
+
+    /// Return the usable size of the largest class which has a free slot in an existing slab,
+    /// or 0 if no class has.
+    pub(crate) fn largest_free_slot(&self) -> usize {
+        (0..NUM_CLASSES)
+            .rev()
+            .find(|idx| !self.lists[*idx].0.is_null())
+            .map_or(0, |idx| USABLE_SIZES[idx])
+    }
+
+    /// Return statistics of each slab class.
+    pub(crate) fn class_stats(&self) -> [ClassStats; NUM_CLASSES] {
+        struct Stats([ClassStats; NUM_CLASSES], usize);
+
+        impl Visitor for Stats {
+            fn visit<SLAB: Slab>(
+                &mut self,
+                class_size: usize,
+                partial: *mut SLAB,
+                full: *mut SLAB,
+            ) {
+                let stats = &mut self.0[self.1];
+                stats.class_size = class_size;
+                unsafe {
+                    for_each_slab(partial, |slab| {
+                        stats.partial += 1;
+                        stats.live += slab.num();
+                    });
+                    for_each_slab(full, |slab| {
+                        stats.full += 1;
+                        stats.live += slab.num();
+                    });
                 }
+                stats.capacity = (stats.partial + stats.full) * SLAB::CAPACITY;
+                self.1 += 1;
             }
         }
+
+        let mut stats = Stats([ClassStats::default(); NUM_CLASSES], 0);
+        self.visit(&mut stats);
+        stats.0
+    }
+
+    /// Push `slabs` empty slabs of `class_size` to the partial list
+    /// and return the number of slabs actually pushed,
+    /// which is less than `slabs` if `page_alloc` runs out of pages.
+    ///
+    /// Panics if `class_size` is not a size of the slab classes.
+    pub(crate) fn prewarm(&mut self, class_size: usize, slabs: usize) -> usize {
+        let Some(idx) = CLASS_SIZES.iter().position(|c| *c == class_size) else {
+            panic!("invalid class size");
+        };
+
+        for n in 0..slabs {
+            let capacity = with_slab_type!(idx, S => unsafe {
+                prewarm_memory(
+                    &mut self.page_alloc,
+                    typed::<S>(&mut self.lists[idx].0),
+                    self.cache_coloring.then_some(&mut self.colors[idx]),
+                    self.mapf,
+                )
+                .then_some(S::CAPACITY)
+            });
+
+            let Some(capacity) = capacity else {
+                return n;
+            };
+            self.slab_free_bytes += capacity * USABLE_SIZES[idx];
+
+            // the new slab is at the head of the partial list
+            let page = self.lists[idx].0 as usize;
+            self.cancel_unmap(page, page + SIZE_64K);
+
+            #[cfg(feature = "trace")]
+            (self.trace)(crate::TraceEvent::MapPage { addr: page });
+        }
+
+        slabs
+    }
+
+    /// Derive the next color of each class and the scan order of `page_alloc` from `seed`.
+    pub(crate) fn set_seed(&mut self, seed: u64) {
+        let mut state = seed;
+        for color in self.colors.iter_mut() {
+            // splitmix64
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *color = (z ^ (z >> 31)) as usize;
+        }
+
+        self.page_alloc.set_seed(seed);
+    }
+
+    /// Queue `page` freed to `page_alloc` to be unmapped later in the `defer_unmap` mode.
+    /// Return `false` if it must be unmapped immediately.
+    pub(crate) fn queue_unmap(&mut self, page: usize) -> bool {
+        if !self.defer_unmap || self.num_unmaps == UNMAP_QUEUE_LEN {
+            return false;
+        }
+
+        self.unmaps[self.num_unmaps] = page;
+        self.num_unmaps += 1;
+        true
+    }
+
+    /// Remove queued pages in `[start, end)`, which are taken from `page_alloc` again
+    /// and must not be unmapped.
+    pub(crate) fn cancel_unmap(&mut self, start: usize, end: usize) {
+        let mut i = 0;
+        while i < self.num_unmaps {
+            if start <= self.unmaps[i] && self.unmaps[i] < end {
+                self.num_unmaps -= 1;
+                self.unmaps[i] = self.unmaps[self.num_unmaps];
+            } else {
+                i += 1;
+            }
+        }
+    }
+
+    /// Call `f(page)` for each queued page, and empty the queue.
+    pub(crate) fn flush_unmaps(&mut self, f: &mut dyn FnMut(usize)) {
+        for page in self.unmaps[..self.num_unmaps].iter() {
+            f(*page);
+        }
+        self.num_unmaps = 0;
+    }
+
+    /// Return empty slabs of all the classes to `page_alloc`, including those kept by
+    /// `keep_empty` or `prewarm`, and return the number of returned pages.
+    /// `f` is called with the address of each returned page.
+    pub(crate) fn reclaim(&mut self, f: &mut dyn FnMut(usize)) -> usize {
+        let mut pages = 0;
+        for (idx, usable) in USABLE_SIZES.iter().enumerate() {
+            with_slab_type!(idx, S => {
+                let n = unsafe {
+                    reclaim_memory(&mut self.page_alloc, typed::<S>(&mut self.lists[idx].0), f)
+                };
+                self.slab_free_bytes -= n * S::CAPACITY * usable;
+                pages += n;
+            });
+        }
+
+        pages
+    }
+
+    #[cfg(test)]
+    pub(crate) unsafe fn slab_alloc(&mut self, size: usize) -> Option<*mut u8> {
+        self.slab_alloc_index(class_index(size)?)
+    }
+
+    /// Allocate an object of class `idx`, which may be larger than the class of the request.
+    pub(crate) unsafe fn slab_alloc_index(&mut self, idx: usize) -> Option<*mut u8> {
+        with_slab_type!(idx, S => self.alloc_class::<S>(idx, false))
+    }
+
+    /// Allocate an object from class `idx`, whose slab type is `SLAB`.
+    /// The object has no inline header if `external` is true.
+    unsafe fn alloc_class<SLAB: Slab>(&mut self, idx: usize, external: bool) -> Option<*mut u8> {
+        let (lists, usable) = if external {
+            (&mut self.external_lists, CLASS_SIZES[idx])
+        } else {
+            (&mut self.lists, USABLE_SIZES[idx])
+        };
+
+        // A new slab is pulled from `page_alloc` if there is no partial slab.
+        let new_slab = lists[idx].0.is_null();
+
+        let (partial, full) = &mut lists[idx];
+        let result = alloc_memory(
+            &mut self.page_alloc,
+            typed::<SLAB>(partial),
+            typed::<SLAB>(full),
+            self.cache_coloring.then_some(&mut self.colors[idx]),
+            external,
+            self.mapf,
+        )?;
+
+        #[cfg(feature = "trace")]
+        if new_slab {
+            let addr = result as usize & !(SIZE_64K - 1);
+            (self.trace)(crate::TraceEvent::MapPage { addr });
+        }
+
+        #[cfg(feature = "stats")]
+        {
+            self.alloc_count[idx] += 1;
+        }
+
+        if new_slab {
+            self.slab_free_bytes += SLAB::CAPACITY * usable;
+        }
+        self.slab_free_bytes -= usable;
+
+        Some(result)
+    }
+
+    /// Allocate an object of at least `size` bytes without the inline header,
+    /// and return it with the size of its class, all of which is usable.
+    ///
+    /// Slabs of such objects are recorded in the side table `external_slabs`
+    /// because objects cannot point to their slabs.
+    pub(crate) unsafe fn slab_alloc_external(&mut self, size: usize) -> Option<(*mut u8, usize)> {
+        let idx = CLASS_SIZES.iter().position(|c| *c >= size)?;
+
+        let new_slab = self.external_lists[idx].0.is_null();
+        if new_slab && self.num_external_slabs == MAX_EXTERNAL_SLABS {
+            return None;
+        }
+
+        let ptr = with_slab_type!(idx, S => self.alloc_class::<S>(idx, true))?;
+
+        if new_slab {
+            let page = ptr as usize & !(SIZE_64K - 1);
+            self.external_slabs[self.num_external_slabs] = (page, idx);
+            self.num_external_slabs += 1;
+        }
+
+        Some((ptr, CLASS_SIZES[idx]))
+    }
+
+    /// Deallocate an object returned by `slab_alloc_external`,
+    /// and return the size of its class and a 64KiB page address if page should be unmapped.
+    ///
+    /// Panics if the page of `ptr` is not in the side table.
+    pub(crate) unsafe fn slab_dealloc_external(&mut self, ptr: *mut u8) -> (usize, Option<usize>) {
+        let page = ptr as usize & !(SIZE_64K - 1);
+        let Some(pos) = self.external_slab(page) else {
+            panic!("not an external slab object");
+        };
+        let idx = self.external_slabs[pos].1;
+
+        #[cfg(feature = "stats")]
+        {
+            self.free_count[idx] += 1;
+        }
+
+        with_slab_type!(idx, S => {
+            let (partial, full) = &mut self.external_lists[idx];
+            let result = dealloc_memory(
+                ptr,
+                page,
+                &mut self.page_alloc,
+                typed::<S>(partial),
+                typed::<S>(full),
+                0,
+                true,
+            );
+
+            self.slab_free_bytes += CLASS_SIZES[idx];
+            if result.is_some() {
+                // the slab is returned to `page_alloc`
+                self.slab_free_bytes -= S::CAPACITY * CLASS_SIZES[idx];
+                self.num_external_slabs -= 1;
+                self.external_slabs[pos] = self.external_slabs[self.num_external_slabs];
+            }
+
+            (CLASS_SIZES[idx], result)
+        })
+    }
+
+    /// Return the position of `page` in the side table of slabs without inline headers.
+    pub(crate) fn external_slab(&self, page: usize) -> Option<usize> {
+        self.external_slabs[..self.num_external_slabs]
+            .iter()
+            .position(|(p, _)| *p == page)
     }
 
     /// Return a 64KiB page address if page should be unmapped.
+    ///
+    /// Panics if `ptr` or the back-pointer in its header is not in the heap,
+    /// if the back-pointer is not the page of `ptr`, or if the page has no valid class,
+    /// instead of reading memory at a corrupted address.
     pub(crate) unsafe fn slab_dealloc(&mut self, ptr: *mut u8) -> Option<usize> {
-        let addr_slab = *((ptr as usize - 8) as *const u64);
-        let size = *((addr_slab + 65532) as *const u32);
+        let (start, end) = self.page_alloc.range();
+
+        let addr = ptr as usize;
+        if addr < start + 8 || addr >= end {
+            panic!("invalid slab pointer");
+        }
+
+        // Every object lies on the page of its slab.
+        let (addr_slab, tag) = read_object_header(addr);
+        if addr_slab != addr & !(SIZE_64K - 1) || addr_slab < start || addr_slab >= end {
+            panic!("invalid slab pointer");
+        }
+
+        // The class is checked before `usable_size`, which underflows for a size below 16.
+        let size = class_size_of_slab(addr_slab);
+        let Some(idx) = CLASS_SIZES.iter().position(|c| *c == size) else {
+            panic!("invalid slab pointer");
+        };
+        if tag > usable_size(size) {
+            panic!("invalid slab pointer");
+        }
 
         /*
                 driver::uart::puts("dealloc:\n");
@@ -267,168 +966,305 @@ impl<PAGEALLOC: MemAlloc> SlabAllocator<PAGEALLOC> {
                 driver::uart::decimal(size as u64);
                 driver::uart::puts("\n");
         */
-        match size {
-            16 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab16_partial,
-                &mut self.slab16_full,
-            ),
-            32 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab32_partial,
-                &mut self.slab32_full,
-            ),
-            64 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab64_partial,
-                &mut self.slab64_full,
-            ),
-            128 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab128_partial,
-                &mut self.slab128_full,
-            ),
-            256 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab256_partial,
-                &mut self.slab256_full,
-            ),
-            512 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab512_partial,
-                &mut self.slab512_full,
-            ),
-            1024 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab1024_partial,
-                &mut self.slab1024_full,
-            ),
-            2040 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab2040_partial,
-                &mut self.slab2040_full,
-            ),
-            4088 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab4088_partial,
-                &mut self.slab4088_full,
-            ),
-            8184 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab8184_partial,
-                &mut self.slab8184_full,
-            ),
-            16376 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab16376_partial,
-                &mut self.slab16376_full,
-            ),
-            32752 => dealloc_memory(
-                ptr,
-                addr_slab as usize,
-                &mut self.page_alloc,
-                &mut self.slab32752_partial,
-                &mut self.slab32752_full,
-            ),
-            65512 => dealloc_memory(
+
+        #[cfg(feature = "stats")]
+        {
+            self.free_count[idx] += 1;
+        }
+
+        with_slab_type!(idx, S => {
+            let (partial, full) = &mut self.lists[idx];
+            let result = dealloc_memory(
                 ptr,
-                addr_slab as usize,
+                addr_slab,
                 &mut self.page_alloc,
-                &mut self.slab65512_partial,
-                &mut self.slab65512_full,
-            ),
-            _ => None,
-        }
+                typed::<S>(partial),
+                typed::<S>(full),
+                self.keep_empty[idx],
+                false,
+            );
+
+            self.slab_free_bytes += USABLE_SIZES[idx];
+            if result.is_some() {
+                // the slab is returned to `page_alloc`
+                self.slab_free_bytes -= S::CAPACITY * USABLE_SIZES[idx];
+            }
+
+            result
+        })
     }
 
     pub(crate) fn new(addr: usize, size: usize) -> Self {
+        #[allow(unused_mut)]
+        let mut page_alloc = PAGEALLOC::new(addr, size);
+
+        // `slab_dealloc` recovers a slab by masking the address of an object.
+        if page_alloc.page_alignment() < SIZE_64K {
+            panic!("pages of the backend are not 64KiB aligned");
+        }
+
         Self {
-            page_alloc: PAGEALLOC::new(addr, size),
-            slab16_partial: null_mut(),
-            slab32_partial: null_mut(),
-            slab64_partial: null_mut(),
-            slab128_partial: null_mut(),
-            slab256_partial: null_mut(),
-            slab512_partial: null_mut(),
-            slab1024_partial: null_mut(),
-            slab2040_partial: null_mut(),
-            slab4088_partial: null_mut(),
-            slab8184_partial: null_mut(),
-            slab16376_partial: null_mut(),
-            slab32752_partial: null_mut(),
-            slab65512_partial: null_mut(),
-            slab16_full: null_mut(),
-            slab32_full: null_mut(),
-            slab64_full: null_mut(),
-            slab128_full: null_mut(),
-            slab256_full: null_mut(),
-            slab512_full: null_mut(),
-            slab1024_full: null_mut(),
-            slab2040_full: null_mut(),
-            slab4088_full: null_mut(),
-            slab8184_full: null_mut(),
-            slab16376_full: null_mut(),
-            slab32752_full: null_mut(),
-            slab65512_full: null_mut(),
-        }
-    }
-}
-
-trait Slab {
+            #[cfg(feature = "debug_registry")]
+            registry: crate::registry::Registry::new(&mut page_alloc, size),
+            page_alloc,
+            live_bytes: 0,
+            peak_bytes: 0,
+            live_count: 0,
+            large_bytes: 0,
+            slab_free_bytes: 0,
+            #[cfg(feature = "track")]
+            tracker: crate::track::Tracker::new(),
+            mapf: |_, _| {},
+            #[cfg(feature = "trace")]
+            trace: |_| {},
+            cache_coloring: false,
+            colors: [0; NUM_CLASSES],
+            #[cfg(feature = "stats")]
+            alloc_count: [0; NUM_CLASSES],
+            #[cfg(feature = "stats")]
+            free_count: [0; NUM_CLASSES],
+            #[cfg(feature = "stats")]
+            histogram_base: [0; NUM_CLASSES],
+            #[cfg(feature = "stats")]
+            requested_bytes: 0,
+            #[cfg(feature = "stats")]
+            rounded_bytes: 0,
+            keep_empty: [0; NUM_CLASSES],
+            defer_unmap: false,
+            unmaps: [0; UNMAP_QUEUE_LEN],
+            num_unmaps: 0,
+            lists: [(null_mut(), null_mut()); NUM_CLASSES],
+            external_lists: [(null_mut(), null_mut()); NUM_CLASSES],
+            external_slabs: [(0, 0); MAX_EXTERNAL_SLABS],
+            num_external_slabs: 0,
+        }
+    }
+}
+
+trait Slab: Sized {
+    /// Number of bytes of the page a slab fills, which is allocated from the page allocator.
+    const PAGE_SIZE: usize;
+
+    /// Number of objects a slab can hold.
+    const CAPACITY: usize;
+
+    /// Number of cache colors, which is how many cache lines fit in the unused tail of `buf` plus one.
+    const COLORS: usize;
+
     fn alloc(&mut self) -> *mut u8;
     fn free(&mut self, ptr: *mut u8);
+    /// Allocate an object without the inline header, and return the start of its slot,
+    /// whose whole size of the class is usable.
+    fn alloc_external(&mut self) -> *mut u8;
+    /// Deallocate an object returned by `alloc_external`.
+    ///
+    /// Panics if `ptr` is not the start of a slot.
+    fn free_external(&mut self, ptr: *mut u8);
     fn is_full(&self) -> bool;
     fn is_empty(&self) -> bool;
-    fn init(&mut self);
+    /// Return the number of allocated objects.
+    fn num(&self) -> usize;
+    /// Initialize the slab placing the first object `color` bytes after the start of `buf`.
+    fn init(&mut self, color: usize);
     fn next(&self) -> *mut Self;
     fn prev(&self) -> *mut Self;
     fn set_next(&mut self, next: *mut Self);
     fn set_prev(&mut self, prev: *mut Self);
+
+    /// Call `f(addr, usable_size)` for each allocated object.
+    /// `addr` is the address returned by `alloc`.
+    #[cfg(any(test, feature = "debug"))]
+    fn for_each_live(&self, f: &mut dyn FnMut(usize, usize));
+    /// Return whether the checksum of the metadata matches.
+    #[cfg(feature = "checksum")]
+    fn sealed(&self) -> bool;
+    /// Panic with the address of the slab if the checksum of the metadata does not match.
+    #[cfg(feature = "checksum")]
+    fn verify(&self) {
+        if !self.sealed() {
+            panic!(
+                "corrupted slab metadata at {:#x}",
+                self as *const Self as usize
+            );
+        }
+    }
+    /// Update the checksum after modifying the metadata.
+    #[cfg(feature = "checksum")]
+    fn seal(&mut self);
     // fn print(&self);
 }
 
+/// Return a checksum of the metadata of a slab,
+/// where `misc` packs the fields other than `prev` and `next`.
+#[cfg(feature = "checksum")]
+fn checksum(prev: usize, next: usize, misc: u64) -> u16 {
+    let x = prev as u64 ^ (next as u64).rotate_left(7) ^ misc.rotate_left(29);
+
+    // The seed keeps zeroed metadata from having a valid checksum.
+    ((x ^ 0x6d65_6d61_635f_636b).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 48) as u16
+}
+
+/// Mask of the low bits of the header of a slab object, which are zero in the back-pointer
+/// to its 64KiB-aligned slab and hold the size accounted for the object by `Allocator`.
+const SIZE_TAG_MASK: usize = SIZE_64K - 1;
+
+/// Return the back-pointer to the slab of the object at `addr`,
+/// and the size tagged by `set_object_size`, which is 0 if it has never been tagged.
+///
+/// # Safety
+///
+/// `addr - 8` must be readable.
+pub(crate) unsafe fn read_object_header(addr: usize) -> (usize, usize) {
+    // `addr` may be misaligned if it is not a slab object.
+    let header = ((addr - 8) as *const u64).read_unaligned() as usize;
+    (header & !SIZE_TAG_MASK, header & SIZE_TAG_MASK)
+}
+
+/// Tag the header of the slab object at `addr` with `size`,
+/// so that the object can be freed without its size, e.g. by `Allocator::free_unsized`.
+///
+/// # Safety
+///
+/// `addr` must be a live object of a slab on a 64KiB page.
+pub(crate) unsafe fn set_object_size(addr: usize, size: usize) {
+    debug_assert!(size <= MAX_SLAB_SIZE);
+    let header = (addr - 8) as *mut usize;
+    *header = (*header & !SIZE_TAG_MASK) | size;
+}
+
+/// Return the size of the class recorded in the slab at `addr_slab`.
+///
+/// # Safety
+///
+/// `addr_slab` must be the address of a slab.
+pub(crate) unsafe fn class_size_of_slab(addr_slab: usize) -> usize {
+    // `size` is the last field of every slab.
+    *((addr_slab + SIZE_64K - 2) as *const u16) as usize
+}
+
+/// Return the number of words of the L2 bitmap of a small slab of `size`-byte objects
+/// on a `page`-byte page, which is the least number covering the objects beside the bitmaps.
+const fn small_words(page: usize, size: usize) -> usize {
+    let mut n = 1;
+    while n * 64 < (page - 32 - 8 * n) / size {
+        n += 1;
+    }
+    n
+}
+
+/// Return a bitmap word whose lowest `bits` bits are set,
+/// which marks the slots beyond the capacity as used.
+const fn low_bits(bits: usize) -> u64 {
+    if bits == 0 {
+        0
+    } else {
+        !0 >> (64 - bits)
+    }
+}
+
+/// A slab of objects of `$size` bytes, which is a power of two, on a page of `$page` bytes.
+/// The page is 64KiB unless given.
+///
+/// Slots are tracked by an L1 bitmap of words of an L2 bitmap which are full,
+/// so a slab holds at most 64 * 64 objects.
 macro_rules! SlabSmall {
-    ($id:ident, $n:expr, $shift:expr, $l1val:expr, $l2val:expr, $size:expr) => {
+    ($id:ident, $size:expr) => {
+        SlabSmall!($id, $size, SIZE_64K);
+    };
+    ($id:ident, $size:expr, $page:expr) => {
         #[repr(C)]
         struct $id {
-            buf: [u8; 65536 - 32 - 8 * $n],
+            buf: [u8; $page - 32 - 8 * small_words($page, $size)],
             l1_bitmap: u64,
-            l2_bitmap: [u64; $n],
+            l2_bitmap: [u64; small_words($page, $size)],
             prev: *mut $id,
             next: *mut $id,
-            num: u32,
-            size: u32,
+            num: u16,
+            color: u16,
+            #[cfg_attr(not(any(feature = "checksum", feature = "debug")), allow(dead_code))]
+            checksum: u16,
+            size: u16,
+        }
+
+        // A slab fills its page, and `size` is its last field.
+        const _: () = assert!(core::mem::size_of::<$id>() == $page);
+        const _: () = assert!(small_words($page, $size) <= 64);
+
+        impl $id {
+            const WORDS: usize = small_words($page, $size);
+            const BUF_LEN: usize = $page - 32 - 8 * Self::WORDS;
+
+            /// Mark the lowest free slot as used, and return the offset of the slot in `buf`.
+            ///
+            /// Slot `i` is the bit `63 - i` of the bitmaps, so the leading ones are the lowest slots,
+            /// and objects of a fresh slab are allocated in ascending order of addresses.
+            fn take_slot(&mut self) -> usize {
+                let idx1 = (!self.l1_bitmap).leading_zeros() as usize;
+                let idx2 = (!self.l2_bitmap[idx1]).leading_zeros() as usize;
+
+                self.l2_bitmap[idx1] |= 1 << (63 - idx2);
+                if self.l2_bitmap[idx1] == !0 {
+                    self.l1_bitmap |= 1 << (63 - idx1);
+                }
+
+                let size = self.size as usize;
+                let idx = idx1 * size * 64 + idx2 * size + self.color as usize;
+
+                if idx >= Self::BUF_LEN {
+                    panic!("allocation error");
+                }
+
+                self.num += 1;
+                idx
+            }
+
+            /// Return the offset in `buf` of the slot starting at `addr`.
+            ///
+            /// Panics if `addr` is not the start of a slot of this slab,
+            /// instead of indexing the bitmaps out of bounds.
+            fn slot_offset(&self, addr: usize) -> usize {
+                let org = self as *const $id as usize;
+                let offset = addr.wrapping_sub(org);
+                let color = self.color as usize;
+                if offset < color
+                    || offset - color >= Self::CAPACITY * $size
+                    || (offset - color) % $size != 0
+                {
+                    panic!("invalid slab pointer");
+                }
+                offset
+            }
+
+            /// Mark the slot at `offset` in `buf` as free.
+            fn release_slot(&mut self, offset: usize) {
+                let len = offset - self.color as usize;
+                let idx = len / $size;
+
+                let idx1 = idx >> 6; // divide by 64
+                let idx2 = idx & 0b111111;
+
+                self.l1_bitmap &= !(1 << (63 - idx1));
+                self.l2_bitmap[idx1] &= !(1 << (63 - idx2));
+                self.num -= 1;
+            }
         }
 
         impl Slab for $id {
+            const PAGE_SIZE: usize = $page;
+
+            const CAPACITY: usize = Self::BUF_LEN / $size;
+
+            const COLORS: usize = Self::BUF_LEN % $size / CACHE_LINE + 1;
+
             fn next(&self) -> *mut Self {
                 self.next
             }
 
             fn set_next(&mut self, next: *mut Self) {
                 self.next = next;
+
+                #[cfg(feature = "checksum")]
+                self.seal();
             }
 
             fn prev(&self) -> *mut Self {
@@ -437,32 +1273,49 @@ macro_rules! SlabSmall {
 
             fn set_prev(&mut self, prev: *mut Self) {
                 self.prev = prev;
-            }
 
-            // +------------------+
-            // | pointer to slab  |
-            // |    (8 bytes)     |
-            // +------------------+ <- return value
-            // |       data       |
-            // | (size - 8 bytes) |
-            // |                  |
-            /// allocate a memory region whose size is self.size - 8 bytes
-            fn alloc(&mut self) -> *mut u8 {
-                let idx1 = (!self.l1_bitmap).leading_zeros() as usize;
-                let idx2 = (!self.l2_bitmap[idx1]).leading_zeros() as usize;
+                #[cfg(feature = "checksum")]
+                self.seal();
+            }
 
-                self.l2_bitmap[idx1] |= 1 << (63 - idx2);
-                if self.l2_bitmap[idx1] == !0 {
-                    self.l1_bitmap |= 1 << (63 - idx1);
-                }
+            #[cfg(feature = "checksum")]
+            fn sealed(&self) -> bool {
+                let misc = self.num as u64 | (self.color as u64) << 16 | (self.size as u64) << 32;
+                checksum(self.prev as usize, self.next as usize, misc) == self.checksum
+            }
 
-                let size = self.size as usize;
-                let idx = idx1 * size * 64 + idx2 * size;
+            #[cfg(feature = "checksum")]
+            fn seal(&mut self) {
+                let misc = self.num as u64 | (self.color as u64) << 16 | (self.size as u64) << 32;
+                self.checksum = checksum(self.prev as usize, self.next as usize, misc);
+            }
 
-                if idx >= 65536 - 32 - 8 * $n {
-                    panic!("allocation error");
+            #[cfg(any(test, feature = "debug"))]
+            fn for_each_live(&self, f: &mut dyn FnMut(usize, usize)) {
+                for idx in 0..Self::CAPACITY {
+                    let idx1 = idx >> 6;
+                    let idx2 = idx & 0b111111;
+                    if self.l2_bitmap[idx1] & (1 << (63 - idx2)) != 0 {
+                        let addr =
+                            &self.buf[idx * $size + self.color as usize + 8] as *const u8 as usize;
+                        f(addr, $size - 8);
+                    }
                 }
+            }
+
+            // +------------------+
+            // | pointer to slab  |
+            // |    (8 bytes)     |
+            // +------------------+ <- return value
+            // |       data       |
+            // | (size - 8 bytes) |
+            // |                  |
+            /// allocate a memory region whose size is self.size - 8 bytes
+            fn alloc(&mut self) -> *mut u8 {
+                #[cfg(feature = "checksum")]
+                self.verify();
 
+                let idx = self.take_slot();
                 let ptr = &mut (self.buf[idx]) as *mut u8;
                 let ptr64 = ptr as *mut usize;
 
@@ -471,27 +1324,64 @@ macro_rules! SlabSmall {
                     *ptr64 = self as *mut $id as usize;
                 }
 
-                self.num += 1;
+                #[cfg(feature = "checksum")]
+                self.seal();
 
                 &mut (self.buf[idx + 8]) as *mut u8
             }
 
             /// deallocate the memory region pointed by ptr which is returned by alloc
             fn free(&mut self, ptr: *mut u8) {
-                let addr = ptr as usize - 8;
-                let org = self as *mut $id as usize;
-                let len = addr - org;
-                let idx = (len >> $shift) as usize;
+                #[cfg(feature = "checksum")]
+                self.verify();
 
-                let idx1 = idx >> 6; // divide by 64
-                let idx2 = idx & 0b111111;
+                let offset = self.slot_offset((ptr as usize).wrapping_sub(8));
 
-                self.l1_bitmap &= !(1 << (63 - idx1));
-                self.l2_bitmap[idx1] &= !(1 << (63 - idx2));
-                self.num -= 1;
+                // scrub the data, but keep the header
+                #[cfg(feature = "zero_on_free")]
+                unsafe {
+                    core::ptr::write_bytes(ptr, 0, $size - 8)
+                };
+
+                self.release_slot(offset);
+
+                #[cfg(feature = "checksum")]
+                self.seal();
+            }
+
+            fn alloc_external(&mut self) -> *mut u8 {
+                #[cfg(feature = "checksum")]
+                self.verify();
+
+                let idx = self.take_slot();
+
+                #[cfg(feature = "checksum")]
+                self.seal();
+
+                &mut (self.buf[idx]) as *mut u8
+            }
+
+            fn free_external(&mut self, ptr: *mut u8) {
+                #[cfg(feature = "checksum")]
+                self.verify();
+
+                let offset = self.slot_offset(ptr as usize);
+
+                #[cfg(feature = "zero_on_free")]
+                unsafe {
+                    core::ptr::write_bytes(ptr, 0, $size)
+                };
+
+                self.release_slot(offset);
+
+                #[cfg(feature = "checksum")]
+                self.seal();
             }
 
             fn is_full(&self) -> bool {
+                #[cfg(feature = "checksum")]
+                self.verify();
+
                 self.l1_bitmap == !0
             }
 
@@ -499,16 +1389,30 @@ macro_rules! SlabSmall {
                 self.num == 0
             }
 
-            fn init(&mut self) {
-                self.l1_bitmap = $l1val;
+            fn num(&self) -> usize {
+                self.num as usize
+            }
+
+            fn init(&mut self, color: usize) {
+                // words and slots beyond the capacity are used
+                self.l1_bitmap = low_bits(64 - Self::WORDS);
                 for it in self.l2_bitmap.iter_mut() {
                     *it = 0;
                 }
-                self.l2_bitmap[$n - 1] = $l2val;
+                self.l2_bitmap[Self::WORDS - 1] = low_bits(Self::WORDS * 64 - Self::CAPACITY);
                 self.prev = null_mut();
                 self.next = null_mut();
                 self.num = 0;
+                self.color = color as u16;
                 self.size = $size;
+
+                #[cfg(feature = "checksum")]
+                self.seal();
+
+                #[cfg(all(feature = "debug", not(feature = "checksum")))]
+                {
+                    self.checksum = LIVE_MAGIC;
+                }
             }
 
             // fn print(&self) {
@@ -543,40 +1447,13 @@ macro_rules! SlabSmall {
     };
 }
 
-// l1_bitmap = 0 (initial value)
-// l2_bitmap[63] = 0xFFFF FFFF | 0b11 << 32 (initial value)
-// size = 16
-SlabSmall!(Slab16, 64, 4, 0, 0xFFFFFFFF | (0b11 << 32), 16);
-
-// l1_bitmap = 0xFFFF FFFF (initial value)
-// l2_bitmap[31] = 0b111111111 (initial value)
-// size = 32
-SlabSmall!(Slab32, 32, 5, 0xFFFFFFFF, 0b111111111, 32);
-
-// l1_bitmap = 0xFFFF FFFF FFFF (initial value)
-// l2_bitmap[15] = 0b111 (initial value)
-// size = 64
-SlabSmall!(Slab64, 16, 6, 0xFFFFFFFFFFFF, 0b111, 64);
-
-// l1_bitmap = 0xFFFF FFFF FFFF FF (initial value)
-// l2_bitmap[7] = 0b1 (initial value)
-// size = 128
-SlabSmall!(Slab128, 8, 7, 0xFFFFFFFFFFFFFF, 1, 128);
-
-// l1_bitmap = 0xFFFF FFFF FFFF FFF (initial value)
-// l2_bitmap[3] = 0b1 (initial value)
-// size = 256
-SlabSmall!(Slab256, 4, 8, 0xFFFFFFFFFFFFFFF, 1, 256);
-
-// l1_bitmap = 0x3FFF FFFF FFFF FFFF (initial value)
-// l2_bitmap[1] = 0b1 (initial value)
-// size = 512
-SlabSmall!(Slab512, 2, 9, 0x3FFFFFFFFFFFFFFF, 1, 512);
-
-// l1_bitmap = 0x7FFF FFFF FFFF FFFF (initial value)
-// l2_bitmap[0] = 0b1 (initial value)
-// size = 1024
-SlabSmall!(Slab1024, 1, 10, 0x7FFFFFFFFFFFFFFF, 1, 1024);
+SlabSmall!(Slab16, 16);
+SlabSmall!(Slab32, 32);
+SlabSmall!(Slab64, 64);
+SlabSmall!(Slab128, 128);
+SlabSmall!(Slab256, 256);
+SlabSmall!(Slab512, 512);
+SlabSmall!(Slab1024, 1024);
 
 #[repr(C)]
 struct SlabMemory {
@@ -584,25 +1461,62 @@ struct SlabMemory {
     slab: usize,
 }
 
+/// A slab of at most 64 objects of `$size` bytes on a page of `$page` bytes.
+/// The page is 64KiB unless given.
 macro_rules! SlabLarge {
-    ($id:ident, $l1val:expr, $size:expr) => {
+    ($id:ident, $size:expr) => {
+        SlabLarge!($id, $size, SIZE_64K);
+    };
+    ($id:ident, $size:expr, $page:expr) => {
         #[repr(C)]
         struct $id {
-            buf: [u8; 65504],
+            buf: [u8; $page - 32],
             prev: *mut $id,
             next: *mut $id,
             l1_bitmap: u64,
-            num: u32,
-            size: u32,
+            num: u16,
+            color: u16,
+            #[cfg_attr(not(any(feature = "checksum", feature = "debug")), allow(dead_code))]
+            checksum: u16,
+            size: u16,
+        }
+
+        // A slab fills its page, and `size` is its last field.
+        const _: () = assert!(core::mem::size_of::<$id>() == $page);
+        const _: () = assert!(($page - 32) / $size <= 64);
+
+        impl $id {
+            /// Mark the lowest free slot as used, and return the index of the slot.
+            fn take_slot(&mut self) -> usize {
+                let idx1 = (!self.l1_bitmap).leading_zeros() as usize;
+                self.l1_bitmap |= 1 << (63 - idx1);
+                self.num += 1;
+                idx1
+            }
+
+            /// Mark the slot of `idx1` as free.
+            fn release_slot(&mut self, idx1: usize) {
+                self.l1_bitmap &= !(1 << (63 - idx1));
+                self.num -= 1;
+            }
         }
 
         impl Slab for $id {
+            const PAGE_SIZE: usize = $page;
+
+            const CAPACITY: usize = ($page - 32) / $size;
+
+            const COLORS: usize = ($page - 32) % $size / CACHE_LINE + 1;
+
             fn next(&self) -> *mut Self {
                 self.next
             }
 
             fn set_next(&mut self, next: *mut Self) {
                 self.next = next;
+
+                #[cfg(feature = "checksum")]
+                self.seal();
             }
 
             fn prev(&self) -> *mut Self {
@@ -611,6 +1525,32 @@ macro_rules! SlabLarge {
 
             fn set_prev(&mut self, prev: *mut Self) {
                 self.prev = prev;
+
+                #[cfg(feature = "checksum")]
+                self.seal();
+            }
+
+            #[cfg(feature = "checksum")]
+            fn sealed(&self) -> bool {
+                let misc = self.num as u64 | (self.color as u64) << 16 | (self.size as u64) << 32;
+                checksum(self.prev as usize, self.next as usize, misc) == self.checksum
+            }
+
+            #[cfg(feature = "checksum")]
+            fn seal(&mut self) {
+                let misc = self.num as u64 | (self.color as u64) << 16 | (self.size as u64) << 32;
+                self.checksum = checksum(self.prev as usize, self.next as usize, misc);
+            }
+
+            #[cfg(any(test, feature = "debug"))]
+            fn for_each_live(&self, f: &mut dyn FnMut(usize, usize)) {
+                for idx in 0..Self::CAPACITY {
+                    if self.l1_bitmap & (1 << (63 - idx)) != 0 {
+                        let addr =
+                            &self.buf[idx * $size + self.color as usize + 16] as *const u8 as usize;
+                        f(addr, $size - 16);
+                    }
+                }
             }
 
             // +-------------------+
@@ -625,10 +1565,11 @@ macro_rules! SlabLarge {
             // |                   |
             /// allocate a memory region whose size is self.size - 16 bytes
             fn alloc(&mut self) -> *mut u8 {
-                let idx1 = (!self.l1_bitmap).leading_zeros() as usize;
-                self.l1_bitmap |= 1 << (63 - idx1);
+                #[cfg(feature = "checksum")]
+                self.verify();
 
-                let idx = idx1 * self.size as usize;
+                let idx1 = self.take_slot();
+                let idx = idx1 * self.size as usize + self.color as usize;
                 let ptr = &mut (self.buf[idx]) as *mut u8;
                 let mem = ptr as *mut SlabMemory;
 
@@ -638,21 +1579,73 @@ macro_rules! SlabLarge {
                     (*mem).slab = self as *mut $id as usize;
                 }
 
-                self.num += 1;
+                #[cfg(feature = "checksum")]
+                self.seal();
 
                 &mut (self.buf[idx + 16]) as *mut u8
             }
 
             /// deallocate the memory region pointed by ptr which is returned by alloc
             fn free(&mut self, ptr: *mut u8) {
+                #[cfg(feature = "checksum")]
+                self.verify();
+
+                // scrub the data, but keep the header
+                #[cfg(feature = "zero_on_free")]
+                unsafe {
+                    core::ptr::write_bytes(ptr, 0, $size - 16)
+                };
+
                 let addr = ptr as usize;
                 let idx1 = unsafe { *((addr - 16) as *mut usize) };
+                self.release_slot(idx1);
 
-                self.l1_bitmap &= !(1 << (63 - idx1));
-                self.num -= 1;
+                #[cfg(feature = "checksum")]
+                self.seal();
+            }
+
+            fn alloc_external(&mut self) -> *mut u8 {
+                #[cfg(feature = "checksum")]
+                self.verify();
+
+                let idx1 = self.take_slot();
+                let idx = idx1 * self.size as usize + self.color as usize;
+
+                #[cfg(feature = "checksum")]
+                self.seal();
+
+                &mut (self.buf[idx]) as *mut u8
+            }
+
+            fn free_external(&mut self, ptr: *mut u8) {
+                #[cfg(feature = "checksum")]
+                self.verify();
+
+                let org = self as *mut $id as usize;
+                let idx = (ptr as usize).wrapping_sub(org);
+                let color = self.color as usize;
+                if idx < color
+                    || idx - color >= Self::CAPACITY * $size
+                    || (idx - color) % $size != 0
+                {
+                    panic!("invalid slab pointer");
+                }
+
+                #[cfg(feature = "zero_on_free")]
+                unsafe {
+                    core::ptr::write_bytes(ptr, 0, $size)
+                };
+
+                self.release_slot((idx - color) / $size);
+
+                #[cfg(feature = "checksum")]
+                self.seal();
             }
 
             fn is_full(&self) -> bool {
+                #[cfg(feature = "checksum")]
+                self.verify();
+
                 self.l1_bitmap == !0
             }
 
@@ -660,12 +1653,26 @@ macro_rules! SlabLarge {
                 self.num == 0
             }
 
-            fn init(&mut self) {
+            fn num(&self) -> usize {
+                self.num as usize
+            }
+
+            fn init(&mut self, color: usize) {
                 self.prev = null_mut();
                 self.next = null_mut();
-                self.l1_bitmap = $l1val;
+                // slots beyond the capacity are used
+                self.l1_bitmap = low_bits(64 - Self::CAPACITY);
                 self.size = $size;
                 self.num = 0;
+                self.color = color as u16;
+
+                #[cfg(feature = "checksum")]
+                self.seal();
+
+                #[cfg(all(feature = "debug", not(feature = "checksum")))]
+                {
+                    self.checksum = LIVE_MAGIC;
+                }
             }
 
             // fn print(&self) {
@@ -685,42 +1692,62 @@ macro_rules! SlabLarge {
     };
 }
 
-// l1_bitmap = 0xFFFF FFFF (initial value)
-// size = 2040
-SlabLarge!(Slab2040, 0xFFFFFFFF, 2040);
-
-// l1_bitmap = 0xFFFF FFFF FFFF (initial value)
-// size = 4088
-SlabLarge!(Slab4088, 0xFFFFFFFFFFFF, 4088);
-
-// l1_bitmap = 0xFFFF FFFF FFFF FF (initial value)
-// size = 8184
-SlabLarge!(Slab8184, 0xFFFFFFFFFFFFFF, 8184);
-
-// l1_bitmap = 0xFFFF FFFF FFFF FFF (initial value)
-// size = 16376
-SlabLarge!(Slab16376, 0xFFFFFFFFFFFFFFF, 16376);
-
-// l1_bitmap = 0x3FFF FFFF FFFF FFFF (initial value)
-// size = 32752
-SlabLarge!(Slab32752, 0x3FFFFFFFFFFFFFFF, 32752);
-
+SlabLarge!(Slab2040, 2040);
+SlabLarge!(Slab4088, 4088);
+SlabLarge!(Slab8184, 8184);
+SlabLarge!(Slab16376, 16376);
+SlabLarge!(Slab32752, 32752);
+
+// Slabs on pages smaller than 64KiB, whose geometry is checked by `test_page_size`.
+// `SlabAllocator` finds the class of a slab at the end of a 64KiB page,
+// so its classes are on 64KiB pages.
+#[cfg(test)]
+SlabSmall!(Slab16Page4K, 16, 4096);
+#[cfg(test)]
+SlabSmall!(Slab256Page16K, 256, 16384);
+#[cfg(test)]
+SlabLarge!(Slab2040Page16K, 2040, 16384);
+
+/// A slab holding a single object, which is always full.
+///
+/// There is no class between 32752 and 65512 bytes,
+/// because a slab is a single 64KiB page and two objects larger than 32752 bytes never fit in one.
+/// Such an object would take a whole page anyway, so it is served by this class,
+/// and requests larger than `MAX_SLAB_SIZE` go to the page allocator.
 #[repr(C)]
 struct Slab65512 {
     buf: [u8; 65512],
     prev: *mut Slab65512,
     next: *mut Slab65512,
     num: u32,
-    size: u32, // must be 65512
+    #[cfg_attr(not(any(feature = "checksum", feature = "debug")), allow(dead_code))]
+    checksum: u16,
+    size: u16, // must be 65512
 }
 
 impl Slab for Slab65512 {
+    const PAGE_SIZE: usize = SIZE_64K;
+
+    const CAPACITY: usize = 1;
+
+    const COLORS: usize = 1;
+
     fn next(&self) -> *mut Self {
         self.next
     }
 
+    #[cfg(any(test, feature = "debug"))]
+    fn for_each_live(&self, f: &mut dyn FnMut(usize, usize)) {
+        if self.num != 0 {
+            f(&self.buf[8] as *const u8 as usize, 65504);
+        }
+    }
+
     fn set_next(&mut self, next: *mut Self) {
         self.next = next;
+
+        #[cfg(feature = "checksum")]
+        self.seal();
     }
 
     fn prev(&self) -> *mut Self {
@@ -729,6 +1756,21 @@ impl Slab for Slab65512 {
 
     fn set_prev(&mut self, prev: *mut Self) {
         self.prev = prev;
+
+        #[cfg(feature = "checksum")]
+        self.seal();
+    }
+
+    #[cfg(feature = "checksum")]
+    fn sealed(&self) -> bool {
+        let misc = self.num as u64 | (self.size as u64) << 32;
+        checksum(self.prev as usize, self.next as usize, misc) == self.checksum
+    }
+
+    #[cfg(feature = "checksum")]
+    fn seal(&mut self) {
+        let misc = self.num as u64 | (self.size as u64) << 32;
+        self.checksum = checksum(self.prev as usize, self.next as usize, misc);
     }
 
     // +------------------+
@@ -740,6 +1782,9 @@ impl Slab for Slab65512 {
     // |                  |
     /// allocate a memory region whose size is 65504 bytes
     fn alloc(&mut self) -> *mut u8 {
+        #[cfg(feature = "checksum")]
+        self.verify();
+
         let ptr = &mut (self.buf[0]) as *mut u8;
         let ptr64 = ptr as *mut usize;
 
@@ -750,14 +1795,63 @@ impl Slab for Slab65512 {
 
         self.num = 1;
 
+        #[cfg(feature = "checksum")]
+        self.seal();
+
         &mut (self.buf[8]) as *mut u8
     }
 
     fn free(&mut self, _ptr: *mut u8) {
+        #[cfg(feature = "checksum")]
+        self.verify();
+
+        // scrub the data, but keep the header
+        #[cfg(feature = "zero_on_free")]
+        unsafe {
+            core::ptr::write_bytes(_ptr, 0, 65504)
+        };
+
+        self.num = 0;
+
+        #[cfg(feature = "checksum")]
+        self.seal();
+    }
+
+    fn alloc_external(&mut self) -> *mut u8 {
+        #[cfg(feature = "checksum")]
+        self.verify();
+
+        self.num = 1;
+
+        #[cfg(feature = "checksum")]
+        self.seal();
+
+        &mut (self.buf[0]) as *mut u8
+    }
+
+    fn free_external(&mut self, ptr: *mut u8) {
+        #[cfg(feature = "checksum")]
+        self.verify();
+
+        if !core::ptr::eq(ptr, &self.buf[0]) {
+            panic!("invalid slab pointer");
+        }
+
+        #[cfg(feature = "zero_on_free")]
+        unsafe {
+            core::ptr::write_bytes(ptr, 0, 65512)
+        };
+
         self.num = 0;
+
+        #[cfg(feature = "checksum")]
+        self.seal();
     }
 
     fn is_full(&self) -> bool {
+        #[cfg(feature = "checksum")]
+        self.verify();
+
         true
     }
 
@@ -765,11 +1859,23 @@ impl Slab for Slab65512 {
         self.num == 0
     }
 
-    fn init(&mut self) {
+    fn num(&self) -> usize {
+        self.num as usize
+    }
+
+    fn init(&mut self, _color: usize) {
         self.next = null_mut();
         self.prev = null_mut();
         self.size = 65512;
         self.num = 0;
+
+        #[cfg(feature = "checksum")]
+        self.seal();
+
+        #[cfg(all(feature = "debug", not(feature = "checksum")))]
+        {
+            self.checksum = LIVE_MAGIC;
+        }
     }
 
     // fn print(&self) {
@@ -824,3 +1930,522 @@ impl Slab for Slab65512 {
 //     print_slabs!("slab65512", slab65512_partial, slab65512_full);
 //     driver::uart::puts("\n");
 // }
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{class_index, class_size, usable_size, SlabAllocator, CLASS_SIZES, MAX_SLAB_SIZE};
+    use crate::{pager::PageManager, MemAlloc, SIZE_64K};
+
+    const HEAP_SIZE: usize = 32 * 1024 * 1024;
+
+    fn init() -> (SlabAllocator<PageManager>, *mut u8) {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        (SlabAllocator::new(ptr as usize, HEAP_SIZE), ptr)
+    }
+
+    fn free(ptr: *mut u8) {
+        let layout = std::alloc::Layout::from_size_align(HEAP_SIZE, crate::ALIGNMENT).unwrap();
+        unsafe { std::alloc::dealloc(ptr, layout) };
+    }
+
+    /// Run `f`, which is expected to panic, on a fresh allocator,
+    /// and free the heap before the panic goes on.
+    fn init_panicking(f: impl FnOnce(&mut SlabAllocator<PageManager>, *mut u8)) {
+        let (mut slab, ptr) = init();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(&mut slab, ptr)));
+        free(ptr);
+        if let Err(err) = result {
+            std::panic::resume_unwind(err);
+        }
+    }
+
+    /// Return the class of the slab which `ptr` belongs to.
+    unsafe fn class_of(ptr: *mut u8) -> usize {
+        let addr_slab = *((ptr as usize - 8) as *const u64);
+        super::class_size_of_slab(addr_slab as usize)
+    }
+
+    #[test]
+    fn test_class_boundary() {
+        const CLASSES: [usize; 13] = [
+            16, 32, 64, 128, 256, 512, 1024, 2040, 4088, 8184, 16376, 32752, 65512,
+        ];
+
+        let (mut slab, ptr) = init();
+
+        let mut sizes = std::vec![0];
+        for shift in 3..=10 {
+            let n = 1 << shift;
+            sizes.extend([n - 1, n, n + 1]);
+        }
+        for class in CLASSES {
+            let usable = usable_size(class);
+            sizes.extend([usable - 1, usable, usable + 1]);
+        }
+
+        for size in sizes {
+            // the smallest class which can hold `size` bytes
+            let expected = CLASSES.iter().find(|c| usable_size(**c) >= size).copied();
+            assert_eq!(class_size(size), expected, "size = {size}");
+
+            if size > MAX_SLAB_SIZE {
+                assert_eq!(expected, None);
+                assert_eq!(unsafe { slab.slab_alloc(size) }, None);
+                continue;
+            }
+
+            let mem = unsafe { slab.slab_alloc(size) }.unwrap();
+            assert_eq!(unsafe { class_of(mem) }, expected.unwrap(), "size = {size}");
+
+            // all usable bytes can be written
+            unsafe { core::ptr::write_bytes(mem, 0xFF, size) };
+
+            unsafe { slab.slab_dealloc(mem) };
+        }
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_dispatch_all_sizes() {
+        let (mut slab, ptr) = init();
+
+        for size in 0..=MAX_SLAB_SIZE {
+            let mem = unsafe { slab.slab_alloc(size) }.unwrap();
+            assert_eq!(
+                unsafe { class_of(mem) },
+                class_size(size).unwrap(),
+                "size = {size}"
+            );
+            unsafe { slab.slab_dealloc(mem) };
+        }
+
+        assert_eq!(unsafe { slab.slab_alloc(MAX_SLAB_SIZE + 1) }, None);
+
+        free(ptr);
+    }
+
+    /// Measure allocation throughput with a size mix dominated by small objects.
+    ///
+    /// Run by `cargo test --release -- --ignored --nocapture bench_slab_alloc`.
+    #[test]
+    #[ignore]
+    fn bench_slab_alloc() {
+        use rand::{Rng, SeedableRng};
+
+        const LIVE: usize = 1024;
+        const ITER: usize = 4_000_000;
+
+        let (mut slab, ptr) = init();
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let sizes: std::vec::Vec<usize> = (0..ITER)
+            .map(|_| match rng.gen_range(0..100) {
+                0..=69 => rng.gen_range(1..=64),
+                70..=89 => rng.gen_range(65..=512),
+                90..=98 => rng.gen_range(513..=4096),
+                _ => rng.gen_range(4097..=MAX_SLAB_SIZE),
+            })
+            .collect();
+
+        let mut live: [*mut u8; LIVE] = [core::ptr::null_mut(); LIVE];
+
+        let start = std::time::Instant::now();
+        for (i, size) in sizes.iter().enumerate() {
+            let slot = &mut live[i % LIVE];
+            if !slot.is_null() {
+                unsafe { slab.slab_dealloc(*slot) };
+            }
+            *slot = unsafe { slab.slab_alloc(*size) }.unwrap();
+        }
+        let elapsed = start.elapsed();
+
+        for mem in live {
+            unsafe { slab.slab_dealloc(mem) };
+        }
+
+        std::println!(
+            "{} allocations in {:?} ({:.1} Mops/s)",
+            ITER,
+            elapsed,
+            ITER as f64 / elapsed.as_secs_f64() / 1e6
+        );
+
+        free(ptr);
+    }
+
+    /// Compare the table lookup of `class_index` with a cascade of comparisons,
+    /// which was used before, on a size mix dominated by small objects.
+    ///
+    /// Run by `cargo test --release -- --ignored --nocapture bench_class_index`.
+    #[test]
+    #[ignore]
+    fn bench_class_index() {
+        use rand::{Rng, SeedableRng};
+
+        const ITER: usize = 16_000_000;
+
+        fn cascade(size: usize) -> Option<usize> {
+            CLASS_SIZES.iter().position(|c| usable_size(*c) >= size)
+        }
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let sizes: std::vec::Vec<usize> = (0..ITER)
+            .map(|_| match rng.gen_range(0..100) {
+                0..=69 => rng.gen_range(1..=64),
+                70..=89 => rng.gen_range(65..=512),
+                90..=98 => rng.gen_range(513..=4096),
+                _ => rng.gen_range(4097..=MAX_SLAB_SIZE),
+            })
+            .collect();
+
+        let run = |name: &str, f: fn(usize) -> Option<usize>| {
+            let start = std::time::Instant::now();
+            let sum: usize = sizes
+                .iter()
+                .map(|size| f(core::hint::black_box(*size)).unwrap())
+                .sum();
+            let elapsed = start.elapsed();
+
+            std::println!(
+                "{name}: {:.1} Mops/s (checksum = {sum})",
+                ITER as f64 / elapsed.as_secs_f64() / 1e6
+            );
+            sum
+        };
+
+        assert_eq!(run("table", class_index), run("cascade", cascade));
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid slab pointer")]
+    fn test_dealloc_invalid_back_pointer() {
+        init_panicking(|slab, ptr| {
+            // A page which is not a slab, whose header points right after the heap.
+            let page = slab.page_alloc.alloc(SIZE_64K).unwrap();
+            unsafe {
+                *(page.add(8) as *mut usize) = ptr as usize + HEAP_SIZE;
+                slab.slab_dealloc(page.add(16));
+            }
+        });
+    }
+
+    #[test]
+    fn test_dealloc_non_slab_page() {
+        init_panicking(|slab, _ptr| {
+            let page = slab.page_alloc.alloc(SIZE_64K).unwrap();
+            let addr = page as usize;
+
+            // A page which is not a slab: its class word is not a class,
+            // or its header has a tag larger than the class or points to another page.
+            for (header, class) in [
+                (addr, 0),
+                (addr, 8),
+                (addr | 100, 16),
+                (addr + SIZE_64K, 16),
+            ] {
+                unsafe {
+                    *(page.add(8) as *mut usize) = header;
+                    *(page.add(SIZE_64K - 2) as *mut u16) = class;
+                }
+                let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| unsafe {
+                    slab.slab_dealloc(page.add(16))
+                }));
+                let err = result.unwrap_err();
+                assert_eq!(
+                    err.downcast_ref::<&str>(),
+                    Some(&"invalid slab pointer"),
+                    "header = {header:#x}, class = {class}"
+                );
+            }
+
+            slab.page_alloc.free(page);
+        });
+    }
+
+    #[test]
+    fn test_free_misaligned() {
+        use super::{Slab, Slab16Page4K};
+
+        let layout = std::alloc::Layout::from_size_align(4096, 4096).unwrap();
+        let mem = unsafe { std::alloc::alloc(layout) };
+        let slab = unsafe { &mut *(mem as *mut Slab16Page4K) };
+        slab.init(0);
+
+        let ptr = slab.alloc();
+        let last = (0..Slab16Page4K::CAPACITY - 1)
+            .map(|_| slab.alloc())
+            .last()
+            .unwrap();
+
+        // off a slot boundary, and past the last slot
+        let misaligned = unsafe { ptr.add(4) };
+        let beyond = unsafe { last.add(16) };
+        for bad in [misaligned, beyond] {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| slab.free(bad)));
+            assert!(result.is_err());
+        }
+        assert_eq!(slab.num(), Slab16Page4K::CAPACITY);
+
+        slab.free(ptr);
+        assert_eq!(slab.alloc(), ptr);
+
+        unsafe { std::alloc::dealloc(mem, layout) };
+    }
+
+    #[test]
+    fn test_ascending_order() {
+        let (mut slab, ptr) = init();
+        slab.cache_coloring = true;
+
+        for (idx, class_size) in CLASS_SIZES.iter().enumerate() {
+            let n = super::object_count(*class_size);
+            let v: std::vec::Vec<_> = (0..n)
+                .map(|_| unsafe { slab.slab_alloc_index(idx).unwrap() })
+                .collect();
+
+            // a fresh slab hands out its slots from the lowest address
+            assert!(v.windows(2).all(|w| w[0] < w[1]), "class {class_size}");
+            assert!(v
+                .iter()
+                .all(|p| *p as usize & !(SIZE_64K - 1) == v[0] as usize & !(SIZE_64K - 1)));
+
+            // and reuses the lowest free slot
+            if n > 2 {
+                unsafe {
+                    slab.slab_dealloc(v[n - 1]);
+                    slab.slab_dealloc(v[1]);
+                    assert_eq!(slab.slab_alloc_index(idx), Some(v[1]));
+                    assert_eq!(slab.slab_alloc_index(idx), Some(v[n - 1]));
+                }
+            }
+
+            for p in v {
+                unsafe { slab.slab_dealloc(p) };
+            }
+        }
+
+        free(ptr);
+    }
+
+    /// A backend which hands out 4KiB aligned pages.
+    struct Misaligned;
+
+    impl MemAlloc for Misaligned {
+        fn alloc(&mut self, _size: usize) -> Option<*mut u8> {
+            None
+        }
+
+        fn free(&mut self, _addr: *mut u8) {}
+
+        fn new(_start_addr: usize, _size: usize) -> Self {
+            Misaligned
+        }
+
+        fn range(&self) -> (usize, usize) {
+            (0, 0)
+        }
+
+        fn page_alignment(&self) -> usize {
+            4096
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "pages of the backend are not 64KiB aligned")]
+    fn test_misaligned_backend() {
+        SlabAllocator::<Misaligned>::new(0, HEAP_SIZE);
+    }
+
+    /// Fill a slab of `S`, whose objects are `size` bytes with a header of `header` bytes,
+    /// and free all of them.
+    fn fill_slab<S: super::Slab>(size: usize, header: usize) {
+        let page = S::PAGE_SIZE;
+        let layout = std::alloc::Layout::from_size_align(page, page).unwrap();
+        let mem = unsafe { std::alloc::alloc(layout) };
+        let slab = unsafe { &mut *(mem as *mut S) };
+        slab.init(0);
+
+        let mut v = std::vec::Vec::new();
+        while !slab.is_full() {
+            v.push(slab.alloc() as usize);
+        }
+        assert_eq!(v.len(), S::CAPACITY);
+
+        // objects are packed from the start of the page, before the 32-byte tail at least
+        for (i, ptr) in v.iter().enumerate() {
+            assert_eq!(ptr - header, mem as usize + i * size);
+            assert!(ptr - header + size <= mem as usize + page - 32);
+            assert_eq!(unsafe { *((ptr - 8) as *const usize) }, mem as usize);
+        }
+
+        for ptr in v.iter() {
+            slab.free(*ptr as *mut u8);
+        }
+        assert!(slab.is_empty());
+        assert_eq!(slab.alloc() as usize, v[0]);
+
+        unsafe { std::alloc::dealloc(mem, layout) };
+    }
+
+    #[test]
+    fn test_page_size() {
+        use super::{low_bits, small_words, Slab, Slab16Page4K, Slab2040Page16K, Slab256Page16K};
+
+        // 4096 - 32 - 8 * 4 bytes for 252 objects, tracked by 4 words
+        assert_eq!(small_words(4096, 16), 4);
+        assert_eq!(Slab16Page4K::CAPACITY, 252);
+        fill_slab::<Slab16Page4K>(16, 8);
+
+        assert_eq!(small_words(16384, 256), 1);
+        assert_eq!(Slab256Page16K::CAPACITY, 63);
+        fill_slab::<Slab256Page16K>(256, 8);
+
+        assert_eq!(Slab2040Page16K::CAPACITY, 8);
+        fill_slab::<Slab2040Page16K>(2040, 16);
+
+        // the geometry of 64KiB pages
+        let words = [64, 32, 16, 8, 4, 2, 1];
+        let l1 = [
+            0,
+            0xFFFFFFFF,
+            0xFFFFFFFFFFFF,
+            0xFFFFFFFFFFFFFF,
+            0xFFFFFFFFFFFFFFF,
+            0x3FFFFFFFFFFFFFFF,
+            0x7FFFFFFFFFFFFFFF,
+        ];
+        let l2 = [0xFFFFFFFF | (0b11 << 32), 0b111111111, 0b111, 1, 1, 1, 1];
+        for (i, size) in CLASS_SIZES[..7].iter().enumerate() {
+            let n = small_words(SIZE_64K, *size);
+            let capacity = (SIZE_64K - 32 - 8 * n) / size;
+            assert_eq!(n, words[i]);
+            assert_eq!(low_bits(64 - n), l1[i]);
+            assert_eq!(low_bits(n * 64 - capacity), l2[i]);
+        }
+    }
+
+    #[test]
+    fn test_page_size_lists() {
+        use super::{alloc_memory, dealloc_memory, Slab, Slab16Page4K};
+
+        let (mut slab, ptr) = init();
+        let page_alloc = &mut slab.page_alloc;
+        let before = page_alloc.free_bytes();
+        let (mut partial, mut full) = (core::ptr::null_mut(), core::ptr::null_mut());
+
+        // the first slab becomes full, and the last object is on a second slab
+        let capacity = Slab16Page4K::CAPACITY;
+        let v: std::vec::Vec<*mut u8> = (0..=capacity)
+            .map(|_| unsafe {
+                alloc_memory::<_, Slab16Page4K>(
+                    page_alloc,
+                    &mut partial,
+                    &mut full,
+                    None,
+                    false,
+                    |_, _| {},
+                )
+            })
+            .collect::<Option<_>>()
+            .unwrap();
+
+        // slabs are found by masking objects with their page size
+        let page = |p: *mut u8| p as usize & !(Slab16Page4K::PAGE_SIZE - 1);
+        assert!(v[..capacity].iter().all(|p| page(*p) == full as usize));
+        assert_eq!(page(v[capacity]), partial as usize);
+
+        // and their pages are freed when they become empty
+        let unmapped = v
+            .iter()
+            .filter_map(|p| unsafe {
+                dealloc_memory(*p, page(*p), page_alloc, &mut partial, &mut full, 0, false)
+            })
+            .count();
+        assert_eq!(unmapped, 2);
+        assert!(partial.is_null() && full.is_null());
+        assert_eq!(page_alloc.free_bytes(), before);
+
+        free(ptr);
+    }
+
+    #[test]
+    fn test_whole_page_class() {
+        let (mut slab, ptr) = init();
+
+        // the largest class packing two objects into a page
+        assert_eq!(super::object_count(32752), 2);
+        assert_eq!(super::object_count(65512), 1);
+
+        let a = unsafe { slab.slab_alloc(usable_size(32752)) }.unwrap();
+        let b = unsafe { slab.slab_alloc(usable_size(32752)) }.unwrap();
+        assert_eq!(a as usize & !(SIZE_64K - 1), b as usize & !(SIZE_64K - 1));
+
+        // a larger object takes a whole page
+        let c = unsafe { slab.slab_alloc(usable_size(32752) + 1) }.unwrap();
+        assert_eq!(unsafe { class_of(c) }, 65512);
+        let pages = slab.class_pages();
+        assert_eq!(pages[11], 1);
+        assert_eq!(pages[12], 1);
+
+        unsafe {
+            slab.slab_dealloc(a);
+            slab.slab_dealloc(b);
+            slab.slab_dealloc(c);
+        }
+        assert_eq!(slab.class_pages(), [0; super::NUM_CLASSES]);
+
+        free(ptr);
+    }
+
+    #[cfg(feature = "checksum")]
+    #[test]
+    #[should_panic(expected = "corrupted slab metadata")]
+    fn test_checksum() {
+        init_panicking(|slab, _ptr| {
+            let mem = unsafe { slab.slab_alloc(8) }.unwrap();
+            let addr_slab = unsafe { *((mem as usize - 8) as *const usize) };
+
+            // an overrun into the metadata of the slab
+            let next = addr_slab + core::mem::offset_of!(super::Slab16, next);
+            unsafe { *(next as *mut usize) = 0xdead_0000 };
+
+            unsafe { slab.slab_alloc(8) };
+        });
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    #[should_panic(expected = "page of a live slab is allocated again")]
+    fn test_live_page() {
+        init_panicking(|slab, _ptr| {
+            let mem = unsafe { slab.slab_alloc(8) }.unwrap();
+            let addr_slab = unsafe { *((mem as usize - 8) as *const usize) };
+
+            // a bug of the page allocator: the page of the live slab is handed out again
+            slab.page_alloc.free(addr_slab as *mut u8);
+            unsafe { slab.slab_alloc(100) };
+        });
+    }
+
+    #[cfg(feature = "debug")]
+    #[test]
+    fn test_released_page() {
+        let (mut slab, ptr) = init();
+
+        // pages of released slabs are reused without false alarms
+        for size in [8, 100, 2000, 60000] {
+            let mem = unsafe { slab.slab_alloc(size) }.unwrap();
+            unsafe { slab.slab_dealloc(mem) };
+        }
+
+        assert_eq!(slab.prewarm(16, 2), 2);
+        slab.reclaim(&mut |_| {});
+        assert_eq!(slab.prewarm(32, 2), 2);
+
+        free(ptr);
+    }
+}
@@ -0,0 +1,229 @@
+//! A page allocator delegating to the global allocator of `std`, for host-side testing.
+//!
+//! ```
+//! use memac::{stdpager::StdPager, Allocator};
+//! use core::alloc::{GlobalAlloc, Layout};
+//!
+//! let alloc = Allocator::<StdPager>::with_std_pager();
+//!
+//! let layout = Layout::from_size_align(128, 32).unwrap();
+//! let mem = unsafe { alloc.alloc(layout) };
+//! unsafe { alloc.dealloc(mem, layout) };
+//! ```
+
+extern crate std;
+
+use crate::{Allocator, MemAlloc, MASK_64K, SIZE_64K};
+use core::alloc::Layout;
+use std::vec::Vec;
+
+/// A page allocator handing out 64KiB aligned blocks allocated by `std::alloc::alloc`.
+///
+/// There is no heap to reserve in advance, and the number of pages is limited only by the host.
+/// Blocks which are still allocated are returned to `std` when the pager is dropped.
+pub struct StdPager {
+    blocks: Vec<(usize, Layout)>, // handed-out blocks
+    used: usize,                  // sum of the sizes of `blocks`
+}
+
+impl StdPager {
+    fn alloc_layout(&mut self, layout: Layout) -> Option<*mut u8> {
+        let ptr = unsafe { std::alloc::alloc(layout) };
+        if ptr.is_null() {
+            return None;
+        }
+
+        // The block may hold the metadata of a slab left by a pager dropped with live objects,
+        // but `std` never hands out memory in use.
+        #[cfg(feature = "debug")]
+        unsafe {
+            crate::slab::release_slab(ptr as usize)
+        };
+
+        self.blocks.push((ptr as usize, layout));
+        self.used += layout.size();
+        Some(ptr)
+    }
+}
+
+impl MemAlloc for StdPager {
+    fn alloc(&mut self, size: usize) -> Option<*mut u8> {
+        self.alloc_aligned(size, SIZE_64K)
+    }
+
+    fn free(&mut self, addr: *mut u8) {
+        let Some(idx) = self.blocks.iter().position(|(a, _)| *a == addr as usize) else {
+            panic!("invalid address");
+        };
+
+        let (addr, layout) = self.blocks.swap_remove(idx);
+        self.used -= layout.size();
+        unsafe { std::alloc::dealloc(addr as *mut u8, layout) };
+    }
+
+    fn alloc_aligned(&mut self, size: usize, align: usize) -> Option<*mut u8> {
+        let size = size.max(1).checked_next_multiple_of(SIZE_64K)?;
+        let layout = Layout::from_size_align(size, align.max(SIZE_64K)).ok()?;
+        self.alloc_layout(layout)
+    }
+
+    /// The global allocator of `std` satisfies any valid alignment.
+    fn max_alignment(&self) -> usize {
+        1 << (usize::BITS - 1)
+    }
+
+    /// Blocks can be anywhere, so the whole address space is reported.
+    fn range(&self) -> (usize, usize) {
+        (0, usize::MAX)
+    }
+
+    fn for_each_used(&self, f: &mut dyn FnMut(usize, usize)) {
+        for (addr, layout) in self.blocks.iter() {
+            f(*addr, layout.size());
+        }
+    }
+
+    fn free_bytes(&self) -> usize {
+        usize::MAX - self.used
+    }
+
+    fn free_all(&mut self) {
+        for (addr, layout) in self.blocks.drain(..) {
+            unsafe { std::alloc::dealloc(addr as *mut u8, layout) };
+        }
+        self.used = 0;
+    }
+
+    /// The arguments are ignored, because pages are allocated by `std`.
+    fn new(_start_addr: usize, _size: usize) -> Self {
+        StdPager {
+            blocks: Vec::new(),
+            used: 0,
+        }
+    }
+}
+
+impl Drop for StdPager {
+    fn drop(&mut self) {
+        self.free_all();
+    }
+}
+
+impl Allocator<StdPager> {
+    /// Return an allocator taking pages from `std` on demand.
+    ///
+    /// The allocator covers the whole address space instead of a heap.
+    pub fn with_std_pager() -> Self {
+        // The heap spans the whole address space, but pages come from the global allocator,
+        // so it is not registered as a heap overlapping others.
+        let mut alloc = Allocator::new();
+        alloc
+            .try_init_heap(0, usize::MAX & !MASK_64K, false)
+            .unwrap();
+        alloc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::StdPager;
+    use crate::{Allocator, MemAlloc, SIZE_64K};
+    use core::alloc::{GlobalAlloc, Layout};
+    use rand::{Rng, SeedableRng};
+    use std::vec::Vec;
+
+    #[test]
+    fn test_std_pager() {
+        let mut pager = StdPager::new(0, 0);
+
+        let a = pager.alloc(1).unwrap();
+        let b = pager.alloc(SIZE_64K + 1).unwrap();
+        let c = pager.alloc_aligned(SIZE_64K, SIZE_64K * 4).unwrap();
+        assert_eq!(a as usize % SIZE_64K, 0);
+        assert_eq!(b as usize % SIZE_64K, 0);
+        assert_eq!(c as usize % (SIZE_64K * 4), 0);
+        assert_eq!(pager.free_bytes(), usize::MAX - SIZE_64K * 4);
+
+        let mut used = 0;
+        pager.for_each_used(&mut |_, size| used += size);
+        assert_eq!(used, SIZE_64K * 4);
+
+        pager.free(b);
+        assert_eq!(pager.free_bytes(), usize::MAX - SIZE_64K * 2);
+
+        pager.free_all();
+        assert_eq!(pager.free_bytes(), usize::MAX);
+        pager.for_each_used(&mut |_, _| panic!("a block is left"));
+
+        // `d` is returned on drop
+        let _d = pager.alloc(SIZE_64K).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid address")]
+    fn test_std_pager_free_unknown() {
+        let mut pager = StdPager::new(0, 0);
+        let a = pager.alloc(SIZE_64K).unwrap();
+        pager.free(a);
+        pager.free(a);
+    }
+
+    #[test]
+    fn test_slab_stress() {
+        let alloc = Allocator::<StdPager>::with_std_pager();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let mut live = Vec::new();
+
+        for i in 0..20000 {
+            if live.is_empty() || rng.gen_bool(0.6) {
+                let size = rng.gen_range(1..=4096);
+                let align = 1 << rng.gen_range(0..=6);
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let mem = unsafe { alloc.alloc(layout) };
+                assert!(!mem.is_null());
+                assert_eq!(mem as usize % align, 0);
+                unsafe { core::ptr::write_bytes(mem, i as u8, size) };
+                live.push((mem, layout, i as u8));
+            } else {
+                let (mem, layout, val) = live.swap_remove(rng.gen_range(0..live.len()));
+                for j in 0..layout.size() {
+                    assert_eq!(unsafe { *mem.add(j) }, val);
+                }
+                unsafe { alloc.dealloc(mem, layout) };
+            }
+        }
+
+        for (mem, layout, _) in live.drain(..) {
+            unsafe { alloc.dealloc(mem, layout) };
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+    }
+
+    #[test]
+    fn test_free_unsized() {
+        let alloc = Allocator::<StdPager>::with_std_pager();
+
+        let layout = Layout::from_size_align(100, 64).unwrap();
+        let a = unsafe { alloc.alloc(layout) };
+        let b = unsafe { alloc.alloc(Layout::from_size_align(100, 8).unwrap()) };
+        unsafe {
+            alloc.free_unsized(a);
+            alloc.free_unsized(b);
+        }
+        assert_eq!(alloc.live_bytes(), 0);
+    }
+
+    #[test]
+    fn test_drop_live() {
+        // pages of slabs with live objects go back to `std` and may be handed out again
+        for _ in 0..4 {
+            let alloc = Allocator::<StdPager>::with_std_pager();
+            for size in [8, 100, 3000] {
+                let layout = Layout::from_size_align(size, 8).unwrap();
+                assert!(!unsafe { alloc.alloc(layout) }.is_null());
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+//! A side table recording live allocations with their sequence numbers.
+
+/// Maximum number of allocations which can be tracked at once.
+/// Allocations beyond this are not tracked.
+pub(crate) const TRACK_CAPACITY: usize = 1024;
+
+pub(crate) struct Tracker {
+    entries: [(usize, u64); TRACK_CAPACITY], // (address, sequence number), address 0 means empty
+    seq: u64,                                // sequence number of the last allocation
+}
+
+impl Tracker {
+    pub(crate) const fn new() -> Self {
+        Tracker {
+            entries: [(0, 0); TRACK_CAPACITY],
+            seq: 0,
+        }
+    }
+
+    pub(crate) fn insert(&mut self, addr: usize) {
+        self.seq += 1;
+        if let Some(entry) = self.entries.iter_mut().find(|(a, _)| *a == 0) {
+            *entry = (addr, self.seq);
+        }
+    }
+
+    pub(crate) fn remove(&mut self, addr: usize) {
+        if let Some(entry) = self.entries.iter_mut().find(|(a, _)| *a == addr) {
+            *entry = (0, 0);
+        }
+    }
+
+    /// Return the address of the oldest live allocation and
+    /// the number of allocations made after it.
+    pub(crate) fn oldest(&self) -> Option<(usize, u64)> {
+        self.entries
+            .iter()
+            .filter(|(addr, _)| *addr != 0)
+            .min_by_key(|(_, seq)| *seq)
+            .map(|(addr, seq)| (*addr, self.seq - seq))
+    }
+}